@@ -0,0 +1,102 @@
+//! Compare the io::Write and BufMut encoding paths
+
+use bytes::{BufMut, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rs9p::serialize::{self, Encodable};
+use rs9p::*;
+
+fn messages() -> Vec<(&'static str, Msg)> {
+    let mut dirents = DirEntryData::new();
+    for i in 0..64 {
+        dirents.push(DirEntry {
+            qid: Qid {
+                typ: QidType::FILE,
+                version: 0,
+                path: i,
+            },
+            offset: i,
+            typ: 0,
+            name: format!("file{}", i).into(),
+        });
+    }
+
+    vec![
+        (
+            "Rread 8K",
+            Msg {
+                tag: 1,
+                body: Fcall::Rread {
+                    data: Data(vec![0xa5; 8192]),
+                },
+            },
+        ),
+        (
+            "Twalk",
+            Msg {
+                tag: 1,
+                body: Fcall::Twalk {
+                    fid: 1,
+                    newfid: 2,
+                    wnames: vec!["usr".into(), "share".into(), "doc".into()],
+                },
+            },
+        ),
+        (
+            "Rreaddir 64",
+            Msg {
+                tag: 1,
+                body: Fcall::Rreaddir { data: dirents },
+            },
+        ),
+        (
+            "Rgetattr",
+            Msg {
+                tag: 1,
+                body: Fcall::Rgetattr {
+                    valid: GetattrMask::ALL,
+                    qid: Default::default(),
+                    stat: Stat {
+                        mode: FileMode::new(FileType::Regular, 0o644),
+                        uid: 1000,
+                        gid: 1000,
+                        nlink: 1,
+                        rdev: 0,
+                        size: 4096,
+                        blksize: 4096,
+                        blocks: 8,
+                        atime: Time { sec: 0, nsec: 0 },
+                        mtime: Time { sec: 0, nsec: 0 },
+                        ctime: Time { sec: 0, nsec: 0 },
+                        btime: Time { sec: 0, nsec: 0 },
+                        gen: 0,
+                        data_version: 0,
+                    },
+                },
+            },
+        ),
+    ]
+}
+
+fn encode(c: &mut Criterion) {
+    for (name, msg) in messages() {
+        let mut group = c.benchmark_group(name);
+        group.bench_function("write_msg", |b| {
+            b.iter(|| {
+                let mut writer = BytesMut::with_capacity(msg.encoded_len()).writer();
+                serialize::write_msg(&mut writer, black_box(&msg)).unwrap();
+                writer.into_inner()
+            })
+        });
+        group.bench_function("write_msg_buf", |b| {
+            b.iter(|| {
+                let mut buf = BytesMut::with_capacity(msg.encoded_len());
+                serialize::write_msg_buf(&mut buf, black_box(&msg)).unwrap();
+                buf
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
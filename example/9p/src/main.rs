@@ -0,0 +1,296 @@
+use {
+    futures::StreamExt,
+    rs9p::{
+        client::{Client, Fid, Fs, RemoteFile},
+        errno::*,
+        *,
+    },
+    std::{io::Write, path::Path},
+    tokio::{fs, io},
+};
+
+const USAGE: &str = "\
+Usage: 9p [--trace] [-u uname] [-A aname] [-m msize] proto!address!port command [args...]
+  where: proto = tcp | unix
+
+Commands:
+  ls [-l] [path...]        list directories
+  stat path...             print the attributes of files
+  cat path...              print files on stdout
+  write path               write stdin to a file
+  put local remote         copy a local file to the server
+  get remote local         copy a file from the server
+  rm [-r] path...          remove files, or directories with everything in them
+  mkdir [-p] path...       create directories
+  mv from to               rename a file
+  ln [-s] target path      create a hard or symbolic link
+  readlink path            print the target of a symbolic link
+  getxattr path [name]     print an extended attribute, or list their names
+  statfs [path]            print the statistics of the file system";
+
+struct Options {
+    trace: bool,
+    uname: String,
+    aname: String,
+    msize: u32,
+}
+
+async fn ninep_main(args: Vec<String>) -> rs9p::Result<i32> {
+    let mut options = Options {
+        trace: false,
+        uname: std::env::var("USER").unwrap_or_else(|_| "nobody".to_owned()),
+        aname: String::new(),
+        msize: 8192,
+    };
+
+    let mut args = args.into_iter().skip(1).peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+        match (arg.as_str(), args.peek()) {
+            ("--trace", _) => options.trace = true,
+            ("-u", Some(_)) => options.uname = args.next().unwrap(),
+            ("-A", Some(_)) => options.aname = args.next().unwrap(),
+            ("-m", Some(msize)) => match msize.parse() {
+                Ok(msize) => {
+                    options.msize = msize;
+                    args.next();
+                }
+                Err(_) => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+    let (addr, command) = match (args.next(), args.next()) {
+        (Some(addr), Some(command)) => (addr, command),
+        _ => return usage(),
+    };
+    let args: Vec<String> = args.collect();
+
+    if options.trace {
+        env_logger::Builder::new()
+            .filter_module("rs9p::client", log::LevelFilter::Debug)
+            .format(|buf, record| writeln!(buf, "{}", record.args()))
+            .init();
+    } else {
+        env_logger::init();
+    }
+
+    let client = Client::connect_with_msize(&addr, options.msize).await?;
+    let uid = nix::unistd::getuid().as_raw();
+    let root = client.attach(&options.uname, &options.aname, uid).await?;
+    let fs = Fs::new(root, nix::unistd::getgid().as_raw());
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match (command.as_str(), args.as_slice()) {
+        ("ls", ["-l", paths @ ..]) => ls(&fs, paths, true).await?,
+        ("ls", paths) => ls(&fs, paths, false).await?,
+        ("stat", paths) if !paths.is_empty() => {
+            for path in paths {
+                print_stat(path, &fs.symlink_metadata(path).await?);
+            }
+        }
+        ("cat", paths) if !paths.is_empty() => {
+            for path in paths {
+                let mut file = fs.open(path, LOpenFlags::RDONLY).await?;
+                io::copy(&mut file, &mut io::stdout()).await?;
+                file.close().await?;
+            }
+        }
+        ("write", [path]) => {
+            let mut file = create(&fs, path).await?;
+            io::copy(&mut io::stdin(), &mut file).await?;
+            file.close().await?;
+        }
+        ("put", [local, remote]) => {
+            let mut src = fs::File::open(local).await?;
+            let mut file = create(&fs, remote).await?;
+            io::copy(&mut src, &mut file).await?;
+            file.close().await?;
+        }
+        ("get", [remote, local]) => {
+            let mut file = fs.open(remote, LOpenFlags::RDONLY).await?;
+            let mut dst = fs::File::create(local).await?;
+            io::copy(&mut file, &mut dst).await?;
+            file.close().await?;
+        }
+        ("rm", ["-r", paths @ ..]) if !paths.is_empty() => rm(&fs, paths, true).await?,
+        ("rm", paths) if !paths.is_empty() => rm(&fs, paths, false).await?,
+        ("mkdir", ["-p", paths @ ..]) if !paths.is_empty() => {
+            for path in paths {
+                fs.create_dir_all(path, 0o755).await?;
+            }
+        }
+        ("mkdir", paths) if !paths.is_empty() => {
+            for path in paths {
+                fs.create_dir(path, 0o755).await?;
+            }
+        }
+        ("mv", [from, to]) => fs.rename(from, to).await?,
+        ("ln", ["-s", target, path]) => fs.symlink(target, path).await?,
+        ("ln", [target, path]) => fs.hard_link(target, path).await?,
+        ("readlink", [path]) => println!("{}", fs.read_link(path).await?.display()),
+        ("getxattr", [path]) => {
+            // The names come NUL terminated
+            for name in getxattr(&fs, path, "").await?.split(|&b| b == 0) {
+                if !name.is_empty() {
+                    println!("{}", String::from_utf8_lossy(name));
+                }
+            }
+        }
+        ("getxattr", [path, name]) => {
+            std::io::stdout().write_all(&getxattr(&fs, path, name).await?)?;
+        }
+        ("statfs", []) => print_statfs(&fs.root().statfs().await?),
+        ("statfs", [path]) => print_statfs(&fs.walk(path).await?.statfs().await?),
+        _ => return usage(),
+    }
+
+    Ok(0)
+}
+
+fn usage() -> rs9p::Result<i32> {
+    eprintln!("{}", USAGE);
+    Ok(2)
+}
+
+// Open the file at path for writing from the start, creating it if needed
+async fn create(fs: &Fs, path: &str) -> rs9p::Result<RemoteFile> {
+    let flags = LOpenFlags::WRONLY | LOpenFlags::TRUNC;
+    match fs.open(path, flags).await {
+        Err(e) if e.errno() == ENOENT => fs.create(path, flags, 0o644).await,
+        file => file,
+    }
+}
+
+async fn ls(fs: &Fs, paths: &[&str], long: bool) -> rs9p::Result<()> {
+    let paths = match paths {
+        [] => &["/"],
+        paths => paths,
+    };
+    for (i, path) in paths.iter().enumerate() {
+        let stat = fs.metadata(path).await?;
+        if stat.mode.file_type() != Some(FileType::Directory) {
+            print_entry(path, &stat, long);
+            continue;
+        }
+
+        let mut entries = Vec::new();
+        let mut read_dir = fs.read_dir(path).await?;
+        while let Some(entry) = read_dir.next().await {
+            entries.push(entry?);
+        }
+        entries.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        if paths.len() > 1 {
+            println!("{}{}:", if i > 0 { "\n" } else { "" }, path);
+        }
+        for (entry, stat) in entries {
+            print_entry(&entry.name.to_string_lossy(), &stat, long);
+        }
+    }
+    Ok(())
+}
+
+async fn rm(fs: &Fs, paths: &[&str], recursive: bool) -> rs9p::Result<()> {
+    for path in paths {
+        let stat = fs.symlink_metadata(path).await?;
+        match stat.mode.file_type() {
+            Some(FileType::Directory) if recursive => fs.remove_dir_all(path).await?,
+            Some(FileType::Directory) => fs.remove_dir(path).await?,
+            _ => fs.remove_file(path).await?,
+        }
+    }
+    Ok(())
+}
+
+// Read the extended attribute name of the file at path, the list of names if empty
+async fn getxattr(fs: &Fs, path: &str, name: &str) -> rs9p::Result<Vec<u8>> {
+    let (fid, size): (Fid, u64) = fs.walk(path).await?.xattrwalk(name).await?;
+    let mut value = Vec::with_capacity(size as usize);
+    while (value.len() as u64) < size {
+        let data = fid.read(value.len() as u64, fid.client().iounit()).await?;
+        if data.is_empty() {
+            break;
+        }
+        value.extend_from_slice(&data);
+    }
+    fid.clunk().await?;
+    Ok(value)
+}
+
+fn print_entry(name: &str, stat: &Stat, long: bool) {
+    if long {
+        println!(
+            "{} {:>3} {:>5} {:>5} {:>10} {}",
+            mode_string(stat.mode),
+            stat.nlink,
+            stat.uid,
+            stat.gid,
+            stat.size,
+            name
+        );
+    } else {
+        println!("{}", name);
+    }
+}
+
+fn print_stat(path: &str, stat: &Stat) {
+    println!("  File: {}", Path::new(path).display());
+    println!(
+        "  Size: {:<12} Blocks: {:<8} IO Block: {}",
+        stat.size, stat.blocks, stat.blksize
+    );
+    println!(
+        "  Mode: ({:04o}/{})",
+        stat.mode.permissions(),
+        mode_string(stat.mode)
+    );
+    println!(
+        "  Uid: {:<6} Gid: {:<6} Links: {}",
+        stat.uid, stat.gid, stat.nlink
+    );
+    println!("Access: {}.{:09}", stat.atime.sec, stat.atime.nsec);
+    println!("Modify: {}.{:09}", stat.mtime.sec, stat.mtime.nsec);
+    println!("Change: {}.{:09}", stat.ctime.sec, stat.ctime.nsec);
+}
+
+fn print_statfs(statfs: &Statfs) {
+    println!("Type: {:#x} Namelen: {}", statfs.typ, statfs.namelen);
+    println!(
+        "Block size: {:<8} Blocks: Total: {:<10} Free: {:<10} Available: {}",
+        statfs.bsize, statfs.blocks, statfs.bfree, statfs.bavail
+    );
+    println!("Inodes: Total: {:<10} Free: {}", statfs.files, statfs.ffree);
+}
+
+// Format mode as ls -l does, e.g. drwxr-xr-x
+fn mode_string(mode: FileMode) -> String {
+    let typ = match mode.file_type() {
+        Some(FileType::Directory) => 'd',
+        Some(FileType::Symlink) => 'l',
+        Some(FileType::CharDevice) => 'c',
+        Some(FileType::BlockDevice) => 'b',
+        Some(FileType::Fifo) => 'p',
+        Some(FileType::Socket) => 's',
+        Some(FileType::Regular) | None => '-',
+    };
+    let perm = mode.permissions();
+    let mut s = String::with_capacity(10);
+    s.push(typ);
+    for shift in [6, 3, 0] {
+        let bits = perm >> shift;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+#[tokio::main]
+async fn main() {
+    let args = std::env::args().collect();
+    let exit_code = ninep_main(args).await.unwrap_or_else(|e| {
+        eprintln!("9p: {}", e);
+        1
+    });
+
+    std::process::exit(exit_code);
+}
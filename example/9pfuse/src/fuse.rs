@@ -0,0 +1,1168 @@
+//! Just enough of the FUSE kernel protocol to serve a mount from `/dev/fuse`.
+//!
+//! Requests are read from the device one at a time and parsed into an
+//! `Operation`; each comes with a `Reply` which may be sent from any thread.
+//! The layouts follow `include/uapi/linux/fuse.h` of protocol 7.31.
+
+use {
+    nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        mount::MsFlags,
+        sys::{
+            socket::{
+                recvmsg, socketpair, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag,
+                SockType,
+            },
+            stat::{stat, SFlag},
+            uio::IoVec,
+        },
+        unistd::{close, getgid, getuid},
+    },
+    rs9p::Time,
+    std::{
+        ffi::OsStr,
+        fs::File,
+        io::{self, Read, Write},
+        os::unix::{
+            ffi::OsStrExt,
+            io::{AsRawFd, FromRawFd, RawFd},
+        },
+        path::Path,
+        process::Command,
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// Node id the kernel uses for the root of the mount
+pub const ROOT_ID: u64 = 1;
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+// fuse_init_out grew to its current size in 7.23
+const MIN_KERNEL_MINOR_VERSION: u32 = 23;
+
+// The largest write the kernel sends, which is also its default without FUSE_MAX_PAGES
+const MAX_WRITE: u32 = 128 * 1024;
+// Room for the headers of a write in front of its data
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+// Capabilities taken when the kernel offers them in FUSE_INIT
+const FUSE_ASYNC_READ: u32 = 1 << 0;
+const FUSE_POSIX_LOCKS: u32 = 1 << 1;
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_ATIME_NOW: u32 = 1 << 7;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+const DIRENT_LEN: usize = 24;
+
+mod opcode {
+    pub const LOOKUP: u32 = 1;
+    pub const FORGET: u32 = 2;
+    pub const GETATTR: u32 = 3;
+    pub const SETATTR: u32 = 4;
+    pub const READLINK: u32 = 5;
+    pub const SYMLINK: u32 = 6;
+    pub const MKNOD: u32 = 8;
+    pub const MKDIR: u32 = 9;
+    pub const UNLINK: u32 = 10;
+    pub const RMDIR: u32 = 11;
+    pub const RENAME: u32 = 12;
+    pub const LINK: u32 = 13;
+    pub const OPEN: u32 = 14;
+    pub const READ: u32 = 15;
+    pub const WRITE: u32 = 16;
+    pub const STATFS: u32 = 17;
+    pub const RELEASE: u32 = 18;
+    pub const FSYNC: u32 = 20;
+    pub const SETXATTR: u32 = 21;
+    pub const GETXATTR: u32 = 22;
+    pub const LISTXATTR: u32 = 23;
+    pub const REMOVEXATTR: u32 = 24;
+    pub const FLUSH: u32 = 25;
+    pub const INIT: u32 = 26;
+    pub const OPENDIR: u32 = 27;
+    pub const READDIR: u32 = 28;
+    pub const RELEASEDIR: u32 = 29;
+    pub const GETLK: u32 = 31;
+    pub const SETLK: u32 = 32;
+    pub const SETLKW: u32 = 33;
+    pub const CREATE: u32 = 35;
+    pub const INTERRUPT: u32 = 36;
+    pub const BATCH_FORGET: u32 = 42;
+    pub const RENAME2: u32 = 45;
+}
+
+/// Attributes of a file as the kernel caches them
+pub struct FileAttr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: Time,
+    pub mtime: Time,
+    pub ctime: Time,
+    /// File type and permissions, as in `st_mode`
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+}
+
+pub struct Kstatfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+/// A POSIX record lock, `end` included
+pub struct FileLock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+pub enum TimeOrNow {
+    Specific(Time),
+    Now,
+}
+
+/// A request of the kernel on the node `nodeid`
+pub struct Request<'a> {
+    pub nodeid: u64,
+    pub gid: u32,
+    pub op: Operation<'a>,
+}
+
+pub enum Operation<'a> {
+    Lookup {
+        name: &'a OsStr,
+    },
+    /// Lookups of nodes to forget, which get no reply
+    Forget(Vec<(u64, u64)>),
+    Getattr,
+    Setattr {
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    },
+    Readlink,
+    Symlink {
+        name: &'a OsStr,
+        target: &'a OsStr,
+    },
+    Mknod {
+        name: &'a OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+    },
+    Mkdir {
+        name: &'a OsStr,
+        mode: u32,
+        umask: u32,
+    },
+    Unlink {
+        name: &'a OsStr,
+    },
+    Rmdir {
+        name: &'a OsStr,
+    },
+    Rename {
+        name: &'a OsStr,
+        newparent: u64,
+        newname: &'a OsStr,
+        flags: u32,
+    },
+    Link {
+        ino: u64,
+        newname: &'a OsStr,
+    },
+    Open {
+        flags: i32,
+    },
+    Read {
+        fh: u64,
+        offset: u64,
+        size: u32,
+    },
+    Write {
+        fh: u64,
+        offset: u64,
+        data: &'a [u8],
+    },
+    Statfs,
+    Release {
+        fh: u64,
+    },
+    Fsync {
+        fh: u64,
+    },
+    Setxattr {
+        name: &'a OsStr,
+        value: &'a [u8],
+        flags: i32,
+    },
+    Getxattr {
+        name: &'a OsStr,
+        size: u32,
+    },
+    Listxattr {
+        size: u32,
+    },
+    Removexattr {
+        name: &'a OsStr,
+    },
+    Flush,
+    Opendir,
+    Readdir {
+        fh: u64,
+        offset: u64,
+        size: u32,
+    },
+    Releasedir {
+        fh: u64,
+    },
+    Getlk {
+        fh: u64,
+        lock: FileLock,
+    },
+    Setlk {
+        fh: u64,
+        lock: FileLock,
+        sleep: bool,
+    },
+    Create {
+        name: &'a OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+    },
+    /// Anything else, answered with ENOSYS
+    Unsupported(u32),
+}
+
+/// A mounted file system and the device its requests come from
+pub struct Session {
+    dev: Arc<File>,
+    buf: Vec<u8>,
+}
+
+impl Session {
+    /// Mount a FUSE file system named `fsname` on `mountpoint`.
+    ///
+    /// mount(2) needs CAP_SYS_ADMIN; without it the setuid `fusermount3`
+    /// (or `fusermount`) of libfuse mounts on our behalf.
+    pub fn mount(fsname: &str, mountpoint: &Path) -> io::Result<Session> {
+        let dev = match mount_fuse(fsname, mountpoint) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                fusermount(fsname, mountpoint)?
+            }
+            res => res?,
+        };
+        let mut session = Session {
+            dev: Arc::new(dev),
+            buf: vec![0; BUFFER_SIZE],
+        };
+        session.init()?;
+        Ok(session)
+    }
+
+    /// Wait for the next request, `None` once the file system is unmounted.
+    ///
+    /// A request which cannot be parsed is failed with EIO and skipped.
+    pub fn next(&mut self) -> io::Result<Option<(Request<'_>, Reply)>> {
+        let len = loop {
+            let len = match self.receive()? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let mut args = Args(&self.buf[..len]);
+            let (opcode, unique, nodeid, _) = match args.header() {
+                Ok(header) => header,
+                Err(e) => {
+                    log::warn!("FUSE request of {} bytes: {}", len, e);
+                    continue;
+                }
+            };
+            match (opcode, parse(opcode, nodeid, &mut args)) {
+                // Interrupts are not acted upon and get no reply
+                (opcode::INTERRUPT, _) => {}
+                // The reply answers with EIO as it drops
+                (_, Err(e)) => {
+                    log::warn!("FUSE request {} with opcode {}: {}", unique, opcode, e);
+                    drop(Reply::new(self.dev.clone(), unique));
+                }
+                (_, Ok(_)) => break len,
+            }
+        };
+
+        // Parsed again to borrow from buf only once no more reads follow
+        let mut args = Args(&self.buf[..len]);
+        let (opcode, unique, nodeid, gid) = args.header()?;
+        let op = parse(opcode, nodeid, &mut args)?;
+        let mut reply = Reply::new(self.dev.clone(), unique);
+        if let Operation::Forget(_) = op {
+            reply.sent = true;
+        }
+        let req = Request { nodeid, gid, op };
+        Ok(Some((req, reply)))
+    }
+
+    // Read one request into buf, retrying those interrupted before we got them
+    fn receive(&mut self) -> io::Result<Option<usize>> {
+        loop {
+            match (&*self.dev).read(&mut self.buf) {
+                Ok(len) => return Ok(Some(len)),
+                Err(e) => match e.raw_os_error() {
+                    Some(nix::libc::ENOENT) | Some(nix::libc::EINTR) | Some(nix::libc::EAGAIN) => {}
+                    Some(nix::libc::ENODEV) => return Ok(None),
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    // Answer FUSE_INIT, which the kernel sends before anything else
+    fn init(&mut self) -> io::Result<()> {
+        let len = self.receive()?.ok_or(nix::errno::Errno::ENODEV)?;
+        let mut args = Args(&self.buf[..len]);
+        let (opcode, unique, ..) = args.header()?;
+        let reply = Reply::new(self.dev.clone(), unique);
+        let (major, minor) = (args.u32()?, args.u32()?);
+        let (max_readahead, flags) = (args.u32()?, args.u32()?);
+        if opcode != opcode::INIT || major < KERNEL_VERSION || minor < MIN_KERNEL_MINOR_VERSION {
+            reply.error(nix::libc::EPROTO);
+            return Err(nix::errno::Errno::EPROTO.into());
+        }
+
+        // A kernel of a later major version asks again with ours
+        reply.send(&init_out(max_readahead, flags));
+        if major > KERNEL_VERSION {
+            self.init()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// fuse_init_out, taking what we use of the capabilities offered in flags
+fn init_out(max_readahead: u32, flags: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    put_u32(&mut out, KERNEL_VERSION);
+    put_u32(&mut out, KERNEL_MINOR_VERSION);
+    put_u32(&mut out, max_readahead);
+    put_u32(
+        &mut out,
+        flags & (FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_BIG_WRITES),
+    );
+    // max_background and congestion_threshold are left to the kernel
+    put_u32(&mut out, 0);
+    put_u32(&mut out, MAX_WRITE);
+    // time_gran: timestamps are kept to the nanosecond
+    put_u32(&mut out, 1);
+    out.resize(64, 0);
+    out
+}
+
+fn mount_fuse(fsname: &str, mountpoint: &Path) -> io::Result<File> {
+    let rootmode = SFlag::from_bits_truncate(stat(mountpoint)?.st_mode) & SFlag::S_IFMT;
+    let dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let data = format!(
+        "fd={},rootmode={:o},user_id={},group_id={},default_permissions",
+        dev.as_raw_fd(),
+        rootmode.bits(),
+        getuid(),
+        getgid()
+    );
+    nix::mount::mount(
+        Some(fsname),
+        mountpoint,
+        Some("fuse.9p"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(data.as_str()),
+    )?;
+    Ok(dev)
+}
+
+// fusermount mounts and passes the opened /dev/fuse back over the socket in _FUSE_COMMFD
+fn fusermount(fsname: &str, mountpoint: &Path) -> io::Result<File> {
+    let (ours, theirs) = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::empty(),
+    )?;
+    let ours = unsafe { File::from_raw_fd(ours) };
+    fcntl(ours.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+    let options = format!("fsname={},subtype=9p,default_permissions", fsname);
+    let mut status = Err(io::ErrorKind::NotFound.into());
+    for program in ["fusermount3", "fusermount"] {
+        status = Command::new(program)
+            .arg("-o")
+            .arg(&options)
+            .arg("--")
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.to_string())
+            .status();
+        match status {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            _ => break,
+        }
+    }
+    close(theirs)?;
+    if !status?.success() {
+        return Err(io::Error::other("fusermount failed"));
+    }
+
+    let mut byte = [0];
+    let iov = [IoVec::from_mut_slice(&mut byte)];
+    let mut cmsgs = nix::cmsg_space!(RawFd);
+    let msg = recvmsg(
+        ours.as_raw_fd(),
+        &iov,
+        Some(&mut cmsgs),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(unsafe { File::from_raw_fd(fd) });
+            }
+        }
+    }
+    Err(io::Error::other("fusermount sent no file descriptor"))
+}
+
+fn parse<'a>(opcode: u32, nodeid: u64, args: &mut Args<'a>) -> io::Result<Operation<'a>> {
+    let op = match opcode {
+        opcode::LOOKUP => Operation::Lookup { name: args.name()? },
+        opcode::FORGET => Operation::Forget(vec![(nodeid, args.u64()?)]),
+        opcode::BATCH_FORGET => {
+            let count = args.u32()?;
+            args.skip(4)?;
+            let nodes = (0..count)
+                .map(|_| Ok((args.u64()?, args.u64()?)))
+                .collect::<io::Result<_>>()?;
+            Operation::Forget(nodes)
+        }
+        opcode::GETATTR => Operation::Getattr,
+        opcode::SETATTR => {
+            let valid = args.u32()?;
+            args.skip(4 + 8)?;
+            let size = args.u64()?;
+            args.skip(8)?;
+            let (atime, mtime) = (args.u64()?, args.u64()?);
+            args.skip(8)?;
+            let (atimensec, mtimensec) = (args.u32()?, args.u32()?);
+            args.skip(4)?;
+            let mode = args.u32()?;
+            args.skip(4)?;
+            let (uid, gid) = (args.u32()?, args.u32()?);
+            args.skip(4)?;
+
+            let is = |bit| valid & bit != 0;
+            let time = |set, now, sec, nsec| match (is(set), is(now)) {
+                (_, true) => Some(TimeOrNow::Now),
+                (true, false) => Some(TimeOrNow::Specific(Time {
+                    sec,
+                    nsec: nsec as u64,
+                })),
+                _ => None,
+            };
+            Operation::Setattr {
+                mode: Some(mode).filter(|_| is(FATTR_MODE)),
+                uid: Some(uid).filter(|_| is(FATTR_UID)),
+                gid: Some(gid).filter(|_| is(FATTR_GID)),
+                size: Some(size).filter(|_| is(FATTR_SIZE)),
+                atime: time(FATTR_ATIME, FATTR_ATIME_NOW, atime, atimensec),
+                mtime: time(FATTR_MTIME, FATTR_MTIME_NOW, mtime, mtimensec),
+            }
+        }
+        opcode::READLINK => Operation::Readlink,
+        opcode::SYMLINK => Operation::Symlink {
+            name: args.name()?,
+            target: args.name()?,
+        },
+        opcode::MKNOD => {
+            let (mode, rdev, umask) = (args.u32()?, args.u32()?, args.u32()?);
+            args.skip(4)?;
+            Operation::Mknod {
+                name: args.name()?,
+                mode,
+                umask,
+                rdev,
+            }
+        }
+        opcode::MKDIR => Operation::Mkdir {
+            mode: args.u32()?,
+            umask: args.u32()?,
+            name: args.name()?,
+        },
+        opcode::UNLINK => Operation::Unlink { name: args.name()? },
+        opcode::RMDIR => Operation::Rmdir { name: args.name()? },
+        opcode::RENAME | opcode::RENAME2 => {
+            let newparent = args.u64()?;
+            let flags = match opcode {
+                opcode::RENAME2 => {
+                    let flags = args.u32()?;
+                    args.skip(4)?;
+                    flags
+                }
+                _ => 0,
+            };
+            Operation::Rename {
+                name: args.name()?,
+                newparent,
+                newname: args.name()?,
+                flags,
+            }
+        }
+        opcode::LINK => Operation::Link {
+            ino: args.u64()?,
+            newname: args.name()?,
+        },
+        opcode::OPEN => Operation::Open {
+            flags: args.u32()? as i32,
+        },
+        opcode::READ => {
+            let (fh, offset, size) = (args.u64()?, args.u64()?, args.u32()?);
+            Operation::Read { fh, offset, size }
+        }
+        opcode::WRITE => {
+            let (fh, offset, size) = (args.u64()?, args.u64()?, args.u32()?);
+            args.skip(4 + 8 + 4 + 4)?;
+            Operation::Write {
+                fh,
+                offset,
+                data: args.bytes(size as usize)?,
+            }
+        }
+        opcode::STATFS => Operation::Statfs,
+        opcode::RELEASE => Operation::Release { fh: args.u64()? },
+        opcode::FSYNC => Operation::Fsync { fh: args.u64()? },
+        opcode::SETXATTR => {
+            let (size, flags) = (args.u32()?, args.u32()?);
+            Operation::Setxattr {
+                name: args.name()?,
+                value: args.bytes(size as usize)?,
+                flags: flags as i32,
+            }
+        }
+        opcode::GETXATTR => {
+            let size = args.u32()?;
+            args.skip(4)?;
+            Operation::Getxattr {
+                name: args.name()?,
+                size,
+            }
+        }
+        opcode::LISTXATTR => Operation::Listxattr { size: args.u32()? },
+        opcode::REMOVEXATTR => Operation::Removexattr { name: args.name()? },
+        opcode::FLUSH => Operation::Flush,
+        opcode::OPENDIR => Operation::Opendir,
+        opcode::READDIR => {
+            let (fh, offset, size) = (args.u64()?, args.u64()?, args.u32()?);
+            Operation::Readdir { fh, offset, size }
+        }
+        opcode::RELEASEDIR => Operation::Releasedir { fh: args.u64()? },
+        opcode::GETLK | opcode::SETLK | opcode::SETLKW => {
+            let fh = args.u64()?;
+            args.skip(8)?;
+            let lock = FileLock {
+                start: args.u64()?,
+                end: args.u64()?,
+                typ: args.u32()?,
+                pid: args.u32()?,
+            };
+            match opcode {
+                opcode::GETLK => Operation::Getlk { fh, lock },
+                _ => Operation::Setlk {
+                    fh,
+                    lock,
+                    sleep: opcode == opcode::SETLKW,
+                },
+            }
+        }
+        opcode::CREATE => {
+            let (flags, mode, umask) = (args.u32()?, args.u32()?, args.u32()?);
+            args.skip(4)?;
+            Operation::Create {
+                name: args.name()?,
+                mode,
+                umask,
+                flags: flags as i32,
+            }
+        }
+        opcode => Operation::Unsupported(opcode),
+    };
+    Ok(op)
+}
+
+// The arguments of a request in native byte order
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "short FUSE request",
+            ));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.bytes(len).map(drop)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_ne_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_ne_bytes(b))
+    }
+
+    // A NUL terminated name
+    fn name(&mut self) -> io::Result<&'a OsStr> {
+        let len =
+            self.0.iter().position(|&b| b == 0).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unterminated FUSE name")
+            })?;
+        let name = self.bytes(len + 1)?;
+        Ok(OsStr::from_bytes(&name[..len]))
+    }
+
+    // opcode, unique, nodeid and gid of fuse_in_header
+    fn header(&mut self) -> io::Result<(u32, u64, u64, u32)> {
+        let header = self.bytes(IN_HEADER_LEN)?;
+        let mut args = Args(&header[4..]);
+        let (opcode, unique, nodeid) = (args.u32()?, args.u64()?, args.u64()?);
+        args.skip(4)?;
+        Ok((opcode, unique, nodeid, args.u32()?))
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_ne_bytes());
+}
+
+// fuse_attr
+fn put_attr(out: &mut Vec<u8>, attr: &FileAttr) {
+    for n in [attr.ino, attr.size, attr.blocks] {
+        put_u64(out, n);
+    }
+    for t in [&attr.atime, &attr.mtime, &attr.ctime] {
+        put_u64(out, t.sec);
+    }
+    for t in [&attr.atime, &attr.mtime, &attr.ctime] {
+        put_u32(out, t.nsec as u32);
+    }
+    for n in [
+        attr.mode,
+        attr.nlink,
+        attr.uid,
+        attr.gid,
+        attr.rdev,
+        attr.blksize,
+    ] {
+        put_u32(out, n);
+    }
+    // flags
+    put_u32(out, 0);
+}
+
+// fuse_entry_out
+fn put_entry(out: &mut Vec<u8>, ttl: Duration, attr: &FileAttr) {
+    put_u64(out, attr.ino);
+    // generation: inode numbers are qid paths, never reused for another file
+    put_u64(out, 0);
+    put_u64(out, ttl.as_secs());
+    put_u64(out, ttl.as_secs());
+    put_u32(out, ttl.subsec_nanos());
+    put_u32(out, ttl.subsec_nanos());
+    put_attr(out, attr);
+}
+
+/// The answer to one request, written to the device as a whole.
+///
+/// A request dropped without an answer is failed with EIO so the kernel
+/// does not wait for it forever.
+pub struct Reply {
+    dev: Arc<File>,
+    unique: u64,
+    sent: bool,
+}
+
+impl Reply {
+    fn new(dev: Arc<File>, unique: u64) -> Reply {
+        Reply {
+            dev,
+            unique,
+            sent: false,
+        }
+    }
+
+    fn reply(&mut self, error: i32, body: &[u8]) {
+        let mut out = Vec::with_capacity(OUT_HEADER_LEN + body.len());
+        put_u32(&mut out, (OUT_HEADER_LEN + body.len()) as u32);
+        put_u32(&mut out, -error as u32);
+        put_u64(&mut out, self.unique);
+        out.extend_from_slice(body);
+        // Fails only if the request was interrupted meanwhile
+        if let Err(e) = (&*self.dev).write_all(&out) {
+            log::debug!("Reply to {} not delivered: {}", self.unique, e);
+        }
+        self.sent = true;
+    }
+
+    fn send(mut self, body: &[u8]) {
+        self.reply(0, body)
+    }
+
+    pub fn error(mut self, errno: i32) {
+        self.reply(errno, &[])
+    }
+
+    pub fn ok(self) {
+        self.send(&[])
+    }
+
+    pub fn data(self, data: &[u8]) {
+        self.send(data)
+    }
+
+    pub fn entry(self, ttl: Duration, attr: &FileAttr) {
+        let mut out = Vec::with_capacity(128);
+        put_entry(&mut out, ttl, attr);
+        self.send(&out)
+    }
+
+    pub fn attr(self, ttl: Duration, attr: &FileAttr) {
+        let mut out = Vec::with_capacity(104);
+        put_u64(&mut out, ttl.as_secs());
+        put_u32(&mut out, ttl.subsec_nanos());
+        put_u32(&mut out, 0);
+        put_attr(&mut out, attr);
+        self.send(&out)
+    }
+
+    pub fn opened(self, fh: u64) {
+        let mut out = Vec::with_capacity(16);
+        put_u64(&mut out, fh);
+        put_u64(&mut out, 0);
+        self.send(&out)
+    }
+
+    pub fn created(self, ttl: Duration, attr: &FileAttr, fh: u64) {
+        let mut out = Vec::with_capacity(144);
+        put_entry(&mut out, ttl, attr);
+        put_u64(&mut out, fh);
+        put_u64(&mut out, 0);
+        self.send(&out)
+    }
+
+    pub fn written(self, size: u32) {
+        let mut out = Vec::with_capacity(8);
+        put_u32(&mut out, size);
+        put_u32(&mut out, 0);
+        self.send(&out)
+    }
+
+    pub fn statfs(self, st: &Kstatfs) {
+        let mut out = Vec::with_capacity(80);
+        for n in [st.blocks, st.bfree, st.bavail, st.files, st.ffree] {
+            put_u64(&mut out, n);
+        }
+        for n in [st.bsize, st.namelen, st.frsize] {
+            put_u32(&mut out, n);
+        }
+        out.resize(80, 0);
+        self.send(&out)
+    }
+
+    /// The size of an extended attribute or of the list of names
+    pub fn xattr_size(self, size: u32) {
+        let mut out = Vec::with_capacity(8);
+        put_u32(&mut out, size);
+        put_u32(&mut out, 0);
+        self.send(&out)
+    }
+
+    pub fn locked(self, lock: &FileLock) {
+        let mut out = Vec::with_capacity(24);
+        put_u64(&mut out, lock.start);
+        put_u64(&mut out, lock.end);
+        put_u32(&mut out, lock.typ);
+        put_u32(&mut out, lock.pid);
+        self.send(&out)
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.reply(nix::libc::EIO, &[]);
+        }
+    }
+}
+
+/// Directory entries filling a FUSE_READDIR reply of at most `size` bytes
+pub struct DirBuf {
+    buf: Vec<u8>,
+    size: usize,
+}
+
+impl DirBuf {
+    pub fn new(size: u32) -> DirBuf {
+        DirBuf {
+            buf: Vec::new(),
+            size: size as usize,
+        }
+    }
+
+    /// Add an entry whose successor is at `offset`, or return true if it does not fit.
+    pub fn add(&mut self, ino: u64, offset: u64, typ: u8, name: &OsStr) -> bool {
+        let name = name.as_bytes();
+        let len = (DIRENT_LEN + name.len() + 7) & !7;
+        if self.buf.len() + len > self.size {
+            return true;
+        }
+        let end = self.buf.len() + len;
+        put_u64(&mut self.buf, ino);
+        put_u64(&mut self.buf, offset);
+        put_u32(&mut self.buf, name.len() as u32);
+        put_u32(&mut self.buf, typ as u32);
+        self.buf.extend_from_slice(name);
+        self.buf.resize(end, 0);
+        false
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::convert::TryInto};
+
+    // A session whose device is one end of a socket pair keeping message boundaries
+    fn session() -> (Session, File) {
+        let (ours, kernel) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let session = Session {
+            dev: Arc::new(unsafe { File::from_raw_fd(ours) }),
+            buf: vec![0; BUFFER_SIZE],
+        };
+        (session, unsafe { File::from_raw_fd(kernel) })
+    }
+
+    // fuse_in_header followed by args
+    fn request(opcode: u32, unique: u64, args: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, (IN_HEADER_LEN + args.len()) as u32);
+        put_u32(&mut buf, opcode);
+        put_u64(&mut buf, unique);
+        put_u64(&mut buf, ROOT_ID);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 100);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 0);
+        buf.extend_from_slice(args);
+        buf
+    }
+
+    // fuse_out_header of the next reply: len, error and unique
+    fn reply_header(kernel: &mut File) -> (usize, i32, u64) {
+        let mut buf = vec![0; BUFFER_SIZE];
+        let len = kernel.read(&mut buf).unwrap();
+        let mut out = Args(&buf[..len]);
+        let (total, error, unique) = (out.u32().unwrap(), out.u32().unwrap(), out.u64().unwrap());
+        assert_eq!(total as usize, len);
+        (len, error as i32, unique)
+    }
+
+    #[test]
+    fn unparsable_requests_fail_alone() {
+        let (mut session, mut kernel) = session();
+        // A write shorter than its fuse_write_in
+        kernel
+            .write_all(&request(opcode::WRITE, 1, &[0; 8]))
+            .unwrap();
+        kernel
+            .write_all(&request(opcode::GETATTR, 2, &[0; 16]))
+            .unwrap();
+
+        let (req, reply) = session.next().unwrap().unwrap();
+        assert!(matches!(req.op, Operation::Getattr));
+        assert_eq!(req.gid, 100);
+        assert_eq!(
+            reply_header(&mut kernel),
+            (OUT_HEADER_LEN, -nix::libc::EIO, 1)
+        );
+        drop(reply);
+        assert_eq!(
+            reply_header(&mut kernel),
+            (OUT_HEADER_LEN, -nix::libc::EIO, 2)
+        );
+    }
+
+    // Native endian fields at the offsets of fuse.h
+    fn get32(buf: &[u8], at: usize) -> u32 {
+        u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn get64(buf: &[u8], at: usize) -> u64 {
+        u64::from_ne_bytes(buf[at..at + 8].try_into().unwrap())
+    }
+
+    fn set32(buf: &mut [u8], at: usize, n: u32) {
+        buf[at..at + 4].copy_from_slice(&n.to_ne_bytes());
+    }
+
+    fn set64(buf: &mut [u8], at: usize, n: u64) {
+        buf[at..at + 8].copy_from_slice(&n.to_ne_bytes());
+    }
+
+    fn attr() -> FileAttr {
+        let time = |sec, nsec| Time { sec, nsec };
+        FileAttr {
+            ino: 1,
+            size: 2,
+            blocks: 3,
+            atime: time(4, 7),
+            mtime: time(5, 8),
+            ctime: time(6, 9),
+            mode: 10,
+            nlink: 11,
+            uid: 12,
+            gid: 13,
+            rdev: 14,
+            blksize: 15,
+        }
+    }
+
+    #[test]
+    fn attr_layout() {
+        let mut out = Vec::new();
+        put_attr(&mut out, &attr());
+        assert_eq!(out.len(), 88);
+        for (at, n) in [(0, 1), (8, 2), (16, 3), (24, 4), (32, 5), (40, 6)] {
+            assert_eq!(get64(&out, at), n);
+        }
+        for (i, at) in (48..88).step_by(4).enumerate() {
+            // flags, the last field, is 0
+            assert_eq!(get32(&out, at), if at == 84 { 0 } else { 7 + i as u32 });
+        }
+    }
+
+    #[test]
+    fn entry_layout() {
+        let mut out = Vec::new();
+        put_entry(&mut out, Duration::new(2, 3), &attr());
+        assert_eq!(out.len(), 128);
+        // nodeid, generation, entry_valid, attr_valid and their nanoseconds
+        assert_eq!(get64(&out, 0), 1);
+        assert_eq!(get64(&out, 8), 0);
+        assert_eq!((get64(&out, 16), get64(&out, 24)), (2, 2));
+        assert_eq!((get32(&out, 32), get32(&out, 36)), (3, 3));
+        // attr.ino
+        assert_eq!(get64(&out, 40), 1);
+    }
+
+    #[test]
+    fn init_out_layout() {
+        let out = init_out(4096, !0);
+        assert_eq!(out.len(), 64);
+        assert_eq!((get32(&out, 0), get32(&out, 4)), (7, 31));
+        assert_eq!(get32(&out, 8), 4096);
+        assert_eq!(
+            get32(&out, 12),
+            FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_BIG_WRITES
+        );
+        // max_background and congestion_threshold
+        assert_eq!(get32(&out, 16), 0);
+        assert_eq!(get32(&out, 20), MAX_WRITE);
+        assert_eq!(get32(&out, 24), 1);
+        // max_pages, map_alignment, flags2 and unused
+        assert!(out[28..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reply_sizes() {
+        let (session, mut kernel) = session();
+        let reply = || Reply::new(session.dev.clone(), 1);
+        let statfs = Kstatfs {
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            bsize: 0,
+            namelen: 0,
+            frsize: 0,
+        };
+        let lock = FileLock {
+            start: 0,
+            end: 0,
+            typ: 0,
+            pid: 0,
+        };
+
+        // fuse_attr_out, fuse_entry_out with fuse_open_out, fuse_kstatfs, fuse_lk_out
+        reply().attr(Duration::ZERO, &attr());
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 104);
+        reply().entry(Duration::ZERO, &attr());
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 128);
+        reply().created(Duration::ZERO, &attr(), 1);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 144);
+        reply().opened(1);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 16);
+        reply().statfs(&statfs);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 80);
+        reply().locked(&lock);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 24);
+        reply().written(1);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 8);
+        reply().xattr_size(1);
+        assert_eq!(reply_header(&mut kernel).0, OUT_HEADER_LEN + 8);
+    }
+
+    #[test]
+    fn dirents_are_aligned() {
+        let mut dir = DirBuf::new(80);
+        assert!(!dir.add(1, 2, 4, OsStr::new("a")));
+        assert!(!dir.add(3, 4, 8, OsStr::new("12345678")));
+        // 24 + 9 bytes padded to 40 do not fit in the 16 left
+        assert!(dir.add(5, 6, 8, OsStr::new("123456789")));
+
+        let buf = dir.as_bytes();
+        assert_eq!(buf.len(), 64);
+        assert_eq!((get64(buf, 0), get64(buf, 8)), (1, 2));
+        assert_eq!((get32(buf, 16), get32(buf, 20)), (1, 4));
+        assert_eq!(&buf[24..32], b"a\0\0\0\0\0\0\0");
+        assert_eq!((get64(buf, 32), get64(buf, 40)), (3, 4));
+        assert_eq!((get32(buf, 48), get32(buf, 52)), (8, 8));
+        assert_eq!(&buf[56..64], b"12345678");
+    }
+
+    #[test]
+    fn setattr_layout() {
+        let mut setattr_in = [0; 88];
+        let valid = FATTR_MODE | FATTR_UID | FATTR_SIZE | FATTR_ATIME | FATTR_MTIME_NOW;
+        set32(&mut setattr_in, 0, valid);
+        set64(&mut setattr_in, 8, 99);
+        set64(&mut setattr_in, 16, 1000);
+        set64(&mut setattr_in, 24, 99);
+        set64(&mut setattr_in, 32, 20);
+        set64(&mut setattr_in, 40, 30);
+        set64(&mut setattr_in, 48, 99);
+        set32(&mut setattr_in, 56, 21);
+        set32(&mut setattr_in, 60, 31);
+        set32(&mut setattr_in, 64, 99);
+        set32(&mut setattr_in, 68, 0o644);
+        set32(&mut setattr_in, 76, 5);
+        set32(&mut setattr_in, 80, 6);
+
+        let mut args = Args(&setattr_in);
+        let op = parse(opcode::SETATTR, ROOT_ID, &mut args).unwrap();
+        assert!(args.0.is_empty());
+        match op {
+            Operation::Setattr {
+                mode: Some(0o644),
+                uid: Some(5),
+                gid: None,
+                size: Some(1000),
+                atime: Some(TimeOrNow::Specific(Time { sec: 20, nsec: 21 })),
+                mtime: Some(TimeOrNow::Now),
+            } => {}
+            _ => panic!("SETATTR parsed wrong"),
+        }
+    }
+
+    #[test]
+    fn write_layout() {
+        let mut write_in = vec![0; 40];
+        set64(&mut write_in, 0, 7);
+        set64(&mut write_in, 8, 4096);
+        set32(&mut write_in, 16, 5);
+        set32(&mut write_in, 20, !0);
+        set64(&mut write_in, 24, !0);
+        set32(&mut write_in, 32, !0);
+        write_in.extend_from_slice(b"hello");
+
+        let mut args = Args(&write_in);
+        match parse(opcode::WRITE, ROOT_ID, &mut args).unwrap() {
+            Operation::Write {
+                fh: 7,
+                offset: 4096,
+                data: b"hello",
+            } => {}
+            _ => panic!("WRITE parsed wrong"),
+        }
+        assert!(args.0.is_empty());
+    }
+
+    #[test]
+    fn lk_layout() {
+        let mut lk_in = [0; 48];
+        set64(&mut lk_in, 0, 7);
+        set64(&mut lk_in, 8, !0);
+        set64(&mut lk_in, 16, 10);
+        set64(&mut lk_in, 24, 20);
+        set32(&mut lk_in, 32, 1);
+        set32(&mut lk_in, 36, 42);
+
+        for (opcode, blocking) in [(opcode::SETLK, false), (opcode::SETLKW, true)] {
+            let mut args = Args(&lk_in);
+            match parse(opcode, ROOT_ID, &mut args).unwrap() {
+                Operation::Setlk { fh: 7, lock, sleep } => {
+                    assert_eq!((lock.start, lock.end, lock.typ, lock.pid), (10, 20, 1, 42));
+                    assert_eq!(sleep, blocking);
+                }
+                _ => panic!("SETLK parsed wrong"),
+            }
+        }
+    }
+}
@@ -0,0 +1,847 @@
+mod fuse;
+
+use {
+    fuse::{DirBuf, FileAttr, FileLock, Kstatfs, Operation, Reply, Request, Session, TimeOrNow},
+    nix::fcntl::OFlag,
+    rs9p::{
+        client::{Client, Fid},
+        errno::*,
+        *,
+    },
+    std::{
+        collections::HashMap,
+        ffi::{OsStr, OsString},
+        future::Future,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::runtime::{Handle, Runtime},
+};
+
+// How long the kernel trusts attributes and names before asking again
+const TTL: Duration = Duration::from_secs(1);
+
+// Inode number the kernel uses for the root of the mount
+const ROOT_INO: u64 = fuse::ROOT_ID;
+
+// How often a blocking lock is tried again while the server reports it blocked
+const LOCK_RETRY: Duration = Duration::from_millis(100);
+
+// A file the kernel knows about, by its inode number
+struct Node {
+    // Never opened, only walked from and used for attributes
+    fid: Arc<Fid>,
+    // Lookups not yet forgotten by the kernel
+    nlookup: u64,
+    // Where the node was last looked up, to walk to it again when a directory above is renamed
+    parent: u64,
+    name: OsString,
+}
+
+#[derive(Default)]
+struct Tables {
+    nodes: HashMap<u64, Node>,
+    // Open fids, by FUSE file handle
+    handles: HashMap<u64, Arc<Fid>>,
+    next_fh: u64,
+}
+
+// The state shared by the tasks answering the kernel
+struct Bridge {
+    // qid.path of the root, which the kernel knows as ROOT_INO
+    root_path: u64,
+    // Identifies our POSIX locks on the server together with the pid
+    client_id: String,
+    tables: Mutex<Tables>,
+}
+
+impl Bridge {
+    fn new(root: Fid, root_path: u64) -> Bridge {
+        let mut tables = Tables::default();
+        let node = Node {
+            fid: Arc::new(root),
+            nlookup: 1,
+            parent: ROOT_INO,
+            name: OsString::new(),
+        };
+        tables.nodes.insert(ROOT_INO, node);
+        Bridge {
+            root_path,
+            client_id: nix::sys::utsname::uname().nodename().to_owned(),
+            tables: Mutex::new(tables),
+        }
+    }
+
+    // qid.path is the inode number, except that the root and ROOT_INO swap places
+    fn ino(&self, path: u64) -> u64 {
+        if path == self.root_path {
+            ROOT_INO
+        } else if path == ROOT_INO {
+            self.root_path
+        } else {
+            path
+        }
+    }
+
+    fn node(&self, ino: u64) -> Result<Arc<Fid>> {
+        let tables = self.tables.lock().unwrap();
+        let node = tables.nodes.get(&ino).ok_or(error::Error::No(ESTALE))?;
+        Ok(node.fid.clone())
+    }
+
+    fn handle(&self, fh: u64) -> Result<Arc<Fid>> {
+        let tables = self.tables.lock().unwrap();
+        let fid = tables.handles.get(&fh).ok_or(error::Error::No(EBADF))?;
+        Ok(fid.clone())
+    }
+
+    fn open_handle(&self, fid: Fid) -> u64 {
+        let mut tables = self.tables.lock().unwrap();
+        tables.next_fh += 1;
+        let fh = tables.next_fh;
+        tables.handles.insert(fh, Arc::new(fid));
+        fh
+    }
+
+    async fn close_handle(&self, fh: u64) -> Result<()> {
+        let fid = self.tables.lock().unwrap().handles.remove(&fh);
+        // A request still running on the fid clunks it when done
+        match fid.map(Arc::try_unwrap) {
+            Some(Ok(fid)) => fid.clunk().await,
+            _ => Ok(()),
+        }
+    }
+
+    // Count a lookup of name in parent, walked to by fid, which replaces the fid of its inode:
+    // the one walked under an earlier name may be gone on servers tracking fids by path
+    fn remember(&self, parent: u64, name: &OsStr, fid: Fid, qid: Qid, stat: &Stat) -> FileAttr {
+        let (ino, fid) = (self.ino(qid.path), Arc::new(fid));
+        let mut tables = self.tables.lock().unwrap();
+        let node = tables.nodes.entry(ino).or_insert_with(|| Node {
+            fid: fid.clone(),
+            nlookup: 0,
+            parent,
+            name: name.to_owned(),
+        });
+        node.fid = fid;
+        node.nlookup += 1;
+        node.parent = parent;
+        node.name = name.to_owned();
+        file_attr(ino, stat)
+    }
+
+    // Names leading from the node dir down to each node below it
+    fn below(&self, dir: u64) -> Vec<(u64, Vec<OsString>)> {
+        let tables = self.tables.lock().unwrap();
+        let mut below = Vec::new();
+        for &ino in tables.nodes.keys() {
+            let (mut at, mut wnames) = (ino, Vec::new());
+            // Bounded in case the recorded names were left in a loop by renames of others
+            for _ in 0..tables.nodes.len() {
+                match tables.nodes.get(&at) {
+                    Some(node) if at != dir && at != ROOT_INO => {
+                        wnames.push(node.name.clone());
+                        at = node.parent;
+                    }
+                    _ => break,
+                }
+            }
+            if at == dir && ino != dir {
+                wnames.reverse();
+                below.push((ino, wnames));
+            }
+        }
+        below
+    }
+
+    fn forget(&self, ino: u64, nlookup: u64) {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(node) = tables.nodes.get_mut(&ino) {
+            node.nlookup = node.nlookup.saturating_sub(nlookup);
+            if node.nlookup == 0 && ino != ROOT_INO {
+                tables.nodes.remove(&ino);
+            }
+        }
+    }
+
+    async fn lookup(&self, parent: u64, name: &OsStr) -> Result<FileAttr> {
+        let fid = self.node(parent)?.walk(&[name]).await?;
+        let (_, qid, stat) = fid.getattr(GetattrMask::BASIC).await?;
+        Ok(self.remember(parent, name, fid, qid, &stat))
+    }
+
+    async fn getattr(&self, ino: u64) -> Result<FileAttr> {
+        let (_, _, stat) = self.node(ino)?.getattr(GetattrMask::BASIC).await?;
+        Ok(file_attr(ino, &stat))
+    }
+
+    async fn setattr(&self, ino: u64, valid: SetattrMask, stat: SetAttr) -> Result<FileAttr> {
+        self.node(ino)?.setattr(valid, stat).await?;
+        self.getattr(ino).await
+    }
+
+    // The kernel moves its entries itself, so the node and those below it are walked to
+    // again under their new names in case their fids went with the old ones
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<()> {
+        let (olddir, newdir) = (self.node(parent)?, self.node(newparent)?);
+        olddir.renameat(name, &newdir, newname).await?;
+        let fid = match newdir.walk(&[newname]).await {
+            Ok(fid) => Arc::new(fid),
+            Err(_) => return Ok(()),
+        };
+        let ino = match fid.qid() {
+            Some(qid) => self.ino(qid.path),
+            None => return Ok(()),
+        };
+        if let Some(node) = self.tables.lock().unwrap().nodes.get_mut(&ino) {
+            node.fid = fid.clone();
+            node.parent = newparent;
+            node.name = newname.to_owned();
+        }
+
+        for (below, wnames) in self.below(ino) {
+            // Left to fail with the old fid if it cannot be reached
+            if let Ok(walked) = fid.walk(&wnames).await {
+                if let Some(node) = self.tables.lock().unwrap().nodes.get_mut(&below) {
+                    node.fid = Arc::new(walked);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn open(&self, ino: u64, flags: LOpenFlags) -> Result<u64> {
+        let mut fid = self.node(ino)?.walk::<&str>(&[]).await?;
+        fid.lopen(flags).await?;
+        Ok(self.open_handle(fid))
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<(FileAttr, u64)> {
+        let dir = self.node(parent)?;
+        let mut fid = dir.walk::<&str>(&[]).await?;
+        fid.lcreate(name, flags | LOpenFlags::CREATE, mode, gid)
+            .await?;
+        let fh = self.open_handle(fid);
+        match self.lookup(parent, name).await {
+            Ok(attr) => Ok((attr, fh)),
+            Err(e) => {
+                let _ = self.close_handle(fh).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let fid = self.handle(fh)?;
+        let iounit = fid.client().iounit();
+        let mut buf = Vec::with_capacity(size as usize);
+        while buf.len() < size as usize {
+            let count = iounit.min(size - buf.len() as u32);
+            // Only an empty read is the end of the file in 9P, unlike in FUSE
+            let data = fid.read(offset + buf.len() as u64, count).await?;
+            if data.is_empty() {
+                break;
+            }
+            buf.extend_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    async fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        let fid = self.handle(fh)?;
+        let iounit = fid.client().iounit() as usize;
+        let mut written = 0;
+        for chunk in data.chunks(iounit) {
+            let count = fid.write(offset + written as u64, chunk).await?;
+            written += count as usize;
+            if (count as usize) < chunk.len() {
+                break;
+            }
+        }
+        Ok(written as u32)
+    }
+
+    // Read the extended attribute name, or the list of names if empty, of at most size bytes.
+    // The size is returned alone if size is 0.
+    async fn getxattr(&self, ino: u64, name: &OsStr, size: u32) -> Result<Xattr> {
+        let (fid, len) = self.node(ino)?.xattrwalk(name).await?;
+        if size == 0 {
+            return Ok(Xattr::Size(len as u32));
+        }
+        if len > size as u64 {
+            return Err(error::Error::No(ERANGE));
+        }
+
+        let mut value = Vec::with_capacity(len as usize);
+        while (value.len() as u64) < len {
+            let data = fid.read(value.len() as u64, fid.client().iounit()).await?;
+            if data.is_empty() {
+                break;
+            }
+            value.extend_from_slice(&data);
+        }
+        fid.clunk().await?;
+        Ok(Xattr::Data(value))
+    }
+
+    // The attribute is set, or removed if value is empty, when the fid is clunked
+    async fn setxattr(
+        &self,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> Result<()> {
+        let fid = self.node(ino)?.walk::<&str>(&[]).await?;
+        fid.xattrcreate(name, value.len() as u64, flags).await?;
+        let iounit = fid.client().iounit() as usize;
+        let mut offset = 0;
+        for chunk in value.chunks(iounit) {
+            fid.write(offset, chunk).await?;
+            offset += chunk.len() as u64;
+        }
+        fid.clunk().await
+    }
+
+    async fn getlk(&self, fh: u64, flock: Getlock) -> Result<Getlock> {
+        self.handle(fh)?.getlock(flock).await
+    }
+
+    async fn setlk(&self, fh: u64, flock: Flock) -> Result<()> {
+        let fid = self.handle(fh)?;
+        loop {
+            let status = fid.lock(flock.clone()).await?;
+            if status == LockStatus::SUCCESS {
+                return Ok(());
+            } else if status == LockStatus::ERROR {
+                return Err(error::Error::No(ENOLCK));
+            } else if !flock.flags.contains(LockFlag::BLOCK) {
+                return Err(error::Error::No(EAGAIN));
+            }
+            // Blocked, or the server is in its grace period
+            tokio::time::sleep(LOCK_RETRY).await;
+        }
+    }
+}
+
+enum Xattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+/// A FUSE file system forwarding every request to a 9P server.
+///
+/// Requests are read on the FUSE session thread; each spawns a task on
+/// the runtime which talks to the server and replies to the kernel.
+struct NineFuse {
+    rt: Handle,
+    bridge: Arc<Bridge>,
+}
+
+macro_rules! reply_entry {
+    ($reply:expr, $res:expr) => {
+        match $res {
+            Ok(attr) => $reply.entry(TTL, &attr),
+            Err(e) => $reply.error(errno(&e)),
+        }
+    };
+}
+
+macro_rules! reply_empty {
+    ($reply:expr, $res:expr) => {
+        match $res {
+            Ok(()) => $reply.ok(),
+            Err(e) => $reply.error(errno(&e)),
+        }
+    };
+}
+
+impl NineFuse {
+    fn spawn<F, Fut>(&self, f: F)
+    where
+        F: FnOnce(Arc<Bridge>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.rt.spawn(f(self.bridge.clone()));
+    }
+
+    fn dispatch(&self, req: Request, reply: Reply) {
+        let ino = req.nodeid;
+        match req.op {
+            Operation::Lookup { name } => {
+                let name = name.to_owned();
+                self.spawn(|b| async move { reply_entry!(reply, b.lookup(ino, &name).await) });
+            }
+
+            Operation::Forget(nodes) => {
+                // Dropping the fid spawns its Tclunk
+                let _rt = self.rt.enter();
+                for (ino, nlookup) in nodes {
+                    self.bridge.forget(ino, nlookup);
+                }
+            }
+
+            Operation::Getattr => self.spawn(|b| async move {
+                match b.getattr(ino).await {
+                    Ok(attr) => reply.attr(TTL, &attr),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Setattr {
+                mode,
+                uid,
+                gid,
+                size,
+                atime,
+                mtime,
+            } => {
+                let mut valid = SetattrMask::empty();
+                let mut stat = SetAttr {
+                    mode: FileMode::from_bits(mode.unwrap_or(0) & FileMode::PERM_MASK),
+                    uid: uid.unwrap_or(0),
+                    gid: gid.unwrap_or(0),
+                    size: size.unwrap_or(0),
+                    atime: Time { sec: 0, nsec: 0 },
+                    mtime: Time { sec: 0, nsec: 0 },
+                };
+                valid.set(SetattrMask::MODE, mode.is_some());
+                valid.set(SetattrMask::UID, uid.is_some());
+                valid.set(SetattrMask::GID, gid.is_some());
+                valid.set(SetattrMask::SIZE, size.is_some());
+                // The server takes the current time unless told otherwise
+                match atime {
+                    Some(TimeOrNow::Specific(t)) => {
+                        valid |= SetattrMask::ATIME | SetattrMask::ATIME_SET;
+                        stat.atime = t;
+                    }
+                    Some(TimeOrNow::Now) => valid |= SetattrMask::ATIME,
+                    None => {}
+                }
+                match mtime {
+                    Some(TimeOrNow::Specific(t)) => {
+                        valid |= SetattrMask::MTIME | SetattrMask::MTIME_SET;
+                        stat.mtime = t;
+                    }
+                    Some(TimeOrNow::Now) => valid |= SetattrMask::MTIME,
+                    None => {}
+                }
+
+                self.spawn(|b| async move {
+                    match b.setattr(ino, valid, stat).await {
+                        Ok(attr) => reply.attr(TTL, &attr),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            Operation::Readlink => self.spawn(|b| async move {
+                let res = async { b.node(ino)?.readlink().await };
+                match res.await {
+                    Ok(target) => reply.data(AsRef::<[u8]>::as_ref(&target)),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Mknod {
+                name,
+                mode,
+                umask,
+                rdev,
+            } => {
+                let (name, gid) = (name.to_owned(), req.gid);
+                let mode = FileMode::from_bits(mode & !umask);
+                let (major, minor) = (
+                    nix::sys::stat::major(rdev as u64),
+                    nix::sys::stat::minor(rdev as u64),
+                );
+                self.spawn(|b| async move {
+                    let res = async {
+                        b.node(ino)?
+                            .mknod(&name, mode, major as u32, minor as u32, gid)
+                            .await?;
+                        b.lookup(ino, &name).await
+                    };
+                    reply_entry!(reply, res.await)
+                });
+            }
+
+            Operation::Mkdir { name, mode, umask } => {
+                let (name, gid) = (name.to_owned(), req.gid);
+                let mode = FileMode::from_bits(mode & !umask);
+                self.spawn(|b| async move {
+                    let res = async {
+                        b.node(ino)?.mkdir(&name, mode, gid).await?;
+                        b.lookup(ino, &name).await
+                    };
+                    reply_entry!(reply, res.await)
+                });
+            }
+
+            Operation::Unlink { name } => {
+                let name = name.to_owned();
+                self.spawn(|b| async move {
+                    let res = async {
+                        let dir = b.node(ino)?;
+                        dir.unlinkat(&name, UnlinkatFlags::empty()).await
+                    };
+                    reply_empty!(reply, res.await)
+                });
+            }
+
+            Operation::Rmdir { name } => {
+                let name = name.to_owned();
+                self.spawn(|b| async move {
+                    let res = async {
+                        let dir = b.node(ino)?;
+                        dir.unlinkat(&name, UnlinkatFlags::REMOVEDIR).await
+                    };
+                    reply_empty!(reply, res.await)
+                });
+            }
+
+            Operation::Symlink { name, target } => {
+                let (name, target, gid) = (name.to_owned(), target.to_owned(), req.gid);
+                self.spawn(|b| async move {
+                    let res = async {
+                        b.node(ino)?.symlink(&name, &target, gid).await?;
+                        b.lookup(ino, &name).await
+                    };
+                    reply_entry!(reply, res.await)
+                });
+            }
+
+            // Trenameat has no RENAME_NOREPLACE nor RENAME_EXCHANGE
+            Operation::Rename { flags, .. } if flags != 0 => reply.error(EINVAL as i32),
+            Operation::Rename {
+                name,
+                newparent,
+                newname,
+                ..
+            } => {
+                let (name, newname) = (name.to_owned(), newname.to_owned());
+                self.spawn(|b| async move {
+                    reply_empty!(reply, b.rename(ino, &name, newparent, &newname).await)
+                });
+            }
+
+            Operation::Link {
+                ino: target,
+                newname,
+            } => {
+                let newname = newname.to_owned();
+                self.spawn(|b| async move {
+                    let res = async {
+                        let (fid, dir) = (b.node(target)?, b.node(ino)?);
+                        dir.link(&fid, &newname).await?;
+                        b.lookup(ino, &newname).await
+                    };
+                    reply_entry!(reply, res.await)
+                });
+            }
+
+            Operation::Open { flags } => {
+                let flags = open_flags(flags);
+                self.spawn(|b| async move {
+                    match b.open(ino, flags).await {
+                        Ok(fh) => reply.opened(fh),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            Operation::Read { fh, offset, size } => self.spawn(|b| async move {
+                match b.read(fh, offset, size).await {
+                    Ok(data) => reply.data(&data),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Write { fh, offset, data } => {
+                let data = data.to_vec();
+                self.spawn(|b| async move {
+                    match b.write(fh, offset, &data).await {
+                        Ok(count) => reply.written(count),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            // Writes are not buffered here
+            Operation::Flush => reply.ok(),
+
+            Operation::Release { fh } | Operation::Releasedir { fh } => {
+                self.spawn(|b| async move { reply_empty!(reply, b.close_handle(fh).await) })
+            }
+
+            Operation::Fsync { fh } => self.spawn(|b| async move {
+                let res = async { b.handle(fh)?.fsync().await };
+                reply_empty!(reply, res.await)
+            }),
+
+            Operation::Opendir => self.spawn(|b| async move {
+                let flags = LOpenFlags::RDONLY | LOpenFlags::DIRECTORY;
+                match b.open(ino, flags).await {
+                    Ok(fh) => reply.opened(fh),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Readdir { fh, offset, size } => self.spawn(|b| async move {
+                let res = async {
+                    let fid = b.handle(fh)?;
+                    fid.readdir(offset, fid.client().iounit()).await
+                };
+                match res.await {
+                    Ok(entries) => {
+                        let mut dir = DirBuf::new(size);
+                        for entry in entries {
+                            let ino = b.ino(entry.qid.path);
+                            if dir.add(ino, entry.offset, entry.typ, &entry.name) {
+                                break;
+                            }
+                        }
+                        reply.data(dir.as_bytes())
+                    }
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Statfs => self.spawn(|b| async move {
+                let res = async { b.node(ino)?.statfs().await };
+                match res.await {
+                    Ok(s) => reply.statfs(&Kstatfs {
+                        blocks: s.blocks,
+                        bfree: s.bfree,
+                        bavail: s.bavail,
+                        files: s.files,
+                        ffree: s.ffree,
+                        bsize: s.bsize,
+                        namelen: s.namelen,
+                        frsize: s.bsize,
+                    }),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Setxattr { name, value, flags } => {
+                let (name, value) = (name.to_owned(), value.to_vec());
+                let flags = XattrFlags::from_bits_truncate(flags as u32);
+                self.spawn(|b| async move {
+                    reply_empty!(reply, b.setxattr(ino, &name, &value, flags).await)
+                });
+            }
+
+            Operation::Getxattr { name, size } => {
+                let name = name.to_owned();
+                self.spawn(|b| async move {
+                    match b.getxattr(ino, &name, size).await {
+                        Ok(Xattr::Size(size)) => reply.xattr_size(size),
+                        Ok(Xattr::Data(value)) => reply.data(&value),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            Operation::Listxattr { size } => self.spawn(|b| async move {
+                match b.getxattr(ino, OsStr::new(""), size).await {
+                    Ok(Xattr::Size(size)) => reply.xattr_size(size),
+                    Ok(Xattr::Data(names)) => reply.data(&names),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }),
+
+            Operation::Removexattr { name } => {
+                let name = name.to_owned();
+                self.spawn(|b| async move {
+                    let res = b.setxattr(ino, &name, &[], XattrFlags::REPLACE).await;
+                    reply_empty!(reply, res)
+                });
+            }
+
+            Operation::Create {
+                name,
+                mode,
+                umask,
+                flags,
+            } => {
+                let (name, gid) = (name.to_owned(), req.gid);
+                let mode = FileMode::from_bits(mode & !umask);
+                let flags = LOpenFlags::from(OFlag::from_bits_truncate(flags));
+                self.spawn(|b| async move {
+                    match b.create(ino, &name, flags, mode, gid).await {
+                        Ok((attr, fh)) => reply.created(TTL, &attr, fh),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            Operation::Getlk { fh, lock } => {
+                let flock = Getlock {
+                    typ: LockType::from_bits_truncate(lock.typ as u8),
+                    start: lock.start,
+                    length: lock_length(lock.start, lock.end),
+                    proc_id: lock.pid,
+                    client_id: self.bridge.client_id.clone(),
+                };
+                self.spawn(|b| async move {
+                    match b.getlk(fh, flock).await {
+                        Ok(l) => reply.locked(&FileLock {
+                            start: l.start,
+                            end: lock_end(l.start, l.length),
+                            typ: l.typ.bits() as u32,
+                            pid: l.proc_id,
+                        }),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                });
+            }
+
+            Operation::Setlk { fh, lock, sleep } => {
+                let mut flags = LockFlag::empty();
+                flags.set(LockFlag::BLOCK, sleep);
+                let flock = Flock {
+                    typ: LockType::from_bits_truncate(lock.typ as u8),
+                    flags,
+                    start: lock.start,
+                    length: lock_length(lock.start, lock.end),
+                    proc_id: lock.pid,
+                    client_id: self.bridge.client_id.clone(),
+                };
+                self.spawn(|b| async move { reply_empty!(reply, b.setlk(fh, flock).await) });
+            }
+
+            Operation::Unsupported(opcode) => {
+                log::debug!("Unsupported FUSE opcode {}", opcode);
+                reply.error(ENOSYS as i32)
+            }
+        }
+    }
+}
+
+fn errno(e: &error::Error) -> i32 {
+    e.errno() as i32
+}
+
+// The kernel creates, excludes and truncates before opening
+fn open_flags(flags: i32) -> LOpenFlags {
+    LOpenFlags::from(OFlag::from_bits_truncate(flags))
+        - (LOpenFlags::CREATE | LOpenFlags::EXCL | LOpenFlags::NOCTTY)
+}
+
+// A lock length of 0 reaches the end of the file, as OFFSET_MAX does in FUSE
+fn lock_length(start: u64, end: u64) -> u64 {
+    if end >= i64::MAX as u64 {
+        0
+    } else {
+        end - start + 1
+    }
+}
+
+fn lock_end(start: u64, length: u64) -> u64 {
+    match length {
+        0 => i64::MAX as u64,
+        n => start + n - 1,
+    }
+}
+
+fn file_attr(ino: u64, stat: &Stat) -> FileAttr {
+    FileAttr {
+        ino,
+        size: stat.size,
+        blocks: stat.blocks,
+        atime: stat.atime,
+        mtime: stat.mtime,
+        ctime: stat.ctime,
+        mode: stat.mode.bits(),
+        nlink: stat.nlink as u32,
+        uid: stat.uid,
+        gid: stat.gid,
+        rdev: stat.rdev as u32,
+        blksize: stat.blksize as u32,
+    }
+}
+
+struct Options {
+    uname: String,
+    aname: String,
+    msize: u32,
+}
+
+async fn attach(addr: &str, options: &Options) -> Result<Bridge> {
+    let client = Client::connect_with_msize(addr, options.msize).await?;
+    let uid = nix::unistd::getuid().as_raw();
+    let root = client.attach(&options.uname, &options.aname, uid).await?;
+    let (_, qid, _) = root.getattr(GetattrMask::BASIC).await?;
+    Ok(Bridge::new(root, qid.path))
+}
+
+fn ninepfuse_main(args: Vec<String>) -> Result<i32> {
+    let mut options = Options {
+        uname: std::env::var("USER").unwrap_or_else(|_| "nobody".to_owned()),
+        aname: String::new(),
+        msize: 65536,
+    };
+
+    let mut args = args.into_iter().skip(1).peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+        match (arg.as_str(), args.next()) {
+            ("-u", Some(uname)) => options.uname = uname,
+            ("-A", Some(aname)) => options.aname = aname,
+            ("-m", Some(msize)) => match msize.parse() {
+                Ok(msize) => options.msize = msize,
+                Err(_) => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+    let (addr, mountpoint) = match (args.next(), args.next()) {
+        (Some(addr), Some(mountpoint)) => (addr, PathBuf::from(mountpoint)),
+        _ => return usage(),
+    };
+
+    let rt = Runtime::new()?;
+    let bridge = rt.block_on(attach(&addr, &options))?;
+    let fs = NineFuse {
+        rt: rt.handle().clone(),
+        bridge: Arc::new(bridge),
+    };
+    println!("[*] Mounting {} on {}", addr, mountpoint.display());
+    let mut session = Session::mount(&addr, &mountpoint)?;
+    while let Some((req, reply)) = session.next()? {
+        fs.dispatch(req, reply);
+    }
+    Ok(0)
+}
+
+fn usage() -> Result<i32> {
+    eprintln!("Usage: 9pfuse [-u uname] [-A aname] [-m msize] proto!address!port mountpoint");
+    eprintln!("  where: proto = tcp | unix");
+    Ok(-1)
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = std::env::args().collect();
+    let exit_code = ninepfuse_main(args).unwrap_or_else(|e| {
+        eprintln!("Error: {:?}", e);
+        -1
+    });
+
+    std::process::exit(exit_code);
+}
@@ -0,0 +1,111 @@
+//! Compare sequential reads and writes of a RemoteFile with and without
+//! read-ahead and write-behind, against unpfs over loopback TCP
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rs9p::client::{Client, RemoteFile};
+use rs9p::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const FILE_SIZE: usize = 16 << 20;
+const MSIZE: u32 = 64 * 1024;
+
+// unpfs exporting a directory of its own, killed when dropped
+struct Server {
+    addr: String,
+    export: PathBuf,
+    child: Child,
+}
+
+impl Server {
+    fn start() -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let export = std::env::temp_dir().join(format!("unpfs-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&export).unwrap();
+        std::fs::write(export.join("data"), vec![0xa5; FILE_SIZE]).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_unpfs"))
+            .arg(format!("tcp!127.0.0.1!{}", port))
+            .arg(&export)
+            .spawn()
+            .unwrap();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        Server {
+            addr: format!("tcp!127.0.0.1!{}", port),
+            export,
+            child,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.export);
+    }
+}
+
+fn pipelining(c: &mut Criterion) {
+    let server = Server::start();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let root = rt.block_on(async {
+        let client = Client::connect_with_msize(&server.addr, MSIZE)
+            .await
+            .unwrap();
+        client.attach("", "", 0).await.unwrap()
+    });
+
+    let mut group = c.benchmark_group("sequential");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+
+    for requests in [0, 4, 16] {
+        group.bench_function(format!("read, read-ahead {}", requests), |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let fid = root.walk(&["data"]).await.unwrap();
+                    let mut file = RemoteFile::open(fid, LOpenFlags::RDONLY).await.unwrap();
+                    file.set_read_ahead(requests);
+                    // io::copy reads 8K at a time, as most callers do
+                    let n = tokio::io::copy(&mut file, &mut tokio::io::sink())
+                        .await
+                        .unwrap();
+                    assert_eq!(n, FILE_SIZE as u64);
+                    file.close().await.unwrap();
+                })
+            })
+        });
+    }
+
+    let data = vec![0x5a; FILE_SIZE];
+    for requests in [0, 4, 16] {
+        group.bench_function(format!("write, write-behind {}", requests), |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let fid = root.walk(&["data"]).await.unwrap();
+                    let mut file = RemoteFile::open(fid, LOpenFlags::WRONLY).await.unwrap();
+                    file.set_write_behind(requests);
+                    for chunk in data.chunks(8192) {
+                        file.write_all(chunk).await.unwrap();
+                    }
+                    file.close().await.unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipelining);
+criterion_main!(benches);
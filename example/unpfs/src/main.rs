@@ -1,12 +1,16 @@
 use {
     async_trait::async_trait,
     filetime::FileTime,
-    nix::libc::{O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY},
     rs9p::{
-        srv::{srv_async, Fid, Filesystem},
+        srv::{
+            srv_async, Fid, Filesystem, Rattach, Rclunk, Rfsync, Rgetattr, Rlcreate, Rlopen,
+            Rmkdir, Rread, Rreaddir, Rreadlink, Rrenameat, Rsetattr, Rstatfs, Runlinkat, Rwalk,
+            Rwrite,
+        },
         *,
     },
     std::{
+        ffi::OsStr,
         io::SeekFrom,
         os::unix::{fs::PermissionsExt, io::FromRawFd},
         path::PathBuf,
@@ -35,14 +39,24 @@ use crate::utils::*;
 // The fix is to enumerate the set of flags we support and then and that with
 // the flags received in a TCREATE or TOPEN. This nicely fixes a real problem
 // we are seeing with a file system benchmark.
-const UNIX_FLAGS: u32 = (O_WRONLY | O_RDONLY | O_RDWR | O_CREAT | O_TRUNC) as u32;
+const UNIX_FLAGS: LOpenFlags = LOpenFlags::from_bits_truncate(
+    LOpenFlags::ACCMODE.bits() | LOpenFlags::CREATE.bits() | LOpenFlags::TRUNC.bits(),
+);
 
-#[derive(Default)]
 struct UnpfsFid {
     realpath: RwLock<PathBuf>,
     file: Mutex<Option<fs::File>>,
 }
 
+impl UnpfsFid {
+    fn new(realpath: PathBuf) -> UnpfsFid {
+        UnpfsFid {
+            realpath: RwLock::new(realpath),
+            file: Mutex::new(None),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Unpfs {
     realroot: PathBuf,
@@ -54,28 +68,22 @@ impl Filesystem for Unpfs {
 
     async fn rattach(
         &self,
-        fid: &Fid<Self::Fid>,
+        _fid: u32,
         _afid: Option<&Fid<Self::Fid>>,
         _uname: &str,
         _aname: &str,
         _n_uname: u32,
-    ) -> Result<Fcall> {
-        {
-            let mut realpath = fid.aux.realpath.write().await;
-            *realpath = PathBuf::from(&self.realroot);
-        }
-
-        Ok(Fcall::Rattach {
-            qid: get_qid(&self.realroot).await?,
-        })
+    ) -> Result<(Rattach, Self::Fid)> {
+        let qid = get_qid(&self.realroot).await?;
+        Ok((Rattach { qid }, UnpfsFid::new(self.realroot.clone())))
     }
 
     async fn rwalk(
         &self,
         fid: &Fid<Self::Fid>,
-        newfid: &Fid<Self::Fid>,
-        wnames: &[String],
-    ) -> Result<Fcall> {
+        _newfid: u32,
+        wnames: &[&OsStr],
+    ) -> Result<(Rwalk, Self::Fid)> {
         let mut wqids = Vec::new();
         let mut path = {
             let realpath = fid.aux.realpath.read().await;
@@ -99,21 +107,16 @@ impl Filesystem for Unpfs {
             wqids.push(qid);
         }
 
-        {
-            let mut new_realpath = newfid.aux.realpath.write().await;
-            *new_realpath = path;
-        }
-
-        Ok(Fcall::Rwalk { wqids })
+        Ok((Rwalk { wqids }, UnpfsFid::new(path)))
     }
 
-    async fn rgetattr(&self, fid: &Fid<Self::Fid>, req_mask: GetattrMask) -> Result<Fcall> {
+    async fn rgetattr(&self, fid: &Fid<Self::Fid>, req_mask: GetattrMask) -> Result<Rgetattr> {
         let attr = {
             let realpath = fid.aux.realpath.read().await;
             fs::symlink_metadata(&*realpath).await?
         };
 
-        Ok(Fcall::Rgetattr {
+        Ok(Rgetattr {
             valid: req_mask,
             qid: qid_from_attr(&attr),
             stat: From::from(attr),
@@ -125,14 +128,18 @@ impl Filesystem for Unpfs {
         fid: &Fid<Self::Fid>,
         valid: SetattrMask,
         stat: &SetAttr,
-    ) -> Result<Fcall> {
+    ) -> Result<Rsetattr> {
         let filepath = {
             let realpath = fid.aux.realpath.read().await;
             realpath.clone()
         };
 
         if valid.contains(SetattrMask::MODE) {
-            fs::set_permissions(&filepath, PermissionsExt::from_mode(stat.mode)).await?;
+            fs::set_permissions(
+                &filepath,
+                PermissionsExt::from_mode(stat.mode.permissions()),
+            )
+            .await?;
         }
 
         if valid.intersects(SetattrMask::UID | SetattrMask::GID) {
@@ -179,21 +186,21 @@ impl Filesystem for Unpfs {
             .await;
         }
 
-        Ok(Fcall::Rsetattr)
+        Ok(Rsetattr)
     }
 
-    async fn rreadlink(&self, fid: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rreadlink(&self, fid: &Fid<Self::Fid>) -> Result<Rreadlink> {
         let link = {
             let realpath = fid.aux.realpath.read().await;
             fs::read_link(&*realpath).await?
         };
 
-        Ok(Fcall::Rreadlink {
-            target: link.to_string_lossy().into_owned(),
+        Ok(Rreadlink {
+            target: link.into(),
         })
     }
 
-    async fn rreaddir(&self, fid: &Fid<Self::Fid>, off: u64, count: u32) -> Result<Fcall> {
+    async fn rreaddir(&self, fid: &Fid<Self::Fid>, off: u64, count: u32) -> Result<Rreaddir> {
         let mut dirents = DirEntryData::new();
 
         let offset = if off == 0 {
@@ -219,10 +226,10 @@ impl Filesystem for Unpfs {
             i += 1;
         }
 
-        Ok(Fcall::Rreaddir { data: dirents })
+        Ok(Rreaddir { data: dirents })
     }
 
-    async fn rlopen(&self, fid: &Fid<Self::Fid>, flags: u32) -> Result<Fcall> {
+    async fn rlopen(&self, fid: &Fid<Self::Fid>, flags: LOpenFlags) -> Result<Rlopen> {
         let realpath = {
             let realpath = fid.aux.realpath.read().await;
             realpath.clone()
@@ -230,7 +237,7 @@ impl Filesystem for Unpfs {
 
         let qid = get_qid(&realpath).await?;
         if !qid.typ.contains(QidType::DIR) {
-            let oflags = nix::fcntl::OFlag::from_bits_truncate((flags & UNIX_FLAGS) as i32);
+            let oflags = nix::fcntl::OFlag::from(flags & UNIX_FLAGS);
             let omode = nix::sys::stat::Mode::from_bits_truncate(0);
             let fd = nix::fcntl::open(&realpath, oflags, omode)?;
 
@@ -242,23 +249,23 @@ impl Filesystem for Unpfs {
             }
         }
 
-        Ok(Fcall::Rlopen { qid, iounit: 0 })
+        Ok(Rlopen { qid, iounit: 0 })
     }
 
     async fn rlcreate(
         &self,
         fid: &Fid<Self::Fid>,
-        name: &str,
-        flags: u32,
-        mode: u32,
+        name: &OsStr,
+        flags: LOpenFlags,
+        mode: FileMode,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rlcreate> {
         let path = {
             let realpath = fid.aux.realpath.read().await;
             realpath.join(name)
         };
-        let oflags = nix::fcntl::OFlag::from_bits_truncate((flags & UNIX_FLAGS) as i32);
-        let omode = nix::sys::stat::Mode::from_bits_truncate(mode);
+        let oflags = nix::fcntl::OFlag::from(flags & UNIX_FLAGS);
+        let omode = nix::sys::stat::Mode::from(mode);
         let fd = nix::fcntl::open(&path, oflags, omode)?;
 
         let qid = get_qid(&path).await?;
@@ -273,42 +280,42 @@ impl Filesystem for Unpfs {
             }));
         }
 
-        Ok(Fcall::Rlcreate { qid, iounit: 0 })
+        Ok(Rlcreate { qid, iounit: 0 })
     }
 
-    async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<Fcall> {
+    async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<Rread> {
         let buf = {
             let mut file = fid.aux.file.lock().await;
             let file = file.as_mut().ok_or_else(|| INVALID_FID!())?;
             file.seek(SeekFrom::Start(offset)).await?;
 
-            let mut buf = create_buffer(count as usize);
+            let mut buf = vec![0; count as usize];
             let bytes = file.read(&mut buf[..]).await?;
             buf.truncate(bytes);
             buf
         };
 
-        Ok(Fcall::Rread { data: Data(buf) })
+        Ok(Rread { data: Data(buf) })
     }
 
-    async fn rwrite(&self, fid: &Fid<Self::Fid>, offset: u64, data: &Data) -> Result<Fcall> {
+    async fn rwrite(&self, fid: &Fid<Self::Fid>, offset: u64, data: &[u8]) -> Result<Rwrite> {
         let count = {
             let mut file = fid.aux.file.lock().await;
             let file = file.as_mut().ok_or_else(|| INVALID_FID!())?;
             file.seek(SeekFrom::Start(offset)).await?;
-            file.write(&data.0).await? as u32
+            file.write(data).await? as u32
         };
 
-        Ok(Fcall::Rwrite { count })
+        Ok(Rwrite { count })
     }
 
     async fn rmkdir(
         &self,
         dfid: &Fid<Self::Fid>,
-        name: &str,
-        _mode: u32,
+        name: &OsStr,
+        _mode: FileMode,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rmkdir> {
         let path = {
             let realpath = dfid.aux.realpath.read().await;
             realpath.join(name)
@@ -316,7 +323,7 @@ impl Filesystem for Unpfs {
 
         fs::create_dir(&path).await?;
 
-        Ok(Fcall::Rmkdir {
+        Ok(Rmkdir {
             qid: get_qid(&path).await?,
         })
     }
@@ -324,10 +331,10 @@ impl Filesystem for Unpfs {
     async fn rrenameat(
         &self,
         olddir: &Fid<Self::Fid>,
-        oldname: &str,
+        oldname: &OsStr,
         newdir: &Fid<Self::Fid>,
-        newname: &str,
-    ) -> Result<Fcall> {
+        newname: &OsStr,
+    ) -> Result<Rrenameat> {
         let oldpath = {
             let realpath = olddir.aux.realpath.read().await;
             realpath.join(oldname)
@@ -340,24 +347,30 @@ impl Filesystem for Unpfs {
 
         fs::rename(&oldpath, &newpath).await?;
 
-        Ok(Fcall::Rrenameat)
+        Ok(Rrenameat)
     }
 
-    async fn runlinkat(&self, dirfid: &Fid<Self::Fid>, name: &str, _flags: u32) -> Result<Fcall> {
+    async fn runlinkat(
+        &self,
+        dirfid: &Fid<Self::Fid>,
+        name: &OsStr,
+        flags: UnlinkatFlags,
+    ) -> Result<Runlinkat> {
         let path = {
             let realpath = dirfid.aux.realpath.read().await;
             realpath.join(name)
         };
 
-        match fs::symlink_metadata(&path).await? {
-            ref attr if attr.is_dir() => fs::remove_dir(&path).await?,
-            _ => fs::remove_file(&path).await?,
-        };
+        if flags.contains(UnlinkatFlags::REMOVEDIR) {
+            fs::remove_dir(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
 
-        Ok(Fcall::Runlinkat)
+        Ok(Runlinkat)
     }
 
-    async fn rfsync(&self, fid: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rfsync(&self, fid: &Fid<Self::Fid>) -> Result<Rfsync> {
         {
             let mut file = fid.aux.file.lock().await;
             file.as_mut()
@@ -366,14 +379,14 @@ impl Filesystem for Unpfs {
                 .await?;
         }
 
-        Ok(Fcall::Rfsync)
+        Ok(Rfsync)
     }
 
-    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
-        Ok(Fcall::Rclunk)
+    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Rclunk> {
+        Ok(Rclunk)
     }
 
-    async fn rstatfs(&self, fid: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rstatfs(&self, fid: &Fid<Self::Fid>) -> Result<Rstatfs> {
         let path = {
             let realpath = fid.aux.realpath.read().await;
             realpath.clone()
@@ -384,7 +397,7 @@ impl Filesystem for Unpfs {
             .await
             .unwrap()?;
 
-        Ok(Fcall::Rstatfs {
+        Ok(Rstatfs {
             statfs: From::from(fs),
         })
     }
@@ -25,14 +25,6 @@ macro_rules! INVALID_FID {
     };
 }
 
-pub fn create_buffer(size: usize) -> Vec<u8> {
-    let mut buffer = Vec::with_capacity(size);
-    unsafe {
-        buffer.set_len(size);
-    }
-    buffer
-}
-
 pub async fn get_qid<T: AsRef<Path> + ?Sized>(path: &T) -> rs9p::Result<Qid> {
     Ok(qid_from_attr(&fs::symlink_metadata(path.as_ref()).await?))
 }
@@ -49,19 +41,21 @@ pub async fn get_dirent_from<P: AsRef<Path> + ?Sized>(
     p: &P,
     offset: u64,
 ) -> rs9p::Result<DirEntry> {
+    let attr = fs::symlink_metadata(p.as_ref()).await?;
     Ok(DirEntry {
-        qid: get_qid(p).await?,
+        qid: qid_from_attr(&attr),
         offset,
-        typ: 0,
-        name: p.as_ref().to_string_lossy().into_owned(),
+        typ: FileType::from(attr.file_type()).dirent_type(),
+        name: p.as_ref().as_os_str().into(),
     })
 }
 
 pub async fn get_dirent(entry: &fs::DirEntry, offset: u64) -> rs9p::Result<DirEntry> {
+    let attr = entry.metadata().await?;
     Ok(DirEntry {
-        qid: qid_from_attr(&entry.metadata().await?),
+        qid: qid_from_attr(&attr),
         offset,
-        typ: 0,
-        name: entry.file_name().to_string_lossy().into_owned(),
+        typ: FileType::from(attr.file_type()).dirent_type(),
+        name: entry.file_name().into(),
     })
 }
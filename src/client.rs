@@ -0,0 +1,1079 @@
+//! Asynchronous client side 9P library.
+//!
+//! A `Client` multiplexes concurrent requests over one connection,
+//! matching replies to requests by tag.
+//! Files on the server are reached through `Fid` handles obtained from `Client::attach`.
+//! `blocking` has a synchronous counterpart for code without a tokio runtime.
+//!
+//! # Protocol
+//! 9P2000.L
+
+pub mod blocking;
+mod cache;
+mod file;
+mod fs;
+mod resilience;
+
+pub use self::cache::CachePolicy;
+pub use self::file::RemoteFile;
+pub use self::fs::{Fs, ReadDir};
+pub use self::resilience::Reconnect;
+
+use self::{cache::Cache, resilience::Resilience};
+
+use {
+    crate::{
+        error,
+        error::errno::*,
+        fcall::*,
+        serialize::{self, Encodable},
+        utils::{self, Result},
+    },
+    bytes::BytesMut,
+    futures::sink::SinkExt,
+    std::{
+        collections::{HashMap, HashSet},
+        ffi::OsStr,
+        io,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex as StdMutex,
+        },
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::{TcpStream, UnixStream},
+        sync::{oneshot, Mutex},
+    },
+    tokio_stream::StreamExt,
+    tokio_util::codec::{length_delimited::LengthDelimitedCodec, FramedWrite},
+};
+
+/// msize requested by `Client::connect`
+pub const DEFAULT_MSIZE: u32 = 128 * 1024;
+
+type Writer = FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, LengthDelimitedCodec>;
+
+// Requests waiting for their reply, by tag
+#[derive(Default)]
+struct Pending {
+    replies: HashMap<u16, oneshot::Sender<Fcall>>,
+    // Tags of abandoned requests, reserved until their Tflush is answered
+    flushing: HashSet<u16>,
+    next_tag: u16,
+    // Set once the connection is gone; no more requests can be sent
+    closed: bool,
+}
+
+impl Pending {
+    fn register(&mut self) -> Result<(u16, oneshot::Receiver<Fcall>)> {
+        if self.closed {
+            return Err(error::Error::No(ECONNRESET));
+        }
+        // NOTAG is reserved for Tversion
+        if self.replies.len() + self.flushing.len() >= NOTAG as usize {
+            return Err(error::Error::No(EAGAIN));
+        }
+
+        while self.next_tag == NOTAG
+            || self.replies.contains_key(&self.next_tag)
+            || self.flushing.contains(&self.next_tag)
+        {
+            self.next_tag = self.next_tag.wrapping_add(1);
+        }
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+
+        let (tx, rx) = oneshot::channel();
+        self.replies.insert(tag, tx);
+        Ok((tag, rx))
+    }
+}
+
+// Fid numbers, reused once clunked
+#[derive(Default)]
+struct FidPool {
+    next: u32,
+    free: Vec<u32>,
+}
+
+impl FidPool {
+    fn alloc(&mut self) -> Result<u32> {
+        if let Some(fid) = self.free.pop() {
+            return Ok(fid);
+        }
+        if self.next == NOFID {
+            return Err(error::Error::No(EMFILE));
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    fn release(&mut self, fid: u32) {
+        self.free.push(fid);
+    }
+}
+
+// A single connection to the server; a resilient client replaces it after losing it
+struct Conn {
+    writer: Mutex<Writer>,
+    pending: Arc<StdMutex<Pending>>,
+    msize: AtomicU32,
+}
+
+impl Conn {
+    async fn new<R, W>(reader: R, writer: W, msize: u32) -> Result<Arc<Conn>>
+    where
+        R: 'static + AsyncRead + Send + Unpin,
+        W: 'static + AsyncWrite + Send + Unpin,
+    {
+        check_msize(msize)?;
+        let framedread = LengthDelimitedCodec::builder()
+            .length_field_offset(0)
+            .length_field_length(4)
+            .length_adjustment(-4)
+            .little_endian()
+            .new_read(reader);
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = Box::new(writer);
+        let framedwrite = LengthDelimitedCodec::builder()
+            .length_field_offset(0)
+            .length_field_length(4)
+            .length_adjustment(-4)
+            .little_endian()
+            .new_write(writer);
+
+        let pending = Arc::new(StdMutex::new(Pending::default()));
+        tokio::spawn(receive(framedread, pending.clone()));
+
+        let conn = Arc::new(Conn {
+            writer: Mutex::new(framedwrite),
+            pending,
+            // Until Rversion, only Tversion itself is sent
+            msize: AtomicU32::new(msize),
+        });
+        conn.version(msize).await?;
+        Ok(conn)
+    }
+
+    async fn version(&self, msize: u32) -> Result<()> {
+        let request = Fcall::Tversion {
+            msize,
+            version: P92000L.to_owned(),
+        };
+        match self.send(NOTAG, request).await? {
+            Fcall::Rversion { msize: m, .. } if !msize_fits(m) => Err(error::Error::No(EPROTO)),
+            Fcall::Rversion { msize: m, version } if version == P92000L && m <= msize => {
+                self.msize.store(m, Ordering::Relaxed);
+                Ok(())
+            }
+            Fcall::Rversion { .. } => Err(error::Error::No(EPROTONOSUPPORT)),
+            response => unexpected(response),
+        }
+    }
+
+    fn msize(&self) -> u32 {
+        self.msize.load(Ordering::Relaxed)
+    }
+
+    // Whether the connection is gone, so that no request can succeed on it anymore
+    fn lost(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    async fn call(self: &Arc<Self>, request: Fcall) -> Result<Fcall> {
+        let (tag, reply) = self.pending.lock().unwrap().register()?;
+        let mut abandoned = Abandoned {
+            conn: self,
+            tag: Some(tag),
+        };
+        if let Err(e) = self.write(tag, request).await {
+            abandoned.tag = None;
+            self.pending.lock().unwrap().replies.remove(&tag);
+            return Err(e);
+        }
+        let res = Self::reply(reply).await;
+        abandoned.tag = None;
+        res
+    }
+
+    // Cut short the request with oldtag, whose reply nobody is waiting for anymore
+    fn flush(self: &Arc<Self>, oldtag: u16) {
+        let rt = match tokio::runtime::Handle::try_current() {
+            Ok(rt) => rt,
+            // The reply still frees the tag when it arrives
+            Err(_) => return,
+        };
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.replies.remove(&oldtag).is_none() {
+                return;
+            }
+            pending.flushing.insert(oldtag);
+        }
+
+        // Tflush itself is never flushed, so dropping this task cannot spawn another
+        let conn = self.clone();
+        rt.spawn(async move {
+            let res = async {
+                let (tag, reply) = conn.pending.lock().unwrap().register()?;
+                conn.write(tag, Fcall::Tflush { oldtag }).await?;
+                Self::reply(reply).await
+            };
+            match res.await {
+                Ok(Fcall::Rflush) => {}
+                Ok(response) => error!("Unexpected response: {}", response),
+                Err(e) => debug!("Tflush {}: Error: \"{}\"", oldtag, e),
+            }
+            conn.pending.lock().unwrap().flushing.remove(&oldtag);
+        });
+    }
+
+    // Tversion is sent with NOTAG, which the pending table never hands out
+    async fn send(&self, tag: u16, request: Fcall) -> Result<Fcall> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().replies.insert(tag, tx);
+        self.write(tag, request).await?;
+        Self::reply(rx).await
+    }
+
+    async fn write(&self, tag: u16, body: Fcall) -> Result<()> {
+        let msg = Msg { tag, body };
+        // size[4] is prepended by the codec
+        let len = msg.encoded_len();
+        let msize = self.msize();
+        if len + 4 > msize as usize {
+            return res!(io::Error::from(serialize::EncodeError::MessageTooLarge {
+                len: len + 4,
+                msize,
+            }));
+        }
+
+        let mut buf = BytesMut::with_capacity(len);
+        serialize::write_msg_buf(&mut buf, &msg)?;
+        debug!("\t→ {}", msg);
+        if let Err(e) = self.writer.lock().await.send(buf.freeze()).await {
+            // A broken stream takes the connection with it
+            error!("Error: \"{}\": {:?}", e, e);
+            self.pending.lock().unwrap().closed = true;
+            return Err(error::Error::No(ECONNRESET));
+        }
+        Ok(())
+    }
+
+    async fn reply(reply: oneshot::Receiver<Fcall>) -> Result<Fcall> {
+        match reply.await {
+            Ok(Fcall::Rlerror { ecode }) => {
+                Err(error::Error::No(nix::errno::from_i32(ecode as i32)))
+            }
+            Ok(response) => Ok(response),
+            Err(_) => Err(error::Error::No(ECONNRESET)),
+        }
+    }
+}
+
+struct Inner {
+    conn: StdMutex<Arc<Conn>>,
+    fids: StdMutex<FidPool>,
+    // Set for a client which reconnects by itself
+    resilience: Option<Resilience>,
+}
+
+/// Connection to a 9P2000.L server.
+///
+/// Cloning a `Client` is cheap and shares the connection,
+/// which is closed once the client and all of its `Fid`s are dropped.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+    cache: Option<Arc<Cache>>,
+}
+
+impl Client {
+    /// Connect to a server at `proto!address!port`, e.g. `tcp!127.0.0.1!564`,
+    /// and negotiate 9P2000.L with `DEFAULT_MSIZE`.
+    pub async fn connect(addr: &str) -> Result<Client> {
+        Client::connect_with_msize(addr, DEFAULT_MSIZE).await
+    }
+
+    /// Like `connect`, asking for a maximum message size of `msize`,
+    /// which must leave room for a payload beyond `IOHDRSZ` (EINVAL).
+    pub async fn connect_with_msize(addr: &str, msize: u32) -> Result<Client> {
+        check_msize(msize)?;
+        let (reader, writer) = open(addr).await?;
+        Client::new(reader, writer, msize).await
+    }
+
+    /// Like `connect_with_msize`, reconnecting to `addr` according to `policy`
+    /// whenever the connection is lost.
+    ///
+    /// After reconnecting, every live fid is attached and walked again from the
+    /// path it was reached by, and opened again with its original flags.
+    /// A fid whose path no longer resolves fails with ESTALE from then on.
+    /// Requests which are safe to repeat are retried on the new connection,
+    /// others fail with the error which revealed the loss. Writes to a fid
+    /// opened with `LOpenFlags::APPEND` are not retried.
+    /// Locks and extended attribute fids do not survive a reconnection.
+    pub async fn connect_resilient(addr: &str, msize: u32, policy: Reconnect) -> Result<Client> {
+        check_msize(msize)?;
+        let (reader, writer) = open(addr).await?;
+        let conn = Conn::new(reader, writer, msize).await?;
+        Ok(Client::with_conn(
+            conn,
+            Some(Resilience::new(addr, msize, policy)),
+        ))
+    }
+
+    /// Speak 9P2000.L over an established stream, asking for a maximum message size of `msize`.
+    ///
+    /// Must be called within a tokio runtime, which runs the task receiving replies.
+    pub async fn new<R, W>(reader: R, writer: W, msize: u32) -> Result<Client>
+    where
+        R: 'static + AsyncRead + Send + Unpin,
+        W: 'static + AsyncWrite + Send + Unpin,
+    {
+        let conn = Conn::new(reader, writer, msize).await?;
+        Ok(Client::with_conn(conn, None))
+    }
+
+    fn with_conn(conn: Arc<Conn>, resilience: Option<Resilience>) -> Client {
+        Client {
+            inner: Arc::new(Inner {
+                conn: StdMutex::new(conn),
+                fids: Default::default(),
+                resilience,
+            }),
+            cache: None,
+        }
+    }
+
+    /// Get a client on the same connection which caches attributes and walks
+    /// according to `policy`, for the fids it makes.
+    ///
+    /// Entries are keyed by `qid.path`. Changes made through these fids drop the
+    /// entries they affect, and a qid from the server with a different `version`,
+    /// or attributes with a different `data_version`, than the cached ones drop the
+    /// entries of that file. Other remote changes go unnoticed until the entries expire.
+    pub fn with_cache(&self, policy: CachePolicy) -> Client {
+        Client {
+            inner: self.inner.clone(),
+            cache: Some(Arc::new(Cache::new(policy))),
+        }
+    }
+
+    fn conn(&self) -> Arc<Conn> {
+        self.inner.conn.lock().unwrap().clone()
+    }
+
+    /// Get the maximum message size negotiated with the server.
+    pub fn msize(&self) -> u32 {
+        self.conn().msize()
+    }
+
+    /// Get the largest payload a single `Tread` or `Twrite` can carry.
+    pub fn iounit(&self) -> u32 {
+        self.msize() - IOHDRSZ
+    }
+
+    /// Attach to the file tree `aname` of the server as `uname`, without authentication.
+    pub async fn attach(&self, uname: &str, aname: &str, n_uname: u32) -> Result<Fid> {
+        let fid = self.alloc_fid()?;
+        let request = Fcall::Tattach {
+            fid: fid.fid,
+            afid: NOFID,
+            uname: uname.to_owned(),
+            aname: aname.to_owned(),
+            n_uname,
+        };
+        match self.rpc(request).await {
+            Ok(Fcall::Rattach { qid }) => Ok(fid.with_qid(qid)),
+            Ok(response) => fid.unused(unexpected(response)),
+            Err(e) => fid.unused(Err(e)),
+        }
+    }
+
+    /// Send a request and wait for its reply.
+    ///
+    /// `Rlerror` is returned as an `Err` carrying its errno.
+    /// If the returned future is dropped before the reply arrives, the request is
+    /// flushed with `Tflush` and its tag is reused only once `Rflush` arrives.
+    pub async fn rpc(&self, request: Fcall) -> Result<Fcall> {
+        let resilience = match self.inner.resilience {
+            Some(ref resilience) => resilience,
+            None => return self.conn().call(request).await,
+        };
+        if let Some(res) = resilience.check(&request) {
+            return res;
+        }
+
+        loop {
+            let conn = self.conn();
+            let res = conn.call(request.clone()).await;
+            if res.is_err() && conn.lost() {
+                self.reconnect(&conn).await?;
+                if resilience.idempotent(&request) {
+                    if let Some(res) = resilience.check(&request) {
+                        return res;
+                    }
+                    continue;
+                }
+            }
+            resilience.track(&request, &res);
+            return res;
+        }
+    }
+
+    async fn reconnect(&self, lost: &Arc<Conn>) -> Result<()> {
+        let resilience = self.inner.resilience.as_ref().unwrap();
+        let _reconnecting = resilience.reconnecting.lock().await;
+        // Someone else has already replaced the connection
+        if !Arc::ptr_eq(&self.conn(), lost) {
+            return Ok(());
+        }
+
+        let conn = resilience.reconnect().await?;
+        *self.inner.conn.lock().unwrap() = conn;
+        Ok(())
+    }
+
+    fn alloc_fid(&self) -> Result<Fid> {
+        let fid = self.inner.fids.lock().unwrap().alloc()?;
+        Ok(Fid {
+            client: self.clone(),
+            fid,
+            qid: None,
+            clunked: false,
+        })
+    }
+}
+
+type Stream = (
+    Box<dyn AsyncRead + Send + Unpin>,
+    Box<dyn AsyncWrite + Send + Unpin>,
+);
+
+// Connect to proto!address!port
+async fn open(addr: &str) -> Result<Stream> {
+    let (proto, addr) = utils::parse_proto(addr)
+        .ok_or_else(|| io_err!(InvalidInput, "Invalid protocol or address"))?;
+
+    match proto {
+        "tcp" => {
+            let stream = TcpStream::connect(&addr).await?;
+            stream.set_nodelay(true)?;
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        "unix" => {
+            let (reader, writer) = tokio::io::split(UnixStream::connect(&addr).await?);
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        _ => res!(io_err!(InvalidInput, "Protocol not supported")),
+    }
+}
+
+// Route replies to the requests waiting for them until the connection is gone
+async fn receive<R>(
+    mut framedread: tokio_util::codec::FramedRead<R, LengthDelimitedCodec>,
+    pending: Arc<StdMutex<Pending>>,
+) where
+    R: AsyncRead + Unpin,
+{
+    while let Some(frame) = framedread.next().await {
+        let msg = match frame.and_then(|frame| serialize::decode_msg(&frame)) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Error: \"{}\": {:?}", e, e);
+                break;
+            }
+        };
+        debug!("\t← {}", msg);
+
+        let mut pending = pending.lock().unwrap();
+        match pending.replies.remove(&msg.tag) {
+            Some(reply) => {
+                // The request may have been given up on
+                let _ = reply.send(msg.body);
+            }
+            // Answered before the server saw its Tflush
+            None if pending.flushing.contains(&msg.tag) => {}
+            None => error!("Reply to unknown tag {}: {}", msg.tag, msg),
+        }
+    }
+
+    // Dropping the senders fails the requests still waiting
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.replies.clear();
+    pending.flushing.clear();
+}
+
+// Flushes the request with tag if the future waiting for its reply is dropped
+struct Abandoned<'a> {
+    conn: &'a Arc<Conn>,
+    tag: Option<u16>,
+}
+
+impl Drop for Abandoned<'_> {
+    fn drop(&mut self) {
+        if let Some(tag) = self.tag {
+            self.conn.flush(tag);
+        }
+    }
+}
+
+fn unexpected<T>(response: Fcall) -> Result<T> {
+    error!("Unexpected response: {}", response);
+    Err(error::Error::No(EPROTO))
+}
+
+// An msize must leave room for the payload of a Tread, Twrite or Treaddir
+fn msize_fits(msize: u32) -> bool {
+    msize > IOHDRSZ.max(READDIRHDRSZ)
+}
+
+fn check_msize(msize: u32) -> Result<()> {
+    match msize_fits(msize) {
+        true => Ok(()),
+        false => Err(error::Error::No(EINVAL)),
+    }
+}
+
+/// A fid of the client, pointing to a file on the server.
+///
+/// Dropping a `Fid` clunks it in the background; `clunk` waits for the server to confirm.
+pub struct Fid {
+    client: Client,
+    fid: u32,
+    qid: Option<Qid>,
+    clunked: bool,
+}
+
+impl Fid {
+    /// Get the raw fid.
+    pub fn fid(&self) -> u32 {
+        self.fid
+    }
+
+    /// Get the qid the server returned when this fid was attached, walked to or opened.
+    ///
+    /// A clone made by walking no names has the qid of its origin.
+    pub fn qid(&self) -> Option<Qid> {
+        self.qid
+    }
+
+    /// Get the client this fid belongs to.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    fn with_qid(mut self, qid: Qid) -> Fid {
+        self.qid = Some(qid);
+        self
+    }
+
+    // Let the cache of the client, if any, know about this file
+    fn cached<F: FnOnce(&Cache, Qid)>(&self, f: F) {
+        if let (Some(cache), Some(qid)) = (&self.client.cache, self.qid) {
+            f(cache, qid)
+        }
+    }
+
+    // Attributes of the file at names from this one, if the cache knows them all
+    pub(super) fn cached_getattr(&self, names: &[NineString]) -> Option<Stat> {
+        let (cache, qid) = (self.client.cache.as_ref()?, self.qid?);
+        let qid = match names.is_empty() {
+            true => qid,
+            false => cache.lookup(qid.path, names)?,
+        };
+        cache
+            .getattr(&qid, GetattrMask::BASIC)
+            .map(|(_, _, stat)| stat)
+    }
+
+    // The server never learned about the fid, so its number can be reused right away
+    fn unused<T>(mut self, res: Result<T>) -> Result<T> {
+        self.clunked = true;
+        self.client.inner.fids.lock().unwrap().release(self.fid);
+        res
+    }
+
+    /// Walk to the file at `wnames` relative to this fid, which must not be open.
+    ///
+    /// Walks longer than `MAXWELEM` are split into several requests.
+    /// A walk which cannot reach every name fails with ENOENT.
+    pub async fn walk<S: AsRef<OsStr>>(&self, wnames: &[S]) -> Result<Fid> {
+        let wnames: Vec<NineString> = wnames.iter().map(|s| s.as_ref().into()).collect();
+        let mut new = self.client.alloc_fid()?;
+        new.qid = self.qid;
+        let mut chunks = wnames.chunks(MAXWELEM);
+        let mut from = self.fid;
+
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            let request = Fcall::Twalk {
+                fid: from,
+                newfid: new.fid,
+                wnames: chunk.to_vec(),
+            };
+            let wqids = match self.client.rpc(request).await {
+                Ok(Fcall::Rwalk { wqids }) => wqids,
+                // The first walk creates newfid only if it succeeds
+                Ok(response) if from == self.fid => return new.unused(unexpected(response)),
+                Err(e) if from == self.fid => return new.unused(Err(e)),
+                Ok(response) => return unexpected(response),
+                Err(e) => return Err(e),
+            };
+
+            if wqids.len() != chunk.len() {
+                return match from == self.fid {
+                    true => new.unused(Err(error::Error::No(ENOENT))),
+                    false => Err(error::Error::No(ENOENT)),
+                };
+            }
+            new.cached(|cache, dir| cache.walked(dir.path, chunk, &wqids));
+            if let Some(qid) = wqids.last() {
+                new.qid = Some(*qid);
+            }
+
+            from = new.fid;
+            if chunks.len() == 0 {
+                return Ok(new);
+            }
+        }
+    }
+
+    /// Open the file.
+    ///
+    /// Returns the qid of the file and the iounit, 0 if the server does not limit I/O further.
+    pub async fn lopen(&mut self, flags: LOpenFlags) -> Result<(Qid, u32)> {
+        match self
+            .client
+            .rpc(Fcall::Tlopen {
+                fid: self.fid,
+                flags,
+            })
+            .await?
+        {
+            Fcall::Rlopen { qid, iounit } => {
+                self.qid = Some(qid);
+                self.cached(|cache, qid| cache.observe(&qid));
+                Ok((qid, iounit))
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Create and open the file `name` in this directory; the fid then points to the new file.
+    pub async fn lcreate<S: AsRef<OsStr>>(
+        &mut self,
+        name: S,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<(Qid, u32)> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tlcreate {
+            fid: self.fid,
+            name: name.clone(),
+            flags,
+            mode,
+            gid,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rlcreate { qid, iounit } => {
+                self.cached(|cache, dir| cache.changed(dir.path, &name, Some(qid)));
+                self.qid = Some(qid);
+                Ok((qid, iounit))
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Read at most `count` bytes at `offset`, limited by the iounit of the connection.
+    pub async fn read(&self, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let count = count.min(self.client.iounit());
+        match self
+            .client
+            .rpc(Fcall::Tread {
+                fid: self.fid,
+                offset,
+                count,
+            })
+            .await?
+        {
+            // More than was asked for would overrun the caller's buffer
+            Fcall::Rread { data } if data.0.len() > count as usize => Err(error::Error::No(EPROTO)),
+            Fcall::Rread { data } => Ok(data.0),
+            response => unexpected(response),
+        }
+    }
+
+    /// Write `data` at `offset`, limited by the iounit of the connection.
+    ///
+    /// Returns the number of bytes written.
+    pub async fn write(&self, offset: u64, data: &[u8]) -> Result<u32> {
+        let len = data.len().min(self.client.iounit() as usize);
+        let request = Fcall::Twrite {
+            fid: self.fid,
+            offset,
+            data: Data(data[..len].to_vec()),
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rwrite { count } => {
+                self.cached(|cache, qid| cache.invalidate(qid.path));
+                // More than was sent would have the caller skip past its buffer
+                if count as usize > len {
+                    return Err(error::Error::No(EPROTO));
+                }
+                Ok(count)
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Get the attributes in `req_mask`, along with those the server chose to return.
+    pub async fn getattr(&self, req_mask: GetattrMask) -> Result<(GetattrMask, Qid, Stat)> {
+        let mut cached = None;
+        self.cached(|cache, qid| cached = cache.getattr(&qid, req_mask));
+        if let Some(attr) = cached {
+            return Ok(attr);
+        }
+
+        match self
+            .client
+            .rpc(Fcall::Tgetattr {
+                fid: self.fid,
+                req_mask,
+            })
+            .await?
+        {
+            Fcall::Rgetattr { valid, qid, stat } => {
+                if let Some(ref cache) = self.client.cache {
+                    cache.put_attr(valid, qid, stat);
+                }
+                Ok((valid, qid, stat))
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Set the attributes in `valid` to the values in `stat`.
+    pub async fn setattr(&self, valid: SetattrMask, stat: SetAttr) -> Result<()> {
+        match self
+            .client
+            .rpc(Fcall::Tsetattr {
+                fid: self.fid,
+                valid,
+                stat,
+            })
+            .await?
+        {
+            Fcall::Rsetattr => {
+                self.cached(|cache, qid| cache.invalidate(qid.path));
+                Ok(())
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Read the entries of this open directory which fit in `count` bytes, starting at `offset`.
+    ///
+    /// `offset` is 0 or the `offset` of the last entry already read.
+    pub async fn readdir(&self, offset: u64, count: u32) -> Result<Vec<DirEntry>> {
+        let count = count.min(self.client.msize() - READDIRHDRSZ);
+        match self
+            .client
+            .rpc(Fcall::Treaddir {
+                fid: self.fid,
+                offset,
+                count,
+            })
+            .await?
+        {
+            Fcall::Rreaddir { data } => Ok(data.into_data()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Read the target of this symbolic link.
+    pub async fn readlink(&self) -> Result<NineString> {
+        match self.client.rpc(Fcall::Treadlink { fid: self.fid }).await? {
+            Fcall::Rreadlink { target } => Ok(target),
+            response => unexpected(response),
+        }
+    }
+
+    /// Get the file system statistics.
+    pub async fn statfs(&self) -> Result<Statfs> {
+        match self.client.rpc(Fcall::Tstatfs { fid: self.fid }).await? {
+            Fcall::Rstatfs { statfs } => Ok(statfs),
+            response => unexpected(response),
+        }
+    }
+
+    /// Commit the cached data of this open file to stable storage.
+    pub async fn fsync(&self) -> Result<()> {
+        match self.client.rpc(Fcall::Tfsync { fid: self.fid }).await? {
+            Fcall::Rfsync => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the directory `name` in this directory.
+    pub async fn mkdir<S: AsRef<OsStr>>(&self, name: S, mode: FileMode, gid: u32) -> Result<Qid> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tmkdir {
+            dfid: self.fid,
+            name: name.clone(),
+            mode,
+            gid,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rmkdir { qid } => {
+                self.cached(|cache, dir| cache.changed(dir.path, &name, Some(qid)));
+                Ok(qid)
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the symbolic link `name` to `target` in this directory.
+    pub async fn symlink<S: AsRef<OsStr>, T: AsRef<OsStr>>(
+        &self,
+        name: S,
+        target: T,
+        gid: u32,
+    ) -> Result<Qid> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tsymlink {
+            fid: self.fid,
+            name: name.clone(),
+            symtgt: target.as_ref().into(),
+            gid,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rsymlink { qid } => {
+                self.cached(|cache, dir| cache.changed(dir.path, &name, Some(qid)));
+                Ok(qid)
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the device node or fifo `name` in this directory.
+    pub async fn mknod<S: AsRef<OsStr>>(
+        &self,
+        name: S,
+        mode: FileMode,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<Qid> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tmknod {
+            dfid: self.fid,
+            name: name.clone(),
+            mode,
+            major,
+            minor,
+            gid,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rmknod { qid } => {
+                self.cached(|cache, dir| cache.changed(dir.path, &name, Some(qid)));
+                Ok(qid)
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the hard link `name` in this directory to the file of `fid`.
+    pub async fn link<S: AsRef<OsStr>>(&self, fid: &Fid, name: S) -> Result<()> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tlink {
+            dfid: self.fid,
+            fid: fid.fid,
+            name: name.clone(),
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rlink => {
+                fid.cached(|cache, qid| cache.invalidate(qid.path));
+                self.cached(|cache, dir| cache.changed(dir.path, &name, fid.qid));
+                Ok(())
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Rename the file `oldname` in this directory to `newname` in `newdir`.
+    pub async fn renameat<S: AsRef<OsStr>, T: AsRef<OsStr>>(
+        &self,
+        oldname: S,
+        newdir: &Fid,
+        newname: T,
+    ) -> Result<()> {
+        let (oldname, newname): (NineString, NineString) =
+            (oldname.as_ref().into(), newname.as_ref().into());
+        let request = Fcall::Trenameat {
+            olddirfid: self.fid,
+            oldname: oldname.clone(),
+            newdirfid: newdir.fid,
+            newname: newname.clone(),
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rrenameat => {
+                self.cached(|cache, dir| cache.changed(dir.path, &oldname, None));
+                newdir.cached(|cache, dir| cache.changed(dir.path, &newname, None));
+                Ok(())
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Remove the file `name` from this directory.
+    pub async fn unlinkat<S: AsRef<OsStr>>(&self, name: S, flags: UnlinkatFlags) -> Result<()> {
+        let name: NineString = name.as_ref().into();
+        let request = Fcall::Tunlinkat {
+            dirfd: self.fid,
+            name: name.clone(),
+            flags,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Runlinkat => {
+                self.cached(|cache, dir| cache.changed(dir.path, &name, None));
+                Ok(())
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Acquire or release a POSIX record lock on this open file.
+    pub async fn lock(&self, flock: Flock) -> Result<LockStatus> {
+        match self
+            .client
+            .rpc(Fcall::Tlock {
+                fid: self.fid,
+                flock,
+            })
+            .await?
+        {
+            Fcall::Rlock { status } => Ok(status),
+            response => unexpected(response),
+        }
+    }
+
+    /// Test for a POSIX record lock conflicting with `flock`.
+    pub async fn getlock(&self, flock: Getlock) -> Result<Getlock> {
+        match self
+            .client
+            .rpc(Fcall::Tgetlock {
+                fid: self.fid,
+                flock,
+            })
+            .await?
+        {
+            Fcall::Rgetlock { flock } => Ok(flock),
+            response => unexpected(response),
+        }
+    }
+
+    /// Prepare to read the extended attribute `name`, or the list of names if empty.
+    ///
+    /// Returns a new fid to read the value from, and its size.
+    pub async fn xattrwalk<S: AsRef<OsStr>>(&self, name: S) -> Result<(Fid, u64)> {
+        let new = self.client.alloc_fid()?;
+        let request = Fcall::Txattrwalk {
+            fid: self.fid,
+            newfid: new.fid,
+            name: name.as_ref().into(),
+        };
+        match self.client.rpc(request).await {
+            Ok(Fcall::Rxattrwalk { size }) => Ok((new, size)),
+            Ok(response) => new.unused(unexpected(response)),
+            Err(e) => new.unused(Err(e)),
+        }
+    }
+
+    /// Turn this fid into one to write the extended attribute `name` of `attr_size` bytes.
+    ///
+    /// The attribute is set when the fid is clunked.
+    pub async fn xattrcreate<S: AsRef<OsStr>>(
+        &self,
+        name: S,
+        attr_size: u64,
+        flags: XattrFlags,
+    ) -> Result<()> {
+        let request = Fcall::Txattrcreate {
+            fid: self.fid,
+            name: name.as_ref().into(),
+            attr_size,
+            flags,
+        };
+        match self.client.rpc(request).await? {
+            Fcall::Rxattrcreate => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Release the fid, waiting for the server to confirm.
+    pub async fn clunk(mut self) -> Result<()> {
+        self.clunked = true;
+        let res = self.client.rpc(Fcall::Tclunk { fid: self.fid }).await;
+        self.release();
+        match res? {
+            Fcall::Rclunk => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Remove the file and release the fid, even if the removal fails.
+    pub async fn remove(mut self) -> Result<()> {
+        self.clunked = true;
+        let res = self.client.rpc(Fcall::Tremove { fid: self.fid }).await;
+        self.release();
+        match res? {
+            Fcall::Rremove => {
+                self.cached(|cache, qid| cache.removed(qid.path));
+                Ok(())
+            }
+            response => unexpected(response),
+        }
+    }
+
+    // The server forgets the fid whatever the reply, so its number can be reused
+    fn release(&self) {
+        self.client.inner.fids.lock().unwrap().release(self.fid);
+    }
+}
+
+impl Drop for Fid {
+    fn drop(&mut self) {
+        if self.clunked {
+            return;
+        }
+
+        // Without a runtime the fid is leaked until the connection is closed.
+        // The task holds the bare fid number so that a runtime shutting down
+        // can drop it without spawning another clunk.
+        self.clunked = true;
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            let (client, fid) = (self.client.clone(), self.fid);
+            rt.spawn(async move {
+                let res = client.rpc(Fcall::Tclunk { fid }).await;
+                client.inner.fids.lock().unwrap().release(fid);
+                if let Err(e) = res {
+                    debug!("Tclunk {}: Error: \"{}\"", fid, e);
+                }
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for Fid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Fid")
+            .field("fid", &self.fid)
+            .field("qid", &self.qid)
+            .finish()
+    }
+}
@@ -0,0 +1,690 @@
+//! Blocking client, for code which does not run a tokio runtime.
+//!
+//! Requests are sent one at a time over a std `TcpStream` or `UnixStream`,
+//! each waiting for its reply before the next one is written.
+//!
+//! # Protocol
+//! 9P2000.L
+
+use {
+    super::{check_msize, msize_fits, unexpected, FidPool, DEFAULT_MSIZE},
+    crate::{
+        error,
+        error::errno::*,
+        fcall::*,
+        serialize::{self, Encodable},
+        utils::{self, Result},
+    },
+    byteorder::{LittleEndian, ReadBytesExt},
+    bytes::{BufMut, BytesMut},
+    std::{
+        ffi::OsStr,
+        io::{self, Read, Seek, SeekFrom, Write},
+        net::TcpStream,
+        os::unix::net::UnixStream,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// A bidirectional byte stream a blocking client can speak 9P over.
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+struct Conn {
+    stream: Box<dyn Transport>,
+    // Set once a request failed halfway, leaving the stream out of step
+    closed: bool,
+}
+
+impl Conn {
+    fn call(&mut self, msize: u32, tag: u16, body: Fcall) -> Result<Fcall> {
+        if self.closed {
+            return Err(error::Error::No(ECONNRESET));
+        }
+        let msg = Msg { tag, body };
+        let len = msg.encoded_len() + 4;
+        if len > msize as usize {
+            return res!(io::Error::from(serialize::EncodeError::MessageTooLarge {
+                len,
+                msize,
+            }));
+        }
+
+        let mut buf = BytesMut::with_capacity(len);
+        buf.put_u32_le(len as u32);
+        serialize::write_msg_buf(&mut buf, &msg)?;
+        debug!("\t→ {}", msg);
+
+        let res = self.exchange(&buf, msize);
+        if res.is_err() {
+            self.closed = true;
+        }
+        let reply = res?;
+        debug!("\t← {}", reply);
+
+        match reply {
+            Msg { tag: t, .. } if t != tag => {
+                self.closed = true;
+                error!("Reply to unknown tag {}: {}", t, reply);
+                Err(error::Error::No(EPROTO))
+            }
+            Msg {
+                body: Fcall::Rlerror { ecode },
+                ..
+            } => Err(error::Error::No(nix::errno::from_i32(ecode as i32))),
+            Msg { body, .. } => Ok(body),
+        }
+    }
+
+    // Write a whole request and read the whole reply
+    fn exchange(&mut self, request: &[u8], msize: u32) -> Result<Msg> {
+        self.stream.write_all(request)?;
+        self.stream.flush()?;
+
+        let len = self.stream.read_u32::<LittleEndian>()?;
+        if len < 4 || len > msize {
+            return res!(io_err!(InvalidData, "Invalid message size"));
+        }
+        let mut frame = vec![0; len as usize - 4];
+        self.stream.read_exact(&mut frame)?;
+        Ok(serialize::decode_msg(&frame)?)
+    }
+}
+
+struct Inner {
+    conn: Mutex<Conn>,
+    fids: Mutex<FidPool>,
+    msize: u32,
+}
+
+/// Blocking connection to a 9P2000.L server.
+///
+/// Cloning a `Client` is cheap and shares the connection. Requests from
+/// several threads are serialized, so only one is outstanding at a time.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+impl Client {
+    /// Connect to a server at `proto!address!port`, e.g. `tcp!127.0.0.1!564`,
+    /// and negotiate 9P2000.L with `DEFAULT_MSIZE`.
+    pub fn connect(addr: &str) -> Result<Client> {
+        Client::connect_with_msize(addr, DEFAULT_MSIZE)
+    }
+
+    /// Like `connect`, asking for a maximum message size of `msize`,
+    /// which must leave room for a payload beyond `IOHDRSZ` (EINVAL).
+    pub fn connect_with_msize(addr: &str, msize: u32) -> Result<Client> {
+        check_msize(msize)?;
+        let (proto, addr) = utils::parse_proto(addr)
+            .ok_or_else(|| io_err!(InvalidInput, "Invalid protocol or address"))?;
+
+        match proto {
+            "tcp" => {
+                let stream = TcpStream::connect(&addr)?;
+                stream.set_nodelay(true)?;
+                Client::new(stream, msize)
+            }
+            "unix" => Client::new(UnixStream::connect(&addr)?, msize),
+            _ => res!(io_err!(InvalidInput, "Protocol not supported")),
+        }
+    }
+
+    /// Speak 9P2000.L over an established stream, asking for a maximum message size of `msize`.
+    pub fn new<T: 'static + Transport>(stream: T, msize: u32) -> Result<Client> {
+        check_msize(msize)?;
+        let mut conn = Conn {
+            stream: Box::new(stream),
+            closed: false,
+        };
+        let request = Fcall::Tversion {
+            msize,
+            version: P92000L.to_owned(),
+        };
+        // Until Rversion, only Tversion itself is sent
+        let msize = match conn.call(msize, NOTAG, request)? {
+            Fcall::Rversion { msize: m, .. } if !msize_fits(m) => {
+                return Err(error::Error::No(EPROTO))
+            }
+            Fcall::Rversion { msize: m, version } if version == P92000L && m <= msize => m,
+            Fcall::Rversion { .. } => return Err(error::Error::No(EPROTONOSUPPORT)),
+            response => return unexpected(response),
+        };
+
+        Ok(Client {
+            inner: Arc::new(Inner {
+                conn: Mutex::new(conn),
+                fids: Default::default(),
+                msize,
+            }),
+        })
+    }
+
+    /// Get the maximum message size negotiated with the server.
+    pub fn msize(&self) -> u32 {
+        self.inner.msize
+    }
+
+    /// Get the largest payload a single `Tread` or `Twrite` can carry.
+    pub fn iounit(&self) -> u32 {
+        self.msize() - IOHDRSZ
+    }
+
+    /// Attach to the file tree `aname` of the server as `uname`, without authentication.
+    pub fn attach(&self, uname: &str, aname: &str, n_uname: u32) -> Result<Fid> {
+        let fid = self.alloc_fid()?;
+        let request = Fcall::Tattach {
+            fid: fid.fid,
+            afid: NOFID,
+            uname: uname.to_owned(),
+            aname: aname.to_owned(),
+            n_uname,
+        };
+        match self.rpc(request) {
+            Ok(Fcall::Rattach { qid }) => Ok(fid.with_qid(qid)),
+            Ok(response) => fid.unused(unexpected(response)),
+            Err(e) => fid.unused(Err(e)),
+        }
+    }
+
+    /// Send a request and wait for its reply.
+    ///
+    /// `Rlerror` is returned as an `Err` carrying its errno.
+    pub fn rpc(&self, request: Fcall) -> Result<Fcall> {
+        // With one request at a time, a single tag is enough
+        self.inner
+            .conn
+            .lock()
+            .unwrap()
+            .call(self.inner.msize, 0, request)
+    }
+
+    fn alloc_fid(&self) -> Result<Fid> {
+        let fid = self.inner.fids.lock().unwrap().alloc()?;
+        Ok(Fid {
+            client: self.clone(),
+            fid,
+            qid: None,
+            clunked: false,
+        })
+    }
+}
+
+/// A fid of the blocking client, pointing to a file on the server.
+///
+/// Dropping a `Fid` clunks it, ignoring errors; `clunk` reports them.
+pub struct Fid {
+    client: Client,
+    fid: u32,
+    qid: Option<Qid>,
+    clunked: bool,
+}
+
+impl Fid {
+    /// Get the raw fid.
+    pub fn fid(&self) -> u32 {
+        self.fid
+    }
+
+    /// Get the qid the server returned when this fid was attached, walked to or opened.
+    pub fn qid(&self) -> Option<Qid> {
+        self.qid
+    }
+
+    /// Get the client this fid belongs to.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    fn with_qid(mut self, qid: Qid) -> Fid {
+        self.qid = Some(qid);
+        self
+    }
+
+    // The server never learned about the fid, so its number can be reused right away
+    fn unused<T>(mut self, res: Result<T>) -> Result<T> {
+        self.clunked = true;
+        self.release();
+        res
+    }
+
+    // The server forgets the fid whatever the reply, so its number can be reused
+    fn release(&self) {
+        self.client.inner.fids.lock().unwrap().release(self.fid);
+    }
+
+    /// Walk to the file at `wnames` relative to this fid, which must not be open.
+    ///
+    /// Walks longer than `MAXWELEM` are split into several requests.
+    /// A walk which cannot reach every name fails with ENOENT.
+    pub fn walk<S: AsRef<OsStr>>(&self, wnames: &[S]) -> Result<Fid> {
+        let wnames: Vec<NineString> = wnames.iter().map(|s| s.as_ref().into()).collect();
+        let mut new = self.client.alloc_fid()?;
+        new.qid = self.qid;
+        let mut chunks = wnames.chunks(MAXWELEM);
+        let mut from = self.fid;
+
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            let request = Fcall::Twalk {
+                fid: from,
+                newfid: new.fid,
+                wnames: chunk.to_vec(),
+            };
+            let wqids = match self.client.rpc(request) {
+                Ok(Fcall::Rwalk { wqids }) => wqids,
+                // The first walk creates newfid only if it succeeds
+                Ok(response) if from == self.fid => return new.unused(unexpected(response)),
+                Err(e) if from == self.fid => return new.unused(Err(e)),
+                Ok(response) => return unexpected(response),
+                Err(e) => return Err(e),
+            };
+
+            if wqids.len() != chunk.len() {
+                return match from == self.fid {
+                    true => new.unused(Err(error::Error::No(ENOENT))),
+                    false => Err(error::Error::No(ENOENT)),
+                };
+            }
+            if let Some(qid) = wqids.last() {
+                new.qid = Some(*qid);
+            }
+
+            from = new.fid;
+            if chunks.len() == 0 {
+                return Ok(new);
+            }
+        }
+    }
+
+    /// Open the file.
+    ///
+    /// Returns the qid of the file and the iounit, 0 if the server does not limit I/O further.
+    pub fn lopen(&mut self, flags: LOpenFlags) -> Result<(Qid, u32)> {
+        match self.client.rpc(Fcall::Tlopen {
+            fid: self.fid,
+            flags,
+        })? {
+            Fcall::Rlopen { qid, iounit } => {
+                self.qid = Some(qid);
+                Ok((qid, iounit))
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Create and open the file `name` in this directory; the fid then points to the new file.
+    pub fn lcreate<S: AsRef<OsStr>>(
+        &mut self,
+        name: S,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<(Qid, u32)> {
+        let request = Fcall::Tlcreate {
+            fid: self.fid,
+            name: name.as_ref().into(),
+            flags,
+            mode,
+            gid,
+        };
+        match self.client.rpc(request)? {
+            Fcall::Rlcreate { qid, iounit } => {
+                self.qid = Some(qid);
+                Ok((qid, iounit))
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Read at most `count` bytes at `offset`, limited by the iounit of the connection.
+    pub fn read(&self, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let count = count.min(self.client.iounit());
+        match self.client.rpc(Fcall::Tread {
+            fid: self.fid,
+            offset,
+            count,
+        })? {
+            // More than was asked for would overrun the caller's buffer
+            Fcall::Rread { data } if data.0.len() > count as usize => Err(error::Error::No(EPROTO)),
+            Fcall::Rread { data } => Ok(data.0),
+            response => unexpected(response),
+        }
+    }
+
+    /// Write `data` at `offset`, limited by the iounit of the connection.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<u32> {
+        let len = data.len().min(self.client.iounit() as usize);
+        let request = Fcall::Twrite {
+            fid: self.fid,
+            offset,
+            data: Data(data[..len].to_vec()),
+        };
+        match self.client.rpc(request)? {
+            // More than was sent would have the caller skip past its buffer
+            Fcall::Rwrite { count } if count as usize > len => Err(error::Error::No(EPROTO)),
+            Fcall::Rwrite { count } => Ok(count),
+            response => unexpected(response),
+        }
+    }
+
+    /// Get the attributes in `req_mask`, along with those the server chose to return.
+    pub fn getattr(&self, req_mask: GetattrMask) -> Result<(GetattrMask, Qid, Stat)> {
+        match self.client.rpc(Fcall::Tgetattr {
+            fid: self.fid,
+            req_mask,
+        })? {
+            Fcall::Rgetattr { valid, qid, stat } => Ok((valid, qid, stat)),
+            response => unexpected(response),
+        }
+    }
+
+    /// Set the attributes in `valid` to the values in `stat`.
+    pub fn setattr(&self, valid: SetattrMask, stat: SetAttr) -> Result<()> {
+        match self.client.rpc(Fcall::Tsetattr {
+            fid: self.fid,
+            valid,
+            stat,
+        })? {
+            Fcall::Rsetattr => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Read the entries of this open directory which fit in `count` bytes, starting at `offset`.
+    ///
+    /// `offset` is 0 or the `offset` of the last entry already read.
+    pub fn readdir(&self, offset: u64, count: u32) -> Result<Vec<DirEntry>> {
+        let count = count.min(self.client.msize() - READDIRHDRSZ);
+        match self.client.rpc(Fcall::Treaddir {
+            fid: self.fid,
+            offset,
+            count,
+        })? {
+            Fcall::Rreaddir { data } => Ok(data.into_data()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Read the target of this symbolic link.
+    pub fn readlink(&self) -> Result<NineString> {
+        match self.client.rpc(Fcall::Treadlink { fid: self.fid })? {
+            Fcall::Rreadlink { target } => Ok(target),
+            response => unexpected(response),
+        }
+    }
+
+    /// Get the file system statistics.
+    pub fn statfs(&self) -> Result<Statfs> {
+        match self.client.rpc(Fcall::Tstatfs { fid: self.fid })? {
+            Fcall::Rstatfs { statfs } => Ok(statfs),
+            response => unexpected(response),
+        }
+    }
+
+    /// Commit the cached data of this open file to stable storage.
+    pub fn fsync(&self) -> Result<()> {
+        match self.client.rpc(Fcall::Tfsync { fid: self.fid })? {
+            Fcall::Rfsync => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the directory `name` in this directory.
+    pub fn mkdir<S: AsRef<OsStr>>(&self, name: S, mode: FileMode, gid: u32) -> Result<Qid> {
+        let request = Fcall::Tmkdir {
+            dfid: self.fid,
+            name: name.as_ref().into(),
+            mode,
+            gid,
+        };
+        match self.client.rpc(request)? {
+            Fcall::Rmkdir { qid } => Ok(qid),
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the symbolic link `name` to `target` in this directory.
+    pub fn symlink<S: AsRef<OsStr>, T: AsRef<OsStr>>(
+        &self,
+        name: S,
+        target: T,
+        gid: u32,
+    ) -> Result<Qid> {
+        let request = Fcall::Tsymlink {
+            fid: self.fid,
+            name: name.as_ref().into(),
+            symtgt: target.as_ref().into(),
+            gid,
+        };
+        match self.client.rpc(request)? {
+            Fcall::Rsymlink { qid } => Ok(qid),
+            response => unexpected(response),
+        }
+    }
+
+    /// Create the hard link `name` in this directory to the file of `fid`.
+    pub fn link<S: AsRef<OsStr>>(&self, fid: &Fid, name: S) -> Result<()> {
+        let request = Fcall::Tlink {
+            dfid: self.fid,
+            fid: fid.fid,
+            name: name.as_ref().into(),
+        };
+        match self.client.rpc(request)? {
+            Fcall::Rlink => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Rename the file `oldname` in this directory to `newname` in `newdir`.
+    pub fn renameat<S: AsRef<OsStr>, T: AsRef<OsStr>>(
+        &self,
+        oldname: S,
+        newdir: &Fid,
+        newname: T,
+    ) -> Result<()> {
+        let request = Fcall::Trenameat {
+            olddirfid: self.fid,
+            oldname: oldname.as_ref().into(),
+            newdirfid: newdir.fid,
+            newname: newname.as_ref().into(),
+        };
+        match self.client.rpc(request)? {
+            Fcall::Rrenameat => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Remove the file `name` from this directory.
+    pub fn unlinkat<S: AsRef<OsStr>>(&self, name: S, flags: UnlinkatFlags) -> Result<()> {
+        let request = Fcall::Tunlinkat {
+            dirfd: self.fid,
+            name: name.as_ref().into(),
+            flags,
+        };
+        match self.client.rpc(request)? {
+            Fcall::Runlinkat => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Prepare to read the extended attribute `name`, or the list of names if empty.
+    ///
+    /// Returns a new fid to read the value from, and its size.
+    pub fn xattrwalk<S: AsRef<OsStr>>(&self, name: S) -> Result<(Fid, u64)> {
+        let new = self.client.alloc_fid()?;
+        let request = Fcall::Txattrwalk {
+            fid: self.fid,
+            newfid: new.fid,
+            name: name.as_ref().into(),
+        };
+        match self.client.rpc(request) {
+            Ok(Fcall::Rxattrwalk { size }) => Ok((new, size)),
+            Ok(response) => new.unused(unexpected(response)),
+            Err(e) => new.unused(Err(e)),
+        }
+    }
+
+    /// Release the fid, waiting for the server to confirm.
+    pub fn clunk(mut self) -> Result<()> {
+        self.clunked = true;
+        let res = self.client.rpc(Fcall::Tclunk { fid: self.fid });
+        self.release();
+        match res? {
+            Fcall::Rclunk => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Remove the file and release the fid, even if the removal fails.
+    pub fn remove(mut self) -> Result<()> {
+        self.clunked = true;
+        let res = self.client.rpc(Fcall::Tremove { fid: self.fid });
+        self.release();
+        match res? {
+            Fcall::Rremove => Ok(()),
+            response => unexpected(response),
+        }
+    }
+}
+
+impl Drop for Fid {
+    fn drop(&mut self) {
+        if self.clunked {
+            return;
+        }
+        if let Err(e) = self.client.rpc(Fcall::Tclunk { fid: self.fid }) {
+            debug!("Tclunk {}: Error: \"{}\"", self.fid, e);
+        }
+        self.release();
+    }
+}
+
+impl std::fmt::Debug for Fid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Fid")
+            .field("fid", &self.fid)
+            .field("qid", &self.qid)
+            .finish()
+    }
+}
+
+/// An open file on the server, usable with std's I/O traits.
+///
+/// Reads and writes larger than the iounit are split into several requests.
+/// The fid is clunked when the `File` is dropped, or by `close`.
+#[derive(Debug)]
+pub struct File {
+    fid: Fid,
+    iounit: u32,
+    pos: u64,
+}
+
+impl File {
+    /// Open `fid` with `flags`.
+    pub fn open(mut fid: Fid, flags: LOpenFlags) -> Result<File> {
+        let (_, iounit) = fid.lopen(flags)?;
+        Ok(File::with_iounit(fid, iounit))
+    }
+
+    /// Create and open the file `name` in the directory `fid`.
+    pub fn create<S: AsRef<OsStr>>(
+        mut fid: Fid,
+        name: S,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<File> {
+        let flags = flags | LOpenFlags::CREATE;
+        let (_, iounit) = fid.lcreate(name, flags, mode, gid)?;
+        Ok(File::with_iounit(fid, iounit))
+    }
+
+    // An iounit of 0 leaves I/O limited by msize only
+    fn with_iounit(fid: Fid, iounit: u32) -> File {
+        let max = fid.client().iounit();
+        let iounit = match iounit {
+            0 => max,
+            n => n.min(max),
+        };
+        File {
+            fid,
+            iounit,
+            pos: 0,
+        }
+    }
+
+    /// Get the fid of the file.
+    pub fn fid(&self) -> &Fid {
+        &self.fid
+    }
+
+    /// Clunk the fid, waiting for the server to confirm.
+    pub fn close(self) -> Result<()> {
+        self.fid.clunk()
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            let count = self.iounit.min((buf.len() - n) as u32);
+            let data = match self.fid.read(self.pos, count) {
+                Ok(data) => data,
+                // What was read so far is returned, the error shows up on the next read
+                Err(_) if n > 0 => break,
+                Err(e) => return Err(e.into()),
+            };
+            buf[n..n + data.len()].copy_from_slice(&data);
+            n += data.len();
+            self.pos += data.len() as u64;
+            if data.len() < count as usize {
+                break;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut n = 0;
+        for chunk in buf.chunks(self.iounit as usize) {
+            let count = match self.fid.write(self.pos, chunk) {
+                Ok(count) => count as usize,
+                Err(_) if n > 0 => break,
+                Err(e) => return Err(e.into()),
+            };
+            n += count;
+            self.pos += count as u64;
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let invalid = || io::Error::from(error::Error::No(EINVAL));
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta).ok_or_else(invalid)?,
+            SeekFrom::End(delta) => {
+                let (_, _, stat) = self.fid.getattr(GetattrMask::SIZE)?;
+                stat.size.checked_add_signed(delta).ok_or_else(invalid)?
+            }
+        };
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,208 @@
+//! Caching attributes and walks on the client side.
+
+use {
+    crate::fcall::*,
+    std::{
+        collections::HashMap,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+// Entries kept before expired ones are purged
+const MAX_ENTRIES: usize = 1 << 16;
+
+/// How long a client made by `Client::with_cache` trusts what it learned.
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    /// How long the reply to a `Tgetattr` answers later ones for the same file
+    pub attr_ttl: Duration,
+    /// How long a name walked in a directory is assumed to lead to the same file
+    pub walk_ttl: Duration,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            attr_ttl: Duration::from_secs(1),
+            walk_ttl: Duration::from_secs(1),
+        }
+    }
+}
+
+struct Attr {
+    at: Instant,
+    valid: GetattrMask,
+    qid: Qid,
+    stat: Stat,
+}
+
+struct Walked {
+    at: Instant,
+    qid: Qid,
+}
+
+#[derive(Default)]
+struct Entries {
+    // By qid.path
+    attrs: HashMap<u64, Attr>,
+    // By qid.path of the directory and the name in it
+    walks: HashMap<(u64, NineString), Walked>,
+}
+
+impl Entries {
+    // The file changed on the server: its attributes and, for a directory, its names are stale
+    fn forget(&mut self, path: u64) {
+        self.attrs.remove(&path);
+        self.walks.retain(|(dir, _), _| *dir != path);
+    }
+}
+
+pub(super) struct Cache {
+    policy: CachePolicy,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub(super) fn new(policy: CachePolicy) -> Cache {
+        Cache {
+            policy,
+            entries: Default::default(),
+        }
+    }
+
+    // Attributes of the file with qid covering req_mask, if still fresh
+    pub(super) fn getattr(
+        &self,
+        qid: &Qid,
+        req_mask: GetattrMask,
+    ) -> Option<(GetattrMask, Qid, Stat)> {
+        let mut entries = self.entries.lock().unwrap();
+        let attr = entries.attrs.get(&qid.path)?;
+        if attr.at.elapsed() > self.policy.attr_ttl {
+            entries.attrs.remove(&qid.path);
+            return None;
+        }
+        if !attr.valid.contains(req_mask) {
+            return None;
+        }
+        Some((attr.valid, attr.qid, attr.stat))
+    }
+
+    pub(super) fn put_attr(&self, valid: GetattrMask, qid: Qid, stat: Stat) {
+        let mut entries = self.entries.lock().unwrap();
+        self.observe_locked(&mut entries, &qid, Some((valid, &stat)));
+        let attr = Attr {
+            at: Instant::now(),
+            valid,
+            qid,
+            stat,
+        };
+        entries.attrs.insert(qid.path, attr);
+        self.purge(&mut entries);
+    }
+
+    // The file reached by walking names from the directory from, if every step is still fresh
+    pub(super) fn lookup(&self, from: u64, names: &[NineString]) -> Option<Qid> {
+        let entries = self.entries.lock().unwrap();
+        let mut qid = None;
+        let mut dir = from;
+        for name in names {
+            let walked = entries.walks.get(&(dir, name.clone()))?;
+            if walked.at.elapsed() > self.policy.walk_ttl {
+                return None;
+            }
+            qid = Some(walked.qid);
+            dir = walked.qid.path;
+        }
+        qid
+    }
+
+    // Record the files a successful walk from the directory from went through
+    pub(super) fn walked(&self, from: u64, wnames: &[NineString], wqids: &[Qid]) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut dir = from;
+        for (name, qid) in wnames.iter().zip(wqids) {
+            self.observe_locked(&mut entries, qid, None);
+            let walked = Walked {
+                at: Instant::now(),
+                qid: *qid,
+            };
+            entries.walks.insert((dir, name.clone()), walked);
+            dir = qid.path;
+        }
+        self.purge(&mut entries);
+    }
+
+    // A qid from the server whose version differs from the cached one reveals a remote change
+    pub(super) fn observe(&self, qid: &Qid) {
+        let mut entries = self.entries.lock().unwrap();
+        self.observe_locked(&mut entries, qid, None);
+    }
+
+    // So does a data_version differing from the cached one, when both are valid
+    fn observe_locked(&self, entries: &mut Entries, qid: &Qid, attr: Option<(GetattrMask, &Stat)>) {
+        let changed = match entries.attrs.get(&qid.path) {
+            Some(cached) => {
+                let data_changed = match attr {
+                    Some((valid, stat)) => {
+                        (cached.valid & valid).contains(GetattrMask::DATA_VERSION)
+                            && cached.stat.data_version != stat.data_version
+                    }
+                    None => false,
+                };
+                cached.qid.version != qid.version || data_changed
+            }
+            None => false,
+        };
+        if changed {
+            entries.forget(qid.path);
+        }
+    }
+
+    // The attributes of the file changed locally
+    pub(super) fn invalidate(&self, path: u64) {
+        self.entries.lock().unwrap().attrs.remove(&path);
+    }
+
+    // The contents of the directory changed locally: name now leads to qid, or nowhere
+    pub(super) fn changed(&self, dir: u64, name: &NineString, qid: Option<Qid>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.attrs.remove(&dir);
+        let key = (dir, name.clone());
+        if let Some(old) = entries.walks.remove(&key) {
+            // The file unlinked or renamed away has a new ctime and link count
+            entries.attrs.remove(&old.qid.path);
+        }
+        if let Some(qid) = qid {
+            let walked = Walked {
+                at: Instant::now(),
+                qid,
+            };
+            entries.walks.insert(key, walked);
+        }
+    }
+
+    // The file was removed: forget it and every name leading to it
+    pub(super) fn removed(&self, path: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.forget(path);
+        entries.walks.retain(|_, walked| walked.qid.path != path);
+    }
+
+    fn purge(&self, entries: &mut Entries) {
+        if entries.attrs.len() + entries.walks.len() <= MAX_ENTRIES {
+            return;
+        }
+        let (attr_ttl, walk_ttl) = (self.policy.attr_ttl, self.policy.walk_ttl);
+        entries
+            .attrs
+            .retain(|_, attr| attr.at.elapsed() <= attr_ttl);
+        entries
+            .walks
+            .retain(|_, walked| walked.at.elapsed() <= walk_ttl);
+        if entries.attrs.len() + entries.walks.len() > MAX_ENTRIES {
+            *entries = Entries::default();
+        }
+    }
+}
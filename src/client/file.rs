@@ -0,0 +1,484 @@
+//! File-like handles on top of client fids.
+
+use {
+    super::Fid,
+    crate::{error, fcall::*, utils::Result},
+    bytes::Bytes,
+    futures::{
+        future::BoxFuture,
+        stream::{self, StreamExt},
+    },
+    std::{
+        collections::VecDeque,
+        future::Future,
+        io::{self, SeekFrom},
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    },
+    tokio::{
+        io::{AsyncRead, AsyncSeek, AsyncWrite, AsyncWriteExt, ReadBuf},
+        task::JoinHandle,
+    },
+};
+
+// Treads or Twrites a single read or write keeps outstanding at once
+const MAX_INFLIGHT: usize = 8;
+
+// The operation a RemoteFile is waiting on
+enum Op {
+    Idle,
+    Read(BoxFuture<'static, io::Result<Vec<u8>>>),
+    Write(BoxFuture<'static, io::Result<usize>>),
+    Seek(BoxFuture<'static, io::Result<u64>>),
+}
+
+// A Tread issued ahead of the reader, cancelled when dropped
+struct Ahead(JoinHandle<Result<Vec<u8>>>);
+
+impl Drop for Ahead {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// An open file on the server, usable with tokio's I/O utilities.
+///
+/// Reads and writes larger than the iounit are split into several requests,
+/// which are sent without waiting for each other.
+/// Sequential reads and writes can be pipelined further with `set_read_ahead`
+/// and `set_write_behind`.
+/// The fid is clunked when the `RemoteFile` is dropped, or by `close`.
+pub struct RemoteFile {
+    fid: Arc<Fid>,
+    iounit: usize,
+    // Offset of the next byte returned by a read or written by a write
+    pos: u64,
+    // Data read past what the caller asked for, starting at pos
+    readbuf: Vec<u8>,
+    op: Op,
+    read_ahead: usize,
+    // Treads of ahead_count bytes from pos + readbuf.len() on, in order
+    ahead: VecDeque<Ahead>,
+    // Offset of the next Tread to issue ahead
+    ahead_next: u64,
+    // Bytes asked by each Tread ahead, lowered to what the server returns at most
+    ahead_count: usize,
+    write_behind: usize,
+    // Twrites not waited for yet, in order, with the length of each
+    behind: VecDeque<(JoinHandle<Result<u32>>, usize)>,
+    // Offset following the last Twrite issued behind
+    behind_end: u64,
+    // First error of a Twrite issued behind, not yet returned
+    write_error: Option<io::Error>,
+}
+
+impl RemoteFile {
+    /// Wrap a fid which has already been opened or created.
+    pub fn new(fid: Fid) -> RemoteFile {
+        RemoteFile::with_iounit(fid, 0)
+    }
+
+    /// Open `fid` with `flags`.
+    pub async fn open(mut fid: Fid, flags: LOpenFlags) -> Result<RemoteFile> {
+        let (_, iounit) = fid.lopen(flags).await?;
+        Ok(RemoteFile::with_iounit(fid, iounit))
+    }
+
+    /// Create and open the file `name` in the directory `fid`.
+    pub async fn create<S: AsRef<std::ffi::OsStr>>(
+        mut fid: Fid,
+        name: S,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<RemoteFile> {
+        // Servers such as unpfs pass the flags on to open(2), as Linux sends them
+        let flags = flags | LOpenFlags::CREATE;
+        let (_, iounit) = fid.lcreate(name, flags, mode, gid).await?;
+        Ok(RemoteFile::with_iounit(fid, iounit))
+    }
+
+    // An iounit of 0 leaves I/O limited by msize only
+    fn with_iounit(fid: Fid, iounit: u32) -> RemoteFile {
+        let max = fid.client().iounit();
+        let iounit = match iounit {
+            0 => max,
+            n => n.min(max),
+        };
+        RemoteFile {
+            fid: Arc::new(fid),
+            iounit: iounit as usize,
+            pos: 0,
+            readbuf: Vec::new(),
+            op: Op::Idle,
+            read_ahead: 0,
+            ahead: VecDeque::new(),
+            ahead_next: 0,
+            ahead_count: iounit as usize,
+            write_behind: 0,
+            behind: VecDeque::new(),
+            behind_end: 0,
+            write_error: None,
+        }
+    }
+
+    /// Get the fid of the file.
+    pub fn fid(&self) -> &Fid {
+        &self.fid
+    }
+
+    /// Get the largest payload of a single request on this file.
+    pub fn iounit(&self) -> u32 {
+        self.iounit as u32
+    }
+
+    /// Keep up to `requests` Treads of up to an iounit each in flight ahead of the offset,
+    /// so that sequential reads do not wait for a round trip each.
+    ///
+    /// 0, the default, reads only what each call asks for.
+    pub fn set_read_ahead(&mut self, requests: usize) {
+        self.read_ahead = requests;
+        if let Op::Read(_) = self.op {
+            self.op = Op::Idle;
+        }
+        self.cancel_ahead();
+    }
+
+    /// Let up to `requests` Twrites be in flight without waiting for their replies.
+    ///
+    /// A write then returns as soon as its request is sent, up to an iounit at a time.
+    /// An error of such a write is returned by a later write, or by flushing the file,
+    /// `fsync` or `close`; it is lost if the file is just dropped.
+    /// 0, the default, waits for every write.
+    pub fn set_write_behind(&mut self, requests: usize) {
+        self.write_behind = requests;
+    }
+
+    /// Wait for the writes in flight, then commit the file to stable storage.
+    pub async fn fsync(&mut self) -> Result<()> {
+        self.flush().await?;
+        self.fid.fsync().await
+    }
+
+    /// Finish any pending write and clunk the fid, waiting for the server to confirm.
+    pub async fn close(mut self) -> Result<()> {
+        self.flush().await?;
+        // Cancelled reads let go of the fid once they are done
+        for mut ahead in std::mem::take(&mut self.ahead) {
+            ahead.0.abort();
+            let _ = (&mut ahead.0).await;
+        }
+        let RemoteFile { fid, op, .. } = self;
+        // The fid is shared only with the futures of pending operations
+        drop(op);
+        match Arc::try_unwrap(fid) {
+            Ok(fid) => fid.clunk().await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    // Wait for the writes behind, then for a pending write or seek, whose result moves the offset.
+    // A seek from the end has not asked for the size yet, so it sees the size they leave.
+    fn poll_idle(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_behind(cx, 0))?;
+        let res = match self.op {
+            Op::Idle | Op::Read(_) => Ok(()),
+            Op::Write(ref mut f) => futures::ready!(f.as_mut().poll(cx)).map(|n| {
+                self.pos += n as u64;
+            }),
+            Op::Seek(ref mut f) => futures::ready!(f.as_mut().poll(cx)).map(|pos| {
+                self.pos = pos;
+            }),
+        };
+        if let Op::Write(_) | Op::Seek(_) = self.op {
+            self.op = Op::Idle;
+        }
+        Poll::Ready(res)
+    }
+
+    // Wait until at most keep writes behind are in flight, then return the first error of any
+    fn poll_behind(&mut self, cx: &mut Context, keep: usize) -> Poll<io::Result<()>> {
+        while self.behind.len() > keep {
+            let (write, len) = self.behind.front_mut().unwrap();
+            let res = futures::ready!(Pin::new(write).poll(cx));
+            let len = *len;
+            self.behind.pop_front();
+
+            let e = match res {
+                Ok(Ok(count)) if count as usize == len => continue,
+                Ok(Ok(_)) => io::Error::new(io::ErrorKind::WriteZero, "short write"),
+                Ok(Err(e)) => e.into(),
+                Err(e) => io::Error::other(e),
+            };
+            if self.write_error.is_none() {
+                self.write_error = Some(e);
+            }
+        }
+        match self.write_error.take() {
+            Some(e) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    // Treads issued ahead no longer start at the offset
+    fn cancel_ahead(&mut self) {
+        self.ahead.clear();
+        self.ahead_count = self.iounit;
+    }
+
+    fn poll_read_ahead(&mut self, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        if self.ahead.is_empty() {
+            self.ahead_next = self.pos;
+        }
+        while self.ahead.len() < self.read_ahead {
+            let (fid, offset, count) = (self.fid.clone(), self.ahead_next, self.ahead_count);
+            let read = tokio::spawn(async move { fid.read(offset, count as u32).await });
+            self.ahead.push_back(Ahead(read));
+            self.ahead_next += count as u64;
+        }
+
+        let res = futures::ready!(Pin::new(&mut self.ahead.front_mut().unwrap().0).poll(cx));
+        self.ahead.pop_front();
+        let data = match res {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                self.cancel_ahead();
+                return Poll::Ready(Err(e.into()));
+            }
+            Err(e) => {
+                self.cancel_ahead();
+                return Poll::Ready(Err(io::Error::other(e)));
+            }
+        };
+        // A short read leaves a gap before the next Tread: past the end of the file,
+        // the file may have grown by then, and otherwise the server reads no more
+        // at once (unpfs returns 16K at most), so ask for that much from now on
+        if data.len() < self.ahead_count {
+            self.cancel_ahead();
+            if !data.is_empty() {
+                self.ahead_count = data.len();
+            }
+        }
+        self.readbuf = data;
+        self.consume(buf);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write_behind(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Writes elsewhere in the file wait for those in flight, which could overlap them
+        let keep = match self.behind_end == self.pos {
+            true => self.write_behind - 1,
+            false => 0,
+        };
+        futures::ready!(self.poll_behind(cx, keep))?;
+
+        let len = buf.len().min(self.iounit);
+        let (fid, offset, data) = (self.fid.clone(), self.pos, buf[..len].to_vec());
+        let write = tokio::spawn(async move { fid.write(offset, &data).await });
+        self.behind.push_back((write, len));
+        self.pos += len as u64;
+        self.behind_end = self.pos;
+        Poll::Ready(Ok(len))
+    }
+
+    fn consume(&mut self, buf: &mut ReadBuf) {
+        let n = self.readbuf.len().min(buf.remaining());
+        buf.put_slice(&self.readbuf[..n]);
+        self.readbuf.drain(..n);
+        self.pos += n as u64;
+    }
+}
+
+impl AsyncRead for RemoteFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.readbuf.is_empty() {
+            this.consume(buf);
+            return Poll::Ready(Ok(()));
+        }
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        futures::ready!(this.poll_idle(cx))?;
+        if this.read_ahead > 0 {
+            return this.poll_read_ahead(cx, buf);
+        }
+        if let Op::Idle = this.op {
+            let (fid, offset) = (this.fid.clone(), this.pos);
+            let (len, iounit) = (buf.remaining(), this.iounit);
+            this.op = Op::Read(Box::pin(read_at(fid, offset, len, iounit)));
+        }
+
+        let res = match this.op {
+            Op::Read(ref mut f) => futures::ready!(f.as_mut().poll(cx)),
+            _ => unreachable!(),
+        };
+        this.op = Op::Idle;
+        this.readbuf = res?;
+        this.consume(buf);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RemoteFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Data read ahead may be overwritten
+        if let Op::Read(_) = this.op {
+            this.op = Op::Idle;
+        }
+        this.readbuf.clear();
+        this.cancel_ahead();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if let Op::Seek(_) = this.op {
+            futures::ready!(this.poll_idle(cx))?;
+        }
+        if this.write_behind > 0 {
+            // A write pending from before write behind was switched on
+            if let Op::Write(_) = this.op {
+                futures::ready!(this.poll_idle(cx))?;
+            }
+            return this.poll_write_behind(cx, buf);
+        }
+        if !this.behind.is_empty() {
+            futures::ready!(this.poll_behind(cx, 0))?;
+        }
+        if let Op::Idle = this.op {
+            let (fid, offset, iounit) = (this.fid.clone(), this.pos, this.iounit);
+            let data = Bytes::copy_from_slice(buf);
+            this.op = Op::Write(Box::pin(write_at(fid, offset, data, iounit)));
+        }
+
+        let res = match this.op {
+            Op::Write(ref mut f) => futures::ready!(f.as_mut().poll(cx)),
+            _ => unreachable!(),
+        };
+        this.op = Op::Idle;
+        let n = res?;
+        this.pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+}
+
+impl AsyncSeek for RemoteFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        match this.op {
+            Op::Write(_) | Op::Seek(_) => {
+                return Err(io::Error::other(
+                    "other file operation is pending, call poll_complete before start_seek",
+                ))
+            }
+            Op::Read(_) => this.op = Op::Idle,
+            Op::Idle => {}
+        }
+        this.readbuf.clear();
+        this.cancel_ahead();
+
+        let invalid = || io::Error::from(error::Error::No(error::errno::EINVAL));
+        match position {
+            SeekFrom::Start(pos) => this.pos = pos,
+            SeekFrom::Current(delta) => {
+                this.pos = this.pos.checked_add_signed(delta).ok_or_else(invalid)?
+            }
+            SeekFrom::End(delta) => {
+                let fid = this.fid.clone();
+                this.op = Op::Seek(Box::pin(async move {
+                    let (_, _, stat) = fid.getattr(GetattrMask::SIZE).await?;
+                    stat.size.checked_add_signed(delta).ok_or_else(invalid)
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_idle(cx))?;
+        Poll::Ready(Ok(this.pos))
+    }
+}
+
+impl std::fmt::Debug for RemoteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RemoteFile")
+            .field("fid", &self.fid)
+            .field("iounit", &self.iounit)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+// Read up to len bytes at offset in iounit sized Treads, stopping at the first short one
+async fn read_at(fid: Arc<Fid>, offset: u64, len: usize, iounit: usize) -> io::Result<Vec<u8>> {
+    let reads = (0..len).step_by(iounit).map(|start| {
+        let fid = fid.clone();
+        let count = iounit.min(len - start);
+        async move {
+            let data = fid.read(offset + start as u64, count as u32).await?;
+            Ok::<_, error::Error>((data, count))
+        }
+    });
+    let mut reads = stream::iter(reads).buffered(MAX_INFLIGHT);
+
+    let mut buf = Vec::with_capacity(len);
+    while let Some(res) = reads.next().await {
+        match res {
+            Ok((data, count)) => {
+                buf.extend_from_slice(&data);
+                if data.len() < count {
+                    break;
+                }
+            }
+            // What was read so far is returned, the error shows up on the next read
+            Err(_) if !buf.is_empty() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(buf)
+}
+
+// Write data at offset in iounit sized Twrites, counting up to the first short one
+async fn write_at(fid: Arc<Fid>, offset: u64, data: Bytes, iounit: usize) -> io::Result<usize> {
+    let writes = (0..data.len()).step_by(iounit).map(|start| {
+        let fid = fid.clone();
+        let chunk = data.slice(start..data.len().min(start + iounit));
+        async move {
+            let count = fid.write(offset + start as u64, &chunk).await?;
+            Ok::<_, error::Error>((count as usize, chunk.len()))
+        }
+    });
+    let mut writes = stream::iter(writes).buffered(MAX_INFLIGHT);
+
+    let mut written = 0;
+    while let Some(res) = writes.next().await {
+        match res {
+            Ok((count, len)) => {
+                written += count;
+                if count < len {
+                    break;
+                }
+            }
+            Err(_) if written > 0 => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(written)
+}
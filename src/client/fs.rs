@@ -0,0 +1,322 @@
+//! Path based access to a file tree, in the manner of `std::fs`.
+
+use {
+    super::{Fid, RemoteFile},
+    crate::{error, error::errno::*, fcall::*, utils::Result},
+    futures::{
+        future::BoxFuture,
+        stream::{self, BoxStream},
+    },
+    std::{
+        collections::VecDeque,
+        path::{Component, Path, PathBuf},
+    },
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+};
+
+// Symbolic links followed while resolving a single path, as Linux does
+const MAX_SYMLINKS: usize = 40;
+
+/// The entries of a directory, with the attributes of each, as returned by `Fs::read_dir`.
+///
+/// Symbolic links among them are not followed.
+pub type ReadDir = BoxStream<'static, Result<(DirEntry, Stat)>>;
+
+/// A file tree attached on the server, addressed by paths relative to its root.
+///
+/// Each call walks from the root, so paths longer than `MAXWELEM` names
+/// take several `Twalk`s. Paths are resolved lexically: `..` drops the
+/// previous name before anything is sent to the server.
+#[derive(Debug)]
+pub struct Fs {
+    root: Fid,
+    gid: u32,
+}
+
+impl Fs {
+    /// Use the tree attached as `root`, creating files and directories with the group `gid`.
+    pub fn new(root: Fid, gid: u32) -> Fs {
+        Fs { root, gid }
+    }
+
+    /// Get the fid of the root of the tree.
+    pub fn root(&self) -> &Fid {
+        &self.root
+    }
+
+    /// Walk to `path` without following a symbolic link at its end.
+    pub async fn walk<P: AsRef<Path>>(&self, path: P) -> Result<Fid> {
+        self.root.walk(&names(path.as_ref())).await
+    }
+
+    /// Get the attributes of the file at `path`, following symbolic links.
+    pub async fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Stat> {
+        match self.root.cached_getattr(&names(path.as_ref())) {
+            Some(stat) if stat.mode.file_type() != Some(FileType::Symlink) => Ok(stat),
+            _ => Ok(self.resolve(path.as_ref()).await?.1),
+        }
+    }
+
+    /// Get the attributes of the file at `path`, not following a symbolic link at its end.
+    pub async fn symlink_metadata<P: AsRef<Path>>(&self, path: P) -> Result<Stat> {
+        if let Some(stat) = self.root.cached_getattr(&names(path.as_ref())) {
+            return Ok(stat);
+        }
+        let (_, _, stat) = self.walk(path).await?.getattr(GetattrMask::BASIC).await?;
+        Ok(stat)
+    }
+
+    /// Open the file at `path` with `flags`, following symbolic links.
+    pub async fn open<P: AsRef<Path>>(&self, path: P, flags: LOpenFlags) -> Result<RemoteFile> {
+        let (fid, _) = self.resolve(path.as_ref()).await?;
+        RemoteFile::open(fid, flags).await
+    }
+
+    /// Create the file at `path` and open it with `flags`.
+    pub async fn create<P: AsRef<Path>>(
+        &self,
+        path: P,
+        flags: LOpenFlags,
+        mode: u32,
+    ) -> Result<RemoteFile> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        RemoteFile::create(parent, name, flags, FileMode::from_bits(mode), self.gid).await
+    }
+
+    /// Read the whole file at `path`.
+    pub async fn read_to_end<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let mut file = self.open(path, LOpenFlags::RDONLY).await?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        file.close().await?;
+        Ok(buf)
+    }
+
+    /// Replace the contents of the file at `path` with `data`, creating it if needed.
+    pub async fn write_all<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<()> {
+        let path = path.as_ref();
+        let flags = LOpenFlags::WRONLY | LOpenFlags::TRUNC;
+        let mut file = match self.open(path, flags).await {
+            Err(e) if e.errno() == ENOENT => self.create(path, flags, 0o644).await?,
+            file => file?,
+        };
+        file.write_all(data).await?;
+        file.close().await
+    }
+
+    /// List the directory at `path`, following symbolic links.
+    ///
+    /// The entries are read with as many `Treaddir`s as needed while the stream is polled,
+    /// and `.` and `..` are left out.
+    pub async fn read_dir<P: AsRef<Path>>(&self, path: P) -> Result<ReadDir> {
+        let (dir, _) = self.resolve(path.as_ref()).await?;
+        let mut listing = dir.walk::<&str>(&[]).await?;
+        listing
+            .lopen(LOpenFlags::RDONLY | LOpenFlags::DIRECTORY)
+            .await?;
+
+        let state = (dir, listing, 0, VecDeque::new());
+        let entries = stream::try_unfold(state, |(dir, listing, mut offset, mut buf)| async move {
+            loop {
+                if buf.is_empty() {
+                    buf.extend(listing.readdir(offset, listing.client().iounit()).await?);
+                }
+                let entry: DirEntry = match buf.pop_front() {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+                offset = entry.offset;
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+
+                let stat = match dir.cached_getattr(std::slice::from_ref(&entry.name)) {
+                    Some(stat) => stat,
+                    None => {
+                        let child = dir.walk(&[&entry.name]).await?;
+                        child.getattr(GetattrMask::BASIC).await?.2
+                    }
+                };
+                return Ok(Some(((entry, stat), (dir, listing, offset, buf))));
+            }
+        });
+        Ok(Box::pin(entries))
+    }
+
+    /// Create the directory at `path`.
+    pub async fn create_dir<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        parent
+            .mkdir(name, FileMode::from_bits(mode), self.gid)
+            .await?;
+        Ok(())
+    }
+
+    /// Create the directory at `path` and any of its parents which are missing.
+    pub async fn create_dir_all<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let mut dir = self.root.walk::<&str>(&[]).await?;
+        for name in names(path.as_ref()) {
+            dir = match dir.walk(&[&name]).await {
+                Err(e) if e.errno() == ENOENT => {
+                    match dir.mkdir(&name, FileMode::from_bits(mode), self.gid).await {
+                        // Someone else may have created it meanwhile
+                        Err(e) if e.errno() != EEXIST => return Err(e),
+                        _ => dir.walk(&[&name]).await?,
+                    }
+                }
+                res => res?,
+            };
+        }
+        Ok(())
+    }
+
+    /// Remove the file at `path`, which must not be a directory.
+    pub async fn remove_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        parent.unlinkat(name, UnlinkatFlags::empty()).await
+    }
+
+    /// Remove the empty directory at `path`.
+    pub async fn remove_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        parent.unlinkat(name, UnlinkatFlags::REMOVEDIR).await
+    }
+
+    /// Remove the directory at `path` along with everything in it.
+    ///
+    /// Symbolic links inside are removed, not followed.
+    pub async fn remove_dir_all<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        remove_tree(&parent, name).await
+    }
+
+    /// Rename the file at `from` to `to`, replacing `to` if it exists.
+    pub async fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> Result<()> {
+        let (olddir, oldname) = self.parent(from.as_ref()).await?;
+        let (newdir, newname) = self.parent(to.as_ref()).await?;
+        olddir.renameat(oldname, &newdir, newname).await
+    }
+
+    /// Read the target of the symbolic link at `path`.
+    pub async fn read_link<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        Ok(self.walk(path).await?.readlink().await?.into())
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`.
+    pub async fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(&self, target: P, path: Q) -> Result<()> {
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        parent
+            .symlink(name, target.as_ref().as_os_str(), self.gid)
+            .await?;
+        Ok(())
+    }
+
+    /// Create a hard link at `path` to the file at `original`.
+    pub async fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        original: P,
+        path: Q,
+    ) -> Result<()> {
+        let fid = self.walk(original).await?;
+        let (parent, name) = self.parent(path.as_ref()).await?;
+        parent.link(&fid, name).await
+    }
+
+    /// Set the permission bits of the file at `path`, following symbolic links.
+    pub async fn set_permissions<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let (fid, _) = self.resolve(path.as_ref()).await?;
+        let time = Time { sec: 0, nsec: 0 };
+        let stat = SetAttr {
+            mode: FileMode::from_bits(mode & FileMode::PERM_MASK),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: time,
+            mtime: time,
+        };
+        fid.setattr(SetattrMask::MODE, stat).await
+    }
+
+    // Walk to the directory containing path, which must end with a name
+    async fn parent(&self, path: &Path) -> Result<(Fid, NineString)> {
+        let mut names = names(path);
+        let name = names.pop().ok_or(error::Error::No(EINVAL))?;
+        Ok((self.root.walk(&names).await?, name))
+    }
+
+    // Walk to path, following symbolic links at its end
+    async fn resolve(&self, path: &Path) -> Result<(Fid, Stat)> {
+        let mut names = names(path);
+        for _ in 0..MAX_SYMLINKS {
+            let fid = self.root.walk(&names).await?;
+            let (_, _, stat) = fid.getattr(GetattrMask::BASIC).await?;
+            if stat.mode.file_type() != Some(FileType::Symlink) {
+                return Ok((fid, stat));
+            }
+
+            let target = PathBuf::from(fid.readlink().await?);
+            names.pop();
+            if target.has_root() {
+                names.clear();
+            }
+            push_names(&mut names, &target);
+        }
+        Err(error::Error::No(ELOOP))
+    }
+}
+
+fn names(path: &Path) -> Vec<NineString> {
+    let mut names = Vec::new();
+    push_names(&mut names, path);
+    names
+}
+
+fn push_names(names: &mut Vec<NineString>, path: &Path) {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.into()),
+            Component::ParentDir => {
+                names.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+}
+
+// Remove name from dir, emptying it first if it is a directory
+fn remove_tree(dir: &Fid, name: NineString) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let fid = dir.walk(&[&name]).await?;
+        let (_, _, stat) = fid.getattr(GetattrMask::MODE).await?;
+        if stat.mode.file_type() != Some(FileType::Directory) {
+            return dir.unlinkat(&name, UnlinkatFlags::empty()).await;
+        }
+
+        // Removing entries while listing would disturb the offsets
+        let mut listing = fid.walk::<&str>(&[]).await?;
+        listing
+            .lopen(LOpenFlags::RDONLY | LOpenFlags::DIRECTORY)
+            .await?;
+        let mut children = Vec::new();
+        let mut offset = 0;
+        loop {
+            let entries = listing.readdir(offset, listing.client().iounit()).await?;
+            match entries.last() {
+                Some(last) => offset = last.offset,
+                None => break,
+            }
+            children.extend(
+                entries
+                    .into_iter()
+                    .map(|e| e.name)
+                    .filter(|name| *name != "." && *name != ".."),
+            );
+        }
+        drop(listing);
+
+        for child in children {
+            remove_tree(&fid, child).await?;
+        }
+        dir.unlinkat(&name, UnlinkatFlags::REMOVEDIR).await
+    })
+}
@@ -0,0 +1,277 @@
+//! Reconnecting after the connection to the server is lost.
+
+use {
+    super::{open, Conn},
+    crate::{error, error::errno::*, fcall::*, utils::Result},
+    std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::sync::Mutex as AsyncMutex,
+};
+
+/// How a client made by `Client::connect_resilient` reconnects.
+#[derive(Clone, Debug)]
+pub struct Reconnect {
+    /// Delay after the first failed attempt, doubled after each further one
+    pub initial_delay: Duration,
+    /// Longest delay between two attempts
+    pub max_delay: Duration,
+    /// Attempts made before the request which noticed the loss fails.
+    /// The next request starts over.
+    pub max_attempts: u32,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            max_attempts: 10,
+        }
+    }
+}
+
+// The Tattach a fid descends from
+struct Attach {
+    uname: String,
+    aname: String,
+    n_uname: u32,
+}
+
+// How to reach a fid again on a new connection
+#[derive(Clone, Default)]
+struct LiveFid {
+    // None if the fid cannot be reached again, e.g. an xattr fid
+    attach: Option<Arc<Attach>>,
+    wnames: Vec<NineString>,
+    flags: Option<LOpenFlags>,
+}
+
+#[derive(Default)]
+struct Fids {
+    live: HashMap<u32, LiveFid>,
+    // Fids which could not be reached again, until they are clunked
+    stale: HashSet<u32>,
+}
+
+pub(super) struct Resilience {
+    addr: String,
+    msize: u32,
+    policy: Reconnect,
+    pub(super) reconnecting: AsyncMutex<()>,
+    fids: Mutex<Fids>,
+}
+
+impl Resilience {
+    pub(super) fn new(addr: &str, msize: u32, policy: Reconnect) -> Resilience {
+        Resilience {
+            addr: addr.to_owned(),
+            msize,
+            policy,
+            reconnecting: AsyncMutex::new(()),
+            fids: Default::default(),
+        }
+    }
+
+    // Whether request can be sent again when its reply was lost with the connection.
+    // Writes are, except on fids opened to append, where the data would land twice.
+    pub(super) fn idempotent(&self, request: &Fcall) -> bool {
+        if let Fcall::Twrite { fid, .. } = *request {
+            let fids = self.fids.lock().unwrap();
+            return !matches!(
+                fids.live.get(&fid),
+                Some(LiveFid { flags: Some(flags), .. }) if flags.contains(LOpenFlags::APPEND)
+            );
+        }
+        matches!(
+            *request,
+            Fcall::Tattach { .. }
+                | Fcall::Twalk { .. }
+                | Fcall::Tlopen { .. }
+                | Fcall::Tread { .. }
+                | Fcall::Tgetattr { .. }
+                | Fcall::Tsetattr { .. }
+                | Fcall::Treaddir { .. }
+                | Fcall::Treadlink { .. }
+                | Fcall::Tstatfs { .. }
+                | Fcall::Tfsync { .. }
+                | Fcall::Tgetlock { .. }
+                | Fcall::Txattrwalk { .. }
+                | Fcall::Tclunk { .. }
+        )
+    }
+
+    // Answer requests on stale fids without bothering the server
+    pub(super) fn check(&self, request: &Fcall) -> Option<Result<Fcall>> {
+        let mut fids = self.fids.lock().unwrap();
+        match *request {
+            Fcall::Tclunk { fid } if fids.stale.remove(&fid) => Some(Ok(Fcall::Rclunk)),
+            Fcall::Tremove { fid } if fids.stale.remove(&fid) => {
+                Some(Err(error::Error::No(ESTALE)))
+            }
+            _ if request.fids().iter().any(|fid| fids.stale.contains(fid)) => {
+                Some(Err(error::Error::No(ESTALE)))
+            }
+            _ => None,
+        }
+    }
+
+    // Record how the fids of request can be reached again
+    pub(super) fn track(&self, request: &Fcall, res: &Result<Fcall>) {
+        // Neither creating nor truncating the file again
+        let reopen =
+            |flags: LOpenFlags| flags - (LOpenFlags::CREATE | LOpenFlags::EXCL | LOpenFlags::TRUNC);
+
+        let mut fids = self.fids.lock().unwrap();
+        match (request, res) {
+            (
+                Fcall::Tattach {
+                    fid,
+                    uname,
+                    aname,
+                    n_uname,
+                    ..
+                },
+                Ok(_),
+            ) => {
+                let attach = Attach {
+                    uname: uname.clone(),
+                    aname: aname.clone(),
+                    n_uname: *n_uname,
+                };
+                let live = LiveFid {
+                    attach: Some(Arc::new(attach)),
+                    ..Default::default()
+                };
+                fids.live.insert(*fid, live);
+            }
+            (
+                Fcall::Twalk {
+                    fid,
+                    newfid,
+                    wnames,
+                },
+                Ok(Fcall::Rwalk { wqids }),
+            ) if wqids.len() == wnames.len() => {
+                let mut live = fids.live.get(fid).cloned().unwrap_or_default();
+                live.wnames.extend(wnames.iter().cloned());
+                live.flags = None;
+                fids.live.insert(*newfid, live);
+            }
+            (Fcall::Tlopen { fid, flags }, Ok(_)) => {
+                if let Some(live) = fids.live.get_mut(fid) {
+                    live.flags = Some(reopen(*flags));
+                }
+            }
+            (
+                Fcall::Tlcreate {
+                    fid, name, flags, ..
+                },
+                Ok(_),
+            ) => {
+                if let Some(live) = fids.live.get_mut(fid) {
+                    live.wnames.push(name.clone());
+                    live.flags = Some(reopen(*flags));
+                }
+            }
+            (Fcall::Txattrwalk { newfid, .. }, Ok(_)) => {
+                fids.live.insert(*newfid, LiveFid::default());
+            }
+            (Fcall::Txattrcreate { fid, .. }, Ok(_)) => {
+                if let Some(live) = fids.live.get_mut(fid) {
+                    live.attach = None;
+                }
+            }
+            // The fid is gone whatever the reply
+            (Fcall::Tclunk { fid }, _) | (Fcall::Tremove { fid }, _) => {
+                fids.live.remove(fid);
+            }
+            _ => {}
+        }
+    }
+
+    // Connect again, retrying according to the policy, and restore the live fids
+    pub(super) async fn reconnect(&self) -> Result<Arc<Conn>> {
+        let mut delay = self.policy.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match self.establish().await {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt >= self.policy.max_attempts => return Err(e),
+                Err(e) => {
+                    info!("Reconnecting to {}: Error: \"{}\"", self.addr, e);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.policy.max_delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn establish(&self) -> Result<Arc<Conn>> {
+        let (reader, writer) = open(&self.addr).await?;
+        let conn = Conn::new(reader, writer, self.msize).await?;
+
+        let live: Vec<(u32, LiveFid)> = self
+            .fids
+            .lock()
+            .unwrap()
+            .live
+            .iter()
+            .map(|(fid, live)| (*fid, live.clone()))
+            .collect();
+        for (fid, live) in live {
+            match restore(&conn, fid, &live).await {
+                Ok(()) => {}
+                // Lost again, start over
+                Err(e) if conn.lost() => return Err(e),
+                Err(e) => {
+                    debug!("Restoring fid {}: Error: \"{}\"", fid, e);
+                    let mut fids = self.fids.lock().unwrap();
+                    if fids.live.remove(&fid).is_some() {
+                        fids.stale.insert(fid);
+                    }
+                }
+            }
+        }
+        Ok(conn)
+    }
+}
+
+// Attach, walk and open fid on conn as it was on the lost connection
+async fn restore(conn: &Arc<Conn>, fid: u32, live: &LiveFid) -> Result<()> {
+    let attach = live.attach.as_ref().ok_or(error::Error::No(ESTALE))?;
+    let request = Fcall::Tattach {
+        fid,
+        afid: NOFID,
+        uname: attach.uname.clone(),
+        aname: attach.aname.clone(),
+        n_uname: attach.n_uname,
+    };
+    conn.call(request).await?;
+
+    let res = async {
+        for chunk in live.wnames.chunks(MAXWELEM) {
+            let request = Fcall::Twalk {
+                fid,
+                newfid: fid,
+                wnames: chunk.to_vec(),
+            };
+            match conn.call(request).await? {
+                Fcall::Rwalk { wqids } if wqids.len() == chunk.len() => {}
+                _ => return Err(error::Error::No(ESTALE)),
+            }
+        }
+        if let Some(flags) = live.flags {
+            conn.call(Fcall::Tlopen { fid, flags }).await?;
+        }
+        Ok(())
+    };
+    let res = res.await;
+    if res.is_err() {
+        let _ = conn.call(Fcall::Tclunk { fid }).await;
+    }
+    res
+}
@@ -7,10 +7,18 @@
 //! Using the Linux system errno numbers is the expected behaviour.
 
 use crate::error::errno::*;
+use crate::serialize::{DecodeError, EncodeError};
 use std::io::ErrorKind::*;
 use std::{fmt, io};
 
 fn errno_from_io_error(e: &io::Error) -> nix::errno::Errno {
+    if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<EncodeError>()) {
+        return e.errno();
+    }
+    if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<DecodeError>()) {
+        return e.errno();
+    }
+
     e.raw_os_error()
         .map(nix::errno::from_i32)
         .unwrap_or_else(|| match e.kind() {
@@ -94,6 +102,15 @@ impl From<nix::Error> for Error {
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::No(e) => io::Error::from_raw_os_error(e as i32),
+            Error::Io(e) => e,
+        }
+    }
+}
+
 /// The system errno definitions.
 ///
 /// # Protocol
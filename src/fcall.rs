@@ -3,12 +3,48 @@
 //! # Protocol
 //! 9P2000.L
 
-use std::fs;
+use std::ffi::{OsStr, OsString};
 use std::mem::{size_of, size_of_val};
+use std::ops::Deref;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs};
 
 use bitflags::bitflags;
 use enum_primitive::*;
+use nix::fcntl::OFlag;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// bitflags! which also lists every flag with its name, for serde
+macro_rules! named_bitflags {
+    (
+        $(#[$outer:meta])*
+        pub struct $name:ident: $typ:ty {
+            $(
+                $(#[$inner:ident $($args:tt)*])*
+                const $flag:ident = $value:expr;
+            )*
+        }
+    ) => {
+        bitflags! {
+            $(#[$outer])*
+            pub struct $name: $typ {
+                $(
+                    $(#[$inner $($args)*])*
+                    const $flag = $value;
+                )*
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl $name {
+            pub(crate) const NAMES: &'static [(&'static str, $name)] =
+                &[$((stringify!($flag), $name::$flag)),*];
+        }
+    };
+}
 
 /// 9P2000 version string
 pub const P92000: &str = "9P2000";
@@ -40,6 +76,9 @@ pub const NONUNAME: u32 = !0;
 /// size[4] Tread/Twrite[2] tag[2] fid[4] offset[8] count[4]
 pub const IOHDRSZ: u32 = 24;
 
+/// Maximum number of elements in a single `Twalk`/`Rwalk`
+pub const MAXWELEM: usize = 16;
+
 /// Room for readdir header
 pub const READDIRHDRSZ: u32 = 24;
 
@@ -50,6 +89,9 @@ pub const V9FS_PORT: u16 = 564;
 ///
 /// Types in this module are not used 9P2000.L
 pub mod p92000 {
+    #[cfg(feature = "serde")]
+    use serde::{Deserialize, Serialize};
+
     /// The type of I/O
     ///
     /// Open mode to be checked against the permissions for the file.
@@ -96,6 +138,7 @@ pub mod p92000 {
     ///
     /// NOTE: Defined as `Dir` in libc.h of Plan 9
     #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub struct Stat {
         /// Server type
         pub typ: u16,
@@ -141,7 +184,7 @@ pub mod p92000 {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// File lock type, Flock.typ
     pub struct LockType: u8 {
         const RDLOCK    = 0;
@@ -150,7 +193,7 @@ bitflags! {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// File lock flags, Flock.flags
     pub struct LockFlag: u32 {
         #[doc = "Blocking request"]
@@ -160,7 +203,7 @@ bitflags! {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// File lock status
     pub struct LockStatus: u8 {
         const SUCCESS   = 0;
@@ -170,7 +213,7 @@ bitflags! {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// Bits in Qid.typ
     ///
     /// QidType can be constructed from std::fs::FileType via From trait
@@ -222,7 +265,7 @@ impl<'a> From<&'a ::std::fs::FileType> for QidType {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// Bits in `mask` and `valid` of `Tgetattr` and `Rgetattr`.
     ///
     /// # Protocol
@@ -251,7 +294,7 @@ bitflags! {
     }
 }
 
-bitflags! {
+named_bitflags! {
     /// Bits in `mask` of `Tsetattr`.
     ///
     /// If a time bit is set without the corresponding SET bit, the current
@@ -272,6 +315,352 @@ bitflags! {
     }
 }
 
+named_bitflags! {
+    /// Bits in `flags` of `Tlopen` and `Tlcreate`, `P9_DOTL_*` in Linux.
+    ///
+    /// The values are fixed by the protocol while the host's `O_*` flags
+    /// differ between architectures, so convert through `OFlag::from` and
+    /// `LOpenFlags::from` instead of reinterpreting the bits.
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    pub struct LOpenFlags: u32 {
+        const RDONLY    = 0o00000000;
+        const WRONLY    = 0o00000001;
+        const RDWR      = 0o00000002;
+        const NOACCESS  = 0o00000003;
+        const CREATE    = 0o00000100;
+        const EXCL      = 0o00000200;
+        const NOCTTY    = 0o00000400;
+        const TRUNC     = 0o00001000;
+        const APPEND    = 0o00002000;
+        const NONBLOCK  = 0o00004000;
+        const DSYNC     = 0o00010000;
+        const FASYNC    = 0o00020000;
+        const DIRECT    = 0o00040000;
+        const LARGEFILE = 0o00100000;
+        const DIRECTORY = 0o00200000;
+        const NOFOLLOW  = 0o00400000;
+        const NOATIME   = 0o01000000;
+        const CLOEXEC   = 0o02000000;
+        const SYNC      = 0o04000000;
+
+        #[doc = "Mask for the access mode"]
+        const ACCMODE   = 0o00000003;
+    }
+}
+
+impl LOpenFlags {
+    /// Get the access mode, one of `RDONLY`, `WRONLY`, `RDWR` or `NOACCESS`
+    pub fn access_mode(&self) -> LOpenFlags {
+        *self & LOpenFlags::ACCMODE
+    }
+}
+
+// Flags other than the access mode and their host counterparts
+fn lopen_flag_map() -> &'static [(LOpenFlags, OFlag)] {
+    &[
+        (LOpenFlags::CREATE, OFlag::O_CREAT),
+        (LOpenFlags::EXCL, OFlag::O_EXCL),
+        (LOpenFlags::NOCTTY, OFlag::O_NOCTTY),
+        (LOpenFlags::TRUNC, OFlag::O_TRUNC),
+        (LOpenFlags::APPEND, OFlag::O_APPEND),
+        (LOpenFlags::NONBLOCK, OFlag::O_NONBLOCK),
+        (LOpenFlags::DSYNC, OFlag::O_DSYNC),
+        (LOpenFlags::FASYNC, OFlag::O_ASYNC),
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        (LOpenFlags::DIRECT, OFlag::O_DIRECT),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (LOpenFlags::LARGEFILE, OFlag::O_LARGEFILE),
+        (LOpenFlags::DIRECTORY, OFlag::O_DIRECTORY),
+        (LOpenFlags::NOFOLLOW, OFlag::O_NOFOLLOW),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        (LOpenFlags::NOATIME, OFlag::O_NOATIME),
+        (LOpenFlags::CLOEXEC, OFlag::O_CLOEXEC),
+        (LOpenFlags::SYNC, OFlag::O_SYNC),
+    ]
+}
+
+/// Flags without a host counterpart are dropped
+impl From<LOpenFlags> for OFlag {
+    fn from(flags: LOpenFlags) -> OFlag {
+        let mut oflag = match flags.access_mode() {
+            LOpenFlags::RDONLY => OFlag::O_RDONLY,
+            LOpenFlags::WRONLY => OFlag::O_WRONLY,
+            LOpenFlags::RDWR => OFlag::O_RDWR,
+            _ => OFlag::O_ACCMODE,
+        };
+        for (f, o) in lopen_flag_map() {
+            if flags.contains(*f) {
+                oflag |= *o;
+            }
+        }
+        oflag
+    }
+}
+
+/// Host flags without a protocol counterpart are dropped
+impl From<OFlag> for LOpenFlags {
+    fn from(oflag: OFlag) -> LOpenFlags {
+        let acc = oflag & OFlag::O_ACCMODE;
+        let mut flags = if acc == OFlag::O_RDONLY {
+            LOpenFlags::RDONLY
+        } else if acc == OFlag::O_WRONLY {
+            LOpenFlags::WRONLY
+        } else if acc == OFlag::O_RDWR {
+            LOpenFlags::RDWR
+        } else {
+            LOpenFlags::NOACCESS
+        };
+        for (f, o) in lopen_flag_map() {
+            // Some flags such as O_LARGEFILE are 0 on 64-bit hosts
+            if !o.is_empty() && oflag.contains(*o) {
+                flags |= *f;
+            }
+        }
+        flags
+    }
+}
+
+/// File type stored in the `S_IFMT` bits of a mode
+///
+/// # Protocol
+/// 9P2000.L
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FileType {
+    Fifo,
+    CharDevice,
+    Directory,
+    BlockDevice,
+    Regular,
+    Symlink,
+    Socket,
+}
+
+impl FileType {
+    /// Mask for the file type bits of a mode
+    pub const S_IFMT: u32 = 0o170000;
+
+    /// Get the file type from the `S_IFMT` bits of `mode`
+    pub fn from_mode(mode: u32) -> Option<FileType> {
+        match mode & Self::S_IFMT {
+            0o010000 => Some(FileType::Fifo),
+            0o020000 => Some(FileType::CharDevice),
+            0o040000 => Some(FileType::Directory),
+            0o060000 => Some(FileType::BlockDevice),
+            0o100000 => Some(FileType::Regular),
+            0o120000 => Some(FileType::Symlink),
+            0o140000 => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Get the `S_IFMT` bits of this file type
+    pub fn bits(&self) -> u32 {
+        match *self {
+            FileType::Fifo => 0o010000,
+            FileType::CharDevice => 0o020000,
+            FileType::Directory => 0o040000,
+            FileType::BlockDevice => 0o060000,
+            FileType::Regular => 0o100000,
+            FileType::Symlink => 0o120000,
+            FileType::Socket => 0o140000,
+        }
+    }
+
+    /// Get the `d_type` of `struct dirent` used in `DirEntry.typ`
+    pub fn dirent_type(&self) -> u8 {
+        // DT_* is S_IFMT shifted down
+        (self.bits() >> 12) as u8
+    }
+}
+
+impl From<FileType> for QidType {
+    fn from(typ: FileType) -> Self {
+        match typ {
+            FileType::Directory => QidType::DIR,
+            FileType::Symlink => QidType::SYMLINK,
+            _ => QidType::FILE,
+        }
+    }
+}
+
+impl From<FileType> for nix::sys::stat::SFlag {
+    fn from(typ: FileType) -> Self {
+        use nix::sys::stat::SFlag;
+        match typ {
+            FileType::Fifo => SFlag::S_IFIFO,
+            FileType::CharDevice => SFlag::S_IFCHR,
+            FileType::Directory => SFlag::S_IFDIR,
+            FileType::BlockDevice => SFlag::S_IFBLK,
+            FileType::Regular => SFlag::S_IFREG,
+            FileType::Symlink => SFlag::S_IFLNK,
+            FileType::Socket => SFlag::S_IFSOCK,
+        }
+    }
+}
+
+impl<'a> From<&'a fs::FileType> for FileType {
+    fn from(typ: &'a fs::FileType) -> Self {
+        use std::os::unix::fs::FileTypeExt;
+        if typ.is_dir() {
+            FileType::Directory
+        } else if typ.is_symlink() {
+            FileType::Symlink
+        } else if typ.is_fifo() {
+            FileType::Fifo
+        } else if typ.is_char_device() {
+            FileType::CharDevice
+        } else if typ.is_block_device() {
+            FileType::BlockDevice
+        } else if typ.is_socket() {
+            FileType::Socket
+        } else {
+            FileType::Regular
+        }
+    }
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(typ: fs::FileType) -> Self {
+        From::from(&typ)
+    }
+}
+
+/// Mode of a file, the file type and the permission bits
+///
+/// Used in `Stat.mode`, `SetAttr.mode` and the `mode` of
+/// `Tlcreate`, `Tmknod` and `Tmkdir`. The raw value is kept as is,
+/// so a mode without a file type, as in `Tmkdir`, survives the round trip.
+///
+/// # Protocol
+/// 9P2000.L
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileMode(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impl::octal"))] u32);
+
+impl FileMode {
+    /// Mask for the permission bits including setuid, setgid and sticky
+    pub const PERM_MASK: u32 = 0o7777;
+
+    /// Create a mode from a file type and permission bits
+    pub fn new(typ: FileType, perm: u32) -> FileMode {
+        FileMode(typ.bits() | (perm & Self::PERM_MASK))
+    }
+
+    /// Create a mode from its raw value
+    pub const fn from_bits(bits: u32) -> FileMode {
+        FileMode(bits)
+    }
+
+    /// Get the raw value
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Get the file type, `None` if the mode carries none
+    pub fn file_type(&self) -> Option<FileType> {
+        FileType::from_mode(self.0)
+    }
+
+    /// Get the permission bits including setuid, setgid and sticky
+    pub fn permissions(&self) -> u32 {
+        self.0 & Self::PERM_MASK
+    }
+
+    /// Get the `QidType` for this mode
+    pub fn qid_type(&self) -> QidType {
+        self.file_type().map_or(QidType::FILE, From::from)
+    }
+
+    /// Get the `d_type` of `struct dirent`, `DT_UNKNOWN` (0) if the mode carries no type
+    pub fn dirent_type(&self) -> u8 {
+        self.file_type().map_or(0, |t| t.dirent_type())
+    }
+}
+
+impl fmt::Debug for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FileMode({:#o})", self.0)
+    }
+}
+
+impl fmt::Octal for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Octal::fmt(&self.0, f)
+    }
+}
+
+impl From<u32> for FileMode {
+    fn from(bits: u32) -> Self {
+        FileMode(bits)
+    }
+}
+
+/// Permission bits only, the file type is dropped
+impl From<FileMode> for nix::sys::stat::Mode {
+    fn from(mode: FileMode) -> Self {
+        nix::sys::stat::Mode::from_bits_truncate(mode.permissions() as _)
+    }
+}
+
+named_bitflags! {
+    /// Bits in `flags` of `Tunlinkat`
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Default)]
+    pub struct UnlinkatFlags: u32 {
+        #[doc = "Remove a directory instead of a file, `AT_REMOVEDIR`"]
+        const REMOVEDIR = 0x200;
+    }
+}
+
+impl From<UnlinkatFlags> for nix::unistd::UnlinkatFlags {
+    fn from(flags: UnlinkatFlags) -> Self {
+        if flags.contains(UnlinkatFlags::REMOVEDIR) {
+            nix::unistd::UnlinkatFlags::RemoveDir
+        } else {
+            nix::unistd::UnlinkatFlags::NoRemoveDir
+        }
+    }
+}
+
+named_bitflags! {
+    /// Bits in `flags` of `Txattrcreate`
+    ///
+    /// # Protocol
+    /// 9P2000.L
+    #[derive(Default)]
+    pub struct XattrFlags: u32 {
+        #[doc = "Fail if the attribute already exists, `XATTR_CREATE`"]
+        const CREATE    = 0x1;
+        #[doc = "Fail if the attribute does not exist, `XATTR_REPLACE`"]
+        const REPLACE   = 0x2;
+    }
+}
+
+/// Host flags for `setxattr(2)`
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+impl From<XattrFlags> for nix::libc::c_int {
+    fn from(flags: XattrFlags) -> Self {
+        let mut host = 0;
+        if flags.contains(XattrFlags::CREATE) {
+            host |= nix::libc::XATTR_CREATE;
+        }
+        if flags.contains(XattrFlags::REPLACE) {
+            host |= nix::libc::XATTR_REPLACE;
+        }
+        host
+    }
+}
+
 /// Server side data type for path tracking
 ///
 /// The server's unique identification for the file being accessed
@@ -279,12 +668,14 @@ bitflags! {
 /// # Protocol
 /// 9P2000/9P2000.L
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Qid {
     /// Specify whether the file is a directory, append-only file, etc.
     pub typ: QidType,
     /// Version number for a file; typically, it is incremented every time the file is modified
     pub version: u32,
     /// An integer which is unique among all files in the hierarchy
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impl::hex"))]
     pub path: u64,
 }
 
@@ -299,6 +690,7 @@ impl Qid {
 /// # Protocol
 /// 9P2000.L
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Statfs {
     /// Type of file system
     pub typ: u32,
@@ -330,7 +722,7 @@ impl From<nix::sys::statvfs::Statvfs> for Statfs {
             bavail: buf.blocks_available(),
             files: buf.files(),
             ffree: buf.files_free(),
-            fsid: buf.filesystem_id() as u64,
+            fsid: buf.filesystem_id(),
             namelen: buf.name_max() as u32,
         }
     }
@@ -341,6 +733,7 @@ impl From<nix::sys::statvfs::Statvfs> for Statfs {
 /// # Protocol
 /// 9P2000.L
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Time {
     pub sec: u64,
     pub nsec: u64,
@@ -353,9 +746,10 @@ pub struct Time {
 /// # Protocol
 /// 9P2000.L
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Stat {
-    /// Protection
-    pub mode: u32,
+    /// File type and permissions
+    pub mode: FileMode,
     /// User ID of owner
     pub uid: u32,
     /// Group ID of owner
@@ -376,6 +770,12 @@ pub struct Stat {
     pub mtime: Time,
     /// Time of last status change
     pub ctime: Time,
+    /// Time of creation
+    pub btime: Time,
+    /// Inode generation number
+    pub gen: u64,
+    /// Data version, changed whenever the contents of the file change
+    pub data_version: u64,
 }
 
 impl From<fs::Metadata> for Stat {
@@ -388,14 +788,14 @@ impl From<fs::Metadata> for Stat {
 impl<'a> From<&'a fs::Metadata> for Stat {
     fn from(attr: &'a fs::Metadata) -> Self {
         Stat {
-            mode: attr.mode(),
+            mode: FileMode::from_bits(attr.mode()),
             uid: attr.uid(),
             gid: attr.gid(),
             nlink: attr.nlink(),
             rdev: attr.rdev(),
-            size: attr.size() as u64,
-            blksize: attr.blksize() as u64,
-            blocks: attr.blocks() as u64,
+            size: attr.size(),
+            blksize: attr.blksize(),
+            blocks: attr.blocks(),
             atime: Time {
                 sec: attr.atime() as u64,
                 nsec: attr.atime_nsec() as u64,
@@ -408,14 +808,26 @@ impl<'a> From<&'a fs::Metadata> for Stat {
                 sec: attr.ctime() as u64,
                 nsec: attr.ctime_nsec() as u64,
             },
+            // Not every filesystem records the creation time
+            btime: attr
+                .created()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(Time { sec: 0, nsec: 0 }, |d| Time {
+                    sec: d.as_secs(),
+                    nsec: d.subsec_nanos() as u64,
+                }),
+            gen: 0,
+            data_version: 0,
         }
     }
 }
 
 /// Subset of `Stat` used for `Tsetattr`
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SetAttr {
-    pub mode: u32,
+    pub mode: FileMode,
     pub uid: u32,
     pub gid: u32,
     pub size: u64,
@@ -423,11 +835,140 @@ pub struct SetAttr {
     pub mtime: Time,
 }
 
+/// Byte string used for file names on the wire
+///
+/// Neither 9P2000.L nor Linux requires file names to be UTF-8.
+/// `NineString` keeps the raw bytes so any name survives the round trip,
+/// and dereferences to `OsStr` the same way `PathBuf` dereferences to `Path`.
+///
+/// # Protocol
+/// 9P2000.L
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NineString(Vec<u8>);
+
+impl NineString {
+    pub fn new() -> NineString {
+        NineString(Vec::new())
+    }
+
+    /// Get the raw bytes of the name
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Convert into the raw bytes of the name
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        OsStr::from_bytes(&self.0)
+    }
+}
+
+impl fmt::Debug for NineString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+impl Deref for NineString {
+    type Target = OsStr;
+    fn deref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl AsRef<OsStr> for NineString {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl AsRef<Path> for NineString {
+    fn as_ref(&self) -> &Path {
+        Path::new(self.as_os_str())
+    }
+}
+
+impl AsRef<[u8]> for NineString {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for NineString {
+    fn from(v: Vec<u8>) -> Self {
+        NineString(v)
+    }
+}
+
+impl<'a> From<&'a [u8]> for NineString {
+    fn from(v: &'a [u8]) -> Self {
+        NineString(v.to_vec())
+    }
+}
+
+impl From<String> for NineString {
+    fn from(s: String) -> Self {
+        NineString(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for NineString {
+    fn from(s: &'a str) -> Self {
+        NineString(s.as_bytes().to_vec())
+    }
+}
+
+impl From<OsString> for NineString {
+    fn from(s: OsString) -> Self {
+        NineString(s.into_vec())
+    }
+}
+
+impl<'a> From<&'a OsStr> for NineString {
+    fn from(s: &'a OsStr) -> Self {
+        NineString(s.as_bytes().to_vec())
+    }
+}
+
+impl From<PathBuf> for NineString {
+    fn from(p: PathBuf) -> Self {
+        From::from(p.into_os_string())
+    }
+}
+
+impl From<NineString> for OsString {
+    fn from(s: NineString) -> Self {
+        OsString::from_vec(s.0)
+    }
+}
+
+impl From<NineString> for PathBuf {
+    fn from(s: NineString) -> Self {
+        PathBuf::from(OsString::from(s))
+    }
+}
+
+impl PartialEq<str> for NineString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for NineString {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
 /// Directory entry used in `Rreaddir`
 ///
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DirEntry {
     /// Qid for this directory
     pub qid: Qid,
@@ -438,7 +979,7 @@ pub struct DirEntry {
     /// Use `0` if you can't set this properly. It might be enough.
     pub typ: u8,
     /// Directory name
-    pub name: String,
+    pub name: NineString,
 }
 
 impl DirEntry {
@@ -453,6 +994,7 @@ impl DirEntry {
 
 /// Directory entry array
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DirEntryData {
     pub data: Vec<DirEntry>,
 }
@@ -470,8 +1012,12 @@ impl DirEntryData {
         &self.data
     }
 
+    pub fn into_data(self) -> Vec<DirEntry> {
+        self.data
+    }
+
     pub fn size(&self) -> u32 {
-        self.data.iter().fold(0, |a, e| a + e.size()) as u32
+        self.data.iter().fold(0, |a, e| a + e.size())
     }
 
     pub fn push(&mut self, entry: DirEntry) {
@@ -497,6 +1043,7 @@ pub struct Data(pub Vec<u8>);
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Flock {
     pub typ: LockType,
     pub flags: LockFlag,
@@ -511,6 +1058,7 @@ pub struct Flock {
 /// # Protocol
 /// 9P2000.L
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Getlock {
     pub typ: LockType,
     pub start: u64,
@@ -523,6 +1071,7 @@ pub struct Getlock {
 enum_from_primitive! {
     #[doc = "Message type, 9P operations"]
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub enum MsgType {
         // 9P2000.L
         Tlerror         = 6,    // Illegal, never used
@@ -709,6 +1258,7 @@ impl<'a> From<&'a Fcall> for MsgType {
 
 /// A data type encapsulating the various 9P messages
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Fcall {
     // 9P2000.L
     Rlerror {
@@ -722,7 +1272,7 @@ pub enum Fcall {
     },
     Tlopen {
         fid: u32,
-        flags: u32,
+        flags: LOpenFlags,
     },
     Rlopen {
         qid: Qid,
@@ -730,9 +1280,9 @@ pub enum Fcall {
     },
     Tlcreate {
         fid: u32,
-        name: String,
-        flags: u32,
-        mode: u32,
+        name: NineString,
+        flags: LOpenFlags,
+        mode: FileMode,
         gid: u32,
     },
     Rlcreate {
@@ -741,8 +1291,8 @@ pub enum Fcall {
     },
     Tsymlink {
         fid: u32,
-        name: String,
-        symtgt: String,
+        name: NineString,
+        symtgt: NineString,
         gid: u32,
     },
     Rsymlink {
@@ -750,8 +1300,8 @@ pub enum Fcall {
     },
     Tmknod {
         dfid: u32,
-        name: String,
-        mode: u32,
+        name: NineString,
+        mode: FileMode,
         major: u32,
         minor: u32,
         gid: u32,
@@ -762,14 +1312,14 @@ pub enum Fcall {
     Trename {
         fid: u32,
         dfid: u32,
-        name: String,
+        name: NineString,
     },
     Rrename,
     Treadlink {
         fid: u32,
     },
     Rreadlink {
-        target: String,
+        target: NineString,
     },
     Tgetattr {
         fid: u32,
@@ -790,16 +1340,16 @@ pub enum Fcall {
     Txattrwalk {
         fid: u32,
         newfid: u32,
-        name: String,
+        name: NineString,
     },
     Rxattrwalk {
         size: u64,
     },
     Txattrcreate {
         fid: u32,
-        name: String,
+        name: NineString,
         attr_size: u64,
-        flags: u32,
+        flags: XattrFlags,
     },
     Rxattrcreate,
     Treaddir {
@@ -831,13 +1381,13 @@ pub enum Fcall {
     Tlink {
         dfid: u32,
         fid: u32,
-        name: String,
+        name: NineString,
     },
     Rlink,
     Tmkdir {
         dfid: u32,
-        name: String,
-        mode: u32,
+        name: NineString,
+        mode: FileMode,
         gid: u32,
     },
     Rmkdir {
@@ -845,15 +1395,15 @@ pub enum Fcall {
     },
     Trenameat {
         olddirfid: u32,
-        oldname: String,
+        oldname: NineString,
         newdirfid: u32,
-        newname: String,
+        newname: NineString,
     },
     Rrenameat,
     Tunlinkat {
         dirfd: u32,
-        name: String,
-        flags: u32,
+        name: NineString,
+        flags: UnlinkatFlags,
     },
     Runlinkat,
 
@@ -894,7 +1444,7 @@ pub enum Fcall {
     Twalk {
         fid: u32,
         newfid: u32,
-        wnames: Vec<String>,
+        wnames: Vec<NineString>,
     },
     Rwalk {
         wqids: Vec<Qid>,
@@ -1006,6 +1556,7 @@ impl Fcall {
 
 /// Envelope for 9P messages
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Msg {
     /// Chosen and used by the client to identify the message.
     /// The reply to the message will have the same tag
@@ -1013,3 +1564,721 @@ pub struct Msg {
     /// Message body encapsulating the various 9P messages
     pub body: Fcall,
 }
+
+/// Fixed-capacity array holding the elements of a `Twalk`/`Rwalk`
+///
+/// A walk carries at most `MAXWELEM` elements, so they can be kept inline
+/// without allocating. Dereferences to a slice of the elements in use.
+#[derive(Copy, Clone)]
+pub struct WalkArray<T> {
+    len: usize,
+    elems: [T; MAXWELEM],
+}
+
+impl<T: Copy + Default> WalkArray<T> {
+    pub fn new() -> WalkArray<T> {
+        WalkArray {
+            len: 0,
+            elems: [T::default(); MAXWELEM],
+        }
+    }
+
+    /// Append an element
+    ///
+    /// # Panics
+    /// Panics if the array already holds `MAXWELEM` elements.
+    pub fn push(&mut self, elem: T) {
+        self.elems[self.len] = elem;
+        self.len += 1;
+    }
+}
+
+impl<T: Copy + Default> Default for WalkArray<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for WalkArray<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        &self.elems[..self.len]
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for WalkArray<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq> PartialEq for WalkArray<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for WalkArray<T> {}
+
+/// Borrowed view of a `DirEntry`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DirEntryRef<'a> {
+    pub qid: Qid,
+    pub offset: u64,
+    pub typ: u8,
+    pub name: &'a [u8],
+}
+
+impl<'a> From<DirEntryRef<'a>> for DirEntry {
+    fn from(e: DirEntryRef<'a>) -> Self {
+        DirEntry {
+            qid: e.qid,
+            offset: e.offset,
+            typ: e.typ,
+            name: e.name.into(),
+        }
+    }
+}
+
+/// Borrowed view of the packed entries of `Rreaddir`
+///
+/// The entries are validated when the message is decoded and parsed
+/// lazily by `iter`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct DirEntryDataRef<'a>(pub(crate) &'a [u8]);
+
+impl<'a> DirEntryDataRef<'a> {
+    /// Get the raw encoded entries
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<'a> fmt::Debug for DirEntryDataRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Borrowed view of a `Flock`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FlockRef<'a> {
+    pub typ: LockType,
+    pub flags: LockFlag,
+    pub start: u64,
+    pub length: u64,
+    pub proc_id: u32,
+    pub client_id: &'a str,
+}
+
+impl<'a> From<FlockRef<'a>> for Flock {
+    fn from(f: FlockRef<'a>) -> Self {
+        Flock {
+            typ: f.typ,
+            flags: f.flags,
+            start: f.start,
+            length: f.length,
+            proc_id: f.proc_id,
+            client_id: f.client_id.to_owned(),
+        }
+    }
+}
+
+/// Borrowed view of a `Getlock`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GetlockRef<'a> {
+    pub typ: LockType,
+    pub start: u64,
+    pub length: u64,
+    pub proc_id: u32,
+    pub client_id: &'a str,
+}
+
+impl<'a> From<GetlockRef<'a>> for Getlock {
+    fn from(g: GetlockRef<'a>) -> Self {
+        Getlock {
+            typ: g.typ,
+            start: g.start,
+            length: g.length,
+            proc_id: g.proc_id,
+            client_id: g.client_id.to_owned(),
+        }
+    }
+}
+
+/// Borrowed view of a 9P message decoded directly from its frame
+///
+/// Names and payloads point into the frame buffer instead of being copied,
+/// so decoding a `FcallRef` does not allocate.
+/// Names are raw bytes as in `NineString`.
+/// Use `Fcall::from` to get an owned message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FcallRef<'a> {
+    // 9P2000.L
+    Rlerror {
+        ecode: u32,
+    },
+    Tstatfs {
+        fid: u32,
+    },
+    Rstatfs {
+        statfs: Statfs,
+    },
+    Tlopen {
+        fid: u32,
+        flags: LOpenFlags,
+    },
+    Rlopen {
+        qid: Qid,
+        iounit: u32,
+    },
+    Tlcreate {
+        fid: u32,
+        name: &'a [u8],
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    },
+    Rlcreate {
+        qid: Qid,
+        iounit: u32,
+    },
+    Tsymlink {
+        fid: u32,
+        name: &'a [u8],
+        symtgt: &'a [u8],
+        gid: u32,
+    },
+    Rsymlink {
+        qid: Qid,
+    },
+    Tmknod {
+        dfid: u32,
+        name: &'a [u8],
+        mode: FileMode,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    },
+    Rmknod {
+        qid: Qid,
+    },
+    Trename {
+        fid: u32,
+        dfid: u32,
+        name: &'a [u8],
+    },
+    Rrename,
+    Treadlink {
+        fid: u32,
+    },
+    Rreadlink {
+        target: &'a [u8],
+    },
+    Tgetattr {
+        fid: u32,
+        req_mask: GetattrMask,
+    },
+    Rgetattr {
+        valid: GetattrMask,
+        qid: Qid,
+        stat: Stat,
+    },
+    Tsetattr {
+        fid: u32,
+        valid: SetattrMask,
+        stat: SetAttr,
+    },
+    Rsetattr,
+    Txattrwalk {
+        fid: u32,
+        newfid: u32,
+        name: &'a [u8],
+    },
+    Rxattrwalk {
+        size: u64,
+    },
+    Txattrcreate {
+        fid: u32,
+        name: &'a [u8],
+        attr_size: u64,
+        flags: XattrFlags,
+    },
+    Rxattrcreate,
+    Treaddir {
+        fid: u32,
+        offset: u64,
+        count: u32,
+    },
+    Rreaddir {
+        data: DirEntryDataRef<'a>,
+    },
+    Tfsync {
+        fid: u32,
+    },
+    Rfsync,
+    Tlock {
+        fid: u32,
+        flock: FlockRef<'a>,
+    },
+    Rlock {
+        status: LockStatus,
+    },
+    Tgetlock {
+        fid: u32,
+        flock: GetlockRef<'a>,
+    },
+    Rgetlock {
+        flock: GetlockRef<'a>,
+    },
+    Tlink {
+        dfid: u32,
+        fid: u32,
+        name: &'a [u8],
+    },
+    Rlink,
+    Tmkdir {
+        dfid: u32,
+        name: &'a [u8],
+        mode: FileMode,
+        gid: u32,
+    },
+    Rmkdir {
+        qid: Qid,
+    },
+    Trenameat {
+        olddirfid: u32,
+        oldname: &'a [u8],
+        newdirfid: u32,
+        newname: &'a [u8],
+    },
+    Rrenameat,
+    Tunlinkat {
+        dirfd: u32,
+        name: &'a [u8],
+        flags: UnlinkatFlags,
+    },
+    Runlinkat,
+
+    // 9P2000.u
+    Tauth {
+        afid: u32,
+        uname: &'a str,
+        aname: &'a str,
+        n_uname: u32,
+    },
+    Rauth {
+        aqid: Qid,
+    },
+    Tattach {
+        fid: u32,
+        afid: u32,
+        uname: &'a str,
+        aname: &'a str,
+        n_uname: u32,
+    },
+    Rattach {
+        qid: Qid,
+    },
+
+    // 9P2000
+    Tversion {
+        msize: u32,
+        version: &'a str,
+    },
+    Rversion {
+        msize: u32,
+        version: &'a str,
+    },
+    Tflush {
+        oldtag: u16,
+    },
+    Rflush,
+    Twalk {
+        fid: u32,
+        newfid: u32,
+        wnames: WalkArray<&'a [u8]>,
+    },
+    Rwalk {
+        wqids: WalkArray<Qid>,
+    },
+    Tread {
+        fid: u32,
+        offset: u64,
+        count: u32,
+    },
+    Rread {
+        data: &'a [u8],
+    },
+    Twrite {
+        fid: u32,
+        offset: u64,
+        data: &'a [u8],
+    },
+    Rwrite {
+        count: u32,
+    },
+    Tclunk {
+        fid: u32,
+    },
+    Rclunk,
+    Tremove {
+        fid: u32,
+    },
+    Rremove,
+}
+
+impl<'a> FcallRef<'a> {
+    /// Get the newfid which self contains
+    pub fn newfid(&self) -> Option<u32> {
+        match *self {
+            FcallRef::Txattrwalk { newfid, .. } => Some(newfid),
+            FcallRef::Tauth { afid, .. } => Some(afid),
+            FcallRef::Tattach { fid, .. } => Some(fid),
+            FcallRef::Twalk { newfid, .. } => Some(newfid),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, 'b> From<&'b FcallRef<'a>> for MsgType {
+    fn from(fcall: &'b FcallRef<'a>) -> MsgType {
+        match *fcall {
+            FcallRef::Rlerror { .. } => MsgType::Rlerror,
+            FcallRef::Tstatfs { .. } => MsgType::Tstatfs,
+            FcallRef::Rstatfs { .. } => MsgType::Rstatfs,
+            FcallRef::Tlopen { .. } => MsgType::Tlopen,
+            FcallRef::Rlopen { .. } => MsgType::Rlopen,
+            FcallRef::Tlcreate { .. } => MsgType::Tlcreate,
+            FcallRef::Rlcreate { .. } => MsgType::Rlcreate,
+            FcallRef::Tsymlink { .. } => MsgType::Tsymlink,
+            FcallRef::Rsymlink { .. } => MsgType::Rsymlink,
+            FcallRef::Tmknod { .. } => MsgType::Tmknod,
+            FcallRef::Rmknod { .. } => MsgType::Rmknod,
+            FcallRef::Trename { .. } => MsgType::Trename,
+            FcallRef::Rrename => MsgType::Rrename,
+            FcallRef::Treadlink { .. } => MsgType::Treadlink,
+            FcallRef::Rreadlink { .. } => MsgType::Rreadlink,
+            FcallRef::Tgetattr { .. } => MsgType::Tgetattr,
+            FcallRef::Rgetattr { .. } => MsgType::Rgetattr,
+            FcallRef::Tsetattr { .. } => MsgType::Tsetattr,
+            FcallRef::Rsetattr => MsgType::Rsetattr,
+            FcallRef::Txattrwalk { .. } => MsgType::Txattrwalk,
+            FcallRef::Rxattrwalk { .. } => MsgType::Rxattrwalk,
+            FcallRef::Txattrcreate { .. } => MsgType::Txattrcreate,
+            FcallRef::Rxattrcreate => MsgType::Rxattrcreate,
+            FcallRef::Treaddir { .. } => MsgType::Treaddir,
+            FcallRef::Rreaddir { .. } => MsgType::Rreaddir,
+            FcallRef::Tfsync { .. } => MsgType::Tfsync,
+            FcallRef::Rfsync => MsgType::Rfsync,
+            FcallRef::Tlock { .. } => MsgType::Tlock,
+            FcallRef::Rlock { .. } => MsgType::Rlock,
+            FcallRef::Tgetlock { .. } => MsgType::Tgetlock,
+            FcallRef::Rgetlock { .. } => MsgType::Rgetlock,
+            FcallRef::Tlink { .. } => MsgType::Tlink,
+            FcallRef::Rlink => MsgType::Rlink,
+            FcallRef::Tmkdir { .. } => MsgType::Tmkdir,
+            FcallRef::Rmkdir { .. } => MsgType::Rmkdir,
+            FcallRef::Trenameat { .. } => MsgType::Trenameat,
+            FcallRef::Rrenameat => MsgType::Rrenameat,
+            FcallRef::Tunlinkat { .. } => MsgType::Tunlinkat,
+            FcallRef::Runlinkat => MsgType::Runlinkat,
+            FcallRef::Tauth { .. } => MsgType::Tauth,
+            FcallRef::Rauth { .. } => MsgType::Rauth,
+            FcallRef::Tattach { .. } => MsgType::Tattach,
+            FcallRef::Rattach { .. } => MsgType::Rattach,
+            FcallRef::Tversion { .. } => MsgType::Tversion,
+            FcallRef::Rversion { .. } => MsgType::Rversion,
+            FcallRef::Tflush { .. } => MsgType::Tflush,
+            FcallRef::Rflush => MsgType::Rflush,
+            FcallRef::Twalk { .. } => MsgType::Twalk,
+            FcallRef::Rwalk { .. } => MsgType::Rwalk,
+            FcallRef::Tread { .. } => MsgType::Tread,
+            FcallRef::Rread { .. } => MsgType::Rread,
+            FcallRef::Twrite { .. } => MsgType::Twrite,
+            FcallRef::Rwrite { .. } => MsgType::Rwrite,
+            FcallRef::Tclunk { .. } => MsgType::Tclunk,
+            FcallRef::Rclunk => MsgType::Rclunk,
+            FcallRef::Tremove { .. } => MsgType::Tremove,
+            FcallRef::Rremove => MsgType::Rremove,
+        }
+    }
+}
+
+impl<'a> From<FcallRef<'a>> for Fcall {
+    fn from(fcall: FcallRef<'a>) -> Fcall {
+        use crate::fcall::FcallRef as R;
+
+        match fcall {
+            R::Rlerror { ecode } => Fcall::Rlerror { ecode },
+            R::Tstatfs { fid } => Fcall::Tstatfs { fid },
+            R::Rstatfs { statfs } => Fcall::Rstatfs { statfs },
+            R::Tlopen { fid, flags } => Fcall::Tlopen { fid, flags },
+            R::Rlopen { qid, iounit } => Fcall::Rlopen { qid, iounit },
+            R::Tlcreate {
+                fid,
+                name,
+                flags,
+                mode,
+                gid,
+            } => Fcall::Tlcreate {
+                fid,
+                name: name.into(),
+                flags,
+                mode,
+                gid,
+            },
+            R::Rlcreate { qid, iounit } => Fcall::Rlcreate { qid, iounit },
+            R::Tsymlink {
+                fid,
+                name,
+                symtgt,
+                gid,
+            } => Fcall::Tsymlink {
+                fid,
+                name: name.into(),
+                symtgt: symtgt.into(),
+                gid,
+            },
+            R::Rsymlink { qid } => Fcall::Rsymlink { qid },
+            R::Tmknod {
+                dfid,
+                name,
+                mode,
+                major,
+                minor,
+                gid,
+            } => Fcall::Tmknod {
+                dfid,
+                name: name.into(),
+                mode,
+                major,
+                minor,
+                gid,
+            },
+            R::Rmknod { qid } => Fcall::Rmknod { qid },
+            R::Trename { fid, dfid, name } => Fcall::Trename {
+                fid,
+                dfid,
+                name: name.into(),
+            },
+            R::Rrename => Fcall::Rrename,
+            R::Treadlink { fid } => Fcall::Treadlink { fid },
+            R::Rreadlink { target } => Fcall::Rreadlink {
+                target: target.into(),
+            },
+            R::Tgetattr { fid, req_mask } => Fcall::Tgetattr { fid, req_mask },
+            R::Rgetattr { valid, qid, stat } => Fcall::Rgetattr { valid, qid, stat },
+            R::Tsetattr { fid, valid, stat } => Fcall::Tsetattr { fid, valid, stat },
+            R::Rsetattr => Fcall::Rsetattr,
+            R::Txattrwalk { fid, newfid, name } => Fcall::Txattrwalk {
+                fid,
+                newfid,
+                name: name.into(),
+            },
+            R::Rxattrwalk { size } => Fcall::Rxattrwalk { size },
+            R::Txattrcreate {
+                fid,
+                name,
+                attr_size,
+                flags,
+            } => Fcall::Txattrcreate {
+                fid,
+                name: name.into(),
+                attr_size,
+                flags,
+            },
+            R::Rxattrcreate => Fcall::Rxattrcreate,
+            R::Treaddir { fid, offset, count } => Fcall::Treaddir { fid, offset, count },
+            R::Rreaddir { data } => Fcall::Rreaddir {
+                data: DirEntryData::with(data.iter().map(From::from).collect()),
+            },
+            R::Tfsync { fid } => Fcall::Tfsync { fid },
+            R::Rfsync => Fcall::Rfsync,
+            R::Tlock { fid, flock } => Fcall::Tlock {
+                fid,
+                flock: flock.into(),
+            },
+            R::Rlock { status } => Fcall::Rlock { status },
+            R::Tgetlock { fid, flock } => Fcall::Tgetlock {
+                fid,
+                flock: flock.into(),
+            },
+            R::Rgetlock { flock } => Fcall::Rgetlock {
+                flock: flock.into(),
+            },
+            R::Tlink { dfid, fid, name } => Fcall::Tlink {
+                dfid,
+                fid,
+                name: name.into(),
+            },
+            R::Rlink => Fcall::Rlink,
+            R::Tmkdir {
+                dfid,
+                name,
+                mode,
+                gid,
+            } => Fcall::Tmkdir {
+                dfid,
+                name: name.into(),
+                mode,
+                gid,
+            },
+            R::Rmkdir { qid } => Fcall::Rmkdir { qid },
+            R::Trenameat {
+                olddirfid,
+                oldname,
+                newdirfid,
+                newname,
+            } => Fcall::Trenameat {
+                olddirfid,
+                oldname: oldname.into(),
+                newdirfid,
+                newname: newname.into(),
+            },
+            R::Rrenameat => Fcall::Rrenameat,
+            R::Tunlinkat { dirfd, name, flags } => Fcall::Tunlinkat {
+                dirfd,
+                name: name.into(),
+                flags,
+            },
+            R::Runlinkat => Fcall::Runlinkat,
+            R::Tauth {
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => Fcall::Tauth {
+                afid,
+                uname: uname.to_owned(),
+                aname: aname.to_owned(),
+                n_uname,
+            },
+            R::Rauth { aqid } => Fcall::Rauth { aqid },
+            R::Tattach {
+                fid,
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => Fcall::Tattach {
+                fid,
+                afid,
+                uname: uname.to_owned(),
+                aname: aname.to_owned(),
+                n_uname,
+            },
+            R::Rattach { qid } => Fcall::Rattach { qid },
+            R::Tversion { msize, version } => Fcall::Tversion {
+                msize,
+                version: version.to_owned(),
+            },
+            R::Rversion { msize, version } => Fcall::Rversion {
+                msize,
+                version: version.to_owned(),
+            },
+            R::Tflush { oldtag } => Fcall::Tflush { oldtag },
+            R::Rflush => Fcall::Rflush,
+            R::Twalk {
+                fid,
+                newfid,
+                wnames,
+            } => Fcall::Twalk {
+                fid,
+                newfid,
+                wnames: wnames.iter().map(|&n| n.into()).collect(),
+            },
+            R::Rwalk { wqids } => Fcall::Rwalk {
+                wqids: wqids.to_vec(),
+            },
+            R::Tread { fid, offset, count } => Fcall::Tread { fid, offset, count },
+            R::Rread { data } => Fcall::Rread {
+                data: Data(data.to_vec()),
+            },
+            R::Twrite { fid, offset, data } => Fcall::Twrite {
+                fid,
+                offset,
+                data: Data(data.to_vec()),
+            },
+            R::Rwrite { count } => Fcall::Rwrite { count },
+            R::Tclunk { fid } => Fcall::Tclunk { fid },
+            R::Rclunk => Fcall::Rclunk,
+            R::Tremove { fid } => Fcall::Tremove { fid },
+            R::Rremove => Fcall::Rremove,
+        }
+    }
+}
+
+/// Borrowed envelope for 9P messages, see `FcallRef`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MsgRef<'a> {
+    /// Chosen and used by the client to identify the message.
+    /// The reply to the message will have the same tag
+    pub tag: u16,
+    /// Message body borrowing from the frame it was decoded from
+    pub body: FcallRef<'a>,
+}
+
+impl<'a> From<MsgRef<'a>> for Msg {
+    fn from(msg: MsgRef<'a>) -> Msg {
+        Msg {
+            tag: msg.tag,
+            body: From::from(msg.body),
+        }
+    }
+}
+
+#[test]
+fn lopen_flags_convert_to_host() {
+    use nix::fcntl::OFlag;
+
+    // Protocol values are fixed, the host's differ between architectures
+    assert_eq!(0o200000, LOpenFlags::DIRECTORY.bits());
+    assert_eq!(OFlag::O_DIRECTORY, OFlag::from(LOpenFlags::DIRECTORY));
+
+    for acc in [LOpenFlags::RDONLY, LOpenFlags::WRONLY, LOpenFlags::RDWR] {
+        let flags = acc | LOpenFlags::CREATE | LOpenFlags::TRUNC | LOpenFlags::NOFOLLOW;
+        assert_eq!(flags, LOpenFlags::from(OFlag::from(flags)));
+    }
+    assert_eq!(
+        OFlag::O_RDWR | OFlag::O_APPEND | OFlag::O_CLOEXEC,
+        OFlag::from(LOpenFlags::RDWR | LOpenFlags::APPEND | LOpenFlags::CLOEXEC)
+    );
+    assert_eq!(
+        LOpenFlags::WRONLY,
+        (LOpenFlags::WRONLY | LOpenFlags::EXCL).access_mode()
+    );
+}
+
+#[test]
+fn file_mode_derives_types() {
+    let mode = FileMode::from_bits(0o040755);
+    assert_eq!(Some(FileType::Directory), mode.file_type());
+    assert_eq!(0o755, mode.permissions());
+    assert_eq!(QidType::DIR, mode.qid_type());
+    assert_eq!(4, mode.dirent_type()); // DT_DIR
+
+    let link = FileMode::new(FileType::Symlink, 0o777);
+    assert_eq!(0o120777, link.bits());
+    assert_eq!(QidType::SYMLINK, link.qid_type());
+    assert_eq!(10, link.dirent_type()); // DT_LNK
+
+    // Tmkdir carries permissions only
+    let perm = FileMode::from_bits(0o1777);
+    assert_eq!(None, perm.file_type());
+    assert_eq!(QidType::FILE, perm.qid_type());
+    assert_eq!(0, perm.dirent_type()); // DT_UNKNOWN
+    assert_eq!(
+        nix::sys::stat::Mode::from_bits_truncate(0o1777),
+        nix::sys::stat::Mode::from(perm)
+    );
+
+    assert!(matches!(
+        UnlinkatFlags::REMOVEDIR.into(),
+        nix::unistd::UnlinkatFlags::RemoveDir
+    ));
+    assert!(matches!(
+        UnlinkatFlags::empty().into(),
+        nix::unistd::UnlinkatFlags::NoRemoveDir
+    ));
+}
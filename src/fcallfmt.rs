@@ -0,0 +1,405 @@
+//! Human-readable formatting of 9P messages in the style of Plan 9's `fcallfmt`.
+//!
+//! ```text
+//! Twalk tag 3 fid 1 newfid 2 nwname 2 0:usr 1:lib
+//! Rread tag 4 count 12 'hello, world'
+//! ```
+
+use crate::fcall::*;
+use std::fmt;
+
+/// Number of payload bytes shown before the rest is elided
+const DUMPL: usize = 32;
+
+// Payload preview: printable text is quoted, anything else is shown in hex
+struct Dump<'a>(&'a [u8]);
+
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let head = &self.0[..self.0.len().min(DUMPL)];
+        if head
+            .iter()
+            .all(|&c| c.is_ascii_graphic() || c == b' ' || c == b'\t' || c == b'\n')
+        {
+            write!(f, "'{}'", head.escape_ascii())?;
+        } else {
+            for c in head {
+                write!(f, "{:02x}", c)?;
+            }
+        }
+        if head.len() < self.0.len() {
+            f.write_str("...")?;
+        }
+        Ok(())
+    }
+}
+
+// File names are shown as is, escaping anything that is not printable ASCII
+struct Name<'a>(&'a [u8]);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.escape_ascii())
+    }
+}
+
+// Names and payloads of owned messages and of those borrowing from the frame
+trait Bytes {
+    fn bytes(&self) -> &[u8];
+}
+
+impl Bytes for NineString {
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Bytes for Data {
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Bytes for &[u8] {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+// Entries of Rreaddir, owned or borrowing from the frame
+trait Entries {
+    fn count(&self) -> usize;
+    fn nentry(&self) -> usize;
+}
+
+impl Entries for DirEntryData {
+    fn count(&self) -> usize {
+        self.size() as usize
+    }
+
+    fn nentry(&self) -> usize {
+        self.data().len()
+    }
+}
+
+impl Entries for DirEntryDataRef<'_> {
+    fn count(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    fn nentry(&self) -> usize {
+        self.iter().count()
+    }
+}
+
+struct Timespec<'a>(&'a Time);
+
+impl fmt::Display for Timespec<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.0.sec, self.0.nsec)
+    }
+}
+
+/// Formats as `(path version type)` like Plan 9, e.g. `(0000000000000002 0 d)`
+impl fmt::Display for Qid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const TYPES: [(QidType, char); 8] = [
+            (QidType::DIR, 'd'),
+            (QidType::APPEND, 'a'),
+            (QidType::EXCL, 'l'),
+            (QidType::MOUNT, 'm'),
+            (QidType::AUTH, 'A'),
+            (QidType::TMP, 't'),
+            (QidType::SYMLINK, 'L'),
+            (QidType::LINK, 'h'),
+        ];
+
+        write!(f, "({:016x} {} ", self.path, self.version)?;
+        for (typ, c) in TYPES.iter() {
+            if self.typ.contains(*typ) {
+                write!(f, "{}", c)?;
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+// Everything after the message type and tag, for both Fcall and FcallRef
+#[rustfmt::skip]
+macro_rules! fmt_body {
+    ($typ:ident, $fcall:expr, $f:expr) => {{
+        use crate::fcall::$typ::*;
+        let f = $f;
+
+        match $fcall {
+            Rlerror { ecode } => {
+                write!(f, " ecode {} {:?}", ecode, nix::errno::Errno::from_i32(*ecode as i32))
+            }
+            Tstatfs { fid } => write!(f, " fid {}", fid),
+            Rstatfs { statfs: s } => write!(
+                f,
+                " type {:#x} bsize {} blocks {} bfree {} bavail {} files {} ffree {} fsid {:#x} namelen {}",
+                s.typ, s.bsize, s.blocks, s.bfree, s.bavail, s.files, s.ffree, s.fsid, s.namelen
+            ),
+            Tlopen { fid, flags } => write!(f, " fid {} flags {:#o}", fid, flags),
+            Rlopen { qid, iounit } => write!(f, " qid {} iounit {}", qid, iounit),
+            Tlcreate { fid, name, flags, mode, gid } => write!(
+                f,
+                " fid {} name {} flags {:#o} mode {:#o} gid {}",
+                fid, Name(name.bytes()), flags, mode, gid
+            ),
+            Rlcreate { qid, iounit } => write!(f, " qid {} iounit {}", qid, iounit),
+            Tsymlink { fid, name, symtgt, gid } => write!(
+                f,
+                " fid {} name {} symtgt {} gid {}",
+                fid, Name(name.bytes()), Name(symtgt.bytes()), gid
+            ),
+            Rsymlink { qid } => write!(f, " qid {}", qid),
+            Tmknod { dfid, name, mode, major, minor, gid } => write!(
+                f,
+                " dfid {} name {} mode {:#o} major {} minor {} gid {}",
+                dfid, Name(name.bytes()), mode, major, minor, gid
+            ),
+            Rmknod { qid } => write!(f, " qid {}", qid),
+            Trename { fid, dfid, name } => write!(f, " fid {} dfid {} name {}", fid, dfid, Name(name.bytes())),
+            Rrename => Ok(()),
+            Treadlink { fid } => write!(f, " fid {}", fid),
+            Rreadlink { target } => write!(f, " target {}", Name(target.bytes())),
+            Tgetattr { fid, req_mask } => write!(f, " fid {} req_mask {:#x}", fid, req_mask.bits()),
+            Rgetattr { valid, qid, stat: s } => write!(
+                f,
+                " valid {:#x} qid {} mode {:#o} uid {} gid {} nlink {} rdev {} size {} blksize {} blocks {} atime {} mtime {} ctime {} btime {} gen {} data_version {}",
+                valid.bits(), qid, s.mode, s.uid, s.gid, s.nlink, s.rdev, s.size, s.blksize, s.blocks,
+                Timespec(&s.atime), Timespec(&s.mtime), Timespec(&s.ctime), Timespec(&s.btime), s.gen, s.data_version
+            ),
+            Tsetattr { fid, valid, stat: s } => write!(
+                f,
+                " fid {} valid {:#x} mode {:#o} uid {} gid {} size {} atime {} mtime {}",
+                fid, valid.bits(), s.mode, s.uid, s.gid, s.size, Timespec(&s.atime), Timespec(&s.mtime)
+            ),
+            Rsetattr => Ok(()),
+            Txattrwalk { fid, newfid, name } => {
+                write!(f, " fid {} newfid {} name {}", fid, newfid, Name(name.bytes()))
+            }
+            Rxattrwalk { size } => write!(f, " size {}", size),
+            Txattrcreate { fid, name, attr_size, flags } => write!(
+                f,
+                " fid {} name {} attr_size {} flags {:#x}",
+                fid, Name(name.bytes()), attr_size, flags
+            ),
+            Rxattrcreate => Ok(()),
+            Treaddir { fid, offset, count } => {
+                write!(f, " fid {} offset {} count {}", fid, offset, count)
+            }
+            Rreaddir { data } => write!(f, " count {} nentry {}", data.count(), data.nentry()),
+            Tfsync { fid } => write!(f, " fid {}", fid),
+            Rfsync => Ok(()),
+            Tlock { fid, flock: l } => write!(
+                f,
+                " fid {} type {} flags {:#x} start {} length {} proc_id {} client_id {}",
+                fid, l.typ.bits(), l.flags.bits(), l.start, l.length, l.proc_id, l.client_id
+            ),
+            Rlock { status } => write!(f, " status {}", status.bits()),
+            Tgetlock { fid, flock: l } => write!(
+                f,
+                " fid {} type {} start {} length {} proc_id {} client_id {}",
+                fid, l.typ.bits(), l.start, l.length, l.proc_id, l.client_id
+            ),
+            Rgetlock { flock: l } => write!(
+                f,
+                " type {} start {} length {} proc_id {} client_id {}",
+                l.typ.bits(), l.start, l.length, l.proc_id, l.client_id
+            ),
+            Tlink { dfid, fid, name } => write!(f, " dfid {} fid {} name {}", dfid, fid, Name(name.bytes())),
+            Rlink => Ok(()),
+            Tmkdir { dfid, name, mode, gid } => write!(
+                f,
+                " dfid {} name {} mode {:#o} gid {}",
+                dfid, Name(name.bytes()), mode, gid
+            ),
+            Rmkdir { qid } => write!(f, " qid {}", qid),
+            Trenameat { olddirfid, oldname, newdirfid, newname } => write!(
+                f,
+                " olddirfid {} oldname {} newdirfid {} newname {}",
+                olddirfid, Name(oldname.bytes()), newdirfid, Name(newname.bytes())
+            ),
+            Rrenameat => Ok(()),
+            Tunlinkat { dirfd, name, flags } => {
+                write!(f, " dirfd {} name {} flags {:#x}", dirfd, Name(name.bytes()), flags)
+            }
+            Runlinkat => Ok(()),
+
+            Tauth { afid, uname, aname, n_uname } => write!(
+                f,
+                " afid {} uname {} aname {} n_uname {}",
+                afid, uname, aname, n_uname
+            ),
+            Rauth { aqid } => write!(f, " aqid {}", aqid),
+            Tattach { fid, afid, uname, aname, n_uname } => write!(
+                f,
+                " fid {} afid {} uname {} aname {} n_uname {}",
+                fid, afid, uname, aname, n_uname
+            ),
+            Rattach { qid } => write!(f, " qid {}", qid),
+
+            Tversion { msize, version } => write!(f, " msize {} version '{}'", msize, version),
+            Rversion { msize, version } => write!(f, " msize {} version '{}'", msize, version),
+            Tflush { oldtag } => write!(f, " oldtag {}", oldtag),
+            Rflush => Ok(()),
+            Twalk { fid, newfid, wnames } => {
+                write!(f, " fid {} newfid {} nwname {}", fid, newfid, wnames.len())?;
+                for (i, name) in wnames.iter().enumerate() {
+                    write!(f, " {}:{}", i, Name(name.bytes()))?;
+                }
+                Ok(())
+            }
+            Rwalk { wqids } => {
+                write!(f, " nwqid {}", wqids.len())?;
+                for (i, qid) in wqids.iter().enumerate() {
+                    write!(f, " {}:{}", i, qid)?;
+                }
+                Ok(())
+            }
+            Tread { fid, offset, count } => write!(f, " fid {} offset {} count {}", fid, offset, count),
+            Rread { data } => write!(f, " count {} {}", data.bytes().len(), Dump(data.bytes())),
+            Twrite { fid, offset, data } => write!(
+                f,
+                " fid {} offset {} count {} {}",
+                fid, offset, data.bytes().len(), Dump(data.bytes())
+            ),
+            Rwrite { count } => write!(f, " count {}", count),
+            Tclunk { fid } => write!(f, " fid {}", fid),
+            Rclunk => Ok(()),
+            Tremove { fid } => write!(f, " fid {}", fid),
+            Rremove => Ok(()),
+        }
+    }};
+}
+
+fn fmt_body(fcall: &Fcall, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt_body!(Fcall, fcall, f)
+}
+
+fn fmt_body_ref(fcall: &FcallRef, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt_body!(FcallRef, fcall, f)
+}
+
+/// Formats like Plan 9's `fcallfmt` without the tag, e.g. `Tclunk fid 1`
+impl fmt::Display for Fcall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", MsgType::from(self))?;
+        fmt_body(self, f)
+    }
+}
+
+/// Formats like Plan 9's `fcallfmt`, e.g. `Tclunk tag 3 fid 1`
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} tag {}", MsgType::from(&self.body), self.tag)?;
+        fmt_body(&self.body, f)
+    }
+}
+
+/// Formats like `Fcall`, without copying names or payloads
+impl fmt::Display for FcallRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", MsgType::from(self))?;
+        fmt_body_ref(self, f)
+    }
+}
+
+/// Formats like `Msg`, without copying names or payloads
+impl fmt::Display for MsgRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} tag {}", MsgType::from(&self.body), self.tag)?;
+        fmt_body_ref(&self.body, f)
+    }
+}
+
+#[test]
+fn fcallfmt_walk_and_payloads() {
+    let walk = Msg {
+        tag: 3,
+        body: Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".into(), "lib".into()],
+        },
+    };
+    assert_eq!(
+        walk.to_string(),
+        "Twalk tag 3 fid 1 newfid 2 nwname 2 0:usr 1:lib"
+    );
+
+    let read = Msg {
+        tag: 4,
+        body: Fcall::Rread {
+            data: Data(b"hello, world".to_vec()),
+        },
+    };
+    assert_eq!(read.to_string(), "Rread tag 4 count 12 'hello, world'");
+
+    let write = Fcall::Twrite {
+        fid: 1,
+        offset: 0,
+        data: Data(vec![0xff; 100]),
+    };
+    assert_eq!(
+        write.to_string(),
+        format!("Twrite fid 1 offset 0 count 100 {}...", "ff".repeat(DUMPL))
+    );
+
+    let rwalk = Fcall::Rwalk {
+        wqids: vec![Qid {
+            typ: QidType::DIR,
+            version: 0,
+            path: 2,
+        }],
+    };
+    assert_eq!(rwalk.to_string(), "Rwalk nwqid 1 0:(0000000000000002 0 d)");
+}
+
+#[test]
+fn fcallfmt_borrowed_like_owned() {
+    use crate::serialize::{decode_msg_ref, Encodable};
+
+    let msgs = [
+        Fcall::Twrite {
+            fid: 1,
+            offset: 8,
+            data: Data(b"hello".to_vec()),
+        },
+        Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".into(), "caf\u{e9}".into()],
+        },
+        Fcall::Rreaddir {
+            data: DirEntryData::with(vec![DirEntry {
+                qid: Qid::default(),
+                offset: 1,
+                typ: 0,
+                name: "file".into(),
+            }]),
+        },
+        Fcall::Tlock {
+            fid: 1,
+            flock: Flock {
+                typ: LockType::WRLOCK,
+                flags: LockFlag::BLOCK,
+                start: 0,
+                length: 10,
+                proc_id: 42,
+                client_id: "host".to_owned(),
+            },
+        },
+    ];
+    for body in msgs {
+        let msg = Msg { tag: 7, body };
+        let mut frame = Vec::new();
+        msg.encode(&mut frame).unwrap();
+        let borrowed = decode_msg_ref(&frame).unwrap();
+        assert_eq!(borrowed.to_string(), msg.to_string());
+        assert_eq!(borrowed.body.to_string(), msg.body.to_string());
+    }
+}
@@ -16,8 +16,12 @@ extern crate log;
 
 #[macro_use]
 mod utils;
+pub mod client;
 pub mod error;
 pub mod fcall;
+mod fcallfmt;
+#[cfg(feature = "serde")]
+mod serde_impl;
 pub mod serialize;
 pub mod srv;
 
@@ -0,0 +1,201 @@
+//! Serde support for 9P protocol types, enabled with the `serde` feature.
+//!
+//! Representations are chosen to be stable and readable when archived as JSON:
+//! bitflags are lists of flag names, qid paths are hex strings, modes are octal
+//! strings, payloads are base64 and names are strings unless they are not
+//! valid UTF-8.
+
+use crate::fcall::*;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Serialize a u64 as a `0x`-prefixed hex string, used for `Qid.path`
+pub(crate) mod hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:#x}", v))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        let s = String::deserialize(d)?;
+        let digits = s
+            .strip_prefix("0x")
+            .ok_or_else(|| de::Error::custom("expected a 0x-prefixed hex string"))?;
+        u64::from_str_radix(digits, 16).map_err(de::Error::custom)
+    }
+}
+
+/// Serialize a u32 as a `0o`-prefixed octal string, used for `FileMode`
+pub(crate) mod octal {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:#o}", v))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        let s = String::deserialize(d)?;
+        let digits = s
+            .strip_prefix("0o")
+            .ok_or_else(|| de::Error::custom("expected a 0o-prefixed octal string"))?;
+        u32::from_str_radix(digits, 8).map_err(de::Error::custom)
+    }
+}
+
+// Bitflags are written as the list of their flag names, taken from the
+// bitflags definitions. A value equal to a single constant uses that name,
+// so zero-valued constants such as `QidType::FILE` and combined masks such
+// as `GetattrMask::ALL` keep their names. Otherwise the widest constants
+// contained in the value are named first, e.g. `NOACCESS` over `WRONLY`
+// and `RDWR`.
+macro_rules! serde_flags {
+    ($($typ:ident),* $(,)?) => {$(
+        impl Serialize for $typ {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                if let Some((name, _)) = Self::NAMES.iter().find(|(_, f)| f == self) {
+                    return [name].serialize(s);
+                }
+
+                let mut rest = *self;
+                let mut names = Vec::new();
+                while let Some((name, f)) = Self::NAMES
+                    .iter()
+                    .filter(|(_, f)| !f.is_empty() && rest.contains(*f))
+                    .min_by_key(|(_, f)| std::cmp::Reverse(f.bits().count_ones()))
+                {
+                    names.push(*name);
+                    rest.remove(*f);
+                }
+                names.serialize(s)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $typ {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                let names = <Vec<String>>::deserialize(d)?;
+                names.iter().try_fold($typ::empty(), |acc, name| {
+                    Self::NAMES
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, f)| acc | *f)
+                        .ok_or_else(|| {
+                            de::Error::custom(format!(
+                                "unknown {} flag: {}",
+                                stringify!($typ),
+                                name
+                            ))
+                        })
+                })
+            }
+        }
+    )*};
+}
+
+serde_flags!(
+    LockType,
+    LockFlag,
+    LockStatus,
+    QidType,
+    GetattrMask,
+    SetattrMask,
+    LOpenFlags,
+    UnlinkatFlags,
+    XattrFlags,
+);
+
+impl Serialize for Data {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&BASE64.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        BASE64
+            .decode(s.as_bytes())
+            .map(Data)
+            .map_err(de::Error::custom)
+    }
+}
+
+// Names are plain strings when they are valid UTF-8,
+// otherwise `{"base64": "..."}` so that any byte string round-trips.
+// Formats which are not human-readable, such as bincode, take the raw bytes.
+impl Serialize for NineString {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if !s.is_human_readable() {
+            return s.serialize_bytes(self.as_bytes());
+        }
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(name) => s.serialize_str(name),
+            Err(_) => {
+                let mut map = s.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &BASE64.encode(self.as_bytes()))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NineString {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct NineStringVisitor;
+
+        impl<'de> Visitor<'de> for NineStringVisitor {
+            type Value = NineString;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or a map with a base64 entry")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<NineString, E> {
+                Ok(v.into())
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<NineString, E> {
+                Ok(v.into())
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<NineString, A::Error> {
+                let mut bytes = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    if key != "base64" || bytes.is_some() {
+                        return Err(de::Error::custom(format!("unexpected key: {}", key)));
+                    }
+                    let s = map.next_value::<String>()?;
+                    bytes = Some(BASE64.decode(s.as_bytes()).map_err(de::Error::custom)?);
+                }
+                bytes
+                    .map(NineString::from)
+                    .ok_or_else(|| de::Error::missing_field("base64"))
+            }
+        }
+
+        // Only self-describing formats tell a string from a map
+        if d.is_human_readable() {
+            d.deserialize_any(NineStringVisitor)
+        } else {
+            d.deserialize_bytes(NineStringVisitor)
+        }
+    }
+}
+
+// Borrowed messages are serialized through their owned form
+// so that both produce the same representation.
+impl Serialize for FcallRef<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        Fcall::from(*self).serialize(s)
+    }
+}
+
+impl Serialize for MsgRef<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        Msg::from(*self).serialize(s)
+    }
+}
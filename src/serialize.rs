@@ -2,10 +2,11 @@
 
 use crate::fcall::*;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BufMut;
 use num_traits::FromPrimitive;
-use std::io::{Read, Result};
-use std::mem;
-use std::ops::{Shl, Shr};
+use std::convert::TryFrom;
+use std::io::{Cursor, Read, Result};
+use std::{fmt, io, mem};
 
 macro_rules! decode {
     ($decoder:expr) => {
@@ -17,32 +18,157 @@ macro_rules! decode {
     };
 }
 
-// Create an unintialized buffer
-// Safe to use only for writing data to it
-fn create_buffer(size: usize) -> Vec<u8> {
-    let mut buffer = Vec::with_capacity(size);
-    unsafe {
-        buffer.set_len(size);
+// Pass each field to a FieldSink, returning early on its error
+macro_rules! fields {
+    ($sink:expr; $($e:expr),*) => {{
+        $($sink.field($e)?;)*
+    }};
+}
+
+/// Reasons a 9P message could not be decoded
+///
+/// Decoders report these wrapped in an `io::Error` of kind `InvalidData`,
+/// so they can be inspected with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message type is unknown or must never appear on the wire
+    InvalidMsgType(u8),
+    /// The frame ended in the middle of a fixed-size field
+    Truncated,
+    /// A length prefix claims more bytes than remain in the frame
+    LengthOverrun(usize),
+    /// An element count exceeds the limit imposed by the protocol
+    TooManyElements { count: usize, max: usize },
+    /// A string field is not valid UTF-8
+    InvalidUtf8,
+    /// Bytes are left over after the message body
+    TrailingBytes(usize),
+}
+
+impl DecodeError {
+    /// Get an errno representation.
+    pub fn errno(&self) -> nix::errno::Errno {
+        match *self {
+            DecodeError::TooManyElements { .. } => nix::errno::Errno::E2BIG,
+            _ => nix::errno::Errno::EINVAL,
+        }
     }
-    buffer
 }
 
-fn read_exact<R: Read + ?Sized>(r: &mut R, size: usize) -> Result<Vec<u8>> {
-    let mut buf = create_buffer(size);
-    r.read_exact(&mut buf[..]).and(Ok(buf))
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::InvalidMsgType(t) => write!(f, "Invalid message type: {}", t),
+            DecodeError::Truncated => write!(f, "Message truncated"),
+            DecodeError::LengthOverrun(len) => {
+                write!(f, "Length {} exceeds the remaining frame", len)
+            }
+            DecodeError::TooManyElements { count, max } => {
+                write!(f, "Too many elements: {} (max {})", count, max)
+            }
+            DecodeError::InvalidUtf8 => write!(f, "Invalid UTF-8 sequence"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} trailing bytes after message", n),
+        }
+    }
 }
 
-/// A serializing specific result to overload operators on `Result`
-///
-/// # Overloaded operators
-/// <<, >>, ?
-pub struct SResult<T>(::std::io::Result<T>);
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
 
-/// A wrapper class of WriteBytesExt to provide operator overloads
-/// for serializing
+/// Reasons a 9P message could not be encoded
 ///
-/// Operator '<<' serializes the right hand side argument into
-/// the left hand side encoder
+/// Each variant names a field whose length does not fit in its length prefix
+/// on the wire, or a message which does not fit in the negotiated msize.
+/// Encoders report these wrapped in an `io::Error` of kind `InvalidInput`,
+/// and `Error::errno` maps them to the errno for `Rlerror`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// A string is longer than its u16 length prefix allows
+    StringTooLong(usize),
+    /// An array has more elements than its u16 count allows
+    TooManyElements(usize),
+    /// A data payload is longer than its u32 count allows
+    DataTooLong(usize),
+    /// A whole message, including size[4], is longer than msize
+    MessageTooLarge { len: usize, msize: u32 },
+}
+
+impl EncodeError {
+    /// Get an errno representation.
+    pub fn errno(&self) -> nix::errno::Errno {
+        match *self {
+            EncodeError::StringTooLong(_) => nix::errno::Errno::ENAMETOOLONG,
+            EncodeError::TooManyElements(_) | EncodeError::DataTooLong(_) => {
+                nix::errno::Errno::E2BIG
+            }
+            EncodeError::MessageTooLarge { .. } => nix::errno::Errno::EMSGSIZE,
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::StringTooLong(len) => write!(f, "String too long: {} bytes", len),
+            EncodeError::TooManyElements(n) => write!(f, "Too many elements: {}", n),
+            EncodeError::DataTooLong(len) => write!(f, "Data too long: {} bytes", len),
+            EncodeError::MessageTooLarge { len, msize } => {
+                write!(f, "Message too large: {} bytes, msize {}", len, msize)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<EncodeError> for io::Error {
+    fn from(e: EncodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+// Read a length-prefixed payload without trusting the length for allocation.
+// The buffer only grows as far as the reader actually has data, so a bogus
+// length can never allocate more than what remains in the frame.
+fn read_exact<R: Read + ?Sized>(r: &mut R, size: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(size as u64).read_to_end(&mut buf)?;
+    if buf.len() != size {
+        return res!(DecodeError::LengthOverrun(size));
+    }
+    Ok(buf)
+}
+
+// Report running out of input as a typed error rather than a bare EOF
+fn truncated(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::Truncated.into(),
+        _ => e,
+    }
+}
+
+// Decode a u16-counted array whose count is bounded by the protocol
+fn decode_bounded<T: Decodable, R: ReadBytesExt>(r: &mut R, max: usize) -> Result<Vec<T>> {
+    let count: u16 = Decodable::decode(r)?;
+    if count as usize > max {
+        return res!(DecodeError::TooManyElements {
+            count: count as usize,
+            max,
+        });
+    }
+    let mut buf = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        buf.push(Decodable::decode(r)?);
+    }
+    Ok(buf)
+}
+
+/// A wrapper class of WriteBytesExt which counts the bytes it serializes
 #[derive(Clone, Debug)]
 pub struct Encoder<W> {
     writer: W,
@@ -59,7 +185,7 @@ impl<W: WriteBytesExt> Encoder<W> {
         self.bytes
     }
 
-    /// Encode data, equivalent to: decoder << data
+    /// Encode data into the inner writer
     pub fn encode<T: Encodable>(&mut self, data: &T) -> Result<usize> {
         let bytes = data.encode(&mut self.writer)?;
         self.bytes += bytes;
@@ -72,31 +198,29 @@ impl<W: WriteBytesExt> Encoder<W> {
     }
 }
 
-impl<'a, T: Encodable, W: WriteBytesExt> Shl<&'a T> for Encoder<W> {
-    type Output = SResult<Encoder<W>>;
-    fn shl(mut self, rhs: &'a T) -> Self::Output {
-        match self.encode(rhs) {
-            Ok(_) => SResult(Ok(self)),
-            Err(e) => SResult(Err(e)),
-        }
+/// A counterpart of Encoder which only sums up encoded lengths
+#[derive(Clone, Copy, Debug, Default)]
+struct Sizer(usize);
+
+impl Sizer {
+    fn new() -> Sizer {
+        Sizer(0)
     }
-}
 
-impl<'a, T: Encodable, W: WriteBytesExt> Shl<&'a T> for SResult<Encoder<W>> {
-    type Output = Self;
-    fn shl(self, rhs: &'a T) -> Self::Output {
-        match self.0 {
-            Ok(mut encoder) => match encoder.encode(rhs) {
-                Ok(_) => SResult(Ok(encoder)),
-                Err(e) => SResult(Err(e)),
-            },
-            Err(e) => SResult(Err(e)),
-        }
+    /// Return total bytes counted
+    fn len(&self) -> usize {
+        self.0
+    }
+
+    /// Add the encoded length of data without serializing anything
+    fn count<T: Encodable>(&mut self, data: &T) -> usize {
+        let bytes = data.encoded_len();
+        self.0 += bytes;
+        bytes
     }
 }
 
-/// A wrapper class of ReadBytesExt to provide operator overloads
-/// for deserializing
+/// A wrapper class of ReadBytesExt for deserializing
 #[derive(Clone, Debug)]
 pub struct Decoder<R> {
     reader: R,
@@ -115,45 +239,37 @@ impl<R: ReadBytesExt> Decoder<R> {
     }
 }
 
-impl<'a, T: Decodable, R: ReadBytesExt> Shr<&'a mut T> for Decoder<R> {
-    type Output = SResult<Decoder<R>>;
-    fn shr(mut self, rhs: &'a mut T) -> Self::Output {
-        match self.decode() {
-            Ok(r) => {
-                *rhs = r;
-                SResult(Ok(self))
-            }
-            Err(e) => SResult(Err(e)),
-        }
-    }
-}
-
-impl<'a, T: Decodable, R: ReadBytesExt> Shr<&'a mut T> for SResult<Decoder<R>> {
-    type Output = Self;
-    fn shr(self, rhs: &'a mut T) -> Self::Output {
-        match self.0 {
-            Ok(mut decoder) => match decoder.decode() {
-                Ok(r) => {
-                    *rhs = r;
-                    SResult(Ok(decoder))
-                }
-                Err(e) => SResult(Err(e)),
-            },
-            Err(e) => SResult(Err(e)),
-        }
-    }
-}
-
 /// Trait representing a type which can be serialized into binary
 pub trait Encodable {
     /// Encode self to w and returns the number of bytes encoded
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize>;
+
+    /// Number of bytes `encode` would write, without encoding anything
+    ///
+    /// For `Msg` this excludes the leading `size[4]` field.
+    fn encoded_len(&self) -> usize;
+
+    /// Encode self into buf
+    ///
+    /// Writes into `BufMut` cannot fail, only fields which do not fit in
+    /// their length prefix are reported. Reserve `encoded_len` bytes first
+    /// to avoid reallocation.
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError>;
 }
 
 impl Encodable for u8 {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
         w.write_u8(*self).and(Ok(mem::size_of::<Self>()))
     }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        buf.put_u8(*self);
+        Ok(())
+    }
 }
 
 impl Encodable for u16 {
@@ -161,6 +277,15 @@ impl Encodable for u16 {
         w.write_u16::<LittleEndian>(*self)
             .and(Ok(mem::size_of::<Self>()))
     }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        buf.put_u16_le(*self);
+        Ok(())
+    }
 }
 
 impl Encodable for u32 {
@@ -168,6 +293,15 @@ impl Encodable for u32 {
         w.write_u32::<LittleEndian>(*self)
             .and(Ok(mem::size_of::<Self>()))
     }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        buf.put_u32_le(*self);
+        Ok(())
+    }
 }
 
 impl Encodable for u64 {
@@ -175,199 +309,388 @@ impl Encodable for u64 {
         w.write_u64::<LittleEndian>(*self)
             .and(Ok(mem::size_of::<Self>()))
     }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        buf.put_u64_le(*self);
+        Ok(())
+    }
 }
 
 impl Encodable for String {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        let mut bytes = (self.len() as u16).encode(w)?;
+        let len = u16::try_from(self.len()).map_err(|_| EncodeError::StringTooLong(self.len()))?;
+        let mut bytes = len.encode(w)?;
         bytes += w.write_all(self.as_bytes()).and(Ok(self.len()))?;
         Ok(bytes)
     }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u16>() + self.len()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        let len = u16::try_from(self.len()).map_err(|_| EncodeError::StringTooLong(self.len()))?;
+        buf.put_u16_le(len);
+        buf.put_slice(self.as_bytes());
+        Ok(())
+    }
 }
 
-impl Encodable for Qid {
+impl Encodable for NineString {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w) << &self.typ.bits() << &self.version << &self.path {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+        let bytes = self.as_bytes();
+        let len =
+            u16::try_from(bytes.len()).map_err(|_| EncodeError::StringTooLong(bytes.len()))?;
+        let mut n = len.encode(w)?;
+        n += w.write_all(bytes).and(Ok(bytes.len()))?;
+        Ok(n)
+    }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u16>() + self.as_bytes().len()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        let bytes = self.as_bytes();
+        let len =
+            u16::try_from(bytes.len()).map_err(|_| EncodeError::StringTooLong(bytes.len()))?;
+        buf.put_u16_le(len);
+        buf.put_slice(bytes);
+        Ok(())
     }
 }
 
-impl Encodable for Statfs {
+impl Encodable for FileMode {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w)
-            << &self.typ
-            << &self.bsize
-            << &self.blocks
-            << &self.bfree
-            << &self.bavail
-            << &self.files
-            << &self.ffree
-            << &self.fsid
-            << &self.namelen
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+        self.bits().encode(w)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.bits().encoded_len()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        self.bits().encode_buf(buf)
     }
 }
 
-impl Encodable for Time {
+impl Encodable for Data {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w) << &self.sec << &self.nsec {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+        let size = self.0.len();
+        let len = u32::try_from(size).map_err(|_| EncodeError::DataTooLong(size))?;
+        let bytes = len.encode(w)? + size;
+        w.write_all(&self.0)?;
+        Ok(bytes)
+    }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u32>() + self.0.len()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        let size = self.0.len();
+        let len = u32::try_from(size).map_err(|_| EncodeError::DataTooLong(size))?;
+        buf.put_u32_le(len);
+        buf.put_slice(&self.0);
+        Ok(())
     }
 }
 
-impl Encodable for Stat {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w)
-            << &self.mode
-            << &self.uid
-            << &self.gid
-            << &self.nlink
-            << &self.rdev
-            << &self.size
-            << &self.blksize
-            << &self.blocks
-            << &self.atime
-            << &self.mtime
-            << &self.ctime
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+// Receives the fields of a message or of a structure in it, in wire order
+trait FieldSink {
+    type Error;
+    fn field<T: Encodable>(&mut self, data: &T) -> std::result::Result<(), Self::Error>;
+}
+
+impl<W: WriteBytesExt> FieldSink for Encoder<W> {
+    type Error = io::Error;
+    fn field<T: Encodable>(&mut self, data: &T) -> Result<()> {
+        self.encode(data).map(|_| ())
     }
 }
 
-impl Encodable for SetAttr {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w)
-            << &self.mode
-            << &self.uid
-            << &self.gid
-            << &self.size
-            << &self.atime
-            << &self.mtime
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+impl FieldSink for Sizer {
+    type Error = std::convert::Infallible;
+    fn field<T: Encodable>(&mut self, data: &T) -> std::result::Result<(), Self::Error> {
+        self.count(data);
+        Ok(())
     }
 }
 
-impl Encodable for DirEntry {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w) << &self.qid << &self.offset << &self.typ << &self.name {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+// Encodes fields into a BufMut
+struct BufSink<'a, B>(&'a mut B);
+
+impl<B: BufMut> FieldSink for BufSink<'_, B> {
+    type Error = EncodeError;
+    fn field<T: Encodable>(&mut self, data: &T) -> std::result::Result<(), EncodeError> {
+        data.encode_buf(self.0)
     }
 }
 
-impl Encodable for DirEntryData {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match self
-            .data()
-            .iter()
-            .fold(Encoder::new(w) << &self.size(), |acc, e| acc << e)
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+// A type whose wire layout is a list of fields. The list is the only description
+// of the layout, shared by encode, encoded_len and encode_buf.
+trait Fields {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error>;
+}
+
+fn encode_fields<T: Fields, W: WriteBytesExt>(data: &T, w: &mut W) -> Result<usize> {
+    let mut encoder = Encoder::new(w);
+    data.fields(&mut encoder)?;
+    Ok(encoder.bytes_written())
+}
+
+fn fields_len<T: Fields>(data: &T) -> usize {
+    let mut sizer = Sizer::new();
+    match data.fields(&mut sizer) {
+        Ok(()) => sizer.len(),
+        Err(never) => match never {},
     }
 }
 
-impl Encodable for Data {
+fn encode_fields_buf<T: Fields, B: BufMut>(
+    data: &T,
+    buf: &mut B,
+) -> std::result::Result<(), EncodeError> {
+    data.fields(&mut BufSink(buf))
+}
+
+// Implement Encodable for types implementing Fields
+macro_rules! encodable_fields {
+    ($($typ:ty),*) => {$(
+        impl Encodable for $typ {
+            fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
+                encode_fields(self, w)
+            }
+
+            fn encoded_len(&self) -> usize {
+                fields_len(self)
+            }
+
+            fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+                encode_fields_buf(self, buf)
+            }
+        }
+    )*};
+}
+
+// The u32 size of the data which follows, which must fit in it
+struct DataSize(usize);
+
+impl Encodable for DataSize {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        let size = self.0.len();
-        let bytes = (size as u32).encode(w)? + size;
-        w.write_all(&self.0)?;
-        Ok(bytes)
+        let size = u32::try_from(self.0).map_err(|_| EncodeError::DataTooLong(self.0))?;
+        size.encode(w)
+    }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u32>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        let size = u32::try_from(self.0).map_err(|_| EncodeError::DataTooLong(self.0))?;
+        size.encode_buf(buf)
     }
 }
 
-impl Encodable for Flock {
+// The u16 count of the elements which follow, which must fit in it
+struct Count(usize);
+
+impl Encodable for Count {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w)
-            << &self.typ.bits()
-            << &self.flags.bits()
-            << &self.start
-            << &self.length
-            << &self.proc_id
-            << &self.client_id
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
+        let count = u16::try_from(self.0).map_err(|_| EncodeError::TooManyElements(self.0))?;
+        count.encode(w)
+    }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u16>()
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        let count = u16::try_from(self.0).map_err(|_| EncodeError::TooManyElements(self.0))?;
+        count.encode_buf(buf)
+    }
+}
+
+impl Fields for Qid {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(sink; &self.typ.bits(), &self.version, &self.path);
+        Ok(())
+    }
+}
+
+impl Fields for Statfs {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(
+            sink;
+            &self.typ,
+            &self.bsize,
+            &self.blocks,
+            &self.bfree,
+            &self.bavail,
+            &self.files,
+            &self.ffree,
+            &self.fsid,
+            &self.namelen
+        );
+        Ok(())
+    }
+}
+
+impl Fields for Time {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(sink; &self.sec, &self.nsec);
+        Ok(())
+    }
+}
+
+impl Fields for Stat {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(
+            sink;
+            &self.mode,
+            &self.uid,
+            &self.gid,
+            &self.nlink,
+            &self.rdev,
+            &self.size,
+            &self.blksize,
+            &self.blocks,
+            &self.atime,
+            &self.mtime,
+            &self.ctime,
+            &self.btime,
+            &self.gen,
+            &self.data_version
+        );
+        Ok(())
+    }
+}
+
+impl Fields for SetAttr {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(
+            sink;
+            &self.mode,
+            &self.uid,
+            &self.gid,
+            &self.size,
+            &self.atime,
+            &self.mtime
+        );
+        Ok(())
+    }
+}
+
+impl Fields for DirEntry {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(sink; &self.qid, &self.offset, &self.typ, &self.name);
+        Ok(())
+    }
+}
+
+impl Fields for DirEntryData {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        let size = self.data().iter().map(|e| e.size() as usize).sum();
+        sink.field(&DataSize(size))?;
+        for e in self.data() {
+            sink.field(e)?;
         }
+        Ok(())
     }
 }
 
-impl Encodable for Getlock {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match Encoder::new(w)
-            << &self.typ.bits()
-            << &self.start
-            << &self.length
-            << &self.proc_id
-            << &self.client_id
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
+impl Fields for Flock {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(
+            sink;
+            &self.typ.bits(),
+            &self.flags.bits(),
+            &self.start,
+            &self.length,
+            &self.proc_id,
+            &self.client_id
+        );
+        Ok(())
+    }
+}
+
+impl Fields for Getlock {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        fields!(
+            sink;
+            &self.typ.bits(),
+            &self.start,
+            &self.length,
+            &self.proc_id,
+            &self.client_id
+        );
+        Ok(())
+    }
+}
+
+impl<T: Encodable> Fields for Vec<T> {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
+        sink.field(&Count(self.len()))?;
+        for e in self {
+            sink.field(e)?;
         }
+        Ok(())
     }
 }
 
 impl<T: Encodable> Encodable for Vec<T> {
     fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
-        match self
-            .iter()
-            .fold(Encoder::new(w) << &(self.len() as u16), |acc, s| acc << s)
-        {
-            SResult(Ok(enc)) => Ok(enc.bytes_written()),
-            SResult(Err(e)) => Err(e),
-        }
+        encode_fields(self, w)
+    }
+
+    fn encoded_len(&self) -> usize {
+        fields_len(self)
+    }
+
+    fn encode_buf<B: BufMut>(&self, buf: &mut B) -> std::result::Result<(), EncodeError> {
+        encode_fields_buf(self, buf)
     }
 }
 
-impl Encodable for Msg {
-    fn encode<W: WriteBytesExt>(&self, w: &mut W) -> Result<usize> {
+impl Fields for Msg {
+    fn fields<S: FieldSink>(&self, sink: &mut S) -> std::result::Result<(), S::Error> {
         use crate::Fcall::*;
 
         let typ = MsgType::from(&self.body);
-        let buf = Encoder::new(w) << &(typ as u8) << &self.tag;
+        fields!(sink; &(typ as u8), &self.tag);
 
-        let buf = match self.body {
+        match self.body {
             // 9P2000.L
-            Rlerror { ref ecode } => buf << ecode,
-            Tstatfs { ref fid } => buf << fid,
-            Rstatfs { ref statfs } => buf << statfs,
-            Tlopen { ref fid, ref flags } => buf << fid << flags,
+            Rlerror { ref ecode } => fields!(sink; ecode),
+            Tstatfs { ref fid } => fields!(sink; fid),
+            Rstatfs { ref statfs } => fields!(sink; statfs),
+            Tlopen { ref fid, ref flags } => fields!(sink; fid, &flags.bits()),
             Rlopen {
                 ref qid,
                 ref iounit,
-            } => buf << qid << iounit,
+            } => fields!(sink; qid, iounit),
             Tlcreate {
                 ref fid,
                 ref name,
                 ref flags,
                 ref mode,
                 ref gid,
-            } => buf << fid << name << flags << mode << gid,
+            } => fields!(sink; fid, name, &flags.bits(), mode, gid),
             Rlcreate {
                 ref qid,
                 ref iounit,
-            } => buf << qid << iounit,
+            } => fields!(sink; qid, iounit),
             Tsymlink {
                 ref fid,
                 ref name,
                 ref symtgt,
                 ref gid,
-            } => buf << fid << name << symtgt << gid,
-            Rsymlink { ref qid } => buf << qid,
+            } => fields!(sink; fid, name, symtgt, gid),
+            Rsymlink { ref qid } => fields!(sink; qid),
             Tmknod {
                 ref dfid,
                 ref name,
@@ -375,82 +698,82 @@ impl Encodable for Msg {
                 ref major,
                 ref minor,
                 ref gid,
-            } => buf << dfid << name << mode << major << minor << gid,
-            Rmknod { ref qid } => buf << qid,
+            } => fields!(sink; dfid, name, mode, major, minor, gid),
+            Rmknod { ref qid } => fields!(sink; qid),
             Trename {
                 ref fid,
                 ref dfid,
                 ref name,
-            } => buf << fid << dfid << name,
-            Rrename => buf,
-            Treadlink { ref fid } => buf << fid,
-            Rreadlink { ref target } => buf << target,
+            } => fields!(sink; fid, dfid, name),
+            Rrename => {}
+            Treadlink { ref fid } => fields!(sink; fid),
+            Rreadlink { ref target } => fields!(sink; target),
             Tgetattr {
                 ref fid,
                 ref req_mask,
-            } => buf << fid << &req_mask.bits(),
+            } => fields!(sink; fid, &req_mask.bits()),
             Rgetattr {
                 ref valid,
                 ref qid,
                 ref stat,
-            } => buf << &valid.bits() << qid << stat << &0u64 << &0u64 << &0u64 << &0u64,
+            } => fields!(sink; &valid.bits(), qid, stat),
             Tsetattr {
                 ref fid,
                 ref valid,
                 ref stat,
-            } => buf << fid << &valid.bits() << stat,
-            Rsetattr => buf,
+            } => fields!(sink; fid, &valid.bits(), stat),
+            Rsetattr => {}
             Txattrwalk {
                 ref fid,
                 ref newfid,
                 ref name,
-            } => buf << fid << newfid << name,
-            Rxattrwalk { ref size } => buf << size,
+            } => fields!(sink; fid, newfid, name),
+            Rxattrwalk { ref size } => fields!(sink; size),
             Txattrcreate {
                 ref fid,
                 ref name,
                 ref attr_size,
                 ref flags,
-            } => buf << fid << name << attr_size << flags,
-            Rxattrcreate => buf,
+            } => fields!(sink; fid, name, attr_size, &flags.bits()),
+            Rxattrcreate => {}
             Treaddir {
                 ref fid,
                 ref offset,
                 ref count,
-            } => buf << fid << offset << count,
-            Rreaddir { ref data } => buf << data,
-            Tfsync { ref fid } => buf << fid,
-            Rfsync => buf,
-            Tlock { ref fid, ref flock } => buf << fid << flock,
-            Rlock { ref status } => buf << &status.bits(),
-            Tgetlock { ref fid, ref flock } => buf << fid << flock,
-            Rgetlock { ref flock } => buf << flock,
+            } => fields!(sink; fid, offset, count),
+            Rreaddir { ref data } => fields!(sink; data),
+            Tfsync { ref fid } => fields!(sink; fid),
+            Rfsync => {}
+            Tlock { ref fid, ref flock } => fields!(sink; fid, flock),
+            Rlock { ref status } => fields!(sink; &status.bits()),
+            Tgetlock { ref fid, ref flock } => fields!(sink; fid, flock),
+            Rgetlock { ref flock } => fields!(sink; flock),
             Tlink {
                 ref dfid,
                 ref fid,
                 ref name,
-            } => buf << dfid << fid << name,
-            Rlink => buf,
+            } => fields!(sink; dfid, fid, name),
+            Rlink => {}
             Tmkdir {
                 ref dfid,
                 ref name,
                 ref mode,
                 ref gid,
-            } => buf << dfid << name << mode << gid,
-            Rmkdir { ref qid } => buf << qid,
+            } => fields!(sink; dfid, name, mode, gid),
+            Rmkdir { ref qid } => fields!(sink; qid),
             Trenameat {
                 ref olddirfid,
                 ref oldname,
                 ref newdirfid,
                 ref newname,
-            } => buf << olddirfid << oldname << newdirfid << newname,
-            Rrenameat => buf,
+            } => fields!(sink; olddirfid, oldname, newdirfid, newname),
+            Rrenameat => {}
             Tunlinkat {
                 ref dirfd,
                 ref name,
                 ref flags,
-            } => buf << dirfd << name << flags,
-            Runlinkat => buf,
+            } => fields!(sink; dirfd, name, &flags.bits()),
+            Runlinkat => {}
 
             /*
              * 9P2000.u
@@ -460,16 +783,16 @@ impl Encodable for Msg {
                 ref uname,
                 ref aname,
                 ref n_uname,
-            } => buf << afid << uname << aname << n_uname,
-            Rauth { ref aqid } => buf << aqid,
+            } => fields!(sink; afid, uname, aname, n_uname),
+            Rauth { ref aqid } => fields!(sink; aqid),
             Tattach {
                 ref fid,
                 ref afid,
                 ref uname,
                 ref aname,
                 ref n_uname,
-            } => buf << fid << afid << uname << aname << n_uname,
-            Rattach { ref qid } => buf << qid,
+            } => fields!(sink; fid, afid, uname, aname, n_uname),
+            Rattach { ref qid } => fields!(sink; qid),
 
             /*
              * 9P2000
@@ -477,44 +800,54 @@ impl Encodable for Msg {
             Tversion {
                 ref msize,
                 ref version,
-            } => buf << msize << version,
+            } => fields!(sink; msize, version),
             Rversion {
                 ref msize,
                 ref version,
-            } => buf << msize << version,
-            Tflush { ref oldtag } => buf << oldtag,
-            Rflush => buf,
+            } => fields!(sink; msize, version),
+            Tflush { ref oldtag } => fields!(sink; oldtag),
+            Rflush => {}
             Twalk {
                 ref fid,
                 ref newfid,
                 ref wnames,
-            } => buf << fid << newfid << wnames,
-            Rwalk { ref wqids } => buf << wqids,
+            } => fields!(sink; fid, newfid, wnames),
+            Rwalk { ref wqids } => fields!(sink; wqids),
             Tread {
                 ref fid,
                 ref offset,
                 ref count,
-            } => buf << fid << offset << count,
-            Rread { ref data } => buf << data,
+            } => fields!(sink; fid, offset, count),
+            Rread { ref data } => fields!(sink; data),
             Twrite {
                 ref fid,
                 ref offset,
                 ref data,
-            } => buf << fid << offset << data,
-            Rwrite { ref count } => buf << count,
-            Tclunk { ref fid } => buf << fid,
-            Rclunk => buf,
-            Tremove { ref fid } => buf << fid,
-            Rremove => buf,
-        };
-
-        match buf {
-            SResult(Ok(b)) => Ok(b.bytes_written()),
-            SResult(Err(e)) => Err(e),
+            } => fields!(sink; fid, offset, data),
+            Rwrite { ref count } => fields!(sink; count),
+            Tclunk { ref fid } => fields!(sink; fid),
+            Rclunk => {}
+            Tremove { ref fid } => fields!(sink; fid),
+            Rremove => {}
         }
+
+        Ok(())
     }
 }
 
+encodable_fields!(
+    Qid,
+    Statfs,
+    Time,
+    Stat,
+    SetAttr,
+    DirEntry,
+    DirEntryData,
+    Flock,
+    Getlock,
+    Msg
+);
+
 /// Trait representing a type which can be deserialized from binary
 pub trait Decodable: Sized {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self>;
@@ -522,33 +855,45 @@ pub trait Decodable: Sized {
 
 impl Decodable for u8 {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
-        r.read_u8()
+        r.read_u8().map_err(truncated)
     }
 }
 
 impl Decodable for u16 {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
-        r.read_u16::<LittleEndian>()
+        r.read_u16::<LittleEndian>().map_err(truncated)
     }
 }
 
 impl Decodable for u32 {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
-        r.read_u32::<LittleEndian>()
+        r.read_u32::<LittleEndian>().map_err(truncated)
     }
 }
 
 impl Decodable for u64 {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
-        r.read_u64::<LittleEndian>()
+        r.read_u64::<LittleEndian>().map_err(truncated)
     }
 }
 
 impl Decodable for String {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
         let len: u16 = Decodable::decode(r)?;
-        String::from_utf8(read_exact(r, len as usize)?)
-            .map_err(|_| io_err!(Other, "Invalid UTF-8 sequence"))
+        String::from_utf8(read_exact(r, len as usize)?).map_err(|_| DecodeError::InvalidUtf8.into())
+    }
+}
+
+impl Decodable for NineString {
+    fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
+        let len: u16 = Decodable::decode(r)?;
+        Ok(NineString::from(read_exact(r, len as usize)?))
+    }
+}
+
+impl Decodable for FileMode {
+    fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
+        Ok(FileMode::from_bits(Decodable::decode(r)?))
     }
 }
 
@@ -601,6 +946,9 @@ impl Decodable for Stat {
             atime: Decodable::decode(r)?,
             mtime: Decodable::decode(r)?,
             ctime: Decodable::decode(r)?,
+            btime: Decodable::decode(r)?,
+            gen: Decodable::decode(r)?,
+            data_version: Decodable::decode(r)?,
         })
     }
 }
@@ -631,10 +979,11 @@ impl Decodable for DirEntry {
 
 impl Decodable for DirEntryData {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
-        let count: u32 = Decodable::decode(r)?;
-        let mut data: Vec<DirEntry> = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            data.push(Decodable::decode(r)?);
+        let size: u32 = Decodable::decode(r)?;
+        let mut entries = Cursor::new(read_exact(r, size as usize)?);
+        let mut data = Vec::new();
+        while (entries.position() as usize) < entries.get_ref().len() {
+            data.push(Decodable::decode(&mut entries)?);
         }
         Ok(DirEntryData::with(data))
     }
@@ -672,12 +1021,16 @@ impl Decodable for Getlock {
     }
 }
 
+// A stream has no frame to bound the count by, so the count is never trusted
+// for allocation: each element is read before room is made for it, and a
+// count beyond what the stream holds fails as soon as the stream runs out.
+// Frames decoded with `decode_msg` have every count checked against the frame.
 impl<T: Decodable> Decodable for Vec<T> {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
         let len: u16 = Decodable::decode(r)?;
         let mut buf = Vec::new();
         for _ in 0..len {
-            buf.push(Decodable::decode(r)?);
+            buf.push(Decodable::decode(r).map_err(truncated)?);
         }
         Ok(buf)
     }
@@ -689,7 +1042,8 @@ impl Decodable for Msg {
 
         let mut buf = r;
 
-        let msg_type = MsgType::from_u8(decode!(buf));
+        let typ: u8 = decode!(buf);
+        let msg_type = MsgType::from_u8(typ);
         let tag = decode!(buf);
         let body = match msg_type {
             /*
@@ -704,7 +1058,7 @@ impl Decodable for Msg {
             },
             Some(Tlopen) => Fcall::Tlopen {
                 fid: decode!(buf),
-                flags: decode!(buf),
+                flags: decode!(LOpenFlags, buf),
             },
             Some(Rlopen) => Fcall::Rlopen {
                 qid: decode!(buf),
@@ -713,7 +1067,7 @@ impl Decodable for Msg {
             Some(Tlcreate) => Fcall::Tlcreate {
                 fid: decode!(buf),
                 name: decode!(buf),
-                flags: decode!(buf),
+                flags: decode!(LOpenFlags, buf),
                 mode: decode!(buf),
                 gid: decode!(buf),
             },
@@ -751,16 +1105,11 @@ impl Decodable for Msg {
                 fid: decode!(buf),
                 req_mask: decode!(GetattrMask, buf),
             },
-            Some(Rgetattr) => {
-                let r = Fcall::Rgetattr {
-                    valid: decode!(GetattrMask, buf),
-                    qid: decode!(buf),
-                    stat: decode!(buf),
-                };
-                let (_btime, _gen, _ver): (Time, u64, u64) =
-                    (decode!(buf), decode!(buf), decode!(buf));
-                r
-            }
+            Some(Rgetattr) => Fcall::Rgetattr {
+                valid: decode!(GetattrMask, buf),
+                qid: decode!(buf),
+                stat: decode!(buf),
+            },
             Some(Tsetattr) => Fcall::Tsetattr {
                 fid: decode!(buf),
                 valid: decode!(SetattrMask, buf),
@@ -777,7 +1126,7 @@ impl Decodable for Msg {
                 fid: decode!(buf),
                 name: decode!(buf),
                 attr_size: decode!(buf),
-                flags: decode!(buf),
+                flags: decode!(XattrFlags, buf),
             },
             Some(Rxattrcreate) => Fcall::Rxattrcreate,
             Some(Treaddir) => Fcall::Treaddir {
@@ -825,7 +1174,7 @@ impl Decodable for Msg {
             Some(Tunlinkat) => Fcall::Tunlinkat {
                 dirfd: decode!(buf),
                 name: decode!(buf),
-                flags: decode!(buf),
+                flags: decode!(UnlinkatFlags, buf),
             },
             Some(Runlinkat) => Fcall::Runlinkat,
 
@@ -866,10 +1215,10 @@ impl Decodable for Msg {
             Some(Twalk) => Fcall::Twalk {
                 fid: decode!(buf),
                 newfid: decode!(buf),
-                wnames: decode!(buf),
+                wnames: decode_bounded(buf, MAXWELEM)?,
             },
             Some(Rwalk) => Fcall::Rwalk {
-                wqids: decode!(buf),
+                wqids: decode_bounded(buf, MAXWELEM)?,
             },
             Some(Tread) => Fcall::Tread {
                 fid: decode!(buf),
@@ -889,24 +1238,362 @@ impl Decodable for Msg {
             Some(Rclunk) => Fcall::Rclunk,
             Some(Tremove) => Fcall::Tremove { fid: decode!(buf) },
             Some(Rremove) => Fcall::Rremove,
-            Some(Tlerror) | None => return res!(io_err!(Other, "Invalid message type")),
+            Some(Tlerror) | None => return res!(DecodeError::InvalidMsgType(typ)),
         };
 
         Ok(Msg { tag, body })
     }
 }
 
+/*
+ * Borrowing decoders for MsgRef
+ */
+
+// Split `len` bytes off the front of the frame without copying
+fn take_ref<'a>(r: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if r.len() < len {
+        return res!(DecodeError::LengthOverrun(len));
+    }
+    let (head, tail) = r.split_at(len);
+    *r = tail;
+    Ok(head)
+}
+
+fn decode_bytes_ref<'a>(r: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len: u16 = Decodable::decode(r)?;
+    take_ref(r, len as usize)
+}
+
+fn decode_str_ref<'a>(r: &mut &'a [u8]) -> Result<&'a str> {
+    std::str::from_utf8(decode_bytes_ref(r)?).map_err(|_| DecodeError::InvalidUtf8.into())
+}
+
+fn decode_data_ref<'a>(r: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len: u32 = Decodable::decode(r)?;
+    take_ref(r, len as usize)
+}
+
+// Each element takes at least `min_len` bytes, so the count is checked
+// against the rest of the frame before any element is decoded
+fn decode_walk_ref<'a, T, F>(r: &mut &'a [u8], min_len: usize, mut elem: F) -> Result<WalkArray<T>>
+where
+    T: Copy + Default,
+    F: FnMut(&mut &'a [u8]) -> Result<T>,
+{
+    let count: u16 = Decodable::decode(r)?;
+    if count as usize > MAXWELEM {
+        return res!(DecodeError::TooManyElements {
+            count: count as usize,
+            max: MAXWELEM,
+        });
+    }
+    if count as usize * min_len > r.len() {
+        return res!(DecodeError::LengthOverrun(count as usize * min_len));
+    }
+    let mut elems = WalkArray::new();
+    for _ in 0..count {
+        elems.push(elem(r)?);
+    }
+    Ok(elems)
+}
+
+fn decode_dirent_ref<'a>(r: &mut &'a [u8]) -> Result<DirEntryRef<'a>> {
+    Ok(DirEntryRef {
+        qid: Decodable::decode(r)?,
+        offset: Decodable::decode(r)?,
+        typ: Decodable::decode(r)?,
+        name: decode_bytes_ref(r)?,
+    })
+}
+
+fn decode_dirents_ref<'a>(r: &mut &'a [u8]) -> Result<DirEntryDataRef<'a>> {
+    let data = decode_data_ref(r)?;
+    let mut entries = data;
+    while !entries.is_empty() {
+        decode_dirent_ref(&mut entries)?;
+    }
+    Ok(DirEntryDataRef(data))
+}
+
+impl<'a> DirEntryDataRef<'a> {
+    /// Iterate over the entries without copying their names
+    pub fn iter(&self) -> impl Iterator<Item = DirEntryRef<'a>> {
+        // Entries were validated when the message was decoded
+        let mut entries = self.0;
+        std::iter::from_fn(move || match entries.is_empty() {
+            true => None,
+            false => decode_dirent_ref(&mut entries).ok(),
+        })
+    }
+}
+
+fn decode_flock_ref<'a>(r: &mut &'a [u8]) -> Result<FlockRef<'a>> {
+    Ok(FlockRef {
+        typ: decode!(LockType, *r),
+        flags: decode!(LockFlag, *r),
+        start: Decodable::decode(r)?,
+        length: Decodable::decode(r)?,
+        proc_id: Decodable::decode(r)?,
+        client_id: decode_str_ref(r)?,
+    })
+}
+
+fn decode_getlock_ref<'a>(r: &mut &'a [u8]) -> Result<GetlockRef<'a>> {
+    Ok(GetlockRef {
+        typ: decode!(LockType, *r),
+        start: Decodable::decode(r)?,
+        length: Decodable::decode(r)?,
+        proc_id: Decodable::decode(r)?,
+        client_id: decode_str_ref(r)?,
+    })
+}
+
+/// Helper function to decode a 9P message from a single frame without copying
+///
+/// Performs the same validation as `decode_msg`, but names and payloads
+/// of the returned message borrow from `frame`.
+pub fn decode_msg_ref(frame: &[u8]) -> Result<MsgRef<'_>> {
+    use crate::MsgType::*;
+
+    let mut buf = frame;
+
+    let typ: u8 = decode!(buf);
+    let tag = decode!(buf);
+    let body = match MsgType::from_u8(typ) {
+        /*
+         * 9P2000.L
+         */
+        Some(Rlerror) => FcallRef::Rlerror {
+            ecode: decode!(buf),
+        },
+        Some(Tstatfs) => FcallRef::Tstatfs { fid: decode!(buf) },
+        Some(Rstatfs) => FcallRef::Rstatfs {
+            statfs: decode!(buf),
+        },
+        Some(Tlopen) => FcallRef::Tlopen {
+            fid: decode!(buf),
+            flags: decode!(LOpenFlags, buf),
+        },
+        Some(Rlopen) => FcallRef::Rlopen {
+            qid: decode!(buf),
+            iounit: decode!(buf),
+        },
+        Some(Tlcreate) => FcallRef::Tlcreate {
+            fid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            flags: decode!(LOpenFlags, buf),
+            mode: decode!(buf),
+            gid: decode!(buf),
+        },
+        Some(Rlcreate) => FcallRef::Rlcreate {
+            qid: decode!(buf),
+            iounit: decode!(buf),
+        },
+        Some(Tsymlink) => FcallRef::Tsymlink {
+            fid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            symtgt: decode_bytes_ref(&mut buf)?,
+            gid: decode!(buf),
+        },
+        Some(Rsymlink) => FcallRef::Rsymlink { qid: decode!(buf) },
+        Some(Tmknod) => FcallRef::Tmknod {
+            dfid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            mode: decode!(buf),
+            major: decode!(buf),
+            minor: decode!(buf),
+            gid: decode!(buf),
+        },
+        Some(Rmknod) => FcallRef::Rmknod { qid: decode!(buf) },
+        Some(Trename) => FcallRef::Trename {
+            fid: decode!(buf),
+            dfid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+        },
+        Some(Rrename) => FcallRef::Rrename,
+        Some(Treadlink) => FcallRef::Treadlink { fid: decode!(buf) },
+        Some(Rreadlink) => FcallRef::Rreadlink {
+            target: decode_bytes_ref(&mut buf)?,
+        },
+        Some(Tgetattr) => FcallRef::Tgetattr {
+            fid: decode!(buf),
+            req_mask: decode!(GetattrMask, buf),
+        },
+        Some(Rgetattr) => FcallRef::Rgetattr {
+            valid: decode!(GetattrMask, buf),
+            qid: decode!(buf),
+            stat: decode!(buf),
+        },
+        Some(Tsetattr) => FcallRef::Tsetattr {
+            fid: decode!(buf),
+            valid: decode!(SetattrMask, buf),
+            stat: decode!(buf),
+        },
+        Some(Rsetattr) => FcallRef::Rsetattr,
+        Some(Txattrwalk) => FcallRef::Txattrwalk {
+            fid: decode!(buf),
+            newfid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+        },
+        Some(Rxattrwalk) => FcallRef::Rxattrwalk { size: decode!(buf) },
+        Some(Txattrcreate) => FcallRef::Txattrcreate {
+            fid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            attr_size: decode!(buf),
+            flags: decode!(XattrFlags, buf),
+        },
+        Some(Rxattrcreate) => FcallRef::Rxattrcreate,
+        Some(Treaddir) => FcallRef::Treaddir {
+            fid: decode!(buf),
+            offset: decode!(buf),
+            count: decode!(buf),
+        },
+        Some(Rreaddir) => FcallRef::Rreaddir {
+            data: decode_dirents_ref(&mut buf)?,
+        },
+        Some(Tfsync) => FcallRef::Tfsync { fid: decode!(buf) },
+        Some(Rfsync) => FcallRef::Rfsync,
+        Some(Tlock) => FcallRef::Tlock {
+            fid: decode!(buf),
+            flock: decode_flock_ref(&mut buf)?,
+        },
+        Some(Rlock) => FcallRef::Rlock {
+            status: decode!(LockStatus, buf),
+        },
+        Some(Tgetlock) => FcallRef::Tgetlock {
+            fid: decode!(buf),
+            flock: decode_getlock_ref(&mut buf)?,
+        },
+        Some(Rgetlock) => FcallRef::Rgetlock {
+            flock: decode_getlock_ref(&mut buf)?,
+        },
+        Some(Tlink) => FcallRef::Tlink {
+            dfid: decode!(buf),
+            fid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+        },
+        Some(Rlink) => FcallRef::Rlink,
+        Some(Tmkdir) => FcallRef::Tmkdir {
+            dfid: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            mode: decode!(buf),
+            gid: decode!(buf),
+        },
+        Some(Rmkdir) => FcallRef::Rmkdir { qid: decode!(buf) },
+        Some(Trenameat) => FcallRef::Trenameat {
+            olddirfid: decode!(buf),
+            oldname: decode_bytes_ref(&mut buf)?,
+            newdirfid: decode!(buf),
+            newname: decode_bytes_ref(&mut buf)?,
+        },
+        Some(Rrenameat) => FcallRef::Rrenameat,
+        Some(Tunlinkat) => FcallRef::Tunlinkat {
+            dirfd: decode!(buf),
+            name: decode_bytes_ref(&mut buf)?,
+            flags: decode!(UnlinkatFlags, buf),
+        },
+        Some(Runlinkat) => FcallRef::Runlinkat,
+
+        /*
+         * 9P2000.u
+         */
+        Some(Tauth) => FcallRef::Tauth {
+            afid: decode!(buf),
+            uname: decode_str_ref(&mut buf)?,
+            aname: decode_str_ref(&mut buf)?,
+            n_uname: decode!(buf),
+        },
+        Some(Rauth) => FcallRef::Rauth { aqid: decode!(buf) },
+        Some(Tattach) => FcallRef::Tattach {
+            fid: decode!(buf),
+            afid: decode!(buf),
+            uname: decode_str_ref(&mut buf)?,
+            aname: decode_str_ref(&mut buf)?,
+            n_uname: decode!(buf),
+        },
+        Some(Rattach) => FcallRef::Rattach { qid: decode!(buf) },
+
+        /*
+         * 9P2000
+         */
+        Some(Tversion) => FcallRef::Tversion {
+            msize: decode!(buf),
+            version: decode_str_ref(&mut buf)?,
+        },
+        Some(Rversion) => FcallRef::Rversion {
+            msize: decode!(buf),
+            version: decode_str_ref(&mut buf)?,
+        },
+        Some(Tflush) => FcallRef::Tflush {
+            oldtag: decode!(buf),
+        },
+        Some(Rflush) => FcallRef::Rflush,
+        Some(Twalk) => FcallRef::Twalk {
+            fid: decode!(buf),
+            newfid: decode!(buf),
+            wnames: decode_walk_ref(&mut buf, mem::size_of::<u16>(), decode_bytes_ref)?,
+        },
+        Some(Rwalk) => FcallRef::Rwalk {
+            wqids: decode_walk_ref(&mut buf, Qid::default().encoded_len(), Decodable::decode)?,
+        },
+        Some(Tread) => FcallRef::Tread {
+            fid: decode!(buf),
+            offset: decode!(buf),
+            count: decode!(buf),
+        },
+        Some(Rread) => FcallRef::Rread {
+            data: decode_data_ref(&mut buf)?,
+        },
+        Some(Twrite) => FcallRef::Twrite {
+            fid: decode!(buf),
+            offset: decode!(buf),
+            data: decode_data_ref(&mut buf)?,
+        },
+        Some(Rwrite) => FcallRef::Rwrite {
+            count: decode!(buf),
+        },
+        Some(Tclunk) => FcallRef::Tclunk { fid: decode!(buf) },
+        Some(Rclunk) => FcallRef::Rclunk,
+        Some(Tremove) => FcallRef::Tremove { fid: decode!(buf) },
+        Some(Rremove) => FcallRef::Rremove,
+        Some(Tlerror) | None => return res!(DecodeError::InvalidMsgType(typ)),
+    };
+
+    match buf.len() {
+        0 => Ok(MsgRef { tag, body }),
+        n => res!(DecodeError::TrailingBytes(n)),
+    }
+}
+
 /// Helper function to read a 9P message from a byte-oriented stream
 pub fn read_msg<R: ReadBytesExt>(r: &mut R) -> Result<Msg> {
     Decodable::decode(r)
 }
 
+/// Helper function to decode a 9P message from a single frame
+///
+/// `frame` is the message without its leading `size[4]` field.
+/// Unlike `read_msg`, the message must consume the whole frame, and no
+/// length or element count is believed beyond what remains of the frame.
+pub fn decode_msg(frame: &[u8]) -> Result<Msg> {
+    decode_msg_ref(frame).map(Msg::from)
+}
+
 /// Helper function to write a 9P message into a byte-oriented stream
 pub fn write_msg<W: WriteBytesExt>(w: &mut W, msg: &Msg) -> Result<usize> {
     msg.encode(w)
 }
 
+/// Helper function to encode a 9P message into a `BufMut`
+///
+/// Like `write_msg`, the leading `size[4]` field is not written.
+pub fn write_msg_buf<B: BufMut>(buf: &mut B, msg: &Msg) -> Result<usize> {
+    msg.encode_buf(buf)?;
+    Ok(msg.encoded_len())
+}
+
 #[test]
+#[allow(clippy::needless_borrow)]
 fn encoder_test1() {
     let expected: Vec<u8> = (0..10).collect();
     let mut encoder = Vec::new();
@@ -917,6 +1604,7 @@ fn encoder_test1() {
 }
 
 #[test]
+#[allow(clippy::while_let_loop)]
 fn decoder_test1() {
     use std::io::Cursor;
 
@@ -951,3 +1639,310 @@ fn msg_encode_decode1() {
 
     assert_eq!(expected, actual.unwrap());
 }
+
+#[test]
+fn read_msg_reads_one_message_at_a_time() {
+    let msgs = [
+        Msg {
+            tag: 1,
+            body: Fcall::Tclunk { fid: 2 },
+        },
+        Msg {
+            tag: 3,
+            body: Fcall::Rlerror { ecode: 4 },
+        },
+    ];
+    let mut buf = Vec::new();
+    for msg in &msgs {
+        write_msg(&mut buf, msg).unwrap();
+    }
+
+    let mut stream = Cursor::new(buf);
+    for msg in &msgs {
+        assert_eq!(*msg, read_msg(&mut stream).unwrap());
+    }
+    assert_eq!(stream.position() as usize, stream.get_ref().len());
+}
+
+#[cfg(test)]
+fn decode_error(frame: &[u8]) -> DecodeError {
+    let e = decode_msg(frame).unwrap_err();
+    *e.get_ref().unwrap().downcast_ref::<DecodeError>().unwrap()
+}
+
+#[test]
+fn decode_msg_rejects_trailing_bytes() {
+    let msg = Msg {
+        tag: 1,
+        body: Fcall::Tclunk { fid: 2 },
+    };
+    let mut buf = Vec::new();
+    msg.encode(&mut buf).unwrap();
+    assert_eq!(msg, decode_msg(&buf).unwrap());
+
+    buf.extend_from_slice(&[0, 0, 0]);
+    assert_eq!(DecodeError::TrailingBytes(3), decode_error(&buf));
+}
+
+#[test]
+fn decode_msg_rejects_too_many_wnames() {
+    let msg = Msg {
+        tag: 1,
+        body: Fcall::Twalk {
+            fid: 0,
+            newfid: 1,
+            wnames: vec!["a".into(); MAXWELEM + 1],
+        },
+    };
+    let mut buf = Vec::new();
+    msg.encode(&mut buf).unwrap();
+    assert_eq!(
+        DecodeError::TooManyElements {
+            count: MAXWELEM + 1,
+            max: MAXWELEM
+        },
+        decode_error(&buf)
+    );
+}
+
+#[test]
+fn decode_msg_rejects_length_overrun() {
+    // Twrite claiming 4GB of data followed by only 4 bytes
+    let mut buf = vec![MsgType::Twrite as u8, 1, 0];
+    buf.extend_from_slice(&[0; 4 + 8]);
+    buf.extend_from_slice(&u32::MAX.to_le_bytes());
+    buf.extend_from_slice(b"data");
+    assert_eq!(
+        DecodeError::LengthOverrun(u32::MAX as usize),
+        decode_error(&buf)
+    );
+}
+
+#[test]
+fn element_counts_are_bounded_by_the_input() {
+    // Rwalk claiming MAXWELEM qids followed by none
+    let mut buf = vec![MsgType::Rwalk as u8, 1, 0];
+    buf.extend_from_slice(&(MAXWELEM as u16).to_le_bytes());
+    assert_eq!(
+        DecodeError::LengthOverrun(MAXWELEM * 13),
+        decode_error(&buf)
+    );
+
+    // A stream claiming 65535 qids holding only one
+    let mut buf = u16::MAX.to_le_bytes().to_vec();
+    Qid::default().encode(&mut buf).unwrap();
+    let e = <Vec<Qid>>::decode(&mut Cursor::new(buf)).unwrap_err();
+    assert_eq!(
+        Some(&DecodeError::Truncated),
+        e.get_ref().and_then(|e| e.downcast_ref())
+    );
+}
+
+#[test]
+fn decode_msg_rejects_invalid_type_and_truncation() {
+    assert_eq!(
+        DecodeError::InvalidMsgType(MsgType::Tlerror as u8),
+        decode_error(&[MsgType::Tlerror as u8, 0, 0])
+    );
+    assert_eq!(DecodeError::InvalidMsgType(255), decode_error(&[255, 0, 0]));
+    assert_eq!(DecodeError::Truncated, decode_error(&[]));
+    assert_eq!(
+        DecodeError::Truncated,
+        decode_error(&[MsgType::Tclunk as u8, 0, 0, 1])
+    );
+    assert_eq!(
+        DecodeError::InvalidUtf8,
+        decode_error(&[MsgType::Rversion as u8, 0, 0, 0, 0, 0, 0, 1, 0, 0xff])
+    );
+}
+
+#[test]
+fn rreaddir_encode_decode() {
+    let mut data = DirEntryData::new();
+    for (i, name) in [".", "..", "file"].iter().enumerate() {
+        data.push(DirEntry {
+            qid: Default::default(),
+            offset: i as u64,
+            typ: 0,
+            name: (*name).into(),
+        });
+    }
+    let expected = Msg {
+        tag: 7,
+        body: Fcall::Rreaddir { data },
+    };
+    let mut buf = Vec::new();
+    expected.encode(&mut buf).unwrap();
+    assert_eq!(expected, decode_msg(&buf).unwrap());
+}
+
+#[test]
+fn encode_rejects_oversized_fields() {
+    use crate::error::{errno::*, Error};
+
+    fn encode_errno(body: Fcall) -> nix::errno::Errno {
+        // Both encoding paths must reject the same fields
+        let msg = Msg { tag: 0, body };
+        let errno = Error::from(msg.encode(&mut Vec::new()).unwrap_err()).errno();
+        let e = write_msg_buf(&mut Vec::new(), &msg).unwrap_err();
+        assert_eq!(errno, Error::from(e).errno());
+        errno
+    }
+
+    let target = "x".repeat(70 * 1024).into();
+    assert_eq!(ENAMETOOLONG, encode_errno(Fcall::Rreadlink { target }));
+
+    let wqids = vec![Qid::default(); u16::MAX as usize + 1];
+    assert_eq!(E2BIG, encode_errno(Fcall::Rwalk { wqids }));
+
+    // Lengths that fit exactly are still encoded
+    let mut buf = Vec::new();
+    let target = "x".repeat(u16::MAX as usize).into();
+    Msg {
+        tag: 0,
+        body: Fcall::Rreadlink { target },
+    }
+    .encode(&mut buf)
+    .unwrap();
+    assert_eq!(1 + 2 + 2 + u16::MAX as usize, buf.len());
+}
+
+#[test]
+fn non_utf8_names_encode_decode() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = OsStr::from_bytes(b"caf\xe9");
+    let expected = Msg {
+        tag: 3,
+        body: Fcall::Tlcreate {
+            fid: 1,
+            name: name.into(),
+            flags: LOpenFlags::CREATE | LOpenFlags::WRONLY,
+            mode: FileMode::new(FileType::Regular, 0o644),
+            gid: 0,
+        },
+    };
+    let mut buf = Vec::new();
+    expected.encode(&mut buf).unwrap();
+
+    let actual = decode_msg(&buf).unwrap();
+    assert_eq!(expected, actual);
+    match actual.body {
+        Fcall::Tlcreate { name: ref n, .. } => assert_eq!(name, n.as_os_str()),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn decode_msg_ref_borrows_from_frame() {
+    let mut data = DirEntryData::new();
+    for (i, name) in [".", "..", "file"].iter().enumerate() {
+        data.push(DirEntry {
+            qid: Default::default(),
+            offset: i as u64,
+            typ: 0,
+            name: (*name).into(),
+        });
+    }
+    let bodies = vec![
+        Fcall::Twrite {
+            fid: 1,
+            offset: 2,
+            data: Data(b"payload".to_vec()),
+        },
+        Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["a".into(), "b".into()],
+        },
+        Fcall::Rreaddir { data },
+        Fcall::Tlock {
+            fid: 1,
+            flock: Flock {
+                typ: LockType::WRLOCK,
+                flags: LockFlag::BLOCK,
+                start: 0,
+                length: 10,
+                proc_id: 42,
+                client_id: "client".to_owned(),
+            },
+        },
+    ];
+
+    for body in bodies {
+        let expected = Msg { tag: 5, body };
+        let mut buf = Vec::new();
+        expected.encode(&mut buf).unwrap();
+        let actual = decode_msg_ref(&buf).unwrap();
+        if let FcallRef::Twrite { data, .. } = actual.body {
+            assert_eq!(buf[buf.len() - data.len()..].as_ptr(), data.as_ptr());
+        }
+        assert_eq!(expected, Msg::from(actual));
+    }
+}
+
+#[test]
+fn encoded_len_and_encode_buf_match_encode() {
+    let mut data = DirEntryData::new();
+    data.push(DirEntry {
+        qid: Default::default(),
+        offset: 0,
+        typ: 0,
+        name: "file".into(),
+    });
+    let bodies = vec![
+        Fcall::Rgetattr {
+            valid: GetattrMask::ALL,
+            qid: Default::default(),
+            stat: Stat {
+                mode: FileMode::default(),
+                uid: 0,
+                gid: 0,
+                nlink: 0,
+                rdev: 0,
+                size: 0,
+                blksize: 0,
+                blocks: 0,
+                atime: Time { sec: 0, nsec: 0 },
+                mtime: Time { sec: 0, nsec: 0 },
+                ctime: Time { sec: 0, nsec: 0 },
+                btime: Time { sec: 0, nsec: 0 },
+                gen: 0,
+                data_version: 0,
+            },
+        },
+        Fcall::Rreaddir { data },
+        Fcall::Rread {
+            data: Data(vec![0; 100]),
+        },
+        Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["a".into(), "bc".into()],
+        },
+        Fcall::Rwalk {
+            wqids: vec![Default::default(); 3],
+        },
+        Fcall::Tattach {
+            fid: 1,
+            afid: NOFID,
+            uname: "user".to_owned(),
+            aname: "/".to_owned(),
+            n_uname: 0,
+        },
+        Fcall::Rclunk,
+    ];
+
+    for body in bodies {
+        let msg = Msg { tag: 1, body };
+        let mut buf = Vec::new();
+        let written = msg.encode(&mut buf).unwrap();
+        assert_eq!(written, msg.encoded_len());
+        assert_eq!(buf.len(), msg.encoded_len());
+
+        let mut bufmut = Vec::new();
+        write_msg_buf(&mut bufmut, &msg).unwrap();
+        assert_eq!(buf, bufmut);
+    }
+}
@@ -8,45 +8,376 @@ use {
         error,
         error::errno::*,
         fcall::*,
-        serialize,
+        serialize::{self, Encodable},
         utils::{self, Result},
     },
     async_trait::async_trait,
-    bytes::buf::{Buf, BufMut},
+    bytes::{Bytes, BytesMut},
     futures::sink::SinkExt,
-    std::{collections::HashMap, sync::Arc},
+    num_traits::FromPrimitive,
+    std::{
+        collections::HashMap,
+        ffi::OsStr,
+        io,
+        os::unix::ffi::OsStrExt,
+        sync::{
+            atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+            Arc, Weak,
+        },
+    },
     tokio::{
         io::{AsyncRead, AsyncWrite},
         net::{TcpListener, UnixListener},
-        sync::{Mutex, RwLock},
+        sync::{watch, Mutex, RwLock},
     },
     tokio_stream::StreamExt,
-    tokio_util::codec::length_delimited::LengthDelimitedCodec,
+    tokio_util::codec::{length_delimited::LengthDelimitedCodec, FramedWrite},
 };
 
+mod compat;
+mod response;
+
+pub use self::compat::{Compat, FcallFilesystem};
+pub use self::response::*;
+
 /// Represents a fid of clients holding associated `Filesystem::Fid`.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Fid<T> {
     /// Raw client side fid.
     fid: u32,
 
+    /// Type of the file this fid points to, from the qid the server last replied with.
+    qid_type: QidType,
+
+    /// Mode the fid was opened with, if it is open.
+    mode: Option<LOpenFlags>,
+
+    /// Byte ranges locked through this fid which are still held.
+    locks: Vec<Lock>,
+
     /// `Filesystem::Fid` associated with this fid.
     /// Changing this value affects the continuous callbacks.
     pub aux: T,
 }
 
 impl<T> Fid<T> {
+    fn new(fid: u32, aux: T) -> Fid<T> {
+        Fid {
+            fid,
+            qid_type: QidType::FILE,
+            mode: None,
+            locks: Vec::new(),
+            aux,
+        }
+    }
+
     /// Get the raw fid.
     pub fn fid(&self) -> u32 {
         self.fid
     }
+
+    /// Get the type of the file this fid points to.
+    pub fn qid_type(&self) -> QidType {
+        self.qid_type
+    }
+
+    /// Get the mode the fid was opened with, or `None` if it is not open.
+    pub fn mode(&self) -> Option<LOpenFlags> {
+        self.mode
+    }
+
+    fn is_dir(&self) -> bool {
+        self.qid_type.contains(QidType::DIR)
+    }
+
+    fn readable(&self) -> bool {
+        matches!(
+            self.mode.map(|m| m.access_mode()),
+            Some(LOpenFlags::RDONLY) | Some(LOpenFlags::RDWR)
+        )
+    }
+
+    fn writable(&self) -> bool {
+        matches!(
+            self.mode.map(|m| m.access_mode()),
+            Some(LOpenFlags::WRONLY) | Some(LOpenFlags::RDWR)
+        )
+    }
+}
+
+/// A byte range locked through a fid by one lock owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Lock {
+    proc_id: u32,
+    typ: LockType,
+    start: u64,
+    // Exclusive, u64::MAX for a lock up to the end of the file
+    end: u64,
+}
+
+// Apply a successful Tlock to the ranges held through a fid.
+// Like POSIX record locks, a lock replaces whatever its owner held in its range,
+// unlocking part of a range splits it, and adjacent ranges of the same type merge.
+fn apply_lock(locks: &mut Vec<Lock>, flock: &FlockRef<'_>) {
+    let (proc_id, start) = (flock.proc_id, flock.start);
+    let end = match flock.length {
+        0 => u64::MAX,
+        length => start.saturating_add(length),
+    };
+
+    let mut held = Vec::with_capacity(locks.len() + 1);
+    for l in locks.drain(..) {
+        if l.proc_id != proc_id || l.end <= start || l.start >= end {
+            held.push(l);
+            continue;
+        }
+        if l.start < start {
+            held.push(Lock { end: start, ..l });
+        }
+        if l.end > end {
+            held.push(Lock { start: end, ..l });
+        }
+    }
+
+    if flock.typ != LockType::UNLOCK {
+        let mut new = Lock {
+            proc_id,
+            typ: flock.typ,
+            start,
+            end,
+        };
+        held.retain(|l| {
+            let adjacent = l.proc_id == proc_id
+                && l.typ == new.typ
+                && (l.end == new.start || l.start == new.end);
+            if adjacent {
+                new.start = new.start.min(l.start);
+                new.end = new.end.max(l.end);
+            }
+            !adjacent
+        });
+        held.push(new);
+    }
+    *locks = held;
+}
+
+/// Server configuration.
+///
+/// The limits apply to each connection separately.
+/// Requests exceeding them fail with the errno given for each limit and are counted in `metrics`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Pass every request on a known fid to the `Filesystem`.
+    ///
+    /// By default the server tracks whether each fid is open, how, and whether it is a directory,
+    /// and rejects requests which are illegal in that state, e.g. `Tread` on an unopened fid,
+    /// with EBADF, ENOTDIR or EISDIR. Enable this for clients which rely on the filesystem
+    /// to be more forgiving.
+    pub lenient: bool,
+
+    /// Maximum number of fids a client may hold at once (EMFILE).
+    pub max_fids: usize,
+
+    /// Maximum `attr_size` of a `Txattrcreate` (E2BIG).
+    pub max_xattr_size: u64,
+
+    /// Maximum number of locks a client may hold through all of its fids (ENOLCK).
+    ///
+    /// Locks are counted as POSIX record locks are held: one per disjoint byte range
+    /// of each lock owner, so relocking a range already held does not count twice.
+    pub max_locks: usize,
+
+    /// Counters of requests rejected by the limits above,
+    /// of walks exceeding `MAXWELEM` elements, which are always rejected (E2BIG),
+    /// and of requests dropped for reusing a tag in flight, along with the requests in flight.
+    pub metrics: Arc<Metrics>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            lenient: false,
+            max_fids: 1 << 20,
+            // XATTR_SIZE_MAX of Linux
+            max_xattr_size: 64 * 1024,
+            max_locks: 1024,
+            metrics: Default::default(),
+        }
+    }
+}
+
+/// Number of requests rejected for exceeding a limit or reusing a tag, summed over all connections,
+/// and the requests in flight on each open connection.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    fids: AtomicU64,
+    walks: AtomicU64,
+    xattrs: AtomicU64,
+    locks: AtomicU64,
+    tags: AtomicU64,
+    // Tags of the connections served with this config, dropped once they close
+    connections: std::sync::Mutex<Vec<Weak<Tags>>>,
+}
+
+impl Metrics {
+    /// Requests which would have created a fid beyond `Config::max_fids`.
+    pub fn fid_limit_hits(&self) -> u64 {
+        self.fids.load(Ordering::Relaxed)
+    }
+
+    /// Walks with more than `MAXWELEM` elements.
+    pub fn walk_limit_hits(&self) -> u64 {
+        self.walks.load(Ordering::Relaxed)
+    }
+
+    /// Xattr creations larger than `Config::max_xattr_size`.
+    pub fn xattr_limit_hits(&self) -> u64 {
+        self.xattrs.load(Ordering::Relaxed)
+    }
+
+    /// Locks which would have exceeded `Config::max_locks`.
+    pub fn lock_limit_hits(&self) -> u64 {
+        self.locks.load(Ordering::Relaxed)
+    }
+
+    /// Requests dropped unanswered for using the tag of a request still in flight.
+    pub fn duplicate_tags(&self) -> u64 {
+        self.tags.load(Ordering::Relaxed)
+    }
+
+    /// Tags and types of the requests in flight, for debugging.
+    ///
+    /// One list, sorted by tag, for each open connection in the order they were accepted.
+    /// The type is `None` for requests of an unknown type, which are answered with an error.
+    pub fn outstanding_tags(&self) -> Vec<Vec<(u16, Option<MsgType>)>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|tags| tags.strong_count() > 0);
+        connections
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|tags| tags.outstanding())
+            .collect()
+    }
+
+    fn connected(&self, tags: &Arc<Tags>) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|tags| tags.strong_count() > 0);
+        connections.push(Arc::downgrade(tags));
+    }
+}
+
+// Count a request rejected by a limit
+fn limit_hit(counter: &AtomicU64, errno: nix::errno::Errno) -> error::Error {
+    counter.fetch_add(1, Ordering::Relaxed);
+    error::Error::No(errno)
+}
+
+// State shared by the requests of a connection
+struct Conn<T> {
+    config: Arc<Config>,
+    fids: RwLock<HashMap<u32, Fid<T>>>,
+    // Unlimited until Tversion negotiates it
+    msize: AtomicU32,
+    // Locks held through all fids
+    locks: AtomicUsize,
+    tags: Arc<Tags>,
+}
+
+// Requests in flight on a connection, by tag
+#[derive(Default)]
+struct Tags {
+    inflight: std::sync::Mutex<HashMap<u16, InFlight>>,
+}
+
+struct InFlight {
+    typ: Option<MsgType>,
+    // Decoded again only if the request is flushed
+    frame: Bytes,
+    // Closed once the request has been answered
+    answered: watch::Receiver<()>,
+}
+
+// Held while a request is served, releasing its tag when dropped
+struct TagGuard {
+    tags: Arc<Tags>,
+    tag: u16,
+    _answered: watch::Sender<()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        self.tags.inflight.lock().unwrap().remove(&self.tag);
+    }
+}
+
+impl Tags {
+    // Reserve the tag of a new request,
+    // or return None if a request in flight already uses it
+    fn begin(self: &Arc<Self>, tag: u16, frame: &Bytes) -> Option<TagGuard> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.contains_key(&tag) {
+            return None;
+        }
+
+        let (tx, rx) = watch::channel(());
+        let typ = MsgType::from_u8(frame[0]);
+        let frame = frame.clone();
+        inflight.insert(
+            tag,
+            InFlight {
+                typ,
+                frame,
+                answered: rx,
+            },
+        );
+        Some(TagGuard {
+            tags: self.clone(),
+            tag,
+            _answered: tx,
+        })
+    }
+
+    // The frame of the request in flight with the tag
+    fn request(&self, tag: u16) -> Option<Bytes> {
+        let inflight = self.inflight.lock().unwrap();
+        inflight.get(&tag).map(|r| r.frame.clone())
+    }
+
+    // Wait until the request with the tag, if any, has been answered
+    async fn answered(&self, tag: u16) {
+        let answered = self
+            .inflight
+            .lock()
+            .unwrap()
+            .get(&tag)
+            .map(|r| r.answered.clone());
+        if let Some(mut answered) = answered {
+            // Fails once the sender is dropped along with the TagGuard
+            while answered.changed().await.is_ok() {}
+        }
+    }
+
+    // Tags and types of the requests in flight, sorted by tag
+    fn outstanding(&self) -> Vec<(u16, Option<MsgType>)> {
+        let mut tags: Vec<_> = self
+            .inflight
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tag, r)| (*tag, r.typ))
+            .collect();
+        tags.sort_unstable_by_key(|(tag, _)| *tag);
+        tags
+    }
 }
 
 #[async_trait]
 /// Filesystem server trait.
 ///
 /// Implementors can represent an error condition by returning an `Err`.
-/// Otherwise, they must return `Fcall` with the required fields filled.
+/// Otherwise, they return the response type of the method, e.g. `Rgetattr` from `rgetattr`,
+/// which the server turns into the `Fcall` sent to the client.
+/// Implementations written against the untyped interface can be served through `Compat`.
 ///
 /// The default implementation, returning EOPNOTSUPP error, is provided to the all methods
 /// except Rversion.
@@ -59,59 +390,67 @@ impl<T> Fid<T> {
 /// 9P2000.L
 pub trait Filesystem: Send {
     /// User defined fid type to be associated with a client's fid.
-    type Fid: Send + Sync + Default;
+    ///
+    /// The value is constructed by the method creating the fid
+    /// (`rattach`, `rauth`, `rwalk` or `rxattrwalk`), which returns it alongside the response.
+    type Fid: Send + Sync;
 
     // 9P2000.L
-    async fn rstatfs(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rstatfs(&self, _: &Fid<Self::Fid>) -> Result<Rstatfs> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rlopen(&self, _: &Fid<Self::Fid>, _flags: u32) -> Result<Fcall> {
+    async fn rlopen(&self, _: &Fid<Self::Fid>, _flags: LOpenFlags) -> Result<Rlopen> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rlcreate(
         &self,
         _: &Fid<Self::Fid>,
-        _name: &str,
-        _flags: u32,
-        _mode: u32,
+        _name: &OsStr,
+        _flags: LOpenFlags,
+        _mode: FileMode,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rlcreate> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rsymlink(
         &self,
         _: &Fid<Self::Fid>,
-        _name: &str,
-        _sym: &str,
+        _name: &OsStr,
+        _sym: &OsStr,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rsymlink> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rmknod(
         &self,
         _: &Fid<Self::Fid>,
-        _name: &str,
-        _mode: u32,
+        _name: &OsStr,
+        _mode: FileMode,
         _major: u32,
         _minor: u32,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rmknod> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rrename(&self, _: &Fid<Self::Fid>, _: &Fid<Self::Fid>, _name: &str) -> Result<Fcall> {
+    async fn rrename(
+        &self,
+        _: &Fid<Self::Fid>,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+    ) -> Result<Rrename> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rreadlink(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rreadlink(&self, _: &Fid<Self::Fid>) -> Result<Rreadlink> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rgetattr(&self, _: &Fid<Self::Fid>, _req_mask: GetattrMask) -> Result<Fcall> {
+    async fn rgetattr(&self, _: &Fid<Self::Fid>, _req_mask: GetattrMask) -> Result<Rgetattr> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
@@ -120,70 +459,75 @@ pub trait Filesystem: Send {
         _: &Fid<Self::Fid>,
         _valid: SetattrMask,
         _stat: &SetAttr,
-    ) -> Result<Fcall> {
+    ) -> Result<Rsetattr> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rxattrwalk(
         &self,
         _: &Fid<Self::Fid>,
-        _: &Fid<Self::Fid>,
-        _name: &str,
-    ) -> Result<Fcall> {
+        _newfid: u32,
+        _name: &OsStr,
+    ) -> Result<(Rxattrwalk, Self::Fid)> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rxattrcreate(
         &self,
         _: &Fid<Self::Fid>,
-        _name: &str,
+        _name: &OsStr,
         _attr_size: u64,
-        _flags: u32,
-    ) -> Result<Fcall> {
+        _flags: XattrFlags,
+    ) -> Result<Rxattrcreate> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rreaddir(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Fcall> {
+    async fn rreaddir(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Rreaddir> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rfsync(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rfsync(&self, _: &Fid<Self::Fid>) -> Result<Rfsync> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rlock(&self, _: &Fid<Self::Fid>, _lock: &Flock) -> Result<Fcall> {
+    async fn rlock(&self, _: &Fid<Self::Fid>, _lock: &Flock) -> Result<Rlock> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rgetlock(&self, _: &Fid<Self::Fid>, _lock: &Getlock) -> Result<Fcall> {
+    async fn rgetlock(&self, _: &Fid<Self::Fid>, _lock: &Getlock) -> Result<Rgetlock> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rlink(&self, _: &Fid<Self::Fid>, _: &Fid<Self::Fid>, _name: &str) -> Result<Fcall> {
+    async fn rlink(&self, _: &Fid<Self::Fid>, _: &Fid<Self::Fid>, _name: &OsStr) -> Result<Rlink> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rmkdir(
         &self,
         _: &Fid<Self::Fid>,
-        _name: &str,
-        _mode: u32,
+        _name: &OsStr,
+        _mode: FileMode,
         _gid: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<Rmkdir> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rrenameat(
         &self,
         _: &Fid<Self::Fid>,
-        _oldname: &str,
+        _oldname: &OsStr,
         _: &Fid<Self::Fid>,
-        _newname: &str,
-    ) -> Result<Fcall> {
+        _newname: &OsStr,
+    ) -> Result<Rrenameat> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn runlinkat(&self, _: &Fid<Self::Fid>, _name: &str, _flags: u32) -> Result<Fcall> {
+    async fn runlinkat(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _flags: UnlinkatFlags,
+    ) -> Result<Runlinkat> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
@@ -192,59 +536,62 @@ pub trait Filesystem: Send {
      */
     async fn rauth(
         &self,
-        _: &Fid<Self::Fid>,
+        _afid: u32,
         _uname: &str,
         _aname: &str,
         _n_uname: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<(Rauth, Self::Fid)> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rattach(
         &self,
-        _: &Fid<Self::Fid>,
+        _fid: u32,
         _afid: Option<&Fid<Self::Fid>>,
         _uname: &str,
         _aname: &str,
         _n_uname: u32,
-    ) -> Result<Fcall> {
+    ) -> Result<(Rattach, Self::Fid)> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     /*
      * 9P2000 subset
      */
-    async fn rflush(&self, _old: Option<&Fcall>) -> Result<Fcall> {
+    /// Called when a request still in flight is flushed, so that it can be cut short.
+    /// `old` is the flushed request, e.g. the `Tread` waiting on a pipe.
+    /// The server answers Rflush once the flushed request has been answered, whatever this returns.
+    async fn rflush(&self, _old: Option<&Fcall>) -> Result<Rflush> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
     async fn rwalk(
         &self,
         _: &Fid<Self::Fid>,
-        _new: &Fid<Self::Fid>,
-        _wnames: &[String],
-    ) -> Result<Fcall> {
+        _newfid: u32,
+        _wnames: &[&OsStr],
+    ) -> Result<(Rwalk, Self::Fid)> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rread(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Fcall> {
+    async fn rread(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Rread> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rwrite(&self, _: &Fid<Self::Fid>, _offset: u64, _data: &Data) -> Result<Fcall> {
+    async fn rwrite(&self, _: &Fid<Self::Fid>, _offset: u64, _data: &[u8]) -> Result<Rwrite> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Rclunk> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rremove(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rremove(&self, _: &Fid<Self::Fid>) -> Result<Rremove> {
         Err(error::Error::No(EOPNOTSUPP))
     }
 
-    async fn rversion(&self, msize: u32, ver: &str) -> Result<Fcall> {
-        Ok(Fcall::Rversion {
+    async fn rversion(&self, msize: u32, ver: &str) -> Result<Rversion> {
+        Ok(Rversion {
             msize,
             version: match ver {
                 P92000L => ver.to_owned(),
@@ -256,81 +603,331 @@ pub trait Filesystem: Send {
 
 #[rustfmt::skip]
 async fn dispatch_once<Fs, FsFid>(
-    msg: &Msg,
+    msg: &MsgRef<'_>,
     fs: Arc<Fs>,
-    fsfids: Arc<RwLock<HashMap<u32, Fid<FsFid>>>>,
+    conn: &Conn<FsFid>,
 ) -> Result<Fcall>
 where
     Fs: Filesystem<Fid = FsFid> + Send + Sync,
-    FsFid: Send + Sync + Default,
+    FsFid: Send + Sync,
 {
-    let newfid = msg.body.newfid().map(|f| Fid {
-        fid: f,
-        aux: Default::default(),
-    });
+    use crate::FcallRef::*;
 
-    use crate::Fcall::*;
+    // Answered once the flushed request has been, without holding any fid
+    if let Tflush { oldtag } = msg.body {
+        let frame = match oldtag != msg.tag {
+            true => conn.tags.request(oldtag),
+            false => None,
+        };
+        if let Some(frame) = frame {
+            // Malformed requests are answered without reaching the filesystem
+            if let Ok(old) = serialize::decode_msg_ref(&frame) {
+                if let Err(e) = fs.rflush(Some(&Fcall::from(old.body))).await {
+                    debug!("Tflush: filesystem could not cut tag {} short: {}", oldtag, e);
+                }
+            }
+            conn.tags.answered(oldtag).await;
+        }
+        return Ok(Fcall::Rflush);
+    }
+
+    // The fid is released even if the filesystem fails to clunk or remove it
+    if let Tclunk { fid } | Tremove { fid } = msg.body {
+        let response = {
+            let fids = conn.fids.read().await;
+            let f = fids.get(&fid).ok_or(error::Error::No(EBADF))?;
+            match msg.body {
+                Tclunk { .. } => fs.rclunk(f).await.map(Fcall::from),
+                _ => fs.rremove(f).await.map(Fcall::from),
+            }
+        };
+        if let Some(f) = conn.fids.write().await.remove(&fid) {
+            conn.locks.fetch_sub(f.locks.len(), Ordering::Relaxed);
+        }
+        return response;
+    }
+
+    let os = OsStr::from_bytes;
+    let (mut wnames_os, flock_owned, getlock_owned);
+    // The aux of the fid created by this request, constructed by the filesystem
+    let mut newaux = None;
     let response = {
-        let fids = fsfids.read().await;
+        let fids = conn.fids.read().await;
         let get_fid = |fid: &u32| fids.get(fid).ok_or(error::Error::No(EBADF));
+        check_newfid(&msg.body, &fids)?;
+        if !conn.config.lenient {
+            check_fid_state(&msg.body, &fids)?;
+        }
+        check_limits(&msg.body, &fids, conn)?;
 
-        let fut = match msg.body {
-            Tstatfs { fid }                                                     => fs.rstatfs(get_fid(&fid)?),
-            Tlopen { fid, ref flags }                                           => fs.rlopen(get_fid(&fid)?, *flags),
-            Tlcreate { fid, ref name, ref flags, ref mode, ref gid }            => fs.rlcreate(get_fid(&fid)?, name, *flags, *mode, *gid),
-            Tsymlink { fid, ref name, ref symtgt, ref gid }                     => fs.rsymlink(get_fid(&fid)?, name, symtgt, *gid),
-            Tmknod { dfid, ref name, ref mode, ref major, ref minor, ref gid }  => fs.rmknod(get_fid(&dfid)?, name, *mode, *major, *minor, *gid),
-            Trename { fid, dfid, ref name }                                     => fs.rrename(get_fid(&fid)?, get_fid(&dfid)?, name),
-            Treadlink { fid }                                                   => fs.rreadlink(get_fid(&fid)?),
-            Tgetattr { fid, ref req_mask }                                      => fs.rgetattr(get_fid(&fid)?, *req_mask),
-            Tsetattr { fid, ref valid, ref stat }                               => fs.rsetattr(get_fid(&fid)?, *valid, stat),
-            Txattrwalk { fid, newfid: _, ref name }                             => fs.rxattrwalk(get_fid(&fid)?, newfid.as_ref().unwrap(), name),
-            Txattrcreate { fid, ref name, ref attr_size, ref flags }            => fs.rxattrcreate(get_fid(&fid)?, name, *attr_size, *flags),
-            Treaddir { fid, ref offset, ref count }                             => fs.rreaddir(get_fid(&fid)?, *offset, *count),
-            Tfsync { fid }                                                      => fs.rfsync(get_fid(&fid)?),
-            Tlock { fid, ref flock }                                            => fs.rlock(get_fid(&fid)?, flock),
-            Tgetlock { fid, ref flock }                                         => fs.rgetlock(get_fid(&fid)?, flock),
-            Tlink { dfid, fid, ref name }                                       => fs.rlink(get_fid(&dfid)?, get_fid(&fid)?, name),
-            Tmkdir { dfid, ref name, ref mode, ref gid }                        => fs.rmkdir(get_fid(&dfid)?, name, *mode, *gid),
-            Trenameat { olddirfid, ref oldname, newdirfid, ref newname }        => fs.rrenameat(get_fid(&olddirfid)?, oldname, get_fid(&newdirfid)?, newname),
-            Tunlinkat { dirfd, ref name, ref flags }                            => fs.runlinkat(get_fid(&dirfd)?, name, *flags) ,
-            Tauth { afid: _, ref uname, ref aname, ref n_uname }                => fs.rauth(newfid.as_ref().unwrap(), uname, aname, *n_uname),
-            Tattach { fid: _, afid: _, ref uname, ref aname, ref n_uname }      => fs.rattach(newfid.as_ref().unwrap(), None, uname, aname, *n_uname),
-            Tversion { ref msize, ref version }                                 => fs.rversion(*msize, version),
-            Tflush { oldtag: _ }                                                => fs.rflush(None),
-            Twalk { fid, newfid: _, ref wnames }                                => fs.rwalk(get_fid(&fid)?, newfid.as_ref().unwrap(), wnames),
-            Tread { fid, ref offset, ref count }                                => fs.rread(get_fid(&fid)?, *offset, *count),
-            Twrite { fid, ref offset, ref data }                                => fs.rwrite(get_fid(&fid)?, *offset, data),
-            Tclunk { fid }                                                      => fs.rclunk(get_fid(&fid)?),
-            Tremove { fid }                                                     => fs.rremove(get_fid(&fid)?),
+        match msg.body {
+            Tstatfs { fid }                                                     => fs.rstatfs(get_fid(&fid)?).await?.into(),
+            Tlopen { fid, flags }                                               => fs.rlopen(get_fid(&fid)?, flags).await?.into(),
+            Tlcreate { fid, name, flags, mode, gid }                            => fs.rlcreate(get_fid(&fid)?, os(name), flags, mode, gid).await?.into(),
+            Tsymlink { fid, name, symtgt, gid }                                 => fs.rsymlink(get_fid(&fid)?, os(name), os(symtgt), gid).await?.into(),
+            Tmknod { dfid, name, mode, major, minor, gid }                      => fs.rmknod(get_fid(&dfid)?, os(name), mode, major, minor, gid).await?.into(),
+            Trename { fid, dfid, name }                                         => fs.rrename(get_fid(&fid)?, get_fid(&dfid)?, os(name)).await?.into(),
+            Treadlink { fid }                                                   => fs.rreadlink(get_fid(&fid)?).await?.into(),
+            Tgetattr { fid, req_mask }                                          => fs.rgetattr(get_fid(&fid)?, req_mask).await?.into(),
+            Tsetattr { fid, valid, ref stat }                                   => fs.rsetattr(get_fid(&fid)?, valid, stat).await?.into(),
+            Txattrwalk { fid, newfid, name }                                    => created(&mut newaux, fs.rxattrwalk(get_fid(&fid)?, newfid, os(name)).await?),
+            Txattrcreate { fid, name, attr_size, flags }                        => fs.rxattrcreate(get_fid(&fid)?, os(name), attr_size, flags).await?.into(),
+            Treaddir { fid, offset, count }                                     => fs.rreaddir(get_fid(&fid)?, offset, count).await?.into(),
+            Tfsync { fid }                                                      => fs.rfsync(get_fid(&fid)?).await?.into(),
+            Tlock { fid, flock }                                                => {
+                flock_owned = Flock::from(flock);
+                fs.rlock(get_fid(&fid)?, &flock_owned).await?.into()
+            }
+            Tgetlock { fid, flock }                                             => {
+                getlock_owned = Getlock::from(flock);
+                fs.rgetlock(get_fid(&fid)?, &getlock_owned).await?.into()
+            }
+            Tlink { dfid, fid, name }                                           => fs.rlink(get_fid(&dfid)?, get_fid(&fid)?, os(name)).await?.into(),
+            Tmkdir { dfid, name, mode, gid }                                    => fs.rmkdir(get_fid(&dfid)?, os(name), mode, gid).await?.into(),
+            Trenameat { olddirfid, oldname, newdirfid, newname }                => fs.rrenameat(get_fid(&olddirfid)?, os(oldname), get_fid(&newdirfid)?, os(newname)).await?.into(),
+            Tunlinkat { dirfd, name, flags }                                    => fs.runlinkat(get_fid(&dirfd)?, os(name), flags).await?.into(),
+            Tauth { afid, uname, aname, n_uname }                               => created(&mut newaux, fs.rauth(afid, uname, aname, n_uname).await?),
+            Tattach { fid, afid, uname, aname, n_uname }                        => {
+                let afid = match afid {
+                    NOFID => None,
+                    afid => Some(get_fid(&afid)?),
+                };
+                created(&mut newaux, fs.rattach(fid, afid, uname, aname, n_uname).await?)
+            }
+            Tversion { msize, version }                                         => fs.rversion(msize, version).await?.into(),
+            Twalk { fid, newfid, ref wnames }                                   => {
+                wnames_os = [OsStr::new(""); MAXWELEM];
+                for (o, name) in wnames_os.iter_mut().zip(wnames.iter()) {
+                    *o = os(name);
+                }
+                created(&mut newaux, fs.rwalk(get_fid(&fid)?, newfid, &wnames_os[..wnames.len()]).await?)
+            }
+            Tread { fid, offset, count }                                        => fs.rread(get_fid(&fid)?, offset, count).await?.into(),
+            Twrite { fid, offset, data }                                        => fs.rwrite(get_fid(&fid)?, offset, data).await?.into(),
             _                                                                   => return Err(error::Error::No(EOPNOTSUPP)),
-        };
+        }
+    };
+
+    let changes_fids = matches!(
+        msg.body,
+        Tlopen { .. } | Tlcreate { .. } | Txattrcreate { .. } | Tlock { .. }
+    );
+    if changes_fids || newaux.is_some() {
+        let mut fids = conn.fids.write().await;
+        track_fid_state(&msg.body, &response, &mut fids, newaux, conn)?;
+    }
+
+    Ok(response)
+}
+
+// Reject a newfid which is already in use, unless a walk replaces its own fid
+fn check_newfid<T>(body: &FcallRef<'_>, fids: &HashMap<u32, Fid<T>>) -> Result<()> {
+    use crate::FcallRef::*;
 
-        fut.await?
+    let fid = match *body {
+        Twalk { fid, .. } | Txattrwalk { fid, .. } => Some(fid),
+        _ => None,
     };
+    match body.newfid() {
+        Some(newfid) if Some(newfid) != fid && fids.contains_key(&newfid) => {
+            Err(error::Error::No(EBADF))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Reject requests which are illegal in the current state of their fids.
+// Unknown fids are left to the dispatcher, which fails them with EBADF.
+#[rustfmt::skip]
+fn check_fid_state<T>(body: &FcallRef<'_>, fids: &HashMap<u32, Fid<T>>) -> Result<()> {
+    use crate::FcallRef::*;
+
+    let check = |fid: &u32, ok: fn(&Fid<T>) -> bool, errno| match fids.get(fid) {
+        Some(f) if !ok(f) => Err(error::Error::No(errno)),
+        _ => Ok(()),
+    };
+    let dir = |fid| check(fid, Fid::is_dir, ENOTDIR);
+    let not_dir = |fid| check(fid, |f| !f.is_dir(), EISDIR);
+    let opened = |fid| check(fid, |f| f.mode.is_some(), EBADF);
+    let closed = |fid| check(fid, |f| f.mode.is_none(), EBADF);
+
+    match *body {
+        Twalk { fid, ref wnames, .. }                   => {
+            closed(&fid)?;
+            if !wnames.is_empty() {
+                dir(&fid)?;
+            }
+            Ok(())
+        }
+        Tlopen { fid, flags }                           => {
+            closed(&fid)?;
+            if flags.access_mode() != LOpenFlags::RDONLY {
+                not_dir(&fid)?;
+            }
+            Ok(())
+        }
+        Tlcreate { fid, .. }                            => closed(&fid).and(dir(&fid)),
+        Txattrcreate { fid, .. }                        => closed(&fid),
+        Tread { fid, .. }                               => check(&fid, Fid::readable, EBADF).and(not_dir(&fid)),
+        Twrite { fid, .. }                              => check(&fid, Fid::writable, EBADF).and(not_dir(&fid)),
+        Treaddir { fid, .. }                            => opened(&fid).and(dir(&fid)),
+        Tfsync { fid } | Tlock { fid, .. } | Tgetlock { fid, .. } => opened(&fid),
+        Tsymlink { fid, .. }                            => dir(&fid),
+        Tmknod { dfid, .. } | Tmkdir { dfid, .. }       => dir(&dfid),
+        Trename { dfid, .. } | Tlink { dfid, .. }       => dir(&dfid),
+        Tunlinkat { dirfd, .. }                         => dir(&dirfd),
+        Trenameat { olddirfid, newdirfid, .. }          => dir(&olddirfid).and(dir(&newdirfid)),
+        _                                               => Ok(()),
+    }
+}
 
-    /* Drop the fid which the Tclunk contains */
-    if let Tclunk { fid } = msg.body {
-        let mut fids = fsfids.write().await;
-        fids.remove(&fid);
+// Reject requests which would take a connection beyond the configured limits.
+// Concurrent requests may all pass, so the fid limit is checked again on insertion.
+fn check_limits<T>(body: &FcallRef<'_>, fids: &HashMap<u32, Fid<T>>, conn: &Conn<T>) -> Result<()> {
+    use crate::FcallRef::*;
+
+    let (config, metrics) = (&conn.config, &conn.config.metrics);
+    match *body {
+        Txattrcreate { attr_size, .. } if attr_size > config.max_xattr_size => {
+            Err(limit_hit(&metrics.xattrs, E2BIG))
+        }
+        Tlock { fid, ref flock } => match fids.get(&fid) {
+            Some(f) => {
+                let mut locks = f.locks.clone();
+                apply_lock(&mut locks, flock);
+                let held = conn.locks.load(Ordering::Relaxed);
+                if locks.len() > f.locks.len()
+                    && held + locks.len() - f.locks.len() > config.max_locks
+                {
+                    return Err(limit_hit(&metrics.locks, ENOLCK));
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        },
+        _ => match body.newfid() {
+            Some(newfid) if !fids.contains_key(&newfid) && fids.len() >= config.max_fids => {
+                Err(limit_hit(&metrics.fids, EMFILE))
+            }
+            _ => Ok(()),
+        },
     }
+}
 
-    if let Some(newfid) = newfid {
-        let mut fids = fsfids.write().await;
-        fids.insert(newfid.fid, newfid);
+// Record how a successful request changed its fids
+fn track_fid_state<T>(
+    body: &FcallRef<'_>,
+    response: &Fcall,
+    fids: &mut HashMap<u32, Fid<T>>,
+    newaux: Option<T>,
+    conn: &Conn<T>,
+) -> Result<()> {
+    use crate::FcallRef::*;
+
+    match (body, response) {
+        (Tlopen { fid, flags }, Fcall::Rlopen { qid, .. })
+        | (Tlcreate { fid, flags, .. }, Fcall::Rlcreate { qid, .. }) => {
+            if let Some(f) = fids.get_mut(fid) {
+                f.qid_type = qid.typ;
+                f.mode = Some(*flags);
+            }
+        }
+        (Txattrcreate { fid, .. }, _) => {
+            if let Some(f) = fids.get_mut(fid) {
+                f.qid_type = QidType::FILE;
+                f.mode = Some(LOpenFlags::WRONLY);
+            }
+        }
+        (Tlock { fid, flock }, Fcall::Rlock { status }) if *status == LockStatus::SUCCESS => {
+            if let Some(f) = fids.get_mut(fid) {
+                conn.locks.fetch_sub(f.locks.len(), Ordering::Relaxed);
+                apply_lock(&mut f.locks, flock);
+                conn.locks.fetch_add(f.locks.len(), Ordering::Relaxed);
+            }
+        }
+        _ => {}
     }
 
-    Ok(response)
+    let (newfid, aux) = match (body.newfid(), newaux) {
+        (Some(newfid), Some(aux)) => (newfid, aux),
+        _ => return Ok(()),
+    };
+    // Concurrent requests may have passed the checks with the same newfid
+    check_newfid(body, fids)?;
+    if !fids.contains_key(&newfid) && fids.len() >= conn.config.max_fids {
+        return Err(limit_hit(&conn.config.metrics.fids, EMFILE));
+    }
+    let mut new = Fid::new(newfid, aux);
+    match (body, response) {
+        (Twalk { fid, wnames, .. }, Fcall::Rwalk { wqids }) => {
+            /* A walk which stops short leaves newfid unaffected */
+            if wqids.len() != wnames.len() {
+                return Ok(());
+            }
+            new.qid_type = match wqids.last() {
+                Some(qid) => qid.typ,
+                None => fids.get(fid).map_or(QidType::FILE, |f| f.qid_type),
+            };
+        }
+        (_, Fcall::Rattach { qid }) => new.qid_type = qid.typ,
+        // Authentication is carried out by reading and writing the afid without opening it
+        (_, Fcall::Rauth { aqid }) => {
+            new.qid_type = aqid.typ;
+            new.mode = Some(LOpenFlags::RDWR);
+        }
+        // An xattr fid is ready to be read
+        (_, Fcall::Rxattrwalk { .. }) => new.mode = Some(LOpenFlags::RDONLY),
+        _ => {}
+    }
+    // A walk replacing its own fid releases the locks held through it
+    if let Some(old) = fids.insert(newfid, new) {
+        conn.locks.fetch_sub(old.locks.len(), Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+// Keep the aux of a newly created fid and pass the response on
+fn created<R: Into<Fcall>, T>(newaux: &mut Option<T>, (response, aux): (R, T)) -> Fcall {
+    *newaux = Some(aux);
+    response.into()
+}
+
+// Encode a response into an exactly sized buffer, refusing to exceed msize
+fn encode_response(msg: &Msg, msize: u32) -> Result<BytesMut> {
+    // size[4] is prepended by the codec
+    let len = msg.encoded_len();
+    if len + 4 > msize as usize {
+        return res!(io::Error::from(serialize::EncodeError::MessageTooLarge {
+            len: len + 4,
+            msize,
+        }));
+    }
+
+    let mut buf = BytesMut::with_capacity(len);
+    serialize::write_msg_buf(&mut buf, msg)?;
+    Ok(buf)
 }
 
-async fn dispatch<Fs, Reader, Writer>(filesystem: Fs, reader: Reader, writer: Writer) -> Result<()>
+async fn dispatch<Fs, Reader, Writer>(
+    filesystem: Fs,
+    config: Arc<Config>,
+    reader: Reader,
+    writer: Writer,
+) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync,
     Reader: 'static + AsyncRead + Send + std::marker::Unpin,
     Writer: 'static + AsyncWrite + Send + std::marker::Unpin,
 {
-    let fsfids = Arc::new(RwLock::new(HashMap::new()));
     let filesystem = Arc::new(filesystem);
+    let conn = Arc::new(Conn {
+        config,
+        fids: RwLock::new(HashMap::new()),
+        msize: AtomicU32::new(u32::MAX),
+        locks: AtomicUsize::new(0),
+        tags: Default::default(),
+    });
+    conn.config.metrics.connected(&conn.tags);
 
     let mut framedread = LengthDelimitedCodec::builder()
         .length_field_offset(0)
@@ -347,61 +944,140 @@ where
     let framedwrite = Arc::new(Mutex::new(framedwrite));
 
     while let Some(bytes) = framedread.next().await {
-        let bytes = bytes?;
+        let bytes = bytes?.freeze();
 
-        let msg = serialize::read_msg(&mut bytes.reader())?;
-        info!("\t← {:?}", msg);
+        // The codec strips size[4], so the frame starts with type[1] tag[2]
+        if bytes.len() < 3 {
+            return res!(io::Error::from(serialize::DecodeError::Truncated));
+        }
+        let tag = u16::from_le_bytes([bytes[1], bytes[2]]);
 
-        let fids = fsfids.clone();
+        let conn = conn.clone();
         let fs = filesystem.clone();
         let framedwrite = framedwrite.clone();
 
+        // NOTAG is reserved for Tversion
+        if tag == NOTAG && MsgType::from_u8(bytes[0]) != Some(MsgType::Tversion) {
+            error!("Tag {}: Error: only Tversion may use NOTAG", tag);
+            let ecode = EBADMSG as u32;
+            reply(&framedwrite, &conn, tag, Fcall::Rlerror { ecode }, None).await;
+            continue;
+        }
+        // A second reply would be taken for the reply to the request in flight,
+        // so a request reusing its tag is dropped unanswered
+        let inflight = match conn.tags.begin(tag, &bytes) {
+            Some(inflight) => inflight,
+            None => {
+                error!("Tag {}: Error: already in use, request dropped", tag);
+                conn.config.metrics.tags.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
         tokio::spawn(async move {
-            let response_fcall = dispatch_once(&msg, fs, fids).await.unwrap_or_else(|e| {
-                error!("{:?}: Error: \"{}\": {:?}", MsgType::from(&msg.body), e, e);
-                Fcall::Rlerror {
-                    ecode: e.errno() as u32,
+            // Decode in place; names and payloads borrow from the frame
+            let response_fcall = match serialize::decode_msg_ref(&bytes) {
+                Ok(msg) => {
+                    info!("\t← {}", msg);
+                    dispatch_once(&msg, fs, &conn).await.map_err(|e| {
+                        error!("{:?}: Error: \"{}\": {:?}", MsgType::from(&msg.body), e, e);
+                        e
+                    })
                 }
-            });
-
-            if MsgType::from(&response_fcall).is_r() {
-                let response = Msg {
-                    tag: msg.tag,
-                    body: response_fcall,
-                };
-
-                let mut writer = bytes::BytesMut::with_capacity(65535).writer();
-                serialize::write_msg(&mut writer, &response).unwrap();
-
-                {
-                    let mut framedwrite_locked = framedwrite.lock().await;
-                    framedwrite_locked
-                        .send(writer.into_inner().freeze())
-                        .await
-                        .unwrap();
+                Err(e) => {
+                    // Only walks carry element counts, which decoding bounds by MAXWELEM
+                    if let Some(serialize::DecodeError::TooManyElements { .. }) =
+                        e.get_ref().and_then(|e| e.downcast_ref())
+                    {
+                        conn.config.metrics.walks.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let e = error::Error::from(e);
+                    error!("Malformed message: \"{}\": {:?}", e, e);
+                    Err(e)
                 }
-                info!("\t→ {:?}", response);
             }
+            .unwrap_or_else(|e| Fcall::Rlerror {
+                ecode: e.errno() as u32,
+            });
+
+            reply(&framedwrite, &conn, tag, response_fcall, Some(inflight)).await;
         });
     }
 
+    let outstanding = conn.tags.outstanding();
+    if !outstanding.is_empty() {
+        debug!(
+            "Connection closed with requests in flight: {:?}",
+            outstanding
+        );
+    }
+
     Ok(())
 }
 
-async fn srv_async_tcp<Fs>(filesystem: Fs, addr: &str) -> Result<()>
+// Encode and send a response, falling back to Rlerror if it cannot be encoded
+async fn reply<W, T>(
+    framedwrite: &Mutex<FramedWrite<W, LengthDelimitedCodec>>,
+    conn: &Conn<T>,
+    tag: u16,
+    body: Fcall,
+    inflight: Option<TagGuard>,
+) where
+    W: AsyncWrite + std::marker::Unpin,
+{
+    let mut response = Msg { tag, body };
+
+    if let Fcall::Rversion { msize: m, .. } = response.body {
+        conn.msize.store(m, Ordering::Relaxed);
+    }
+
+    let frame = match encode_response(&response, conn.msize.load(Ordering::Relaxed)) {
+        Ok(frame) => frame,
+        Err(e) => {
+            // Report the failure instead of sending a corrupt or oversized frame
+            error!(
+                "{:?}: Error: \"{}\": {:?}",
+                MsgType::from(&response.body),
+                e,
+                e
+            );
+            response.body = Fcall::Rlerror {
+                ecode: e.errno() as u32,
+            };
+            encode_response(&response, u32::MAX).unwrap()
+        }
+    };
+
+    {
+        let mut framedwrite_locked = framedwrite.lock().await;
+        // The client may reuse the tag as soon as it sees the response,
+        // while a Tflush waiting for it can only reply after the response is sent
+        drop(inflight);
+        framedwrite_locked.send(frame.freeze()).await.unwrap();
+    }
+    info!("\t→ {}", response);
+}
+
+async fn srv_async_tcp<Fs>(filesystem: Fs, addr: &str, config: Config) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync + Clone,
 {
     let listener = TcpListener::bind(addr).await?;
+    let config = Arc::new(config);
 
     loop {
         let (stream, peer) = listener.accept().await?;
         info!("accepted: {:?}", peer);
+        // Replies to pipelined requests must not wait for the ACK of earlier ones
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("{:?}: could not set TCP_NODELAY: {}", peer, e);
+        }
 
         let fs = filesystem.clone();
+        let config = config.clone();
         tokio::spawn(async move {
             let (readhalf, writehalf) = stream.into_split();
-            let res = dispatch(fs, readhalf, writehalf).await;
+            let res = dispatch(fs, config, readhalf, writehalf).await;
             if let Err(e) = res {
                 error!("Error: {}: {:?}", e, e);
             }
@@ -410,19 +1086,28 @@ where
 }
 
 pub async fn srv_async_unix<Fs>(filesystem: Fs, addr: &str) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    srv_async_unix_with_config(filesystem, addr, Config::default()).await
+}
+
+async fn srv_async_unix_with_config<Fs>(filesystem: Fs, addr: &str, config: Config) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync + Clone,
 {
     let listener = UnixListener::bind(addr)?;
+    let config = Arc::new(config);
 
     loop {
         let (stream, peer) = listener.accept().await?;
         info!("accepted: {:?}", peer);
 
         let fs = filesystem.clone();
+        let config = config.clone();
         tokio::spawn(async move {
             let (readhalf, writehalf) = tokio::io::split(stream);
-            let res = dispatch(fs, readhalf, writehalf).await;
+            let res = dispatch(fs, config, readhalf, writehalf).await;
             if let Err(e) = res {
                 error!("Error: {:?}", e);
             }
@@ -431,6 +1116,13 @@ where
 }
 
 pub async fn srv_async<Fs>(filesystem: Fs, addr: &str) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    srv_async_with_config(filesystem, addr, Config::default()).await
+}
+
+pub async fn srv_async_with_config<Fs>(filesystem: Fs, addr: &str, config: Config) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync + Clone,
 {
@@ -438,8 +1130,564 @@ where
         .ok_or_else(|| io_err!(InvalidInput, "Invalid protocol or address"))?;
 
     match proto {
-        "tcp" => srv_async_tcp(filesystem, &listen_addr).await,
-        "unix" => srv_async_unix(filesystem, &listen_addr).await,
+        "tcp" => srv_async_tcp(filesystem, &listen_addr, config).await,
+        "unix" => srv_async_unix_with_config(filesystem, &listen_addr, config).await,
         _ => Err(From::from(io_err!(InvalidInput, "Protocol not supported"))),
     }
 }
+
+#[cfg(test)]
+struct TestFs;
+
+#[cfg(test)]
+#[async_trait]
+impl Filesystem for TestFs {
+    type Fid = ();
+
+    async fn rauth(&self, _: u32, _: &str, _: &str, _: u32) -> Result<(Rauth, ())> {
+        let aqid = Qid {
+            typ: QidType::AUTH,
+            version: 0,
+            path: 2,
+        };
+        Ok((Rauth { aqid }, ()))
+    }
+
+    // Only afids from Tauth authenticate
+    async fn rattach(
+        &self,
+        _: u32,
+        afid: Option<&Fid<()>>,
+        _: &str,
+        _: &str,
+        _: u32,
+    ) -> Result<(Rattach, ())> {
+        if matches!(afid, Some(f) if !f.qid_type().contains(QidType::AUTH)) {
+            return Err(error::Error::No(EACCES));
+        }
+        let qid = Qid {
+            typ: QidType::DIR,
+            version: 0,
+            path: 0,
+        };
+        Ok((Rattach { qid }, ()))
+    }
+
+    async fn rwalk(&self, _: &Fid<()>, _: u32, wnames: &[&OsStr]) -> Result<(Rwalk, ())> {
+        let wqids = wnames
+            .iter()
+            .map(|name| Qid {
+                typ: if *name == "file" {
+                    QidType::FILE
+                } else {
+                    QidType::DIR
+                },
+                version: 0,
+                path: 1,
+            })
+            .collect();
+        Ok((Rwalk { wqids }, ()))
+    }
+
+    async fn rlopen(&self, fid: &Fid<()>, _: LOpenFlags) -> Result<Rlopen> {
+        let qid = Qid {
+            typ: fid.qid_type(),
+            version: 0,
+            path: 1,
+        };
+        Ok(Rlopen { qid, iounit: 0 })
+    }
+
+    async fn rread(&self, _: &Fid<()>, _: u64, _: u32) -> Result<Rread> {
+        Ok(Rread { data: Data(vec![]) })
+    }
+
+    async fn rlock(&self, _: &Fid<()>, _: &Flock) -> Result<Rlock> {
+        Ok(Rlock {
+            status: LockStatus::SUCCESS,
+        })
+    }
+
+    async fn rclunk(&self, _: &Fid<()>) -> Result<Rclunk> {
+        Ok(Rclunk)
+    }
+
+    // Slow enough for requests sent after it to overtake it
+    async fn rversion(&self, msize: u32, _: &str) -> Result<Rversion> {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        Ok(Rversion {
+            msize,
+            version: P92000L.to_owned(),
+        })
+    }
+}
+
+// Serve TestFs over an in-memory stream, sending each batch of requests at once
+// and waiting for the given number of responses before the next batch
+#[cfg(test)]
+fn serve_test_fs_batches(config: Config, batches: Vec<(Vec<Msg>, usize)>) -> Vec<Msg> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (mut client, server) = tokio::io::duplex(8192);
+        let (reader, writer) = tokio::io::split(server);
+        tokio::spawn(dispatch(TestFs, Arc::new(config), reader, writer));
+
+        let mut responses = Vec::new();
+        for (batch, replies) in batches {
+            for msg in &batch {
+                let mut buf = Vec::new();
+                serialize::write_msg(&mut buf, msg).unwrap();
+                client.write_u32_le(buf.len() as u32 + 4).await.unwrap();
+                client.write_all(&buf).await.unwrap();
+            }
+
+            for _ in 0..replies {
+                let size = client.read_u32_le().await.unwrap();
+                let mut frame = vec![0; size as usize - 4];
+                client.read_exact(&mut frame).await.unwrap();
+                responses.push(serialize::decode_msg(&frame).unwrap());
+            }
+        }
+        responses
+    })
+}
+
+// Send each request in turn, all with the same tag
+#[cfg(test)]
+fn serve_test_fs(config: Config, requests: Vec<Fcall>) -> Vec<Fcall> {
+    let batches = requests
+        .into_iter()
+        .map(|body| (vec![Msg { tag: 1, body }], 1))
+        .collect();
+    serve_test_fs_batches(config, batches)
+        .into_iter()
+        .map(|msg| msg.body)
+        .collect()
+}
+
+#[cfg(test)]
+fn attach_and_walk(wname: &str) -> Vec<Fcall> {
+    vec![
+        Fcall::Tattach {
+            fid: 0,
+            afid: NOFID,
+            uname: "".into(),
+            aname: "".into(),
+            n_uname: 0,
+        },
+        Fcall::Twalk {
+            fid: 0,
+            newfid: 1,
+            wnames: vec![wname.into()],
+        },
+    ]
+}
+
+#[test]
+fn fid_state_is_enforced() {
+    let lopen = |fid, flags| Fcall::Tlopen { fid, flags };
+    let read = |fid| Fcall::Tread {
+        fid,
+        offset: 0,
+        count: 1,
+    };
+    let mut requests = attach_and_walk("file");
+    requests.extend(vec![
+        read(1),
+        Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["x".into()],
+        },
+        lopen(1, LOpenFlags::RDONLY),
+        lopen(1, LOpenFlags::RDONLY),
+        Fcall::Twrite {
+            fid: 1,
+            offset: 0,
+            data: Data(vec![0]),
+        },
+        Fcall::Treaddir {
+            fid: 1,
+            offset: 0,
+            count: 1,
+        },
+        Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec![],
+        },
+        read(1),
+        lopen(0, LOpenFlags::RDWR),
+    ]);
+
+    let errnos: Vec<_> = serve_test_fs(Config::default(), requests)
+        .into_iter()
+        .skip(2)
+        .map(|r| match r {
+            Fcall::Rlerror { ecode } => Some(nix::errno::Errno::from_i32(ecode as i32)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        errnos,
+        vec![
+            Some(EBADF),
+            Some(ENOTDIR),
+            None,
+            Some(EBADF),
+            Some(EBADF),
+            Some(ENOTDIR),
+            Some(EBADF),
+            None,
+            Some(EISDIR),
+        ]
+    );
+}
+
+#[test]
+fn lenient_config_skips_fid_state() {
+    let mut requests = attach_and_walk("file");
+    requests.push(Fcall::Tread {
+        fid: 1,
+        offset: 0,
+        count: 1,
+    });
+
+    let config = Config {
+        lenient: true,
+        ..Default::default()
+    };
+    let responses = serve_test_fs(config, requests);
+    assert_eq!(responses[2], Fcall::Rread { data: Data(vec![]) });
+}
+
+#[test]
+fn resource_limits_are_enforced() {
+    let config = Config {
+        max_fids: 2,
+        max_xattr_size: 16,
+        max_locks: 1,
+        ..Default::default()
+    };
+    let metrics = config.metrics.clone();
+    let walk = |newfid, n| Fcall::Twalk {
+        fid: 0,
+        newfid,
+        wnames: vec!["file".into(); n],
+    };
+    let lock = |typ, proc_id| Fcall::Tlock {
+        fid: 1,
+        flock: Flock {
+            typ,
+            flags: LockFlag::empty(),
+            start: 0,
+            length: 0,
+            proc_id,
+            client_id: "".into(),
+        },
+    };
+    let mut requests = attach_and_walk("file");
+    requests.extend(vec![
+        walk(2, 1),
+        walk(1, MAXWELEM + 1),
+        Fcall::Txattrcreate {
+            fid: 1,
+            name: "user.x".into(),
+            attr_size: 17,
+            flags: XattrFlags::empty(),
+        },
+        Fcall::Tlopen {
+            fid: 1,
+            flags: LOpenFlags::RDWR,
+        },
+        lock(LockType::WRLOCK, 1),
+        lock(LockType::RDLOCK, 1),
+        lock(LockType::WRLOCK, 2),
+        lock(LockType::UNLOCK, 1),
+        lock(LockType::WRLOCK, 2),
+        Fcall::Tclunk { fid: 1 },
+        walk(2, 1),
+    ]);
+
+    let errnos: Vec<_> = serve_test_fs(config, requests)
+        .into_iter()
+        .skip(2)
+        .map(|r| match r {
+            Fcall::Rlerror { ecode } => Some(nix::errno::Errno::from_i32(ecode as i32)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        errnos,
+        vec![
+            Some(EMFILE),
+            Some(E2BIG),
+            Some(E2BIG),
+            None,
+            None,
+            None,
+            Some(ENOLCK),
+            None,
+            None,
+            None,
+            None,
+        ]
+    );
+    assert_eq!(metrics.fid_limit_hits(), 1);
+    assert_eq!(metrics.walk_limit_hits(), 1);
+    assert_eq!(metrics.xattr_limit_hits(), 1);
+    assert_eq!(metrics.lock_limit_hits(), 1);
+}
+
+#[test]
+fn lock_ranges_are_counted_like_posix() {
+    let lock = |typ, start, length| FlockRef {
+        typ,
+        flags: LockFlag::empty(),
+        start,
+        length,
+        proc_id: 1,
+        client_id: "",
+    };
+    let mut locks = Vec::new();
+
+    apply_lock(&mut locks, &lock(LockType::WRLOCK, 0, 10));
+    apply_lock(&mut locks, &lock(LockType::WRLOCK, 0, 10));
+    apply_lock(&mut locks, &lock(LockType::WRLOCK, 10, 10));
+    assert_eq!(locks.len(), 1);
+
+    // Unlocking the middle splits the range, downgrading part of it adds another
+    apply_lock(&mut locks, &lock(LockType::UNLOCK, 5, 5));
+    assert_eq!(locks.len(), 2);
+    apply_lock(&mut locks, &lock(LockType::RDLOCK, 15, 0));
+    assert_eq!(locks.len(), 3);
+
+    apply_lock(&mut locks, &lock(LockType::UNLOCK, 0, 0));
+    assert!(locks.is_empty());
+}
+
+#[test]
+fn removed_fids_are_released() {
+    let config = Config {
+        max_fids: 2,
+        ..Default::default()
+    };
+    let mut requests = attach_and_walk("file");
+    for _ in 0..4 {
+        // TestFs cannot remove anything, which clunks the fid all the same
+        requests.push(Fcall::Tremove { fid: 1 });
+        requests.extend(attach_and_walk("file").into_iter().skip(1));
+    }
+
+    let responses = serve_test_fs(config, requests);
+    let eopnotsupp = Fcall::Rlerror {
+        ecode: EOPNOTSUPP as u32,
+    };
+    for pair in responses[2..].chunks(2) {
+        assert_eq!(pair[0], eopnotsupp);
+        assert!(matches!(pair[1], Fcall::Rwalk { .. }), "{:?}", pair[1]);
+    }
+}
+
+#[test]
+fn newfids_in_use_are_rejected() {
+    let config = Config {
+        max_locks: 1,
+        ..Default::default()
+    };
+    let lock = |fid| Fcall::Tlock {
+        fid,
+        flock: Flock {
+            typ: LockType::WRLOCK,
+            flags: LockFlag::empty(),
+            start: 0,
+            length: 0,
+            proc_id: 1,
+            client_id: "".into(),
+        },
+    };
+    let mut requests = attach_and_walk("file");
+    requests.extend(vec![
+        Fcall::Tlopen {
+            fid: 1,
+            flags: LOpenFlags::RDWR,
+        },
+        lock(1),
+        // Neither may replace fid 1 and forget its lock
+        Fcall::Twalk {
+            fid: 0,
+            newfid: 1,
+            wnames: vec!["file".into()],
+        },
+        Fcall::Tattach {
+            fid: 1,
+            afid: NOFID,
+            uname: "".into(),
+            aname: "".into(),
+            n_uname: 0,
+        },
+        // A walk may replace its own fid
+        Fcall::Twalk {
+            fid: 0,
+            newfid: 0,
+            wnames: vec![],
+        },
+        Fcall::Tclunk { fid: 1 },
+        Fcall::Twalk {
+            fid: 0,
+            newfid: 1,
+            wnames: vec!["file".into()],
+        },
+        Fcall::Tlopen {
+            fid: 1,
+            flags: LOpenFlags::RDWR,
+        },
+        lock(1),
+    ]);
+
+    let errnos: Vec<_> = serve_test_fs(config, requests)
+        .into_iter()
+        .skip(2)
+        .map(|r| match r {
+            Fcall::Rlerror { ecode } => Some(nix::errno::Errno::from_i32(ecode as i32)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        errnos,
+        vec![
+            None,
+            None,
+            Some(EBADF),
+            Some(EBADF),
+            None,
+            None,
+            None,
+            None,
+            None
+        ]
+    );
+}
+
+#[test]
+fn attach_passes_the_afid() {
+    let attach = |fid, afid| Fcall::Tattach {
+        fid,
+        afid,
+        uname: "".into(),
+        aname: "".into(),
+        n_uname: 0,
+    };
+    let requests = vec![
+        Fcall::Tauth {
+            afid: 1,
+            uname: "".into(),
+            aname: "".into(),
+            n_uname: 0,
+        },
+        attach(2, 1),
+        attach(3, 4),
+        attach(4, 2),
+    ];
+
+    let responses = serve_test_fs(Config::default(), requests);
+    assert!(
+        matches!(responses[1], Fcall::Rattach { .. }),
+        "{:?}",
+        responses[1]
+    );
+    let rlerror = |errno| Fcall::Rlerror {
+        ecode: errno as u32,
+    };
+    assert_eq!(responses[2..], [rlerror(EBADF), rlerror(EACCES)]);
+}
+
+#[test]
+fn outstanding_tags_are_exposed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = Config::default();
+    let metrics = config.metrics.clone();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (mut client, server) = tokio::io::duplex(8192);
+        let (reader, writer) = tokio::io::split(server);
+        let served = tokio::spawn(dispatch(TestFs, Arc::new(config), reader, writer));
+
+        // TestFs takes its time to answer Tversion
+        let mut buf = Vec::new();
+        let tversion = Msg {
+            tag: 7,
+            body: Fcall::Tversion {
+                msize: 8192,
+                version: P92000L.to_owned(),
+            },
+        };
+        serialize::write_msg(&mut buf, &tversion).unwrap();
+        client.write_u32_le(buf.len() as u32 + 4).await.unwrap();
+        client.write_all(&buf).await.unwrap();
+        while metrics.outstanding_tags() != [[(7, Some(MsgType::Tversion))]] {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        let size = client.read_u32_le().await.unwrap();
+        let mut frame = vec![0; size as usize - 4];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(metrics.outstanding_tags(), [[]]);
+
+        drop(client);
+        served.await.unwrap().unwrap();
+        assert!(metrics.outstanding_tags().is_empty());
+    });
+}
+
+#[test]
+fn tags_are_tracked_and_flushed() {
+    let version = |tag| Msg {
+        tag,
+        body: Fcall::Tversion {
+            msize: 8192,
+            version: P92000L.to_owned(),
+        },
+    };
+    let requests = vec![
+        version(1),
+        version(1),
+        Msg {
+            tag: NOTAG,
+            body: Fcall::Tclunk { fid: 0 },
+        },
+        Msg {
+            tag: 2,
+            body: Fcall::Tflush { oldtag: 1 },
+        },
+    ];
+    let ebadmsg = Fcall::Rlerror {
+        ecode: EBADMSG as u32,
+    };
+
+    // The second request with tag 1 is dropped
+    let config = Config::default();
+    let metrics = config.metrics.clone();
+    let responses: Vec<_> = serve_test_fs_batches(config, vec![(requests, 3)])
+        .into_iter()
+        .map(|msg| (msg.tag, msg.body))
+        .collect();
+    assert_eq!(metrics.duplicate_tags(), 1);
+    assert_eq!(
+        responses,
+        vec![
+            (NOTAG, ebadmsg),
+            (
+                1,
+                Fcall::Rversion {
+                    msize: 8192,
+                    version: P92000L.to_owned(),
+                }
+            ),
+            (2, Fcall::Rflush),
+        ]
+    );
+}
@@ -0,0 +1,513 @@
+//! Compatibility layer for filesystems returning untyped `Fcall` responses.
+
+use {
+    super::{response::*, Fid, Filesystem},
+    crate::{error, error::errno::*, fcall::*, utils::Result},
+    async_trait::async_trait,
+    nix::errno::Errno,
+    std::{convert::TryFrom, ffi::OsStr},
+};
+
+#[async_trait]
+/// Filesystem server trait with untyped responses.
+///
+/// This is the `Filesystem` interface as it was before responses were typed:
+/// every method returns a free-form `Fcall`, which must be the variant answering the request.
+/// Serve an implementation through `Compat`, which checks that at runtime.
+///
+/// The default implementation, returning EOPNOTSUPP error, is provided to the all methods
+/// except Rversion.
+/// The default implementation of Rversion returns a message accepting 9P2000.L.
+///
+/// # NOTE
+/// Defined as `Srv` in 9p.h of Plan 9.
+///
+/// # Protocol
+/// 9P2000.L
+pub trait FcallFilesystem: Send {
+    /// User defined fid type to be associated with a client's fid.
+    ///
+    /// New fids start out with `Default::default()`, filled in by the method creating them.
+    type Fid: Send + Sync + Default;
+
+    // 9P2000.L
+    async fn rstatfs(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rlopen(&self, _: &Fid<Self::Fid>, _flags: LOpenFlags) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rlcreate(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _flags: LOpenFlags,
+        _mode: FileMode,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rsymlink(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _sym: &OsStr,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rmknod(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _mode: FileMode,
+        _major: u32,
+        _minor: u32,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rrename(
+        &self,
+        _: &Fid<Self::Fid>,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rreadlink(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rgetattr(&self, _: &Fid<Self::Fid>, _req_mask: GetattrMask) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rsetattr(
+        &self,
+        _: &Fid<Self::Fid>,
+        _valid: SetattrMask,
+        _stat: &SetAttr,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rxattrwalk(
+        &self,
+        _: &Fid<Self::Fid>,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rxattrcreate(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _attr_size: u64,
+        _flags: XattrFlags,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rreaddir(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rfsync(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rlock(&self, _: &Fid<Self::Fid>, _lock: &Flock) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rgetlock(&self, _: &Fid<Self::Fid>, _lock: &Getlock) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rlink(&self, _: &Fid<Self::Fid>, _: &Fid<Self::Fid>, _name: &OsStr) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rmkdir(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _mode: FileMode,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rrenameat(
+        &self,
+        _: &Fid<Self::Fid>,
+        _oldname: &OsStr,
+        _: &Fid<Self::Fid>,
+        _newname: &OsStr,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn runlinkat(
+        &self,
+        _: &Fid<Self::Fid>,
+        _name: &OsStr,
+        _flags: UnlinkatFlags,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    /*
+     * 9P2000.u subset
+     */
+    async fn rauth(
+        &self,
+        _: &Fid<Self::Fid>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rattach(
+        &self,
+        _: &Fid<Self::Fid>,
+        _afid: Option<&Fid<Self::Fid>>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    /*
+     * 9P2000 subset
+     */
+    async fn rflush(&self, _old: Option<&Fcall>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rwalk(
+        &self,
+        _: &Fid<Self::Fid>,
+        _new: &Fid<Self::Fid>,
+        _wnames: &[&OsStr],
+    ) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rread(&self, _: &Fid<Self::Fid>, _offset: u64, _count: u32) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rwrite(&self, _: &Fid<Self::Fid>, _offset: u64, _data: &[u8]) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rremove(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+        Err(error::Error::No(EOPNOTSUPP))
+    }
+
+    async fn rversion(&self, msize: u32, ver: &str) -> Result<Fcall> {
+        Ok(Fcall::Rversion {
+            msize,
+            version: match ver {
+                P92000L => ver.to_owned(),
+                _ => VERSION_UNKNOWN.to_owned(),
+            },
+        })
+    }
+}
+
+/// Serves a `FcallFilesystem` as a `Filesystem`.
+///
+/// A method returning the wrong response variant, e.g. `Rlopen` from `rgetattr`,
+/// is logged and answered with EIO instead of being sent to the client as is.
+/// An `Rlerror` is passed through as the error it carries.
+#[derive(Clone, Debug, Default)]
+pub struct Compat<Fs>(pub Fs);
+
+// Check that an untyped response is the one the method should return
+fn expect<R>(res: Result<Fcall>) -> Result<R>
+where
+    R: TryFrom<Fcall, Error = Fcall>,
+{
+    match R::try_from(res?) {
+        Ok(r) => Ok(r),
+        Err(Fcall::Rlerror { ecode }) => Err(error::Error::No(Errno::from_i32(ecode as i32))),
+        Err(other) => {
+            error!(
+                "Filesystem returned {:?} where {} was expected",
+                MsgType::from(&other),
+                std::any::type_name::<R>()
+                    .rsplit("::")
+                    .next()
+                    .unwrap_or_default()
+            );
+            Err(error::Error::No(EIO))
+        }
+    }
+}
+
+// Untyped filesystems fill in a default aux through interior mutability
+fn new_fid<T: Default>(fid: u32) -> Fid<T> {
+    Fid::new(fid, Default::default())
+}
+
+#[async_trait]
+impl<Fs: FcallFilesystem + Sync> Filesystem for Compat<Fs> {
+    type Fid = Fs::Fid;
+
+    async fn rstatfs(&self, fid: &Fid<Self::Fid>) -> Result<Rstatfs> {
+        expect(self.0.rstatfs(fid).await)
+    }
+
+    async fn rlopen(&self, fid: &Fid<Self::Fid>, flags: LOpenFlags) -> Result<Rlopen> {
+        expect(self.0.rlopen(fid, flags).await)
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        flags: LOpenFlags,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<Rlcreate> {
+        expect(self.0.rlcreate(fid, name, flags, mode, gid).await)
+    }
+
+    async fn rsymlink(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        sym: &OsStr,
+        gid: u32,
+    ) -> Result<Rsymlink> {
+        expect(self.0.rsymlink(fid, name, sym, gid).await)
+    }
+
+    async fn rmknod(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        mode: FileMode,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<Rmknod> {
+        expect(self.0.rmknod(fid, name, mode, major, minor, gid).await)
+    }
+
+    async fn rrename(
+        &self,
+        fid: &Fid<Self::Fid>,
+        dfid: &Fid<Self::Fid>,
+        name: &OsStr,
+    ) -> Result<Rrename> {
+        expect(self.0.rrename(fid, dfid, name).await)
+    }
+
+    async fn rreadlink(&self, fid: &Fid<Self::Fid>) -> Result<Rreadlink> {
+        expect(self.0.rreadlink(fid).await)
+    }
+
+    async fn rgetattr(&self, fid: &Fid<Self::Fid>, req_mask: GetattrMask) -> Result<Rgetattr> {
+        expect(self.0.rgetattr(fid, req_mask).await)
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &Fid<Self::Fid>,
+        valid: SetattrMask,
+        stat: &SetAttr,
+    ) -> Result<Rsetattr> {
+        expect(self.0.rsetattr(fid, valid, stat).await)
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &Fid<Self::Fid>,
+        newfid: u32,
+        name: &OsStr,
+    ) -> Result<(Rxattrwalk, Self::Fid)> {
+        let new = new_fid(newfid);
+        let r = expect(self.0.rxattrwalk(fid, &new, name).await)?;
+        Ok((r, new.aux))
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        attr_size: u64,
+        flags: XattrFlags,
+    ) -> Result<Rxattrcreate> {
+        expect(self.0.rxattrcreate(fid, name, attr_size, flags).await)
+    }
+
+    async fn rreaddir(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<Rreaddir> {
+        expect(self.0.rreaddir(fid, offset, count).await)
+    }
+
+    async fn rfsync(&self, fid: &Fid<Self::Fid>) -> Result<Rfsync> {
+        expect(self.0.rfsync(fid).await)
+    }
+
+    async fn rlock(&self, fid: &Fid<Self::Fid>, lock: &Flock) -> Result<Rlock> {
+        expect(self.0.rlock(fid, lock).await)
+    }
+
+    async fn rgetlock(&self, fid: &Fid<Self::Fid>, lock: &Getlock) -> Result<Rgetlock> {
+        expect(self.0.rgetlock(fid, lock).await)
+    }
+
+    async fn rlink(
+        &self,
+        dfid: &Fid<Self::Fid>,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+    ) -> Result<Rlink> {
+        expect(self.0.rlink(dfid, fid, name).await)
+    }
+
+    async fn rmkdir(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        mode: FileMode,
+        gid: u32,
+    ) -> Result<Rmkdir> {
+        expect(self.0.rmkdir(fid, name, mode, gid).await)
+    }
+
+    async fn rrenameat(
+        &self,
+        olddir: &Fid<Self::Fid>,
+        oldname: &OsStr,
+        newdir: &Fid<Self::Fid>,
+        newname: &OsStr,
+    ) -> Result<Rrenameat> {
+        expect(self.0.rrenameat(olddir, oldname, newdir, newname).await)
+    }
+
+    async fn runlinkat(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &OsStr,
+        flags: UnlinkatFlags,
+    ) -> Result<Runlinkat> {
+        expect(self.0.runlinkat(fid, name, flags).await)
+    }
+
+    async fn rauth(
+        &self,
+        afid: u32,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<(Rauth, Self::Fid)> {
+        let new = new_fid(afid);
+        let r = expect(self.0.rauth(&new, uname, aname, n_uname).await)?;
+        Ok((r, new.aux))
+    }
+
+    async fn rattach(
+        &self,
+        fid: u32,
+        afid: Option<&Fid<Self::Fid>>,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<(Rattach, Self::Fid)> {
+        let new = new_fid(fid);
+        let r = expect(self.0.rattach(&new, afid, uname, aname, n_uname).await)?;
+        Ok((r, new.aux))
+    }
+
+    async fn rflush(&self, old: Option<&Fcall>) -> Result<Rflush> {
+        expect(self.0.rflush(old).await)
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &Fid<Self::Fid>,
+        newfid: u32,
+        wnames: &[&OsStr],
+    ) -> Result<(Rwalk, Self::Fid)> {
+        let new = new_fid(newfid);
+        let r = expect(self.0.rwalk(fid, &new, wnames).await)?;
+        Ok((r, new.aux))
+    }
+
+    async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<Rread> {
+        expect(self.0.rread(fid, offset, count).await)
+    }
+
+    async fn rwrite(&self, fid: &Fid<Self::Fid>, offset: u64, data: &[u8]) -> Result<Rwrite> {
+        expect(self.0.rwrite(fid, offset, data).await)
+    }
+
+    async fn rclunk(&self, fid: &Fid<Self::Fid>) -> Result<Rclunk> {
+        expect(self.0.rclunk(fid).await)
+    }
+
+    async fn rremove(&self, fid: &Fid<Self::Fid>) -> Result<Rremove> {
+        expect(self.0.rremove(fid).await)
+    }
+
+    async fn rversion(&self, msize: u32, ver: &str) -> Result<Rversion> {
+        expect(self.0.rversion(msize, ver).await)
+    }
+}
+
+#[test]
+fn compat_rejects_mismatched_responses() {
+    struct Legacy;
+
+    #[async_trait]
+    impl FcallFilesystem for Legacy {
+        type Fid = ();
+
+        async fn rgetattr(&self, _: &Fid<()>, _: GetattrMask) -> Result<Fcall> {
+            Ok(Fcall::Rlopen {
+                qid: Qid::default(),
+                iounit: 0,
+            })
+        }
+
+        async fn rclunk(&self, _: &Fid<()>) -> Result<Fcall> {
+            Ok(Fcall::Rclunk)
+        }
+
+        async fn rremove(&self, _: &Fid<()>) -> Result<Fcall> {
+            Ok(Fcall::Rlerror {
+                ecode: EACCES as u32,
+            })
+        }
+    }
+
+    let fs = Compat(Legacy);
+    let fid = Fid::new(1, ());
+    futures::executor::block_on(async {
+        let e = fs.rgetattr(&fid, GetattrMask::ALL).await.unwrap_err();
+        assert_eq!(e.errno(), EIO);
+        assert_eq!(fs.rclunk(&fid).await.unwrap(), Rclunk);
+        assert_eq!(fs.rremove(&fid).await.unwrap_err().errno(), EACCES);
+        assert_eq!(fs.rstatfs(&fid).await.unwrap_err().errno(), EOPNOTSUPP);
+    });
+}
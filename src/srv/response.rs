@@ -0,0 +1,119 @@
+//! Typed responses returned by `Filesystem` methods.
+//!
+//! Each struct carries exactly the fields of the `Fcall` variant of the same name,
+//! so a method cannot reply with a message of the wrong type.
+
+use crate::fcall::*;
+use std::convert::TryFrom;
+
+macro_rules! responses {
+    (@def $(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub struct $name;
+    };
+    (@def $(#[$meta:meta])* $name:ident { $($field:ident: $ty:ty),* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub struct $name {
+            $(pub $field: $ty,)*
+        }
+    };
+    ($($(#[$meta:meta])* $name:ident $({ $($field:ident: $ty:ty),* $(,)? })?;)*) => {
+        $(
+            responses!(@def $(#[$meta])* $name $({ $($field: $ty),* })?);
+
+            impl From<$name> for Fcall {
+                fn from(r: $name) -> Fcall {
+                    let $name { $($($field),*)? } = r;
+                    Fcall::$name { $($($field),*)? }
+                }
+            }
+
+            /// Fails with the message itself if it is not the matching variant.
+            impl TryFrom<Fcall> for $name {
+                type Error = Fcall;
+
+                fn try_from(fcall: Fcall) -> Result<$name, Fcall> {
+                    match fcall {
+                        Fcall::$name { $($($field),*)? } => Ok($name { $($($field),*)? }),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+responses! {
+    // 9P2000.L
+    /// Response to `Tstatfs`
+    Rstatfs { statfs: Statfs };
+    /// Response to `Tlopen`
+    Rlopen { qid: Qid, iounit: u32 };
+    /// Response to `Tlcreate`
+    Rlcreate { qid: Qid, iounit: u32 };
+    /// Response to `Tsymlink`
+    Rsymlink { qid: Qid };
+    /// Response to `Tmknod`
+    Rmknod { qid: Qid };
+    /// Response to `Trename`
+    Rrename;
+    /// Response to `Treadlink`
+    Rreadlink { target: NineString };
+    /// Response to `Tgetattr`
+    Rgetattr { valid: GetattrMask, qid: Qid, stat: Stat };
+    /// Response to `Tsetattr`
+    Rsetattr;
+    /// Response to `Txattrwalk`
+    Rxattrwalk { size: u64 };
+    /// Response to `Txattrcreate`
+    Rxattrcreate;
+    /// Response to `Treaddir`
+    Rreaddir { data: DirEntryData };
+    /// Response to `Tfsync`
+    Rfsync;
+    /// Response to `Tlock`
+    Rlock { status: LockStatus };
+    /// Response to `Tgetlock`
+    Rgetlock { flock: Getlock };
+    /// Response to `Tlink`
+    Rlink;
+    /// Response to `Tmkdir`
+    Rmkdir { qid: Qid };
+    /// Response to `Trenameat`
+    Rrenameat;
+    /// Response to `Tunlinkat`
+    Runlinkat;
+
+    // 9P2000.u
+    /// Response to `Tauth`
+    Rauth { aqid: Qid };
+    /// Response to `Tattach`
+    Rattach { qid: Qid };
+
+    // 9P2000
+    /// Response to `Tversion`
+    Rversion { msize: u32, version: String };
+    /// Response to `Tflush`
+    Rflush;
+    /// Response to `Twalk`
+    Rwalk { wqids: Vec<Qid> };
+    /// Response to `Tread`
+    Rread { data: Data };
+    /// Response to `Twrite`
+    Rwrite { count: u32 };
+    /// Response to `Tclunk`
+    Rclunk;
+    /// Response to `Tremove`
+    Rremove;
+}
+
+#[test]
+fn responses_convert_to_their_variant_only() {
+    let fcall = Fcall::from(Rwrite { count: 5 });
+    assert_eq!(fcall, Fcall::Rwrite { count: 5 });
+    assert_eq!(Rwrite::try_from(fcall.clone()), Ok(Rwrite { count: 5 }));
+    assert_eq!(Rclunk::try_from(fcall.clone()), Err(fcall));
+    assert_eq!(Rclunk::try_from(Fcall::Rclunk), Ok(Rclunk));
+}
@@ -0,0 +1,130 @@
+//! The blocking client talking to the server over a unix socket, without a runtime of its own.
+
+mod common;
+
+use rs9p::client::blocking::{Client, File};
+use rs9p::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+#[test]
+fn create_write_read_and_list() {
+    let addr = common::serve();
+    let client = Client::connect(&addr).unwrap();
+    let root = client.attach("", "", 0).unwrap();
+    assert_eq!(root.qid().unwrap().typ, QidType::DIR);
+
+    root.mkdir("dir", FileMode::from_bits(0o755), 0).unwrap();
+    let dir = root.walk(&["dir"]).unwrap();
+    let mut file = dir.walk::<&str>(&[]).unwrap();
+    file.lcreate("hello", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+        .unwrap();
+    assert_eq!(file.write(0, b"hello, world").unwrap(), 12);
+    assert_eq!(file.read(7, 100).unwrap(), b"world");
+    let (_, _, stat) = file.getattr(GetattrMask::BASIC).unwrap();
+    assert_eq!(stat.size, 12);
+    file.clunk().unwrap();
+
+    let mut listing = dir.walk::<&str>(&[]).unwrap();
+    listing
+        .lopen(LOpenFlags::RDONLY | LOpenFlags::DIRECTORY)
+        .unwrap();
+    let names: Vec<_> = listing
+        .readdir(0, client.iounit())
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    assert!(names.iter().any(|name| *name == "hello"));
+
+    let err = root.walk(&["dir", "missing"]).unwrap_err();
+    assert_eq!(err.errno(), errno::ENOENT);
+    dir.unlinkat("hello", UnlinkatFlags::empty()).unwrap();
+    assert_eq!(dir.walk(&["hello"]).unwrap_err().errno(), errno::ENOENT);
+}
+
+#[test]
+fn files_split_and_seek() {
+    let addr = common::serve();
+    // A small msize forces reads and writes over several requests
+    let client = Client::connect_with_msize(&addr, 4096 + IOHDRSZ).unwrap();
+    let root = client.attach("", "", 0).unwrap();
+
+    let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    let mut file = File::create(
+        root.walk::<&str>(&[]).unwrap(),
+        "big",
+        LOpenFlags::RDWR,
+        FileMode::from_bits(0o644),
+        0,
+    )
+    .unwrap();
+    file.write_all(&data).unwrap();
+    file.close().unwrap();
+
+    let mut file = File::open(root.walk(&["big"]).unwrap(), LOpenFlags::RDONLY).unwrap();
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 19990);
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, &data[19990..]);
+}
+
+#[test]
+fn clients_are_shared_between_threads() {
+    let addr = common::serve();
+    let client = Client::connect(&addr).unwrap();
+    let root = client.attach("", "", 0).unwrap();
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let client = client.clone();
+            std::thread::spawn(move || {
+                let root = client.attach("", "", 0).unwrap();
+                let name = format!("file{}", i);
+                let mut file = root.walk::<&str>(&[]).unwrap();
+                file.lcreate(&name, LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+                    .unwrap();
+                file.write(0, name.as_bytes()).unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for i in 0..4 {
+        let name = format!("file{}", i);
+        let mut file = root.walk(&[&name]).unwrap();
+        file.lopen(LOpenFlags::RDONLY).unwrap();
+        assert_eq!(file.read(0, 100).unwrap(), name.as_bytes());
+    }
+}
+
+#[test]
+fn tiny_msizes_are_rejected() {
+    let addr = common::serve();
+    let e = Client::connect_with_msize(&addr, IOHDRSZ).err().unwrap();
+    assert_eq!(e.errno(), errno::EINVAL);
+}
+
+#[test]
+fn oversized_reads_are_rejected() {
+    let addr = common::serve_fs(common::GreedyFs);
+    let client = Client::connect(&addr).unwrap();
+    let mut file = File::open(client.attach("", "", 0).unwrap(), LOpenFlags::RDONLY).unwrap();
+    let mut buf = [0; 16];
+    let err = file.read(&mut buf).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(errno::EPROTO as i32));
+}
+
+#[test]
+fn oversized_writes_are_rejected() {
+    let addr = common::serve_fs(common::GreedyFs);
+    let client = Client::connect(&addr).unwrap();
+    let mut file = File::open(client.attach("", "", 0).unwrap(), LOpenFlags::WRONLY).unwrap();
+    let err = file.write_all(b"data").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(errno::EPROTO as i32));
+}
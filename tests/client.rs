@@ -0,0 +1,574 @@
+//! The async client talking to the server over a unix socket.
+
+mod common;
+
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use rs9p::client::{CachePolicy, Client, Fs, Reconnect, RemoteFile, DEFAULT_MSIZE};
+use rs9p::srv::{Fid, Filesystem, Rattach, Rflush, Rlopen, Rread, Rversion};
+use rs9p::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(f)
+}
+
+#[test]
+fn create_write_read_and_list() {
+    let addr = common::serve();
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        assert_eq!(root.qid().unwrap().typ, QidType::DIR);
+
+        root.mkdir("dir", FileMode::from_bits(0o755), 0)
+            .await
+            .unwrap();
+        let dir = root.walk(&["dir"]).await.unwrap();
+        let mut file = dir.walk::<&str>(&[]).await.unwrap();
+        file.lcreate("hello", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+            .await
+            .unwrap();
+        assert_eq!(file.write(0, b"hello, world").await.unwrap(), 12);
+        assert_eq!(file.read(7, 100).await.unwrap(), b"world");
+
+        let (_, qid, stat) = file.getattr(GetattrMask::BASIC).await.unwrap();
+        assert_eq!(qid.typ, QidType::FILE);
+        assert_eq!(stat.size, 12);
+        file.clunk().await.unwrap();
+
+        let mut listing = dir.walk::<&str>(&[]).await.unwrap();
+        listing.lopen(LOpenFlags::RDONLY).await.unwrap();
+        let names: Vec<_> = listing
+            .readdir(0, 4096)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec![NineString::from("hello")]);
+
+        let e = root.walk(&["dir", "missing"]).await.unwrap_err();
+        assert_eq!(e.errno(), errno::ENOENT);
+    });
+}
+
+#[test]
+fn long_walks_are_split() {
+    let addr = common::serve();
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+
+        let names: Vec<String> = (0..MAXWELEM + 4).map(|i| format!("d{}", i)).collect();
+        for depth in 0..names.len() {
+            let parent = root.walk(&names[..depth]).await.unwrap();
+            parent
+                .mkdir(&names[depth], FileMode::from_bits(0o755), 0)
+                .await
+                .unwrap();
+        }
+
+        let deepest = root.walk(&names).await.unwrap();
+        assert_eq!(deepest.qid().unwrap().typ, QidType::DIR);
+    });
+}
+
+#[test]
+fn concurrent_requests_are_multiplexed() {
+    let addr = common::serve();
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        let mut file = root.walk::<&str>(&[]).await.unwrap();
+        file.lcreate("data", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        file.write(0, &data).await.unwrap();
+
+        let reads = (0..64u64).map(|i| file.read(i, 1));
+        let bytes = futures::future::try_join_all(reads).await.unwrap();
+        assert_eq!(bytes.concat(), (0..64).collect::<Vec<u8>>());
+    });
+}
+
+#[test]
+fn remote_files_split_and_seek() {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    let addr = common::serve();
+    block_on(async {
+        // Small enough for a single write to take several Twrites
+        let client = Client::connect_with_msize(&addr, 4096).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        let dir = root.walk::<&str>(&[]).await.unwrap();
+        let mut file =
+            RemoteFile::create(dir, "big", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+                .await
+                .unwrap();
+        assert_eq!(file.iounit(), 4096 - IOHDRSZ);
+
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        tokio::io::copy(&mut &data[..], &mut file).await.unwrap();
+        file.flush().await.unwrap();
+
+        assert_eq!(file.seek(SeekFrom::Start(0)).await.unwrap(), 0);
+        let mut back = Vec::new();
+        file.read_to_end(&mut back).await.unwrap();
+        assert_eq!(back, data);
+
+        assert_eq!(file.seek(SeekFrom::End(-10)).await.unwrap(), 49_990);
+        let mut tail = [0; 10];
+        file.read_exact(&mut tail).await.unwrap();
+        assert_eq!(&tail[..], &data[49_990..]);
+
+        file.seek(SeekFrom::Current(-20)).await.unwrap();
+        file.write_all(b"xyz").await.unwrap();
+        file.seek(SeekFrom::Start(49_980)).await.unwrap();
+        let mut patched = [0; 3];
+        file.read_exact(&mut patched).await.unwrap();
+        assert_eq!(&patched, b"xyz");
+
+        file.close().await.unwrap();
+    });
+}
+
+#[test]
+fn remote_files_read_ahead_and_write_behind() {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    let addr = common::serve();
+    block_on(async {
+        let client = Client::connect_with_msize(&addr, 4096).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        let dir = root.walk::<&str>(&[]).await.unwrap();
+        let mut file =
+            RemoteFile::create(dir, "big", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+                .await
+                .unwrap();
+        file.set_write_behind(4);
+        file.set_read_ahead(4);
+
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        tokio::io::copy(&mut &data[..], &mut file).await.unwrap();
+        // Reads wait for the writes in flight
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut back = Vec::new();
+        file.read_to_end(&mut back).await.unwrap();
+        assert_eq!(back, data);
+
+        // Read ahead starts over where a seek lands
+        file.seek(SeekFrom::Start(10_000)).await.unwrap();
+        let mut some = [0; 100];
+        file.read_exact(&mut some).await.unwrap();
+        assert_eq!(&some[..], &data[10_000..10_100]);
+        file.write_all(b"xyz").await.unwrap();
+        assert_eq!(file.seek(SeekFrom::End(0)).await.unwrap(), 50_000);
+        file.seek(SeekFrom::Start(10_100)).await.unwrap();
+        let mut patched = [0; 3];
+        file.read_exact(&mut patched).await.unwrap();
+        assert_eq!(&patched, b"xyz");
+        file.close().await.unwrap();
+
+        // An error of a write behind shows up on flush
+        let mut file = RemoteFile::open(root.walk(&["big"]).await.unwrap(), LOpenFlags::RDWR)
+            .await
+            .unwrap();
+        file.set_write_behind(4);
+        root.unlinkat("big", UnlinkatFlags::empty()).await.unwrap();
+        assert_eq!(file.write(b"lost").await.unwrap(), 4);
+        let err = file.flush().await.unwrap_err();
+        assert_eq!(error::Error::from(err).errno(), errno::ENOENT);
+        file.close().await.unwrap();
+    });
+}
+
+#[test]
+fn fs_resolves_paths() {
+    use futures::stream::TryStreamExt;
+
+    let addr = common::serve();
+    block_on(async {
+        // Small enough for the listing to take several Treaddirs
+        let client = Client::connect_with_msize(&addr, 512).await.unwrap();
+        let fs = Fs::new(client.attach("", "", 0).await.unwrap(), 0);
+
+        fs.create_dir_all("/a/b/c", 0o755).await.unwrap();
+        fs.create_dir_all("a/b/c", 0o755).await.unwrap();
+        assert_eq!(
+            fs.metadata("a/b").await.unwrap().mode.file_type(),
+            Some(FileType::Directory)
+        );
+
+        for i in 0..40 {
+            let data = vec![b'x'; i];
+            fs.write_all(format!("a/b/c/file{:02}", i), &data)
+                .await
+                .unwrap();
+        }
+        fs.write_all("a/b/c/file03", b"replaced").await.unwrap();
+        assert_eq!(
+            fs.read_to_end("a/./b/../b/c/file03").await.unwrap(),
+            b"replaced"
+        );
+
+        let mut entries: Vec<_> = fs
+            .read_dir("a/b/c")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        entries.sort_by(|a, b| a.0.name.as_bytes().cmp(b.0.name.as_bytes()));
+        assert_eq!(entries.len(), 40);
+        assert_eq!(entries[39].0.name, "file39");
+        assert_eq!(entries[39].1.size, 39);
+
+        fs.symlink("b/c/file03", "a/link").await.unwrap();
+        assert_eq!(
+            fs.read_link("a/link").await.unwrap(),
+            std::path::Path::new("b/c/file03")
+        );
+        assert_eq!(fs.metadata("a/link").await.unwrap().size, 8);
+        assert_eq!(
+            fs.symlink_metadata("a/link")
+                .await
+                .unwrap()
+                .mode
+                .file_type(),
+            Some(FileType::Symlink)
+        );
+        assert_eq!(fs.read_to_end("a/link").await.unwrap(), b"replaced");
+
+        fs.rename("a/b/c/file03", "a/moved").await.unwrap();
+        assert_eq!(fs.read_to_end("a/moved").await.unwrap(), b"replaced");
+        let e = fs.metadata("a/link").await.unwrap_err();
+        assert_eq!(e.errno(), errno::ENOENT);
+
+        let e = fs.remove_dir("a/b").await.unwrap_err();
+        assert_eq!(e.errno(), errno::ENOTEMPTY);
+        fs.remove_dir_all("a").await.unwrap();
+        assert!(fs
+            .read_dir("/")
+            .await
+            .unwrap()
+            .try_next()
+            .await
+            .unwrap()
+            .is_none());
+    });
+}
+
+// Offers an msize too small for any payload
+#[derive(Clone)]
+struct TinyFs;
+
+#[async_trait]
+impl Filesystem for TinyFs {
+    type Fid = ();
+
+    async fn rversion(&self, _: u32, _: &str) -> rs9p::Result<Rversion> {
+        Ok(Rversion {
+            msize: IOHDRSZ,
+            version: P92000L.to_owned(),
+        })
+    }
+}
+
+#[test]
+fn tiny_msizes_are_rejected() {
+    let addr = common::serve();
+    let tiny = common::serve_fs(TinyFs);
+    block_on(async {
+        let e = Client::connect_with_msize(&addr, IOHDRSZ)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.errno(), errno::EINVAL);
+        let e = Client::connect(&tiny).await.err().unwrap();
+        assert_eq!(e.errno(), errno::EPROTO);
+    });
+}
+
+// Reads block until they are flushed
+#[derive(Clone, Default)]
+struct StuckFs {
+    flushed: Arc<Notify>,
+    flushes: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Filesystem for StuckFs {
+    type Fid = ();
+
+    async fn rattach(
+        &self,
+        _: u32,
+        _: Option<&Fid<()>>,
+        _: &str,
+        _: &str,
+        _: u32,
+    ) -> rs9p::Result<(Rattach, ())> {
+        let qid = Qid {
+            typ: QidType::FILE,
+            version: 0,
+            path: 0,
+        };
+        Ok((Rattach { qid }, ()))
+    }
+
+    async fn rlopen(&self, _: &Fid<()>, _: LOpenFlags) -> rs9p::Result<Rlopen> {
+        let qid = Qid {
+            typ: QidType::FILE,
+            version: 0,
+            path: 0,
+        };
+        Ok(Rlopen { qid, iounit: 0 })
+    }
+
+    async fn rread(&self, _: &Fid<()>, _: u64, _: u32) -> rs9p::Result<Rread> {
+        self.flushed.notified().await;
+        Err(Error::No(errno::EINTR))
+    }
+
+    // Only the stuck reads are cut short
+    async fn rflush(&self, old: Option<&Fcall>) -> rs9p::Result<Rflush> {
+        if let Some(Fcall::Tread { .. }) = old {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            self.flushed.notify_one();
+        }
+        Ok(Rflush)
+    }
+}
+
+#[test]
+fn dropped_requests_are_flushed() {
+    let fs = StuckFs::default();
+    let addr = common::serve_fs(fs.clone());
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let mut file = client.attach("", "", 0).await.unwrap();
+        file.lopen(LOpenFlags::RDONLY).await.unwrap();
+
+        let timeout = std::time::Duration::from_millis(20);
+        for _ in 0..3 {
+            tokio::time::timeout(timeout, file.read(0, 1))
+                .await
+                .unwrap_err();
+        }
+
+        // The connection stays usable while the flushes are answered
+        client.attach("", "", 0).await.unwrap();
+        while fs.flushes.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    });
+}
+
+#[test]
+fn resilient_clients_restore_fids() {
+    let addr = common::serve();
+    block_on(async {
+        let proxy = common::Proxy::start(&addr);
+        let policy = Reconnect {
+            initial_delay: std::time::Duration::from_millis(1),
+            ..Default::default()
+        };
+        let client = Client::connect_resilient(&proxy.addr, DEFAULT_MSIZE, policy)
+            .await
+            .unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+
+        let mut file = root.walk::<&str>(&[]).await.unwrap();
+        file.lcreate("file", LOpenFlags::RDWR, FileMode::from_bits(0o644), 0)
+            .await
+            .unwrap();
+        file.write(0, b"survives").await.unwrap();
+        root.mkdir("dir", FileMode::from_bits(0o755), 0)
+            .await
+            .unwrap();
+        let dir = root.walk(&["dir"]).await.unwrap();
+        root.unlinkat("dir", UnlinkatFlags::REMOVEDIR)
+            .await
+            .unwrap();
+
+        proxy.cut();
+
+        // Reads are retried on the new connection with the file opened again
+        assert_eq!(file.read(0, 100).await.unwrap(), b"survives");
+        file.write(8, b"!").await.unwrap();
+        assert_eq!(file.read(0, 100).await.unwrap(), b"survives!");
+
+        let e = dir.getattr(GetattrMask::BASIC).await.unwrap_err();
+        assert_eq!(e.errno(), errno::ESTALE);
+        dir.clunk().await.unwrap();
+
+        proxy.cut();
+
+        // Creating is not repeated, the caller decides
+        let e = root
+            .mkdir("again", FileMode::from_bits(0o755), 0)
+            .await
+            .unwrap_err();
+        assert_eq!(e.errno(), errno::ECONNRESET);
+        root.mkdir("again", FileMode::from_bits(0o755), 0)
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn appending_writes_are_not_retried() {
+    let fs = common::MemFs::new();
+    let addr = common::serve_fs(fs.clone());
+    block_on(async {
+        let proxy = Arc::new(common::Proxy::start(&addr));
+        let policy = Reconnect {
+            initial_delay: std::time::Duration::from_millis(1),
+            ..Default::default()
+        };
+        let client = Client::connect_resilient(&proxy.addr, DEFAULT_MSIZE, policy)
+            .await
+            .unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        let mut file = root.walk::<&str>(&[]).await.unwrap();
+        let flags = LOpenFlags::WRONLY | LOpenFlags::APPEND;
+        file.lcreate("log", flags, FileMode::from_bits(0o644), 0)
+            .await
+            .unwrap();
+
+        // The connection is lost after the server appended, before it answered
+        let cut = proxy.clone();
+        *fs.on_write.lock().unwrap() = Some(Box::new(move || cut.cut()));
+        let e = file.write(0, b"once").await.unwrap_err();
+        assert_eq!(e.errno(), errno::ECONNRESET);
+        assert_eq!(fs.writes.load(Ordering::Relaxed), 1);
+
+        // The fid itself is still reopened and usable
+        file.write(4, b"!").await.unwrap();
+        assert_eq!(fs.writes.load(Ordering::Relaxed), 2);
+    });
+}
+
+#[test]
+fn cached_attributes_are_invalidated() {
+    let fs = common::MemFs::new();
+    let addr = common::serve_fs(fs.clone());
+    let getattrs = || fs.getattrs.load(Ordering::Relaxed);
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let policy = CachePolicy {
+            attr_ttl: std::time::Duration::from_secs(60),
+            walk_ttl: std::time::Duration::from_secs(60),
+        };
+        let cached = Fs::new(
+            client.with_cache(policy).attach("", "", 0).await.unwrap(),
+            0,
+        );
+        let uncached = Fs::new(client.attach("", "", 0).await.unwrap(), 0);
+
+        cached.create_dir("dir", 0o755).await.unwrap();
+        cached.write_all("dir/file", b"12345").await.unwrap();
+        let entries: Vec<_> = cached
+            .read_dir("dir")
+            .await
+            .unwrap()
+            .map(|e| e.unwrap().1.size)
+            .collect()
+            .await;
+        assert_eq!(entries, vec![5]);
+
+        // Answered from what the listing learned
+        let before = getattrs();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 5);
+        assert_eq!(cached.symlink_metadata("dir/file").await.unwrap().size, 5);
+        assert_eq!(getattrs(), before);
+
+        // Writing through the cache drops the stale size
+        cached.write_all("dir/file", b"1234567").await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 7);
+
+        // A write elsewhere goes unnoticed until a newer qid version shows up
+        uncached.write_all("dir/file", b"123").await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 7);
+        cached.open("dir/file", LOpenFlags::RDONLY).await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 3);
+
+        cached.remove_file("dir/file").await.unwrap();
+        let e = cached.metadata("dir/file").await.unwrap_err();
+        assert_eq!(e.errno(), errno::ENOENT);
+    });
+}
+
+#[test]
+fn data_version_changes_are_detected() {
+    let addr = common::serve();
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let policy = CachePolicy {
+            attr_ttl: std::time::Duration::from_secs(60),
+            walk_ttl: std::time::Duration::from_secs(60),
+        };
+        let caching = client.with_cache(policy);
+        let cached = Fs::new(caching.attach("", "", 0).await.unwrap(), 0);
+        let uncached = Fs::new(client.attach("", "", 0).await.unwrap(), 0);
+
+        cached.create_dir("dir", 0o755).await.unwrap();
+        cached.write_all("dir/file", b"12345").await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 5);
+        let dir = cached.root().walk(&["dir"]).await.unwrap();
+        dir.getattr(GetattrMask::BASIC).await.unwrap();
+
+        // Replacing the file elsewhere leaves the qid version of the directory alone
+        uncached.remove_file("dir/file").await.unwrap();
+        uncached.write_all("dir/file", b"123").await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 5);
+
+        // but not its data_version, which drops the names walked in it
+        dir.getattr(GetattrMask::ALL).await.unwrap();
+        assert_eq!(cached.metadata("dir/file").await.unwrap().size, 3);
+    });
+}
+
+#[test]
+fn oversized_reads_are_rejected() {
+    let addr = common::serve_fs(common::GreedyFs);
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let mut fid = client.attach("", "", 0).await.unwrap();
+        fid.lopen(LOpenFlags::RDONLY).await.unwrap();
+        let e = fid.read(0, 16).await.unwrap_err();
+        assert_eq!(e.errno(), errno::EPROTO);
+    });
+}
+
+#[test]
+fn oversized_writes_are_rejected() {
+    use tokio::io::AsyncWriteExt;
+
+    let addr = common::serve_fs(common::GreedyFs);
+    block_on(async {
+        let client = Client::connect(&addr).await.unwrap();
+        let root = client.attach("", "", 0).await.unwrap();
+        let mut fid = root.walk::<&str>(&[]).await.unwrap();
+        fid.lopen(LOpenFlags::WRONLY).await.unwrap();
+        let e = fid.write(0, b"data").await.unwrap_err();
+        assert_eq!(e.errno(), errno::EPROTO);
+
+        // An error rather than a count past the end of the buffer
+        let mut file = RemoteFile::open(root.walk::<&str>(&[]).await.unwrap(), LOpenFlags::WRONLY)
+            .await
+            .unwrap();
+        let res = async {
+            file.write_all(b"data").await?;
+            file.flush().await
+        };
+        let e = res.await.unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(errno::EPROTO as i32));
+    });
+}
@@ -0,0 +1,548 @@
+//! An in-memory filesystem served over a unix socket, for exercising the client.
+
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use rs9p::srv::*;
+use rs9p::*;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+enum Node {
+    Dir,
+    File(Vec<u8>),
+    Symlink(NineString),
+}
+
+struct Entry {
+    path: u64,
+    version: u32,
+    // Changed with the contents of a file or the names in a directory
+    data_version: u64,
+    node: Node,
+}
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
+pub struct MemFs {
+    tree: Arc<Mutex<BTreeMap<PathBuf, Entry>>>,
+    next_path: Arc<AtomicU64>,
+    /// Number of Tgetattr served
+    pub getattrs: Arc<AtomicU64>,
+    /// Number of Twrite served
+    pub writes: Arc<AtomicU64>,
+    /// Called once after the next Twrite is applied, before it is answered
+    pub on_write: Arc<Mutex<Option<Hook>>>,
+}
+
+impl MemFs {
+    pub fn new() -> MemFs {
+        let mut tree = BTreeMap::new();
+        tree.insert(
+            PathBuf::from("/"),
+            Entry {
+                path: 0,
+                version: 0,
+                data_version: 0,
+                node: Node::Dir,
+            },
+        );
+        MemFs {
+            tree: Arc::new(Mutex::new(tree)),
+            next_path: Arc::new(AtomicU64::new(1)),
+            getattrs: Default::default(),
+            writes: Default::default(),
+            on_write: Default::default(),
+        }
+    }
+
+    fn write(&self, fid: &Fid<MemFid>, offset: u64, data: &[u8]) -> Result<Rwrite> {
+        let mut tree = self.tree.lock().unwrap();
+        let mut entry = tree.get_mut(&fid.aux.path());
+        if let Some(entry) = entry.as_mut() {
+            entry.version += 1;
+            entry.data_version += 1;
+        }
+        match entry.map(|e| &mut e.node) {
+            Some(Node::File(content)) => {
+                let end = offset as usize + data.len();
+                if content.len() < end {
+                    content.resize(end, 0);
+                }
+                content[offset as usize..end].copy_from_slice(data);
+                Ok(Rwrite {
+                    count: data.len() as u32,
+                })
+            }
+            Some(Node::Dir) => Err(Error::No(errno::EISDIR)),
+            Some(Node::Symlink(_)) => Err(Error::No(errno::EINVAL)),
+            None => Err(Error::No(errno::ENOENT)),
+        }
+    }
+
+    fn insert(&self, path: PathBuf, node: Node) -> Result<Qid> {
+        let mut tree = self.tree.lock().unwrap();
+        if tree.contains_key(&path) {
+            return Err(Error::No(errno::EEXIST));
+        }
+        let entry = Entry {
+            path: self.next_path.fetch_add(1, Ordering::Relaxed),
+            version: 0,
+            data_version: 0,
+            node,
+        };
+        let qid = qid(&entry);
+        dir_changed(&mut tree, &path);
+        tree.insert(path, entry);
+        Ok(qid)
+    }
+
+    fn qid(&self, path: &Path) -> Result<Qid> {
+        let tree = self.tree.lock().unwrap();
+        tree.get(path).map(qid).ok_or(Error::No(errno::ENOENT))
+    }
+}
+
+fn qid(entry: &Entry) -> Qid {
+    Qid {
+        typ: match entry.node {
+            Node::Dir => QidType::DIR,
+            Node::File(_) => QidType::FILE,
+            Node::Symlink(_) => QidType::SYMLINK,
+        },
+        version: entry.version,
+        path: entry.path,
+    }
+}
+
+// Names in the directory of path changed, though its qid stays the same
+fn dir_changed(tree: &mut BTreeMap<PathBuf, Entry>, path: &Path) {
+    if let Some(dir) = path.parent().and_then(|dir| tree.get_mut(dir)) {
+        dir.data_version += 1;
+    }
+}
+
+fn children<'a>(
+    tree: &'a BTreeMap<PathBuf, Entry>,
+    dir: &'a Path,
+) -> impl Iterator<Item = (&'a PathBuf, &'a Entry)> {
+    tree.iter()
+        .filter(move |(path, _)| path.parent() == Some(dir))
+}
+
+pub struct MemFid {
+    path: Mutex<PathBuf>,
+}
+
+impl MemFid {
+    fn new(path: PathBuf) -> MemFid {
+        MemFid {
+            path: Mutex::new(path),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.path.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Filesystem for MemFs {
+    type Fid = MemFid;
+
+    async fn rattach(
+        &self,
+        _: u32,
+        _: Option<&Fid<MemFid>>,
+        _: &str,
+        _: &str,
+        _: u32,
+    ) -> Result<(Rattach, MemFid)> {
+        let qid = self.qid(Path::new("/"))?;
+        Ok((Rattach { qid }, MemFid::new("/".into())))
+    }
+
+    async fn rwalk(&self, fid: &Fid<MemFid>, _: u32, wnames: &[&OsStr]) -> Result<(Rwalk, MemFid)> {
+        let mut path = fid.aux.path();
+        let mut wqids = Vec::new();
+        for name in wnames {
+            path = match name.to_str() {
+                Some("..") => path.parent().unwrap_or(&path).to_owned(),
+                _ => path.join(name),
+            };
+            match self.qid(&path) {
+                Ok(qid) => wqids.push(qid),
+                Err(e) if wqids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok((Rwalk { wqids }, MemFid::new(path)))
+    }
+
+    async fn rlopen(&self, fid: &Fid<MemFid>, flags: LOpenFlags) -> Result<Rlopen> {
+        let path = fid.aux.path();
+        let mut tree = self.tree.lock().unwrap();
+        let entry = tree.get_mut(&path).ok_or(Error::No(errno::ENOENT))?;
+        if let (Node::File(data), true) = (&mut entry.node, flags.contains(LOpenFlags::TRUNC)) {
+            data.clear();
+        }
+        Ok(Rlopen {
+            qid: qid(entry),
+            iounit: 0,
+        })
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &Fid<MemFid>,
+        name: &OsStr,
+        _: LOpenFlags,
+        _: FileMode,
+        _: u32,
+    ) -> Result<Rlcreate> {
+        let path = fid.aux.path().join(name);
+        let qid = self.insert(path.clone(), Node::File(Vec::new()))?;
+        *fid.aux.path.lock().unwrap() = path;
+        Ok(Rlcreate { qid, iounit: 0 })
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &Fid<MemFid>,
+        name: &OsStr,
+        _: FileMode,
+        _: u32,
+    ) -> Result<Rmkdir> {
+        let qid = self.insert(dfid.aux.path().join(name), Node::Dir)?;
+        Ok(Rmkdir { qid })
+    }
+
+    async fn rread(&self, fid: &Fid<MemFid>, offset: u64, count: u32) -> Result<Rread> {
+        let tree = self.tree.lock().unwrap();
+        match tree.get(&fid.aux.path()).map(|e| &e.node) {
+            Some(Node::File(data)) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + count as usize).min(data.len());
+                Ok(Rread {
+                    data: Data(data[start..end].to_vec()),
+                })
+            }
+            Some(Node::Dir) => Err(Error::No(errno::EISDIR)),
+            Some(Node::Symlink(_)) => Err(Error::No(errno::EINVAL)),
+            None => Err(Error::No(errno::ENOENT)),
+        }
+    }
+
+    async fn rwrite(&self, fid: &Fid<MemFid>, offset: u64, data: &[u8]) -> Result<Rwrite> {
+        let res = self.write(fid, offset, data);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if let Some(hook) = self.on_write.lock().unwrap().take() {
+            hook();
+        }
+        res
+    }
+
+    async fn rgetattr(&self, fid: &Fid<MemFid>, _: GetattrMask) -> Result<Rgetattr> {
+        self.getattrs.fetch_add(1, Ordering::Relaxed);
+        let tree = self.tree.lock().unwrap();
+        let entry = tree.get(&fid.aux.path()).ok_or(Error::No(errno::ENOENT))?;
+        let (typ, size) = match entry.node {
+            Node::Dir => (FileType::Directory, 0),
+            Node::File(ref data) => (FileType::Regular, data.len() as u64),
+            Node::Symlink(ref target) => (FileType::Symlink, target.len() as u64),
+        };
+        let time = Time { sec: 0, nsec: 0 };
+        Ok(Rgetattr {
+            valid: GetattrMask::BASIC | GetattrMask::DATA_VERSION,
+            qid: qid(entry),
+            stat: Stat {
+                mode: FileMode::new(typ, 0o644),
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                rdev: 0,
+                size,
+                blksize: 4096,
+                blocks: size.div_ceil(512),
+                atime: time,
+                mtime: time,
+                ctime: time,
+                btime: time,
+                gen: 0,
+                data_version: entry.data_version,
+            },
+        })
+    }
+
+    async fn rsymlink(
+        &self,
+        dfid: &Fid<MemFid>,
+        name: &OsStr,
+        sym: &OsStr,
+        _: u32,
+    ) -> Result<Rsymlink> {
+        let qid = self.insert(dfid.aux.path().join(name), Node::Symlink(sym.into()))?;
+        Ok(Rsymlink { qid })
+    }
+
+    async fn rreadlink(&self, fid: &Fid<MemFid>) -> Result<Rreadlink> {
+        let tree = self.tree.lock().unwrap();
+        match tree.get(&fid.aux.path()).map(|e| &e.node) {
+            Some(Node::Symlink(target)) => Ok(Rreadlink {
+                target: target.clone(),
+            }),
+            Some(_) => Err(Error::No(errno::EINVAL)),
+            None => Err(Error::No(errno::ENOENT)),
+        }
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &Fid<MemFid>,
+        valid: SetattrMask,
+        stat: &SetAttr,
+    ) -> Result<Rsetattr> {
+        let mut tree = self.tree.lock().unwrap();
+        let entry = tree
+            .get_mut(&fid.aux.path())
+            .ok_or(Error::No(errno::ENOENT))?;
+        if let (Node::File(data), true) = (&mut entry.node, valid.contains(SetattrMask::SIZE)) {
+            data.resize(stat.size as usize, 0);
+        }
+        Ok(Rsetattr)
+    }
+
+    async fn rreaddir(&self, fid: &Fid<MemFid>, offset: u64, count: u32) -> Result<Rreaddir> {
+        let tree = self.tree.lock().unwrap();
+        let dir = fid.aux.path();
+        let mut data = DirEntryData::new();
+        for (i, (path, entry)) in children(&tree, &dir).enumerate().skip(offset as usize) {
+            let dirent = DirEntry {
+                qid: qid(entry),
+                offset: i as u64 + 1,
+                typ: 0,
+                name: path.file_name().unwrap().into(),
+            };
+            if data.size() + dirent.size() > count {
+                break;
+            }
+            data.push(dirent);
+        }
+        Ok(Rreaddir { data })
+    }
+
+    async fn runlinkat(
+        &self,
+        dirfid: &Fid<MemFid>,
+        name: &OsStr,
+        _: UnlinkatFlags,
+    ) -> Result<Runlinkat> {
+        let path = dirfid.aux.path().join(name);
+        let mut tree = self.tree.lock().unwrap();
+        if children(&tree, &path).next().is_some() {
+            return Err(Error::No(errno::ENOTEMPTY));
+        }
+        tree.remove(&path).ok_or(Error::No(errno::ENOENT))?;
+        dir_changed(&mut tree, &path);
+        Ok(Runlinkat)
+    }
+
+    async fn rrenameat(
+        &self,
+        olddir: &Fid<MemFid>,
+        oldname: &OsStr,
+        newdir: &Fid<MemFid>,
+        newname: &OsStr,
+    ) -> Result<Rrenameat> {
+        let (old, new) = (
+            olddir.aux.path().join(oldname),
+            newdir.aux.path().join(newname),
+        );
+        let mut tree = self.tree.lock().unwrap();
+        let moved: Vec<PathBuf> = tree
+            .keys()
+            .filter(|p| p.starts_with(&old))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(Error::No(errno::ENOENT));
+        }
+        for path in moved {
+            let entry = tree.remove(&path).unwrap();
+            tree.insert(new.join(path.strip_prefix(&old).unwrap()), entry);
+        }
+        dir_changed(&mut tree, &old);
+        dir_changed(&mut tree, &new);
+        Ok(Rrenameat)
+    }
+
+    async fn rstatfs(&self, _: &Fid<MemFid>) -> Result<Rstatfs> {
+        let files = self.tree.lock().unwrap().len() as u64;
+        Ok(Rstatfs {
+            statfs: Statfs {
+                typ: 0x01021997,
+                bsize: 4096,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            },
+        })
+    }
+
+    async fn rfsync(&self, _: &Fid<MemFid>) -> Result<Rfsync> {
+        Ok(Rfsync)
+    }
+
+    async fn rclunk(&self, _: &Fid<MemFid>) -> Result<Rclunk> {
+        Ok(Rclunk)
+    }
+
+    async fn rremove(&self, fid: &Fid<MemFid>) -> Result<Rremove> {
+        let path = fid.aux.path();
+        let mut tree = self.tree.lock().unwrap();
+        if children(&tree, &path).next().is_some() {
+            return Err(Error::No(errno::ENOTEMPTY));
+        }
+        tree.remove(&path).ok_or(Error::No(errno::ENOENT))?;
+        dir_changed(&mut tree, &path);
+        Ok(Rremove)
+    }
+}
+
+/// A single file whose reads return one byte more than asked for, and whose
+/// writes claim one byte more than they were given.
+#[derive(Clone)]
+pub struct GreedyFs;
+
+#[async_trait]
+impl Filesystem for GreedyFs {
+    type Fid = ();
+
+    async fn rattach(
+        &self,
+        _: u32,
+        _: Option<&Fid<()>>,
+        _: &str,
+        _: &str,
+        _: u32,
+    ) -> Result<(Rattach, ())> {
+        Ok((
+            Rattach {
+                qid: Qid::default(),
+            },
+            (),
+        ))
+    }
+
+    async fn rwalk(&self, _: &Fid<()>, _: u32, wnames: &[&OsStr]) -> Result<(Rwalk, ())> {
+        let wqids = vec![Qid::default(); wnames.len()];
+        Ok((Rwalk { wqids }, ()))
+    }
+
+    async fn rlopen(&self, _: &Fid<()>, _: LOpenFlags) -> Result<Rlopen> {
+        Ok(Rlopen {
+            qid: Qid::default(),
+            iounit: 0,
+        })
+    }
+
+    async fn rread(&self, _: &Fid<()>, _: u64, count: u32) -> Result<Rread> {
+        Ok(Rread {
+            data: Data(vec![0; count as usize + 1]),
+        })
+    }
+
+    async fn rwrite(&self, _: &Fid<()>, _: u64, data: &[u8]) -> Result<Rwrite> {
+        Ok(Rwrite {
+            count: data.len() as u32 + 1,
+        })
+    }
+}
+
+// A fresh path for a socket, which parse_proto turns into "path:0" given unix!path!0
+fn socket_path() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let mut path = OsString::from(std::env::temp_dir().join("rs9p-test-"));
+    path.push(format!(
+        "{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let path = path.into_string().unwrap();
+    let _ = std::fs::remove_file(format!("{}:0", path));
+    path
+}
+
+/// Serve a fresh `MemFs` on a unix socket of its own and return its address.
+pub fn serve() -> String {
+    serve_fs(MemFs::new())
+}
+
+pub fn serve_fs<Fs>(fs: Fs) -> String
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let path = socket_path();
+    let addr = format!("unix!{}!0", path);
+    let listen = addr.clone();
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(srv_async(fs, &listen))
+            .unwrap()
+    });
+
+    // Wait for the socket to be bound
+    while !Path::new(&format!("{}:0", path)).exists() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    addr
+}
+
+/// Forwards connections to a server and can cut them all, as a server restart would.
+pub struct Proxy {
+    pub addr: String,
+    conns: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+}
+
+impl Proxy {
+    /// Start forwarding to the server at `backend` on the current runtime.
+    pub fn start(backend: &str) -> Proxy {
+        let path = socket_path();
+        let listener = tokio::net::UnixListener::bind(format!("{}:0", path)).unwrap();
+        let backend = format!("{}:0", backend.split('!').nth(1).unwrap());
+        let conns: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>> = Default::default();
+
+        let accepted = conns.clone();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let backend = backend.clone();
+                accepted.lock().unwrap().push(tokio::spawn(async move {
+                    let mut server = tokio::net::UnixStream::connect(backend).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                }));
+            }
+        });
+
+        Proxy {
+            addr: format!("unix!{}!0", path),
+            conns,
+        }
+    }
+
+    /// Close every connection forwarded so far.
+    pub fn cut(&self) {
+        for conn in self.conns.lock().unwrap().drain(..) {
+            conn.abort();
+        }
+    }
+}
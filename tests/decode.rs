@@ -0,0 +1,205 @@
+//! Property tests for decoding untrusted 9P frames.
+//!
+//! No input may panic the decoder, and no length or count read from the
+//! wire may cause an allocation larger than the frame itself justifies.
+
+use proptest::prelude::*;
+use rs9p::serialize::{
+    decode_msg, decode_msg_ref, read_msg, write_msg, write_msg_buf, DecodeError, Encodable,
+};
+use rs9p::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Tracks the largest single allocation made by the current thread
+struct TrackingAlloc;
+
+thread_local! {
+    static LARGEST: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for TrackingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = LARGEST.try_with(|l| l.set(l.get().max(layout.size())));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = LARGEST.try_with(|l| l.set(l.get().max(new_size)));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: TrackingAlloc = TrackingAlloc;
+
+// Decode a frame and return the largest allocation it caused
+fn decode_tracked(frame: &[u8]) -> (std::io::Result<Msg>, usize) {
+    LARGEST.with(|l| l.set(0));
+    let res = decode_msg(frame);
+    (res, LARGEST.with(|l| l.get()))
+}
+
+// Generous bound: growing buffers may double past the frame size, and
+// decoded structures carry some fixed overhead of their own
+fn allocation_bound(frame: &[u8]) -> usize {
+    4 * frame.len() + 4096
+}
+
+fn msg_type() -> impl Strategy<Value = u8> {
+    prop_oneof![
+        Just(MsgType::Tlcreate as u8),
+        Just(MsgType::Treaddir as u8),
+        Just(MsgType::Rreaddir as u8),
+        Just(MsgType::Twalk as u8),
+        Just(MsgType::Rwalk as u8),
+        Just(MsgType::Twrite as u8),
+        Just(MsgType::Rread as u8),
+        Just(MsgType::Tlock as u8),
+        Just(MsgType::Tattach as u8),
+        any::<u8>(),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_frames_never_panic(frame in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let (_, largest) = decode_tracked(&frame);
+        prop_assert!(largest <= allocation_bound(&frame));
+    }
+
+    #[test]
+    fn typed_frames_never_overallocate(
+        typ in msg_type(),
+        tag in any::<u16>(),
+        body in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let mut frame = vec![typ];
+        frame.extend_from_slice(&tag.to_le_bytes());
+        frame.extend_from_slice(&body);
+        let (_, largest) = decode_tracked(&frame);
+        prop_assert!(largest <= allocation_bound(&frame));
+    }
+
+    #[test]
+    fn huge_lengths_are_rejected_without_allocating(
+        len in (1u32 << 16)..,
+        payload in proptest::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let mut frame = vec![MsgType::Rread as u8, 0, 0];
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&payload);
+        let (res, largest) = decode_tracked(&frame);
+        let err = res.unwrap_err();
+        prop_assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<DecodeError>()),
+            Some(&DecodeError::LengthOverrun(len as usize))
+        );
+        prop_assert!(largest <= allocation_bound(&frame));
+    }
+
+    #[test]
+    fn walks_round_trip(
+        wnames in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 1..16), 0..=MAXWELEM),
+        fid in any::<u32>(),
+        newfid in any::<u32>(),
+    ) {
+        let wnames = wnames.into_iter().map(NineString::from).collect();
+        let msg = Msg { tag: 1, body: Fcall::Twalk { fid, newfid, wnames } };
+        let mut frame = Vec::new();
+        msg.encode(&mut frame).unwrap();
+        prop_assert_eq!(decode_msg(&frame).unwrap(), msg);
+    }
+
+    #[test]
+    fn borrowed_decode_matches_owned(
+        typ in msg_type(),
+        tag in any::<u16>(),
+        body in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let mut frame = vec![typ];
+        frame.extend_from_slice(&tag.to_le_bytes());
+        frame.extend_from_slice(&body);
+
+        LARGEST.with(|l| l.set(0));
+        let borrowed = decode_msg_ref(&frame);
+        let largest = LARGEST.with(|l| l.get());
+
+        match (borrowed, decode_msg(&frame)) {
+            (Ok(borrowed), Ok(owned)) => {
+                // A successful borrowed decode must not touch the heap
+                prop_assert_eq!(largest, 0);
+                prop_assert_eq!(Msg::from(borrowed), owned);
+            }
+            (Err(_), Err(_)) => {}
+            (borrowed, owned) => prop_assert!(false, "{:?} != {:?}", borrowed, owned),
+        }
+    }
+
+    #[test]
+    fn encodings_match_frame(
+        typ in msg_type(),
+        tag in any::<u16>(),
+        body in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let mut frame = vec![typ];
+        frame.extend_from_slice(&tag.to_le_bytes());
+        frame.extend_from_slice(&body);
+
+        // Decoding is strict, so a decoded message re-encodes to the same frame
+        if let Ok(msg) = decode_msg(&frame) {
+            prop_assert_eq!(msg.encoded_len(), frame.len());
+
+            let mut buf = Vec::new();
+            write_msg_buf(&mut buf, &msg).unwrap();
+            prop_assert_eq!(buf, frame);
+        }
+    }
+
+    #[test]
+    fn encoded_len_matches_encode(
+        typ in any::<u8>(),
+        tag in any::<u16>(),
+        body in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        // Zeros after the body end whatever it leaves open with empty fields
+        let mut stream = vec![typ];
+        stream.extend_from_slice(&tag.to_le_bytes());
+        stream.extend_from_slice(&body);
+        stream.extend_from_slice(&[0; 64]);
+
+        if let Ok(msg) = read_msg(&mut &stream[..]) {
+            assert_encodings_agree(&msg);
+        }
+    }
+}
+
+fn assert_encodings_agree(msg: &Msg) {
+    let mut buf = Vec::new();
+    let written = write_msg(&mut buf, msg).unwrap();
+    assert_eq!(written, buf.len());
+    assert_eq!(msg.encoded_len(), buf.len());
+
+    let mut bufmut = Vec::new();
+    write_msg_buf(&mut bufmut, msg).unwrap();
+    assert_eq!(bufmut, buf);
+}
+
+#[test]
+fn encoded_len_matches_encode_for_every_type() {
+    use num_traits::FromPrimitive;
+
+    for typ in 0..=u8::MAX {
+        // A stream of zeros decodes to any message, with empty fields
+        let mut stream = vec![typ, 0, 0];
+        stream.extend_from_slice(&[0; 256]);
+        match (MsgType::from_u8(typ), read_msg(&mut &stream[..])) {
+            (Some(MsgType::Tlerror) | None, res) => assert!(res.is_err()),
+            (Some(_), res) => assert_encodings_agree(&res.unwrap()),
+        }
+    }
+}
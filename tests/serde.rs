@@ -0,0 +1,150 @@
+//! JSON representation of protocol types with the `serde` feature
+
+#![cfg(feature = "serde")]
+
+use rs9p::serialize::decode_msg_ref;
+use rs9p::serialize::Encodable;
+use rs9p::*;
+use serde_json::json;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+fn round_trip(msg: &Msg) -> serde_json::Value {
+    let value = serde_json::to_value(msg).unwrap();
+    let back: Msg = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(msg, &back);
+    value
+}
+
+#[test]
+fn qid_path_is_hex_and_flags_are_names() {
+    let msg = Msg {
+        tag: 1,
+        body: Fcall::Rlopen {
+            qid: Qid {
+                typ: QidType::DIR | QidType::TMP,
+                version: 3,
+                path: 0xdead_beef,
+            },
+            iounit: 0,
+        },
+    };
+    assert_eq!(
+        round_trip(&msg),
+        json!({
+            "tag": 1,
+            "body": {
+                "Rlopen": {
+                    "qid": { "typ": ["DIR", "TMP"], "version": 3, "path": "0xdeadbeef" },
+                    "iounit": 0
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn named_constants_keep_their_names() {
+    let msg = Msg {
+        tag: 1,
+        body: Fcall::Tgetattr {
+            fid: 1,
+            req_mask: GetattrMask::ALL,
+        },
+    };
+    assert_eq!(
+        round_trip(&msg)["body"]["Tgetattr"]["req_mask"],
+        json!(["ALL"])
+    );
+
+    let qid = serde_json::to_value(Qid::default()).unwrap();
+    assert_eq!(qid["typ"], json!(["FILE"]));
+
+    let flags = LOpenFlags::NOACCESS | LOpenFlags::CREATE;
+    assert_eq!(
+        serde_json::to_value(flags).unwrap(),
+        json!(["NOACCESS", "CREATE"])
+    );
+}
+
+#[test]
+fn data_is_base64() {
+    let msg = Msg {
+        tag: 2,
+        body: Fcall::Rread {
+            data: Data(b"hello".to_vec()),
+        },
+    };
+    assert_eq!(round_trip(&msg)["body"]["Rread"]["data"], json!("aGVsbG8="));
+}
+
+#[test]
+fn names_fall_back_to_base64() {
+    let msg = Msg {
+        tag: 3,
+        body: Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".into(), OsStr::from_bytes(b"caf\xe9").into()],
+        },
+    };
+    assert_eq!(
+        round_trip(&msg)["body"]["Twalk"]["wnames"],
+        json!(["usr", { "base64": "Y2Fm6Q==" }])
+    );
+}
+
+#[test]
+fn unknown_flag_names_are_rejected() {
+    let value = json!({ "typ": ["DIR", "BOGUS"], "version": 0, "path": "0x0" });
+    assert!(serde_json::from_value::<Qid>(value).is_err());
+}
+
+#[test]
+fn borrowed_messages_serialize_like_owned() {
+    let msg = Msg {
+        tag: 4,
+        body: Fcall::Twrite {
+            fid: 1,
+            offset: 0,
+            data: Data(vec![1, 2, 3]),
+        },
+    };
+    let mut frame = Vec::new();
+    msg.encode(&mut frame).unwrap();
+    let borrowed = decode_msg_ref(&frame).unwrap();
+    assert_eq!(
+        serde_json::to_value(borrowed).unwrap(),
+        serde_json::to_value(&msg).unwrap()
+    );
+}
+
+#[test]
+fn modes_are_octal() {
+    let msg = Msg {
+        tag: 5,
+        body: Fcall::Tmkdir {
+            dfid: 1,
+            name: "dir".into(),
+            mode: FileMode::from_bits(0o755),
+            gid: 0,
+        },
+    };
+    assert_eq!(round_trip(&msg)["body"]["Tmkdir"]["mode"], json!("0o755"));
+}
+
+#[test]
+fn names_are_bytes_in_binary_formats() {
+    let msg = Msg {
+        tag: 6,
+        body: Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["usr".into(), OsStr::from_bytes(b"caf\xe9").into()],
+        },
+    };
+    let mut cbor = Vec::new();
+    ciborium::ser::into_writer(&msg, &mut cbor).unwrap();
+    let back: Msg = ciborium::de::from_reader(&cbor[..]).unwrap();
+    assert_eq!(msg, back);
+}